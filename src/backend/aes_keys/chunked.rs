@@ -0,0 +1,359 @@
+// Chunked AES-256-GCM container for large files
+//
+// Layout: container header (cipher Aes256GcmChunked) followed by segments of
+//   nonce 12 | ciphertext (chunk_size bytes, the last one may be shorter) | tag 16
+// Each segment is sealed with the header, its index and a final flag as
+// additional data, so segments cannot be reordered, dropped or truncated.
+// Segment `i` starts at HEADER_LEN + i * segment_len, which allows any
+// byte range to be decrypted without reading the rest of the file.
+use crate::backend::aes_keys::cipher::gcm::{generate_nonce, AesGcm, NONCE_LEN, TAG_LEN};
+use crate::backend::aes_keys::container::{Cipher, Header, Kdf, HEADER_LEN};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+
+// Default plaintext size of a segment
+pub const DEFAULT_CHUNK_SIZE: u32 = 64 * 1024;
+
+// Builds the additional data authenticated with a segment
+fn segment_aad(header: &[u8; HEADER_LEN], index: u64, last: bool) -> Vec<u8> {
+    let mut aad = Vec::with_capacity(HEADER_LEN + 9);
+    aad.extend_from_slice(header);
+    aad.extend_from_slice(&index.to_be_bytes());
+    aad.push(last as u8);
+    aad
+}
+
+/**
+ * Encrypts a stream of data into a chunked container.
+ *
+ * Data is buffered until a full segment is available, so memory usage is
+ * bounded by the chunk size whatever the size of the file.
+ */
+pub struct ChunkedWriter<W: Write> {
+    inner: W,
+    gcm: AesGcm,
+    header: [u8; HEADER_LEN],
+    chunk_size: usize,
+    index: u64,
+    buffer: Vec<u8>,
+}
+
+impl<W: Write> ChunkedWriter<W> {
+    /**
+     * Creates a writer and writes the container header.
+     *
+     * @param inner - Where the encrypted container is written.
+     * @param key - The encryption key.
+     * @param chunk_size - Plaintext size of each segment.
+     * @return io::Result<Self> - The writer, or the error raised while writing the header.
+     */
+    pub fn new(mut inner: W, key: &[u8], chunk_size: u32) -> io::Result<Self> {
+        let header = Header::new(Cipher::Aes256GcmChunked, Kdf::None, chunk_size).to_bytes();
+        inner.write_all(&header)?;
+        Ok(Self {
+            inner,
+            gcm: AesGcm::new(key),
+            header,
+            chunk_size: chunk_size as usize,
+            index: 0,
+            buffer: Vec::with_capacity(chunk_size as usize),
+        })
+    }
+
+    /**
+     * Encrypts and writes every full segment available.
+     *
+     * A segment is only flushed once more data follows it, because the last
+     * segment has to be sealed with the final flag.
+     *
+     * @param data - The next plaintext bytes.
+     */
+    pub fn write(&mut self, data: &[u8]) -> io::Result<()> {
+        self.buffer.extend_from_slice(data);
+        while self.buffer.len() > self.chunk_size {
+            let rest = self.buffer.split_off(self.chunk_size);
+            let segment = std::mem::replace(&mut self.buffer, rest);
+            self.write_segment(&segment, false)?;
+        }
+        Ok(())
+    }
+
+    /**
+     * Writes the final segment and returns the inner writer.
+     *
+     * @return io::Result<W> - The inner writer.
+     */
+    pub fn finish(mut self) -> io::Result<W> {
+        let segment = std::mem::take(&mut self.buffer);
+        self.write_segment(&segment, true)?;
+        self.inner.flush()?;
+        Ok(self.inner)
+    }
+
+    // Seals one segment under its own random nonce
+    fn write_segment(&mut self, plaintext: &[u8], last: bool) -> io::Result<()> {
+        let nonce = generate_nonce();
+        let aad = segment_aad(&self.header, self.index, last);
+        let sealed = self.gcm.seal(&nonce, &aad, plaintext);
+        self.inner.write_all(&nonce)?;
+        self.inner.write_all(&sealed)?;
+        self.index += 1;
+        Ok(())
+    }
+}
+
+/**
+ * Random-access reader over a chunked container.
+ */
+pub struct ChunkedReader<R: Read + Seek> {
+    inner: R,
+    gcm: AesGcm,
+    header: [u8; HEADER_LEN],
+    chunk_size: u64,
+    segment_count: u64,
+    plaintext_len: u64,
+}
+
+impl<R: Read + Seek> ChunkedReader<R> {
+    /**
+     * Reads the header of a chunked container and computes its layout.
+     *
+     * @param inner - The encrypted container.
+     * @param key - The decryption key.
+     * @return Result<Self, String> - The reader or an error message.
+     */
+    pub fn open(mut inner: R, key: &[u8]) -> Result<Self, String> {
+        let mut header = [0u8; HEADER_LEN];
+        inner.seek(SeekFrom::Start(0)).map_err(|e| e.to_string())?;
+        inner
+            .read_exact(&mut header)
+            .map_err(|_| "Truncated header".to_string())?;
+
+        let parsed = match Header::parse(&header)? {
+            Some(parsed) if parsed.cipher == Cipher::Aes256GcmChunked => parsed,
+            _ => return Err("Not a chunked container".to_string()),
+        };
+        if parsed.chunk_size == 0 {
+            return Err("Invalid chunk size".to_string());
+        }
+
+        let file_len = inner.seek(SeekFrom::End(0)).map_err(|e| e.to_string())?;
+        let chunk_size = parsed.chunk_size as u64;
+        let segment_len = (NONCE_LEN + TAG_LEN) as u64 + chunk_size;
+        let body_len = file_len - HEADER_LEN as u64;
+
+        // Every container holds at least one (possibly empty) final segment
+        let segment_count = body_len.div_ceil(segment_len);
+        let last_len = body_len - (segment_count.max(1) - 1) * segment_len;
+        if segment_count == 0 || last_len < (NONCE_LEN + TAG_LEN) as u64 {
+            return Err("Truncated container".to_string());
+        }
+        let plaintext_len =
+            (segment_count - 1) * chunk_size + last_len - (NONCE_LEN + TAG_LEN) as u64;
+
+        Ok(Self {
+            inner,
+            gcm: AesGcm::new(key),
+            header,
+            chunk_size,
+            segment_count,
+            plaintext_len,
+        })
+    }
+
+    /// Returns the size of the decrypted content.
+    pub fn len(&self) -> u64 {
+        self.plaintext_len
+    }
+
+    /// Returns true if the decrypted content is empty.
+    pub fn is_empty(&self) -> bool {
+        self.plaintext_len == 0
+    }
+
+    /// Returns the number of segments in the container.
+    pub fn segment_count(&self) -> u64 {
+        self.segment_count
+    }
+
+    /// Returns the plaintext size of a full segment.
+    pub fn chunk_size(&self) -> u64 {
+        self.chunk_size
+    }
+
+    /**
+     * Reads and authenticates a single segment.
+     *
+     * @param index - The index of the segment.
+     * @return Result<Vec<u8>, String> - The plaintext of the segment or an error message.
+     */
+    pub fn read_segment(&mut self, index: u64) -> Result<Vec<u8>, String> {
+        if index >= self.segment_count {
+            return Err("Segment out of range".to_string());
+        }
+        let last = index + 1 == self.segment_count;
+        let segment_len = (NONCE_LEN + TAG_LEN) as u64 + self.chunk_size;
+        let plaintext_len = if last {
+            self.plaintext_len - index * self.chunk_size
+        } else {
+            self.chunk_size
+        };
+
+        let mut sealed = vec![0u8; NONCE_LEN + plaintext_len as usize + TAG_LEN];
+        self.inner
+            .seek(SeekFrom::Start(HEADER_LEN as u64 + index * segment_len))
+            .map_err(|e| e.to_string())?;
+        self.inner
+            .read_exact(&mut sealed)
+            .map_err(|e| e.to_string())?;
+
+        let (nonce, body) = sealed.split_at(NONCE_LEN);
+        let nonce: [u8; NONCE_LEN] = nonce.try_into().unwrap();
+        self.gcm
+            .open(&nonce, &segment_aad(&self.header, index, last), body)
+            .map_err(|e| format!("Segment {}: {}", index, e))
+    }
+
+    /**
+     * Decrypts the plaintext bytes in `[start, end)`, touching only the
+     * segments that overlap the range.
+     *
+     * @param start - First plaintext byte.
+     * @param end - One past the last plaintext byte.
+     * @return Result<Vec<u8>, String> - The requested bytes or an error message.
+     */
+    pub fn read_range(&mut self, start: u64, end: u64) -> Result<Vec<u8>, String> {
+        if start > end || end > self.plaintext_len {
+            return Err("Range out of bounds".to_string());
+        }
+        let mut output = Vec::with_capacity((end - start) as usize);
+        if start == end {
+            return Ok(output);
+        }
+        for index in start / self.chunk_size..=(end - 1) / self.chunk_size {
+            let segment = self.read_segment(index)?;
+            let segment_start = index * self.chunk_size;
+            let from = start.saturating_sub(segment_start) as usize;
+            let to = (end - segment_start).min(segment.len() as u64) as usize;
+            output.extend_from_slice(&segment[from..to]);
+        }
+        Ok(output)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    const KEY: [u8; 32] = [7u8; 32];
+    const CHUNK_SIZE: u32 = 16;
+    const SEGMENT_LEN: usize = NONCE_LEN + CHUNK_SIZE as usize + TAG_LEN;
+
+    fn seal(data: &[u8]) -> Vec<u8> {
+        let mut writer = ChunkedWriter::new(Vec::new(), &KEY, CHUNK_SIZE).unwrap();
+        // Uneven writes, segments must not depend on how the data arrives
+        for piece in data.chunks(5) {
+            writer.write(piece).unwrap();
+        }
+        writer.finish().unwrap()
+    }
+
+    fn open_all(sealed: Vec<u8>) -> Result<Vec<u8>, String> {
+        let mut reader = ChunkedReader::open(Cursor::new(sealed), &KEY)?;
+        let mut output = Vec::new();
+        for index in 0..reader.segment_count() {
+            output.extend_from_slice(&reader.read_segment(index)?);
+        }
+        assert_eq!(output.len() as u64, reader.len());
+        Ok(output)
+    }
+
+    fn sample(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i * 31) as u8).collect()
+    }
+
+    #[test]
+    fn round_trip() {
+        for (len, segments) in [(0, 1), (1, 1), (16, 1), (17, 2), (48, 3), (50, 4)] {
+            let data = sample(len);
+            let sealed = seal(&data);
+            let reader = ChunkedReader::open(Cursor::new(sealed.clone()), &KEY).unwrap();
+            assert_eq!(reader.segment_count(), segments, "{} bytes", len);
+            assert_eq!(reader.is_empty(), len == 0);
+            assert_eq!(open_all(sealed).unwrap(), data, "{} bytes", len);
+        }
+    }
+
+    #[test]
+    fn wrong_key_is_rejected() {
+        let sealed = seal(&sample(20));
+        let mut reader = ChunkedReader::open(Cursor::new(sealed), &[8u8; 32]).unwrap();
+        assert!(reader.read_segment(0).is_err());
+    }
+
+    #[test]
+    fn read_range_spans_segments() {
+        let data = sample(50);
+        let mut reader = ChunkedReader::open(Cursor::new(seal(&data)), &KEY).unwrap();
+        assert_eq!(reader.read_range(10, 40).unwrap(), data[10..40]);
+        assert_eq!(reader.read_range(15, 17).unwrap(), data[15..17]);
+        assert_eq!(reader.read_range(16, 32).unwrap(), data[16..32]);
+        assert_eq!(reader.read_range(48, 50).unwrap(), data[48..50]);
+        assert!(reader.read_range(20, 20).unwrap().is_empty());
+        assert!(reader.read_range(0, 51).is_err());
+        assert!(reader.read_range(30, 20).is_err());
+    }
+
+    #[test]
+    fn dropped_final_segment_is_rejected() {
+        // Both a full and a partial final segment
+        for len in [48, 40] {
+            let mut sealed = seal(&sample(len));
+            let last_start = HEADER_LEN + 2 * SEGMENT_LEN;
+            sealed.truncate(last_start);
+            assert!(open_all(sealed).is_err(), "{} bytes", len);
+        }
+        // Cut inside the header and inside the only segment
+        let sealed = seal(&sample(4));
+        assert!(open_all(sealed[..HEADER_LEN - 1].to_vec()).is_err());
+        assert!(open_all(sealed[..HEADER_LEN + NONCE_LEN].to_vec()).is_err());
+    }
+
+    #[test]
+    fn reordered_or_duplicated_segments_are_rejected() {
+        let sealed = seal(&sample(48));
+        let segment = |i: usize| {
+            let start = HEADER_LEN + i * SEGMENT_LEN;
+            sealed[start..start + SEGMENT_LEN].to_vec()
+        };
+        let rebuild = |order: &[usize]| {
+            let mut rebuilt = sealed[..HEADER_LEN].to_vec();
+            for &i in order {
+                rebuilt.extend_from_slice(&segment(i));
+            }
+            rebuilt
+        };
+        assert!(open_all(rebuild(&[0, 1, 2])).is_ok());
+        assert!(open_all(rebuild(&[1, 0, 2])).is_err());
+        assert!(open_all(rebuild(&[0, 2, 1])).is_err());
+        assert!(open_all(rebuild(&[0, 0, 2])).is_err());
+        assert!(open_all(rebuild(&[0, 1, 2, 2])).is_err());
+
+        // Segments of another file sealed with the same key
+        let other = seal(&sample(48));
+        let mut mixed = sealed.clone();
+        mixed[HEADER_LEN..HEADER_LEN + SEGMENT_LEN]
+            .copy_from_slice(&other[HEADER_LEN..HEADER_LEN + SEGMENT_LEN]);
+        assert!(open_all(mixed).is_err());
+    }
+
+    #[test]
+    fn flipped_header_byte_is_rejected() {
+        let sealed = seal(&sample(20));
+        for i in 0..HEADER_LEN {
+            let mut tampered = sealed.clone();
+            tampered[i] ^= 0x01;
+            assert!(open_all(tampered).is_err(), "header byte {}", i);
+        }
+    }
+}
@@ -0,0 +1,241 @@
+// AES-256 block cipher with an implementation chosen at runtime
+//
+// AES-NI is used when the CPU supports it, the constant-time bitsliced
+// software path otherwise. Neither of them indexes memory with secret data.
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+use crate::backend::aes_keys::cipher::aesni::{self, AesNi256};
+use crate::backend::aes_keys::cipher::bitsliced::BitslicedAes256;
+use crate::backend::aes_keys::cipher::{Block, BlockCipher};
+
+/**
+ * Implementation of the AES rounds.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Backend {
+    /// Constant-time software implementation, available everywhere
+    Bitsliced,
+    /// Hardware AES instructions of x86 processors
+    AesNi,
+}
+
+impl Backend {
+    /// Returns the fastest backend supported by this CPU.
+    pub fn detect() -> Self {
+        if Self::AesNi.is_available() {
+            Self::AesNi
+        } else {
+            Self::Bitsliced
+        }
+    }
+
+    /// Returns true if the backend can run on this CPU.
+    pub fn is_available(self) -> bool {
+        match self {
+            Self::Bitsliced => true,
+            #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+            Self::AesNi => aesni::is_supported(),
+            #[cfg(not(any(target_arch = "x86", target_arch = "x86_64")))]
+            Self::AesNi => false,
+        }
+    }
+
+    /// Returns a human-readable name for logs and benchmarks.
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Bitsliced => "bitsliced",
+            Self::AesNi => "aes-ni",
+        }
+    }
+}
+
+// Key schedules are boxed so the cipher stays small to move around
+#[derive(Clone)]
+enum Inner {
+    Bitsliced(Box<BitslicedAes256>),
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    AesNi(Box<AesNi256>),
+}
+
+/**
+ * AES-256 block cipher.
+ *
+ * The encryption and decryption schedules are computed once in `new` and
+ * wiped on drop.
+ */
+#[derive(Clone)]
+pub struct Aes256 {
+    inner: Inner,
+}
+
+impl Aes256 {
+    /**
+     * Creates a cipher on the fastest backend available.
+     *
+     * @param key - The 256-bit key, any other length is a programming error.
+     * @return A new Aes256 instance.
+     */
+    pub fn new(key: &[u8]) -> Self {
+        Self::with_backend(key, Backend::detect()).expect("the detected backend is available")
+    }
+
+    /**
+     * Creates a cipher on a given backend.
+     *
+     * @param key - The 256-bit key, any other length is a programming error.
+     * @param backend - The implementation to use.
+     * @return Option<Self> - None if the backend is not supported by this CPU.
+     */
+    pub fn with_backend(key: &[u8], backend: Backend) -> Option<Self> {
+        let key: &[u8; 32] = key.try_into().expect("AES-256 needs a 32-byte key");
+        let inner = match backend {
+            Backend::Bitsliced => Inner::Bitsliced(Box::new(BitslicedAes256::new(key))),
+            #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+            Backend::AesNi => Inner::AesNi(Box::new(AesNi256::new(key)?)),
+            #[cfg(not(any(target_arch = "x86", target_arch = "x86_64")))]
+            Backend::AesNi => return None,
+        };
+        Some(Self { inner })
+    }
+
+    /// Returns the backend in use.
+    pub fn backend(&self) -> Backend {
+        match self.inner {
+            Inner::Bitsliced(_) => Backend::Bitsliced,
+            #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+            Inner::AesNi(_) => Backend::AesNi,
+        }
+    }
+}
+
+impl BlockCipher for Aes256 {
+    // Batches the blocks as the backend prefers
+    fn encrypt_blocks(&self, blocks: &mut [Block]) {
+        match &self.inner {
+            Inner::Bitsliced(aes) => aes.encrypt_blocks(blocks),
+            #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+            Inner::AesNi(aes) => aes.encrypt_blocks(blocks),
+        }
+    }
+
+    fn decrypt_blocks(&self, blocks: &mut [Block]) {
+        match &self.inner {
+            Inner::Bitsliced(aes) => aes.decrypt_blocks(blocks),
+            #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+            Inner::AesNi(aes) => aes.decrypt_blocks(blocks),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hex(s: &str) -> Vec<u8> {
+        (0..s.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    fn block(s: &str) -> [u8; 16] {
+        hex(s).try_into().unwrap()
+    }
+
+    fn backends() -> Vec<Backend> {
+        [Backend::Bitsliced, Backend::AesNi]
+            .into_iter()
+            .filter(|backend| backend.is_available())
+            .collect()
+    }
+
+    // FIPS-197 appendix C.3
+    #[test]
+    fn fips197_example_vector() {
+        let key = hex("000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f");
+        let plaintext = block("00112233445566778899aabbccddeeff");
+        let ciphertext = block("8ea2b7ca516745bfeafc49904b496089");
+        for backend in backends() {
+            let aes = Aes256::with_backend(&key, backend).unwrap();
+            assert_eq!(
+                aes.encrypt_block(&plaintext),
+                ciphertext,
+                "{}",
+                backend.name()
+            );
+            assert_eq!(
+                aes.decrypt_block(&ciphertext),
+                plaintext,
+                "{}",
+                backend.name()
+            );
+        }
+    }
+
+    // NIST SP 800-38A F.1.5, ECB-AES256
+    #[test]
+    fn sp800_38a_ecb_vectors() {
+        let key = hex("603deb1015ca71be2b73aef0857d77811f352c073b6108d72d9810a30914dff4");
+        let pairs = [
+            (
+                "6bc1bee22e409f96e93d7e117393172a",
+                "f3eed1bdb5d2a03c064b5a7e3db181f8",
+            ),
+            (
+                "ae2d8a571e03ac9c9eb76fac45af8e51",
+                "591ccb10d410ed26dc5ba74a31362870",
+            ),
+            (
+                "30c81c46a35ce411e5fbc1191a0a52ef",
+                "b6ed21b99ca6f4f9f153e7b1beafed1d",
+            ),
+            (
+                "f69f2445df4f9b17ad2b417be66c3710",
+                "23304b7a39f9f3ff067d8d8f9e24ecc7",
+            ),
+        ];
+        for backend in backends() {
+            let aes = Aes256::with_backend(&key, backend).unwrap();
+            let mut blocks: Vec<[u8; 16]> = pairs.iter().map(|(p, _)| block(p)).collect();
+            aes.encrypt_blocks(&mut blocks);
+            for (output, (_, expected)) in blocks.iter().zip(pairs.iter()) {
+                assert_eq!(*output, block(expected), "{}", backend.name());
+            }
+            aes.decrypt_blocks(&mut blocks);
+            for (output, (expected, _)) in blocks.iter().zip(pairs.iter()) {
+                assert_eq!(*output, block(expected), "{}", backend.name());
+            }
+        }
+    }
+
+    #[test]
+    fn backends_agree_on_every_batch_size() {
+        let key: Vec<u8> = (0..32).map(|i| i * 7 + 3).collect();
+        let reference = Aes256::with_backend(&key, Backend::Bitsliced).unwrap();
+        for backend in backends() {
+            let aes = Aes256::with_backend(&key, backend).unwrap();
+            for count in 0..=17 {
+                let input: Vec<[u8; 16]> = (0..count)
+                    .map(|i| std::array::from_fn(|j| (i * 16 + j) as u8))
+                    .collect();
+                let mut blocks = input.clone();
+                aes.encrypt_blocks(&mut blocks);
+                for (output, single) in blocks.iter().zip(input.iter()) {
+                    assert_eq!(
+                        *output,
+                        reference.encrypt_block(single),
+                        "{}",
+                        backend.name()
+                    );
+                }
+                aes.decrypt_blocks(&mut blocks);
+                assert_eq!(blocks, input, "{}", backend.name());
+            }
+        }
+    }
+
+    #[test]
+    fn detect_picks_an_available_backend() {
+        assert!(Backend::detect().is_available());
+        assert_eq!(Aes256::new(&[0u8; 32]).backend(), Backend::detect());
+    }
+}
@@ -0,0 +1,356 @@
+// Constant-time software AES-256 (FIPS-197)
+//
+// The S-box is never read from a table: the bytes of up to four blocks are
+// split into eight bit planes, one u64 per bit position, and the inverse in
+// GF(2^8) is computed on all of them at once as x^254 with plain AND/XOR
+// gates. The linear layers (ShiftRows, MixColumns, AddRoundKey) only use
+// public indices and branch-free arithmetic, so no memory access or branch
+// depends on the key or the data.
+//
+// Bytes are in the FIPS-197 order: byte `i` of a block is row `i % 4` of
+// column `i / 4` of the state.
+use zeroize::Zeroize;
+
+// Number of rounds for AES-256
+const NR: usize = 14;
+// Blocks processed by one pass over the bit planes (64 lanes / 16 bytes)
+pub const PARALLEL_BLOCKS: usize = 4;
+
+// Round constants of the key schedule
+const RCON: [u8; 7] = [0x01, 0x02, 0x04, 0x08, 0x10, 0x20, 0x40];
+
+// Splits up to 64 bytes into bit planes: bit `i` of plane `b` is bit `b` of byte `i`
+fn pack(bytes: &[u8]) -> [u64; 8] {
+    let mut planes = [0u64; 8];
+    for (i, byte) in bytes.iter().enumerate() {
+        for (b, plane) in planes.iter_mut().enumerate() {
+            *plane |= (((byte >> b) & 1) as u64) << i;
+        }
+    }
+    planes
+}
+
+// Inverse of `pack`
+fn unpack(planes: &[u64; 8], bytes: &mut [u8]) {
+    for (i, byte) in bytes.iter_mut().enumerate() {
+        let mut value = 0u8;
+        for (b, plane) in planes.iter().enumerate() {
+            value |= (((plane >> i) & 1) as u8) << b;
+        }
+        *byte = value;
+    }
+}
+
+// Multiplication in GF(2^8) modulo x^8 + x^4 + x^3 + x + 1 on every lane
+fn gf_mul(a: &[u64; 8], b: &[u64; 8]) -> [u64; 8] {
+    let mut product = [0u64; 15];
+    for i in 0..8 {
+        for j in 0..8 {
+            product[i + j] ^= a[i] & b[j];
+        }
+    }
+    // x^k = x^(k-8) * (x^4 + x^3 + x + 1)
+    for k in (8..15).rev() {
+        let high = product[k];
+        product[k - 4] ^= high;
+        product[k - 5] ^= high;
+        product[k - 7] ^= high;
+        product[k - 8] ^= high;
+    }
+    let mut result = [0u64; 8];
+    result.copy_from_slice(&product[..8]);
+    result
+}
+
+// Inverse in GF(2^8) on every lane as x^254, which maps 0 to 0
+fn gf_inv(x: &[u64; 8]) -> [u64; 8] {
+    let x2 = gf_mul(x, x);
+    let x3 = gf_mul(&x2, x);
+    let x6 = gf_mul(&x3, &x3);
+    let x12 = gf_mul(&x6, &x6);
+    let x15 = gf_mul(&x12, &x3);
+    let x30 = gf_mul(&x15, &x15);
+    let x60 = gf_mul(&x30, &x30);
+    let x120 = gf_mul(&x60, &x60);
+    let x240 = gf_mul(&x120, &x120);
+    let x252 = gf_mul(&x240, &x12);
+    gf_mul(&x252, &x2)
+}
+
+// Forward S-box on every lane: affine transform of the inverse
+fn sbox_planes(planes: &[u64; 8]) -> [u64; 8] {
+    let inv = gf_inv(planes);
+    let mut out = [0u64; 8];
+    for (b, plane) in out.iter_mut().enumerate() {
+        let constant = 0u64.wrapping_sub(((0x63u8 >> b) & 1) as u64);
+        *plane = inv[b]
+            ^ inv[(b + 4) % 8]
+            ^ inv[(b + 5) % 8]
+            ^ inv[(b + 6) % 8]
+            ^ inv[(b + 7) % 8]
+            ^ constant;
+    }
+    out
+}
+
+// Inverse S-box on every lane: inverse of the inverse affine transform
+fn inv_sbox_planes(planes: &[u64; 8]) -> [u64; 8] {
+    let mut affine = [0u64; 8];
+    for (b, plane) in affine.iter_mut().enumerate() {
+        let constant = 0u64.wrapping_sub(((0x05u8 >> b) & 1) as u64);
+        *plane = planes[(b + 2) % 8] ^ planes[(b + 5) % 8] ^ planes[(b + 7) % 8] ^ constant;
+    }
+    gf_inv(&affine)
+}
+
+// Applies the S-box to up to 64 bytes in constant time
+pub fn sub_bytes(bytes: &mut [u8]) {
+    let planes = sbox_planes(&pack(bytes));
+    unpack(&planes, bytes);
+}
+
+// Applies the inverse S-box to up to 64 bytes in constant time
+pub fn inv_sub_bytes(bytes: &mut [u8]) {
+    let planes = inv_sbox_planes(&pack(bytes));
+    unpack(&planes, bytes);
+}
+
+// Multiplication by x in GF(2^8) without a data-dependent branch
+fn xtime(x: u8) -> u8 {
+    (x << 1) ^ (0x1B & 0u8.wrapping_sub(x >> 7))
+}
+
+fn shift_rows(block: &mut [u8]) {
+    let old: [u8; 16] = block[..16].try_into().unwrap();
+    for c in 0..4 {
+        for r in 0..4 {
+            block[r + 4 * c] = old[r + 4 * ((c + r) % 4)];
+        }
+    }
+}
+
+fn inv_shift_rows(block: &mut [u8]) {
+    let old: [u8; 16] = block[..16].try_into().unwrap();
+    for c in 0..4 {
+        for r in 0..4 {
+            block[r + 4 * ((c + r) % 4)] = old[r + 4 * c];
+        }
+    }
+}
+
+fn mix_columns(block: &mut [u8]) {
+    for column in block.chunks_exact_mut(4) {
+        let [a0, a1, a2, a3] = [column[0], column[1], column[2], column[3]];
+        let all = a0 ^ a1 ^ a2 ^ a3;
+        column[0] ^= all ^ xtime(a0 ^ a1);
+        column[1] ^= all ^ xtime(a1 ^ a2);
+        column[2] ^= all ^ xtime(a2 ^ a3);
+        column[3] ^= all ^ xtime(a3 ^ a0);
+    }
+}
+
+fn inv_mix_columns(block: &mut [u8]) {
+    // Multiplying by (04 x^2 + 05) first turns InvMixColumns into MixColumns
+    for column in block.chunks_exact_mut(4) {
+        let u = xtime(xtime(column[0] ^ column[2]));
+        let v = xtime(xtime(column[1] ^ column[3]));
+        column[0] ^= u;
+        column[1] ^= v;
+        column[2] ^= u;
+        column[3] ^= v;
+    }
+    mix_columns(block);
+}
+
+fn add_round_key(block: &mut [u8], round_key: &[u8; 16]) {
+    for (byte, key_byte) in block.iter_mut().zip(round_key.iter()) {
+        *byte ^= key_byte;
+    }
+}
+
+/**
+ * Constant-time software AES-256.
+ *
+ * Decryption uses the equivalent inverse cipher (FIPS-197 5.3.5), so it has
+ * its own schedule with InvMixColumns applied to the inner round keys. Both
+ * schedules are wiped when the value is dropped.
+ */
+#[derive(Clone)]
+pub struct BitslicedAes256 {
+    enc: [[u8; 16]; NR + 1],
+    dec: [[u8; 16]; NR + 1],
+}
+
+impl BitslicedAes256 {
+    /**
+     * Runs the key schedule.
+     *
+     * @param key - The 256-bit key.
+     * @return A new BitslicedAes256 instance.
+     */
+    pub fn new(key: &[u8; 32]) -> Self {
+        let mut words = [[0u8; 4]; 4 * (NR + 1)];
+        for (i, word) in words.iter_mut().take(8).enumerate() {
+            word.copy_from_slice(&key[4 * i..4 * i + 4]);
+        }
+        for i in 8..words.len() {
+            let mut temp = words[i - 1];
+            if i % 8 == 0 {
+                temp.rotate_left(1);
+                sub_bytes(&mut temp);
+                temp[0] ^= RCON[i / 8 - 1];
+            } else if i % 8 == 4 {
+                sub_bytes(&mut temp);
+            }
+            for (byte, previous) in temp.iter_mut().zip(words[i - 8].iter()) {
+                *byte ^= previous;
+            }
+            words[i] = temp;
+        }
+
+        let mut enc = [[0u8; 16]; NR + 1];
+        for (round, round_key) in enc.iter_mut().enumerate() {
+            for c in 0..4 {
+                round_key[4 * c..4 * c + 4].copy_from_slice(&words[4 * round + c]);
+            }
+        }
+        words.zeroize();
+
+        let mut dec = enc;
+        for round_key in dec[1..NR].iter_mut() {
+            inv_mix_columns(round_key);
+        }
+        Self { enc, dec }
+    }
+
+    /// Encrypts blocks in place, four at a time.
+    pub fn encrypt_blocks(&self, blocks: &mut [[u8; 16]]) {
+        for batch in blocks.chunks_mut(PARALLEL_BLOCKS) {
+            let mut state = [0u8; 16 * PARALLEL_BLOCKS];
+            let state = &mut state[..16 * batch.len()];
+            for (slot, block) in state.chunks_exact_mut(16).zip(batch.iter()) {
+                slot.copy_from_slice(block);
+                add_round_key(slot, &self.enc[0]);
+            }
+            for round in 1..=NR {
+                sub_bytes(state);
+                for slot in state.chunks_exact_mut(16) {
+                    shift_rows(slot);
+                    if round != NR {
+                        mix_columns(slot);
+                    }
+                    add_round_key(slot, &self.enc[round]);
+                }
+            }
+            for (block, slot) in batch.iter_mut().zip(state.chunks_exact(16)) {
+                block.copy_from_slice(slot);
+            }
+            state.zeroize();
+        }
+    }
+
+    /// Decrypts blocks in place, four at a time.
+    pub fn decrypt_blocks(&self, blocks: &mut [[u8; 16]]) {
+        for batch in blocks.chunks_mut(PARALLEL_BLOCKS) {
+            let mut state = [0u8; 16 * PARALLEL_BLOCKS];
+            let state = &mut state[..16 * batch.len()];
+            for (slot, block) in state.chunks_exact_mut(16).zip(batch.iter()) {
+                slot.copy_from_slice(block);
+                add_round_key(slot, &self.dec[NR]);
+            }
+            for round in (0..NR).rev() {
+                inv_sub_bytes(state);
+                for slot in state.chunks_exact_mut(16) {
+                    inv_shift_rows(slot);
+                    if round != 0 {
+                        inv_mix_columns(slot);
+                    }
+                    add_round_key(slot, &self.dec[round]);
+                }
+            }
+            for (block, slot) in batch.iter_mut().zip(state.chunks_exact(16)) {
+                block.copy_from_slice(slot);
+            }
+            state.zeroize();
+        }
+    }
+}
+
+impl Drop for BitslicedAes256 {
+    fn drop(&mut self) {
+        self.enc.zeroize();
+        self.dec.zeroize();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // FIPS-197 figure 7
+    #[rustfmt::skip]
+    const S_BOX: [u8; 256] = [
+        0x63, 0x7C, 0x77, 0x7B, 0xF2, 0x6B, 0x6F, 0xC5, 0x30, 0x01, 0x67, 0x2B, 0xFE, 0xD7, 0xAB, 0x76,
+        0xCA, 0x82, 0xC9, 0x7D, 0xFA, 0x59, 0x47, 0xF0, 0xAD, 0xD4, 0xA2, 0xAF, 0x9C, 0xA4, 0x72, 0xC0,
+        0xB7, 0xFD, 0x93, 0x26, 0x36, 0x3F, 0xF7, 0xCC, 0x34, 0xA5, 0xE5, 0xF1, 0x71, 0xD8, 0x31, 0x15,
+        0x04, 0xC7, 0x23, 0xC3, 0x18, 0x96, 0x05, 0x9A, 0x07, 0x12, 0x80, 0xE2, 0xEB, 0x27, 0xB2, 0x75,
+        0x09, 0x83, 0x2C, 0x1A, 0x1B, 0x6E, 0x5A, 0xA0, 0x52, 0x3B, 0xD6, 0xB3, 0x29, 0xE3, 0x2F, 0x84,
+        0x53, 0xD1, 0x00, 0xED, 0x20, 0xFC, 0xB1, 0x5B, 0x6A, 0xCB, 0xBE, 0x39, 0x4A, 0x4C, 0x58, 0xCF,
+        0xD0, 0xEF, 0xAA, 0xFB, 0x43, 0x4D, 0x33, 0x85, 0x45, 0xF9, 0x02, 0x7F, 0x50, 0x3C, 0x9F, 0xA8,
+        0x51, 0xA3, 0x40, 0x8F, 0x92, 0x9D, 0x38, 0xF5, 0xBC, 0xB6, 0xDA, 0x21, 0x10, 0xFF, 0xF3, 0xD2,
+        0xCD, 0x0C, 0x13, 0xEC, 0x5F, 0x97, 0x44, 0x17, 0xC4, 0xA7, 0x7E, 0x3D, 0x64, 0x5D, 0x19, 0x73,
+        0x60, 0x81, 0x4F, 0xDC, 0x22, 0x2A, 0x90, 0x88, 0x46, 0xEE, 0xB8, 0x14, 0xDE, 0x5E, 0x0B, 0xDB,
+        0xE0, 0x32, 0x3A, 0x0A, 0x49, 0x06, 0x24, 0x5C, 0xC2, 0xD3, 0xAC, 0x62, 0x91, 0x95, 0xE4, 0x79,
+        0xE7, 0xC8, 0x37, 0x6D, 0x8D, 0xD5, 0x4E, 0xA9, 0x6C, 0x56, 0xF4, 0xEA, 0x65, 0x7A, 0xAE, 0x08,
+        0xBA, 0x78, 0x25, 0x2E, 0x1C, 0xA6, 0xB4, 0xC6, 0xE8, 0xDD, 0x74, 0x1F, 0x4B, 0xBD, 0x8B, 0x8A,
+        0x70, 0x3E, 0xB5, 0x66, 0x48, 0x03, 0xF6, 0x0E, 0x61, 0x35, 0x57, 0xB9, 0x86, 0xC1, 0x1D, 0x9E,
+        0xE1, 0xF8, 0x98, 0x11, 0x69, 0xD9, 0x8E, 0x94, 0x9B, 0x1E, 0x87, 0xE9, 0xCE, 0x55, 0x28, 0xDF,
+        0x8C, 0xA1, 0x89, 0x0D, 0xBF, 0xE6, 0x42, 0x68, 0x41, 0x99, 0x2D, 0x0F, 0xB0, 0x54, 0xBB, 0x16,
+    ];
+
+    #[test]
+    fn sbox_matches_the_table() {
+        for batch in (0..=255u8).collect::<Vec<_>>().chunks(64) {
+            let mut bytes = batch.to_vec();
+            sub_bytes(&mut bytes);
+            for (input, output) in batch.iter().zip(bytes.iter()) {
+                assert_eq!(*output, S_BOX[*input as usize], "S-box of {:#04x}", input);
+            }
+        }
+    }
+
+    #[test]
+    fn inverse_sbox_undoes_the_table() {
+        for batch in (0..=255u8).collect::<Vec<_>>().chunks(64) {
+            let mut bytes: Vec<u8> = batch.iter().map(|input| S_BOX[*input as usize]).collect();
+            inv_sub_bytes(&mut bytes);
+            assert_eq!(bytes, batch);
+        }
+    }
+
+    // FIPS-197 appendix A.3
+    #[test]
+    fn key_expansion_known_answer() {
+        let key: [u8; 32] = [
+            0x60, 0x3d, 0xeb, 0x10, 0x15, 0xca, 0x71, 0xbe, 0x2b, 0x73, 0xae, 0xf0, 0x85, 0x7d,
+            0x77, 0x81, 0x1f, 0x35, 0x2c, 0x07, 0x3b, 0x61, 0x08, 0xd7, 0x2d, 0x98, 0x10, 0xa3,
+            0x09, 0x14, 0xdf, 0xf4,
+        ];
+        let aes = BitslicedAes256::new(&key);
+        assert_eq!(
+            aes.enc[2],
+            [
+                0x9b, 0xa3, 0x54, 0x11, 0x8e, 0x69, 0x25, 0xaf, 0xa5, 0x1a, 0x8b, 0x5f, 0x20, 0x67,
+                0xfc, 0xde
+            ]
+        );
+        assert_eq!(
+            aes.enc[3],
+            [
+                0xa8, 0xb0, 0x9c, 0x1a, 0x93, 0xd1, 0x94, 0xcd, 0xbe, 0x49, 0x84, 0x6e, 0xb7, 0x5d,
+                0x5b, 0x9a
+            ]
+        );
+        assert_eq!(aes.enc[14][12..], [0x70, 0x6c, 0x63, 0x1e]);
+    }
+}
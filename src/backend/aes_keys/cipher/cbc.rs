@@ -0,0 +1,107 @@
+// CBC mode (NIST SP 800-38A 6.2) with PKCS#7 padding
+//
+// Not authenticated: only for interoperability, Vaultify's own data goes
+// through `gcm`.
+use crate::backend::aes_keys::cipher::padding::{pkcs7_pad, pkcs7_unpad};
+use crate::backend::aes_keys::cipher::{Block, BlockCipher, BLOCK_LEN};
+
+/**
+ * Encrypts data in CBC mode.
+ *
+ * @param cipher - The block cipher.
+ * @param iv - The initialization vector, must be unpredictable.
+ * @param data - The plaintext data to encrypt.
+ * @return Vec<u8> - The ciphertext, padded to a whole number of blocks.
+ */
+pub fn encrypt<C: BlockCipher>(cipher: &C, iv: &Block, data: &[u8]) -> Vec<u8> {
+    let mut padded = data.to_vec();
+    pkcs7_pad(&mut padded);
+
+    // Each block depends on the previous ciphertext, so they go one by one
+    let mut previous = *iv;
+    let mut output = Vec::with_capacity(padded.len());
+    for chunk in padded.chunks_exact(BLOCK_LEN) {
+        let block: Block = std::array::from_fn(|i| chunk[i] ^ previous[i]);
+        previous = cipher.encrypt_block(&block);
+        output.extend_from_slice(&previous);
+    }
+    output
+}
+
+/**
+ * Decrypts data encrypted by `encrypt`.
+ *
+ * @param cipher - The block cipher.
+ * @param iv - The initialization vector used when encrypting.
+ * @param data - The ciphertext data to decrypt.
+ * @return Result<Vec<u8>, String> - The plaintext or an error message.
+ */
+pub fn decrypt<C: BlockCipher>(cipher: &C, iv: &Block, data: &[u8]) -> Result<Vec<u8>, String> {
+    if data.is_empty() || !data.len().is_multiple_of(BLOCK_LEN) {
+        return Err("Ciphertext is not a whole number of blocks".to_string());
+    }
+
+    // Unlike encryption, every block can be decrypted at once
+    let mut blocks: Vec<Block> = data
+        .chunks_exact(BLOCK_LEN)
+        .map(|chunk| chunk.try_into().unwrap())
+        .collect();
+    cipher.decrypt_blocks(&mut blocks);
+
+    let mut plaintext = Vec::with_capacity(data.len());
+    let previous = std::iter::once(&iv[..]).chain(data.chunks_exact(BLOCK_LEN));
+    for (block, previous) in blocks.iter().zip(previous) {
+        plaintext.extend(block.iter().zip(previous.iter()).map(|(x, y)| x ^ y));
+    }
+
+    pkcs7_unpad(&mut plaintext)?;
+    Ok(plaintext)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::aes_keys::cipher::Aes256;
+
+    fn hex(s: &str) -> Vec<u8> {
+        (0..s.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    // NIST SP 800-38A F.2.5, CBC-AES256
+    #[test]
+    fn sp800_38a_vectors() {
+        let aes = Aes256::new(&hex(
+            "603deb1015ca71be2b73aef0857d77811f352c073b6108d72d9810a30914dff4",
+        ));
+        let iv: Block = hex("000102030405060708090a0b0c0d0e0f").try_into().unwrap();
+        let plaintext = hex(concat!(
+            "6bc1bee22e409f96e93d7e117393172a",
+            "ae2d8a571e03ac9c9eb76fac45af8e51",
+            "30c81c46a35ce411e5fbc1191a0a52ef",
+            "f69f2445df4f9b17ad2b417be66c3710",
+        ));
+        let ciphertext = hex(concat!(
+            "f58c4c04d6e5f1ba779eabfb5f7bfbd6",
+            "9cfc4e967edb808d679f777bc6702c7d",
+            "39f23369a9d9bacfa530e26304231461",
+            "b2eb05e2c39be9fcda6c19078c6a9d1b",
+        ));
+
+        // The vectors have no padding, which only adds a final block
+        let encrypted = encrypt(&aes, &iv, &plaintext);
+        assert_eq!(encrypted.len(), ciphertext.len() + BLOCK_LEN);
+        assert_eq!(encrypted[..ciphertext.len()], ciphertext);
+        assert_eq!(decrypt(&aes, &iv, &encrypted).unwrap(), plaintext);
+    }
+
+    #[test]
+    fn rejects_partial_blocks() {
+        let aes = Aes256::new(&[0x44; 32]);
+        let iv = [0u8; BLOCK_LEN];
+        assert!(decrypt(&aes, &iv, &[]).is_err());
+        assert!(decrypt(&aes, &iv, &[0u8; 20]).is_err());
+    }
+}
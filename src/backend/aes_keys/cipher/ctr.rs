@@ -0,0 +1,103 @@
+// CTR mode (NIST SP 800-38A 6.5)
+//
+// The counter is the rightmost 32 bits of the block, incremented modulo 2^32
+// as GCM requires (`inc32` in SP 800-38D). Encryption and decryption are the
+// same operation.
+use crate::backend::aes_keys::cipher::{Block, BlockCipher, BLOCK_LEN};
+
+// Counter blocks encrypted per call to the cipher
+const BATCH: usize = 8;
+
+// Increments the rightmost 32 bits of the counter block (mod 2^32)
+pub fn inc32(counter: &mut Block) {
+    let value = u32::from_be_bytes([counter[12], counter[13], counter[14], counter[15]]);
+    counter[12..].copy_from_slice(&value.wrapping_add(1).to_be_bytes());
+}
+
+/**
+ * XORs data in place with the keystream.
+ *
+ * @param cipher - The block cipher.
+ * @param counter - The first counter block, must never be reused with the same key.
+ * @param data - The data to encrypt or decrypt.
+ */
+pub fn apply_keystream<C: BlockCipher>(cipher: &C, counter: &Block, data: &mut [u8]) {
+    let mut counter = *counter;
+    let mut keystream = [[0u8; BLOCK_LEN]; BATCH];
+    for batch in data.chunks_mut(BLOCK_LEN * BATCH) {
+        let blocks = batch.len().div_ceil(BLOCK_LEN);
+        for block in keystream.iter_mut().take(blocks) {
+            *block = counter;
+            inc32(&mut counter);
+        }
+        cipher.encrypt_blocks(&mut keystream[..blocks]);
+        for (chunk, key_block) in batch.chunks_mut(BLOCK_LEN).zip(keystream.iter()) {
+            for (byte, key_byte) in chunk.iter_mut().zip(key_block.iter()) {
+                *byte ^= key_byte;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::aes_keys::cipher::Aes256;
+
+    fn hex(s: &str) -> Vec<u8> {
+        (0..s.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    // NIST SP 800-38A F.5.5, CTR-AES256
+    #[test]
+    fn sp800_38a_vectors() {
+        let aes = Aes256::new(&hex(
+            "603deb1015ca71be2b73aef0857d77811f352c073b6108d72d9810a30914dff4",
+        ));
+        let counter: Block = hex("f0f1f2f3f4f5f6f7f8f9fafbfcfdfeff").try_into().unwrap();
+        let plaintext = hex(concat!(
+            "6bc1bee22e409f96e93d7e117393172a",
+            "ae2d8a571e03ac9c9eb76fac45af8e51",
+            "30c81c46a35ce411e5fbc1191a0a52ef",
+            "f69f2445df4f9b17ad2b417be66c3710",
+        ));
+        let ciphertext = hex(concat!(
+            "601ec313775789a5b7a7f504bbf3d228",
+            "f443e3ca4d62b59aca84e990cacaf5c5",
+            "2b0930daa23de94ce87017ba2d84988d",
+            "dfc9c58db67aada613c2dd08457941a6",
+        ));
+
+        let mut data = plaintext.clone();
+        apply_keystream(&aes, &counter, &mut data);
+        assert_eq!(data, ciphertext);
+        apply_keystream(&aes, &counter, &mut data);
+        assert_eq!(data, plaintext);
+    }
+
+    #[test]
+    fn batches_continue_the_counter() {
+        let aes = Aes256::new(&[0x55; 32]);
+        let counter = [0u8; BLOCK_LEN];
+        let mut data = vec![0u8; BLOCK_LEN * (BATCH + 2) + 3];
+        apply_keystream(&aes, &counter, &mut data);
+
+        let mut expected = counter;
+        for chunk in data.chunks(BLOCK_LEN) {
+            let key_block = aes.encrypt_block(&expected);
+            assert_eq!(chunk, &key_block[..chunk.len()]);
+            inc32(&mut expected);
+        }
+    }
+
+    #[test]
+    fn inc32_wraps_without_carrying() {
+        let mut counter = [0xFF; BLOCK_LEN];
+        inc32(&mut counter);
+        assert_eq!(counter[..12], [0xFF; 12]);
+        assert_eq!(counter[12..], [0; 4]);
+    }
+}
@@ -0,0 +1,92 @@
+// ECB mode of the first releases, kept so their blobs stay readable
+//
+// Those releases loaded each block into the AES state row by row instead of
+// column by column, which amounts to the standard cipher applied to the
+// transposed block. New data is never written in this mode, which leaks
+// repeated blocks and is not authenticated: it goes through `gcm`.
+use crate::backend::aes_keys::cipher::padding::{pkcs7_pad, pkcs7_unpad};
+use crate::backend::aes_keys::cipher::{Block, BlockCipher, BLOCK_LEN};
+
+// Converts between the legacy row-major layout and the FIPS-197 one; the
+// transposition is its own inverse
+fn legacy_transpose(block: &Block) -> Block {
+    std::array::from_fn(|i| block[4 * (i % 4) + i / 4])
+}
+
+/**
+ * Encrypts data with the legacy ECB scheme and PKCS#7 padding.
+ *
+ * Only kept to produce legacy blobs, e.g. in tests.
+ *
+ * @param cipher - The block cipher.
+ * @param data - The plaintext data to encrypt.
+ * @return Vec<u8> - The encrypted ciphertext.
+ */
+pub fn encrypt<C: BlockCipher>(cipher: &C, data: &[u8]) -> Vec<u8> {
+    let mut padded = data.to_vec();
+    pkcs7_pad(&mut padded);
+
+    let mut blocks: Vec<Block> = padded
+        .chunks_exact(BLOCK_LEN)
+        .map(|chunk| legacy_transpose(chunk.try_into().unwrap()))
+        .collect();
+    cipher.encrypt_blocks(&mut blocks);
+    blocks.iter().flat_map(legacy_transpose).collect()
+}
+
+/**
+ * Decrypts data written with the legacy ECB scheme.
+ *
+ * @param cipher - The block cipher.
+ * @param data - The ciphertext data to decrypt.
+ * @return Result<Vec<u8>, String> - The decrypted plaintext or an error message.
+ */
+pub fn decrypt<C: BlockCipher>(cipher: &C, data: &[u8]) -> Result<Vec<u8>, String> {
+    if !data.len().is_multiple_of(BLOCK_LEN) {
+        return Err("Ciphertext is not a whole number of blocks".to_string());
+    }
+
+    let mut blocks: Vec<Block> = data
+        .chunks_exact(BLOCK_LEN)
+        .map(|chunk| legacy_transpose(chunk.try_into().unwrap()))
+        .collect();
+    cipher.decrypt_blocks(&mut blocks);
+    let mut plaintext: Vec<u8> = blocks.iter().flat_map(legacy_transpose).collect();
+
+    pkcs7_unpad(&mut plaintext)?;
+    Ok(plaintext)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::aes_keys::cipher::Aes256;
+
+    #[test]
+    fn round_trip() {
+        let aes = Aes256::new(&[0x11; 32]);
+        for len in [0, 1, 15, 16, 17, 100] {
+            let data: Vec<u8> = (0..len).map(|i| i as u8).collect();
+            let encrypted = encrypt(&aes, &data);
+            assert_eq!(encrypted.len(), (len / BLOCK_LEN + 1) * BLOCK_LEN);
+            assert_eq!(decrypt(&aes, &encrypted).unwrap(), data);
+        }
+    }
+
+    // The legacy layout is the standard cipher on the transposed block
+    #[test]
+    fn blocks_are_transposed_around_the_cipher() {
+        let aes = Aes256::new(&[0x22; 32]);
+        let block: Block = std::array::from_fn(|i| i as u8);
+        let encrypted = encrypt(&aes, &block);
+        let expected = legacy_transpose(&aes.encrypt_block(&legacy_transpose(&block)));
+        assert_eq!(encrypted[..BLOCK_LEN], expected);
+    }
+
+    #[test]
+    fn rejects_truncated_ciphertext() {
+        let aes = Aes256::new(&[0x33; 32]);
+        let encrypted = encrypt(&aes, b"some legacy data");
+        assert!(decrypt(&aes, &encrypted[..encrypted.len() - 1]).is_err());
+    }
+}
@@ -0,0 +1,283 @@
+// GCM mode (NIST SP 800-38D): CTR encryption authenticated with GHASH
+use crate::backend::aes_keys::cipher::ctr::{apply_keystream, inc32};
+use crate::backend::aes_keys::cipher::{Aes256, Block, BlockCipher, BLOCK_LEN};
+use ring::rand::{SecureRandom, SystemRandom}; // For random nonce generation
+
+// Length of the random nonce stored in front of every blob (96 bits)
+pub const NONCE_LEN: usize = 12;
+// Length of the authentication tag appended to every blob (128 bits)
+pub const TAG_LEN: usize = 16;
+
+// Reduction constant of GHASH (x^128 + x^7 + x^2 + x + 1, bit-reflected)
+const GHASH_R: u128 = 0xE1 << 120;
+
+/**
+ * GCM context holding the block cipher and the GHASH subkey.
+ *
+ * Building it once and reusing it avoids running the key schedule for
+ * every blob when many of them are sealed with the same key.
+ */
+pub struct Gcm<C: BlockCipher> {
+    cipher: C,
+    h: u128,
+}
+
+// AES-256-GCM, the only instance Vaultify uses
+pub type AesGcm = Gcm<Aes256>;
+
+impl AesGcm {
+    /**
+     * Creates a new GCM context.
+     *
+     * @param key - The 256-bit AES key.
+     * @return A new AesGcm instance.
+     */
+    pub fn new(key: &[u8]) -> Self {
+        Self::with_cipher(Aes256::new(key))
+    }
+}
+
+impl<C: BlockCipher> Gcm<C> {
+    /**
+     * Creates a new GCM context on a prepared block cipher.
+     *
+     * @param cipher - The block cipher, e.g. AES-256 on a specific backend.
+     * @return A new Gcm instance.
+     */
+    pub fn with_cipher(cipher: C) -> Self {
+        let h = u128::from_be_bytes(cipher.encrypt_block(&[0u8; BLOCK_LEN]));
+        Self { cipher, h }
+    }
+
+    /**
+     * Encrypts and authenticates data.
+     *
+     * @param nonce - The 96-bit nonce, must never be reused with the same key.
+     * @param aad - Additional data that is authenticated but not encrypted.
+     * @param plaintext - The data to encrypt.
+     * @return Vec<u8> - The ciphertext followed by the 16-byte tag.
+     */
+    pub fn seal(&self, nonce: &[u8; NONCE_LEN], aad: &[u8], plaintext: &[u8]) -> Vec<u8> {
+        let j0 = initial_counter(nonce);
+
+        let mut output = Vec::with_capacity(plaintext.len() + TAG_LEN);
+        output.extend_from_slice(plaintext);
+        self.ctr(&j0, &mut output);
+
+        let tag = self.tag(&j0, aad, &output);
+        output.extend_from_slice(&tag);
+        output
+    }
+
+    /**
+     * Checks the tag and decrypts data sealed by `seal`.
+     *
+     * @param nonce - The nonce used when sealing.
+     * @param aad - The additional data used when sealing.
+     * @param data - The ciphertext followed by the 16-byte tag.
+     * @return Result<Vec<u8>, String> - The plaintext, or an error if the tag does not match.
+     */
+    pub fn open(
+        &self,
+        nonce: &[u8; NONCE_LEN],
+        aad: &[u8],
+        data: &[u8],
+    ) -> Result<Vec<u8>, String> {
+        if data.len() < TAG_LEN {
+            return Err("Ciphertext too short".to_string());
+        }
+        let (ciphertext, tag) = data.split_at(data.len() - TAG_LEN);
+        let j0 = initial_counter(nonce);
+
+        // Verify the tag before releasing any plaintext
+        let expected = self.tag(&j0, aad, ciphertext);
+        if !constant_time_eq(&expected, tag) {
+            return Err("Authentication tag mismatch".to_string());
+        }
+
+        let mut plaintext = ciphertext.to_vec();
+        self.ctr(&j0, &mut plaintext);
+        Ok(plaintext)
+    }
+
+    // XORs the data with the keystream, starting at inc32(J0)
+    fn ctr(&self, j0: &Block, data: &mut [u8]) {
+        let mut counter = *j0;
+        inc32(&mut counter);
+        apply_keystream(&self.cipher, &counter, data);
+    }
+
+    // Computes the authentication tag over the additional data and the ciphertext
+    fn tag(&self, j0: &Block, aad: &[u8], ciphertext: &[u8]) -> Block {
+        let mut y = 0u128;
+        y = self.ghash_update(y, aad);
+        y = self.ghash_update(y, ciphertext);
+
+        // Final block: bit lengths of the additional data and the ciphertext
+        let lengths = ((aad.len() as u128 * 8) << 64) | (ciphertext.len() as u128 * 8);
+        y = gf_mul(y ^ lengths, self.h);
+
+        let mask = u128::from_be_bytes(self.cipher.encrypt_block(j0));
+        (y ^ mask).to_be_bytes()
+    }
+
+    // Absorbs data into the GHASH accumulator, zero-padding the last block
+    fn ghash_update(&self, mut y: u128, data: &[u8]) -> u128 {
+        for chunk in data.chunks(BLOCK_LEN) {
+            let mut block = [0u8; BLOCK_LEN];
+            block[..chunk.len()].copy_from_slice(chunk);
+            y = gf_mul(y ^ u128::from_be_bytes(block), self.h);
+        }
+        y
+    }
+}
+
+// Builds J0 = nonce || 0^31 || 1 for a 96-bit nonce
+fn initial_counter(nonce: &[u8; NONCE_LEN]) -> Block {
+    let mut j0 = [0u8; BLOCK_LEN];
+    j0[..NONCE_LEN].copy_from_slice(nonce);
+    j0[15] = 1;
+    j0
+}
+
+// Multiplication in GF(2^128) as defined by GCM, without secret-dependent branches
+fn gf_mul(x: u128, y: u128) -> u128 {
+    let mut z = 0u128;
+    let mut v = y;
+    for i in (0..128).rev() {
+        let bit = (x >> i) & 1;
+        z ^= v & 0u128.wrapping_sub(bit);
+        let lsb = v & 1;
+        v = (v >> 1) ^ (GHASH_R & 0u128.wrapping_sub(lsb));
+    }
+    z
+}
+
+// Compares two byte slices without leaking the position of the first difference
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter()
+        .zip(b.iter())
+        .fold(0u8, |acc, (x, y)| acc | (x ^ y))
+        == 0
+}
+
+// Function that generates a fresh random 96-bit nonce
+pub fn generate_nonce() -> [u8; NONCE_LEN] {
+    let rng = SystemRandom::new();
+    let mut nonce = [0u8; NONCE_LEN];
+    rng.fill(&mut nonce).expect("Failed to generate nonce");
+    nonce
+}
+
+/**
+ * Encrypts data with AES-256-GCM under a fresh random nonce.
+ *
+ * @param data - The plaintext data to encrypt.
+ * @param key - The encryption key.
+ * @return Vec<u8> - nonce || ciphertext || tag.
+ */
+pub fn encrypt(data: &[u8], key: &[u8]) -> Vec<u8> {
+    let nonce = generate_nonce();
+    let sealed = AesGcm::new(key).seal(&nonce, &[], data);
+
+    let mut output = Vec::with_capacity(NONCE_LEN + sealed.len());
+    output.extend_from_slice(&nonce);
+    output.extend_from_slice(&sealed);
+    output
+}
+
+/**
+ * Decrypts a blob produced by `encrypt`.
+ *
+ * @param data - nonce || ciphertext || tag.
+ * @param key - The decryption key.
+ * @return Result<Vec<u8>, String> - The plaintext, or an error if the blob was tampered with.
+ */
+pub fn decrypt(data: &[u8], key: &[u8]) -> Result<Vec<u8>, String> {
+    if data.len() < NONCE_LEN + TAG_LEN {
+        return Err("Ciphertext too short".to_string());
+    }
+    let (nonce, sealed) = data.split_at(NONCE_LEN);
+    let nonce: [u8; NONCE_LEN] = nonce.try_into().unwrap();
+    AesGcm::new(key).open(&nonce, &[], sealed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::aes_keys::cipher::Backend;
+
+    fn hex(s: &str) -> Vec<u8> {
+        (0..s.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    fn contexts(key: &[u8]) -> Vec<AesGcm> {
+        [Backend::Bitsliced, Backend::AesNi]
+            .into_iter()
+            .filter_map(|backend| Aes256::with_backend(key, backend))
+            .map(AesGcm::with_cipher)
+            .collect()
+    }
+
+    // GCM specification (McGrew and Viega), test cases 13 to 16
+    #[test]
+    fn known_answer_vectors() {
+        let nonce = [0u8; NONCE_LEN];
+        for gcm in contexts(&[0u8; 32]) {
+            assert_eq!(
+                gcm.seal(&nonce, &[], &[]),
+                hex("530f8afbc74536b9a963b4f1c4cb738b")
+            );
+            assert_eq!(
+                gcm.seal(&nonce, &[], &[0u8; 16]),
+                hex("cea7403d4d606b6e074ec5d3baf39d18d0d1c8a799996bf0265b98b5d48ab919")
+            );
+        }
+
+        let key = hex("feffe9928665731c6d6a8f9467308308feffe9928665731c6d6a8f9467308308");
+        let nonce: [u8; NONCE_LEN] = hex("cafebabefacedbaddecaf888").try_into().unwrap();
+        let plaintext = hex(
+            "d9313225f88406e5a55909c5aff5269a86a7a9531534f7da2e4c303d8a318a72\
+             1c3c0c95956809532fcf0e2449a6b525b16aedf5aa0de657ba637b391aafd255",
+        );
+        let ciphertext = hex(
+            "522dc1f099567d07f47f37a32a84427d643a8cdcbfe5c0c97598a2bd2555d1aa\
+             8cb08e48590dbb3da7b08b1056828838c5f61e6393ba7a0abcc9f662898015ad",
+        );
+        let aad = hex("feedfacedeadbeeffeedfacedeadbeefabaddad2");
+        for gcm in contexts(&key) {
+            let sealed = gcm.seal(&nonce, &[], &plaintext);
+            assert_eq!(sealed[..64], ciphertext[..]);
+            assert_eq!(sealed[64..], hex("b094dac5d93471bdec1a502270e3cc6c")[..]);
+            assert_eq!(gcm.open(&nonce, &[], &sealed).unwrap(), plaintext);
+
+            let sealed = gcm.seal(&nonce, &aad, &plaintext[..60]);
+            assert_eq!(sealed[..60], ciphertext[..60]);
+            assert_eq!(sealed[60..], hex("76fc6ece0f4e1768cddf8853bb2d551b")[..]);
+            assert_eq!(gcm.open(&nonce, &aad, &sealed).unwrap(), &plaintext[..60]);
+            assert!(gcm.open(&nonce, &aad[1..], &sealed).is_err());
+        }
+    }
+
+    #[test]
+    fn backends_agree_across_counter_batches() {
+        let key: Vec<u8> = (0..32).collect();
+        let nonce = [9u8; NONCE_LEN];
+        let data: Vec<u8> = (0..BLOCK_LEN * 20 + 5).map(|i| i as u8).collect();
+        let sealed: Vec<Vec<u8>> = contexts(&key)
+            .iter()
+            .map(|gcm| gcm.seal(&nonce, b"aad", &data))
+            .collect();
+        for (gcm, output) in contexts(&key).iter().zip(sealed.iter()) {
+            assert_eq!(*output, sealed[0]);
+            assert_eq!(gcm.open(&nonce, b"aad", output).unwrap(), data);
+            assert!(gcm.open(&nonce, b"other", output).is_err());
+        }
+    }
+}
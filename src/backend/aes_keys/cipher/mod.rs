@@ -0,0 +1,61 @@
+// Block cipher and the modes of operation built on top of it
+//
+// `Aes256` is the only block cipher; it picks the constant-time bitsliced
+// implementation or AES-NI at runtime. The modes only rely on the
+// `BlockCipher` trait:
+//   - `gcm`: authenticated encryption, used for everything written today
+//   - `ctr`: the keystream under GCM
+//   - `cbc`: with PKCS#7 padding
+//   - `ecb`: read-only compatibility with the blobs of the first releases
+pub mod aes256;
+
+mod bitsliced;
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+mod aesni;
+
+pub mod padding;
+
+pub mod ecb;
+
+pub mod cbc;
+
+pub mod ctr;
+
+pub mod gcm;
+
+pub use aes256::{Aes256, Backend};
+
+// Size of a block in bytes
+pub const BLOCK_LEN: usize = 16;
+
+// A single cipher block
+pub type Block = [u8; BLOCK_LEN];
+
+/**
+ * 128-bit block cipher with precomputed key schedules.
+ *
+ * Implementations process several blocks per call when they can, so the
+ * modes hand over as many blocks at once as they have available.
+ */
+pub trait BlockCipher {
+    /// Encrypts blocks in place.
+    fn encrypt_blocks(&self, blocks: &mut [Block]);
+
+    /// Decrypts blocks in place.
+    fn decrypt_blocks(&self, blocks: &mut [Block]);
+
+    /// Encrypts a single block.
+    fn encrypt_block(&self, block: &Block) -> Block {
+        let mut blocks = [*block];
+        self.encrypt_blocks(&mut blocks);
+        blocks[0]
+    }
+
+    /// Decrypts a single block.
+    fn decrypt_block(&self, block: &Block) -> Block {
+        let mut blocks = [*block];
+        self.decrypt_blocks(&mut blocks);
+        blocks[0]
+    }
+}
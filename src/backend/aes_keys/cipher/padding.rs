@@ -0,0 +1,50 @@
+// PKCS#7 padding for the block modes that need whole blocks (ECB, CBC)
+use crate::backend::aes_keys::cipher::BLOCK_LEN;
+
+// Function that adds PKCS#7 padding to a vector of bytes to reach a whole number of blocks
+pub fn pkcs7_pad(data: &mut Vec<u8>) {
+    let pad_len = BLOCK_LEN - (data.len() % BLOCK_LEN);
+    data.extend(std::iter::repeat_n(pad_len as u8, pad_len));
+}
+
+// Function that removes PKCS#7 padding from a vector of bytes
+pub fn pkcs7_unpad(data: &mut Vec<u8>) -> Result<(), String> {
+    if data.is_empty() {
+        return Err("Empty data, cannot remove padding".to_string());
+    }
+    let pad_len = *data.last().unwrap() as usize;
+    if pad_len == 0 || pad_len > BLOCK_LEN || pad_len > data.len() {
+        return Err("Invalid PKCS#7 padding".to_string());
+    }
+    let start = data.len() - pad_len;
+    if data[start..].iter().any(|&x| x as usize != pad_len) {
+        return Err("Invalid PKCS#7 padding (incorrect bytes)".to_string());
+    }
+    data.truncate(start);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pad_always_adds_at_least_one_byte() {
+        for len in 0..=2 * BLOCK_LEN {
+            let mut data = vec![0xAA; len];
+            pkcs7_pad(&mut data);
+            assert_eq!(data.len() % BLOCK_LEN, 0);
+            assert!(data.len() > len);
+            pkcs7_unpad(&mut data).unwrap();
+            assert_eq!(data, vec![0xAA; len]);
+        }
+    }
+
+    #[test]
+    fn unpad_rejects_malformed_padding() {
+        assert!(pkcs7_unpad(&mut Vec::new()).is_err());
+        assert!(pkcs7_unpad(&mut vec![1, 2, 0]).is_err());
+        assert!(pkcs7_unpad(&mut vec![1, 3, 2]).is_err());
+        assert!(pkcs7_unpad(&mut vec![17; 17]).is_err());
+    }
+}
@@ -0,0 +1,334 @@
+// Self-describing container for every encrypted file written by Vaultify
+//
+// Layout (big-endian):
+//   magic "VLTF" | version u8 | cipher u8 | kdf u8 | kdf params 3 x u32 | chunk size u32 | nonce 12
+// followed by the ciphertext and its tag. The whole header is authenticated
+// as GCM additional data, so the metadata cannot be altered either.
+use crate::backend::aes_keys::cipher::ecb;
+use crate::backend::aes_keys::cipher::gcm::{self, generate_nonce, AesGcm, NONCE_LEN};
+use crate::backend::aes_keys::cipher::{Aes256, BLOCK_LEN};
+
+// Magic bytes opening every container
+pub const MAGIC: &[u8; 4] = b"VLTF";
+// Current format version
+pub const FORMAT_VERSION: u8 = 1;
+// Size of the serialized header
+pub const HEADER_LEN: usize = 4 + 1 + 1 + 1 + 12 + 4 + NONCE_LEN;
+
+/**
+ * Cipher used for the payload of a container.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Cipher {
+    /// AES-256-GCM over the whole payload
+    Aes256Gcm,
+    /// AES-256-GCM over fixed-size segments, see `chunked`
+    Aes256GcmChunked,
+}
+
+impl Cipher {
+    fn id(&self) -> u8 {
+        match self {
+            Self::Aes256Gcm => 1,
+            Self::Aes256GcmChunked => 2,
+        }
+    }
+
+    fn from_id(id: u8) -> Result<Self, String> {
+        match id {
+            1 => Ok(Self::Aes256Gcm),
+            2 => Ok(Self::Aes256GcmChunked),
+            _ => Err(format!("Unknown cipher id {}", id)),
+        }
+    }
+}
+
+/**
+ * Key derivation used to obtain the key that sealed a container.
+ *
+ * `None` is used for random keys (vault keys), the other variants record
+ * the parameters needed to derive the same key from a password again.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kdf {
+    None,
+    Pbkdf2Sha256 {
+        iterations: u32,
+    },
+    Argon2id {
+        memory_kib: u32,
+        iterations: u32,
+        parallelism: u32,
+    },
+}
+
+impl Kdf {
+    fn to_bytes(self) -> [u8; 13] {
+        let (id, params) = match self {
+            Self::None => (0u8, [0u32; 3]),
+            Self::Pbkdf2Sha256 { iterations } => (1u8, [iterations, 0, 0]),
+            Self::Argon2id {
+                memory_kib,
+                iterations,
+                parallelism,
+            } => (2u8, [memory_kib, iterations, parallelism]),
+        };
+        let mut bytes = [0u8; 13];
+        bytes[0] = id;
+        for (i, param) in params.iter().enumerate() {
+            bytes[1 + 4 * i..5 + 4 * i].copy_from_slice(&param.to_be_bytes());
+        }
+        bytes
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Result<Self, String> {
+        let param = |i: usize| {
+            u32::from_be_bytes([
+                bytes[1 + 4 * i],
+                bytes[2 + 4 * i],
+                bytes[3 + 4 * i],
+                bytes[4 + 4 * i],
+            ])
+        };
+        match bytes[0] {
+            0 => Ok(Self::None),
+            1 => Ok(Self::Pbkdf2Sha256 {
+                iterations: param(0),
+            }),
+            2 => Ok(Self::Argon2id {
+                memory_kib: param(0),
+                iterations: param(1),
+                parallelism: param(2),
+            }),
+            id => Err(format!("Unknown kdf id {}", id)),
+        }
+    }
+}
+
+/**
+ * Header placed in front of every encrypted file.
+ */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Header {
+    pub version: u8,
+    pub cipher: Cipher,
+    pub kdf: Kdf,
+    pub chunk_size: u32,
+    pub nonce: [u8; NONCE_LEN],
+}
+
+impl Header {
+    /**
+     * Creates a header for the current format version with a fresh nonce.
+     *
+     * @param cipher - The cipher used for the payload.
+     * @param kdf - How the sealing key was derived.
+     * @param chunk_size - Plaintext size of each chunk, 0 for a single blob.
+     * @return A new Header instance.
+     */
+    pub fn new(cipher: Cipher, kdf: Kdf, chunk_size: u32) -> Self {
+        Self {
+            version: FORMAT_VERSION,
+            cipher,
+            kdf,
+            chunk_size,
+            nonce: generate_nonce(),
+        }
+    }
+
+    /// Serializes the header.
+    pub fn to_bytes(&self) -> [u8; HEADER_LEN] {
+        let mut bytes = [0u8; HEADER_LEN];
+        bytes[..4].copy_from_slice(MAGIC);
+        bytes[4] = self.version;
+        bytes[5] = self.cipher.id();
+        bytes[6..19].copy_from_slice(&self.kdf.to_bytes());
+        bytes[19..23].copy_from_slice(&self.chunk_size.to_be_bytes());
+        bytes[23..].copy_from_slice(&self.nonce);
+        bytes
+    }
+
+    /**
+     * Parses the header at the start of a blob.
+     *
+     * @param data - The encrypted blob.
+     * @return Ok(None) for a headerless (legacy) blob, the header otherwise.
+     */
+    pub fn parse(data: &[u8]) -> Result<Option<Self>, String> {
+        if data.len() < MAGIC.len() || &data[..MAGIC.len()] != MAGIC {
+            return Ok(None);
+        }
+        if data.len() < HEADER_LEN {
+            return Err("Truncated header".to_string());
+        }
+        if data[4] != FORMAT_VERSION {
+            return Err(format!("Unsupported format version {}", data[4]));
+        }
+        let mut nonce = [0u8; NONCE_LEN];
+        nonce.copy_from_slice(&data[23..HEADER_LEN]);
+        Ok(Some(Self {
+            version: data[4],
+            cipher: Cipher::from_id(data[5])?,
+            kdf: Kdf::from_bytes(&data[6..19])?,
+            chunk_size: u32::from_be_bytes([data[19], data[20], data[21], data[22]]),
+            nonce,
+        }))
+    }
+}
+
+/**
+ * Encrypts data into a container.
+ *
+ * @param data - The plaintext data to encrypt.
+ * @param key - The encryption key.
+ * @param kdf - How `key` was derived, recorded in the header.
+ * @return Vec<u8> - header || ciphertext || tag.
+ */
+pub fn seal(data: &[u8], key: &[u8], kdf: Kdf) -> Vec<u8> {
+    seal_with(&Header::new(Cipher::Aes256Gcm, kdf, 0), data, key)
+}
+
+/**
+ * Encrypts data into a container using a prepared header.
+ *
+ * @param header - The header to write, its nonce must be fresh.
+ * @param data - The plaintext data to encrypt.
+ * @param key - The encryption key.
+ * @return Vec<u8> - header || ciphertext || tag.
+ */
+pub fn seal_with(header: &Header, data: &[u8], key: &[u8]) -> Vec<u8> {
+    let header_bytes = header.to_bytes();
+    let sealed = AesGcm::new(key).seal(&header.nonce, &header_bytes, data);
+
+    let mut output = Vec::with_capacity(HEADER_LEN + sealed.len());
+    output.extend_from_slice(&header_bytes);
+    output.extend_from_slice(&sealed);
+    output
+}
+
+/**
+ * Decrypts a container, or a legacy headerless blob.
+ *
+ * Headerless blobs are either bare `gcm::encrypt` output or the ECB blobs of
+ * the first releases. A blob carrying a header is never retried as legacy, nor
+ * is one that only lacks the magic bytes. Readers should write legacy blobs
+ * back as containers once opened, see `is_legacy`.
+ *
+ * @param data - The encrypted blob.
+ * @param key - The decryption key.
+ * @return Result<Vec<u8>, String> - The plaintext or an error message.
+ */
+pub fn open(data: &[u8], key: &[u8]) -> Result<Vec<u8>, String> {
+    match Header::parse(data)? {
+        Some(header) => match header.cipher {
+            Cipher::Aes256Gcm => {
+                AesGcm::new(key).open(&header.nonce, &data[..HEADER_LEN], &data[HEADER_LEN..])
+            }
+            Cipher::Aes256GcmChunked => {
+                Err("Chunked container, read it with chunked::ChunkedReader".to_string())
+            }
+        },
+        None => open_legacy(data, key),
+    }
+}
+
+// Compatibility path for blobs written before the container format existed
+fn open_legacy(data: &[u8], key: &[u8]) -> Result<Vec<u8>, String> {
+    gcm::decrypt(data, key).or_else(|e| {
+        // ECB is not authenticated, so only blobs it could have written get there
+        if fits_legacy_ecb(data) {
+            ecb::decrypt(&Aes256::new(key), data)
+        } else {
+            Err(e)
+        }
+    })
+}
+
+// Whether a blob has the shape of the ECB output of the first releases:
+// whole blocks, and not a container whose magic bytes were damaged
+fn fits_legacy_ecb(data: &[u8]) -> bool {
+    if data.is_empty() || !data.len().is_multiple_of(BLOCK_LEN) {
+        return false;
+    }
+    let mut header = data[..HEADER_LEN.min(data.len())].to_vec();
+    header[..MAGIC.len()].copy_from_slice(MAGIC);
+    !matches!(Header::parse(&header), Ok(Some(_)))
+}
+
+/**
+ * Tells whether a blob still uses a legacy headerless format, and should be
+ * sealed again as a container after being opened.
+ *
+ * @param data - The encrypted blob.
+ * @return bool - true if the blob has no container header.
+ */
+pub fn is_legacy(data: &[u8]) -> bool {
+    data.len() < MAGIC.len() || &data[..MAGIC.len()] != MAGIC
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn seal_round_trip_keeps_the_kdf() {
+        let key = [1u8; 32];
+        let kdf = Kdf::Argon2id {
+            memory_kib: 19456,
+            iterations: 2,
+            parallelism: 1,
+        };
+        let sealed = seal(b"secret", &key, kdf);
+        assert!(!is_legacy(&sealed));
+        assert_eq!(Header::parse(&sealed).unwrap().unwrap().kdf, kdf);
+        assert_eq!(open(&sealed, &key).unwrap(), b"secret");
+        assert!(open(&sealed, &[2u8; 32]).is_err());
+    }
+
+    #[test]
+    fn header_is_authenticated() {
+        let key = [3u8; 32];
+        let mut sealed = seal(b"secret", &key, Kdf::None);
+        sealed[6] = 1;
+        assert!(open(&sealed, &key).is_err());
+    }
+
+    #[test]
+    fn opens_headerless_blobs() {
+        let key = [4u8; 32];
+        let bare_gcm = gcm::encrypt(b"from gcm", &key);
+        assert!(is_legacy(&bare_gcm));
+        assert_eq!(open(&bare_gcm, &key).unwrap(), b"from gcm");
+
+        let legacy_ecb = ecb::encrypt(&Aes256::new(&key), b"from the first releases");
+        assert!(is_legacy(&legacy_ecb));
+        assert_eq!(open(&legacy_ecb, &key).unwrap(), b"from the first releases");
+    }
+
+    #[test]
+    fn damaged_magic_is_not_opened_as_legacy() {
+        let key = [5u8; 32];
+        // 13 bytes of plaintext give a whole number of blocks, as ECB output has
+        let sealed = seal(b"thirteen byte", &key, Kdf::None);
+        assert!(sealed.len().is_multiple_of(BLOCK_LEN));
+        for i in 0..MAGIC.len() {
+            for value in 0..=255u8 {
+                let mut tampered = sealed.clone();
+                tampered[i] = value;
+                if tampered[i] != sealed[i] {
+                    assert!(open(&tampered, &key).is_err());
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn legacy_ecb_needs_whole_blocks() {
+        let key = [6u8; 32];
+        let legacy_ecb = ecb::encrypt(&Aes256::new(&key), b"legacy");
+        let mut extended = legacy_ecb.clone();
+        extended.push(0);
+        assert!(open(&extended, &key).is_err());
+        assert!(open(&[], &key).is_err());
+    }
+}
@@ -0,0 +1,206 @@
+// X25519 keypair of a user, so vault keys can be sealed to them while they are offline
+//
+// The public key is stored in clear with the account. The private key is
+// sealed under the user key, bound to the user ID, so only the logged-in
+// user can open what was sealed to them. Sealing to a public key is an
+// ephemeral X25519 exchange, HKDF-SHA256 over the shared secret and both
+// public keys, then AES-256-GCM:
+//   ephemeral public key (32) || nonce || ciphertext || tag
+use crate::backend::aes_keys::cipher::gcm::{generate_nonce, AesGcm, NONCE_LEN, TAG_LEN};
+use ring::hkdf;
+use ring::rand::{SecureRandom, SystemRandom};
+use x25519_dalek::{PublicKey, StaticSecret};
+use zeroize::Zeroizing;
+
+// Size of a public or private key in bytes
+pub const KEY_LEN: usize = 32;
+
+/**
+ * X25519 keypair of a user.
+ */
+pub struct UserKeypair {
+    secret: StaticSecret,
+    public: PublicKey,
+}
+
+impl UserKeypair {
+    /**
+     * Draws a fresh keypair from the system CSPRNG.
+     *
+     * @return A new UserKeypair instance.
+     */
+    pub fn generate() -> Self {
+        Self::from_secret(StaticSecret::from(random_bytes()))
+    }
+
+    fn from_secret(secret: StaticSecret) -> Self {
+        let public = PublicKey::from(&secret);
+        Self { secret, public }
+    }
+
+    /**
+     * Returns the public key, to store with the account.
+     *
+     * @return [u8; KEY_LEN] - The public key.
+     */
+    pub fn public_key(&self) -> [u8; KEY_LEN] {
+        self.public.to_bytes()
+    }
+
+    /**
+     * Seals the private key under a user key.
+     *
+     * @param user_id - The ID of the user.
+     * @param user_key - The current user key.
+     * @return Vec<u8> - nonce || ciphertext || tag, to store.
+     */
+    pub fn wrap_private(&self, user_id: u32, user_key: &[u8]) -> Vec<u8> {
+        let nonce = generate_nonce();
+        let mut output = nonce.to_vec();
+        output.extend(AesGcm::new(user_key).seal(
+            &nonce,
+            &private_aad(user_id),
+            self.secret.as_bytes(),
+        ));
+        output
+    }
+
+    /**
+     * Opens a private key sealed by `wrap_private`.
+     *
+     * @param user_id - The ID of the user.
+     * @param user_key - The user key the private key was sealed with.
+     * @param wrapped - The stored private key.
+     * @return Result<Self, String> - The keypair or an error message.
+     */
+    pub fn unwrap_private(user_id: u32, user_key: &[u8], wrapped: &[u8]) -> Result<Self, String> {
+        if wrapped.len() < NONCE_LEN + TAG_LEN {
+            return Err("Private key too short".to_string());
+        }
+        let (nonce, sealed) = wrapped.split_at(NONCE_LEN);
+        let bytes = Zeroizing::new(AesGcm::new(user_key).open(
+            &nonce.try_into().unwrap(),
+            &private_aad(user_id),
+            sealed,
+        )?);
+        let bytes: [u8; KEY_LEN] = bytes
+            .as_slice()
+            .try_into()
+            .map_err(|_| "Invalid private key".to_string())?;
+        Ok(Self::from_secret(StaticSecret::from(bytes)))
+    }
+
+    /**
+     * Opens data sealed to this keypair by `seal_to`.
+     *
+     * @param sealed - The sealed data.
+     * @return Result<Zeroizing<Vec<u8>>, String> - The data or an error message.
+     */
+    pub fn open_sealed(&self, sealed: &[u8]) -> Result<Zeroizing<Vec<u8>>, String> {
+        if sealed.len() < KEY_LEN + NONCE_LEN + TAG_LEN {
+            return Err("Sealed data too short".to_string());
+        }
+        let (ephemeral, rest) = sealed.split_at(KEY_LEN);
+        let ephemeral = PublicKey::from(<[u8; KEY_LEN]>::try_from(ephemeral).unwrap());
+        let shared = self.secret.diffie_hellman(&ephemeral);
+        if !shared.was_contributory() {
+            return Err("Invalid ephemeral key".to_string());
+        }
+        let aad = box_aad(&ephemeral, &self.public);
+        let key = box_key(shared.as_bytes(), &aad);
+        let (nonce, data) = rest.split_at(NONCE_LEN);
+        AesGcm::new(&key[..])
+            .open(&nonce.try_into().unwrap(), &aad, data)
+            .map(Zeroizing::new)
+    }
+}
+
+/**
+ * Seals data to a public key, only the matching private key opens it.
+ *
+ * @param public_key - The public key of the recipient.
+ * @param data - The data to seal.
+ * @return Result<Vec<u8>, String> - The sealed data, or an error if the public key is invalid.
+ */
+pub fn seal_to(public_key: &[u8], data: &[u8]) -> Result<Vec<u8>, String> {
+    let recipient: [u8; KEY_LEN] = public_key
+        .try_into()
+        .map_err(|_| "Invalid public key".to_string())?;
+    let recipient = PublicKey::from(recipient);
+
+    let ephemeral = StaticSecret::from(random_bytes());
+    let ephemeral_public = PublicKey::from(&ephemeral);
+    let shared = ephemeral.diffie_hellman(&recipient);
+    if !shared.was_contributory() {
+        return Err("Invalid public key".to_string());
+    }
+    let aad = box_aad(&ephemeral_public, &recipient);
+    let key = box_key(shared.as_bytes(), &aad);
+
+    let nonce = generate_nonce();
+    let mut output = ephemeral_public.to_bytes().to_vec();
+    output.extend_from_slice(&nonce);
+    output.extend(AesGcm::new(&key[..]).seal(&nonce, &aad, data));
+    Ok(output)
+}
+
+fn random_bytes() -> [u8; KEY_LEN] {
+    let mut bytes = [0u8; KEY_LEN];
+    SystemRandom::new()
+        .fill(&mut bytes)
+        .expect("Failed to generate keypair");
+    bytes
+}
+
+// Authenticated data binding a private key to its user
+fn private_aad(user_id: u32) -> Vec<u8> {
+    let mut aad = b"keypair".to_vec();
+    aad.extend_from_slice(&user_id.to_be_bytes());
+    aad
+}
+
+// Both public keys, so a sealed box cannot be replayed to another recipient
+fn box_aad(ephemeral: &PublicKey, recipient: &PublicKey) -> Vec<u8> {
+    let mut aad = ephemeral.to_bytes().to_vec();
+    aad.extend_from_slice(recipient.as_bytes());
+    aad
+}
+
+fn box_key(shared: &[u8], aad: &[u8]) -> Zeroizing<[u8; KEY_LEN]> {
+    let mut key = Zeroizing::new([0u8; KEY_LEN]);
+    hkdf::Salt::new(hkdf::HKDF_SHA256, aad)
+        .extract(shared)
+        .expand(&[b"vaultify sealed box"], hkdf::HKDF_SHA256)
+        .and_then(|okm| okm.fill(&mut key[..]))
+        .expect("HKDF output of one block");
+    key
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sealed_data_opens_only_with_the_recipient_key() {
+        let recipient = UserKeypair::generate();
+        let sealed = seal_to(&recipient.public_key(), b"vault key").unwrap();
+        assert_eq!(&recipient.open_sealed(&sealed).unwrap()[..], b"vault key");
+        assert!(UserKeypair::generate().open_sealed(&sealed).is_err());
+
+        let mut tampered = sealed.clone();
+        tampered[KEY_LEN + NONCE_LEN] ^= 1;
+        assert!(recipient.open_sealed(&tampered).is_err());
+        assert!(seal_to(&[0u8; KEY_LEN], b"vault key").is_err());
+        assert!(seal_to(&[1u8; 5], b"vault key").is_err());
+    }
+
+    #[test]
+    fn private_key_round_trip() {
+        let keypair = UserKeypair::generate();
+        let wrapped = keypair.wrap_private(3, &[6u8; 32]);
+        let opened = UserKeypair::unwrap_private(3, &[6u8; 32], &wrapped).unwrap();
+        assert_eq!(opened.public_key(), keypair.public_key());
+        assert!(UserKeypair::unwrap_private(4, &[6u8; 32], &wrapped).is_err());
+        assert!(UserKeypair::unwrap_private(3, &[7u8; 32], &wrapped).is_err());
+    }
+}
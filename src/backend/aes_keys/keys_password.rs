@@ -1,9 +1,120 @@
 // Importing the necessary libraries
+use crate::backend::aes_keys::container::Kdf; // To record how a user key was derived
+use crate::backend::aes_keys::secret_key::SecretKey; // To wipe derived keys once dropped
+use argon2::{Algorithm, Argon2, Params, Version}; // For memory-hard key derivation via Argon2id
 use ring::pbkdf2; // For password-based key derivation via PBKDF2
 use ring::rand::{SecureRandom, SystemRandom}; // For secure random number generation
+use serde::{Deserialize, Serialize}; // For reading the parameters from the server configuration
 use sha2::{Digest, Sha256}; // For SHA256 hashing
 use std::num::NonZeroU32; // For working with non-zero integers
 
+// Number of PBKDF2 iterations used by accounts created before per-user salts
+pub const USER_KEY_ITERATIONS: u32 = 10_000;
+
+// Size of the random salt stored for every user
+pub const USER_SALT_LEN: usize = 16;
+
+/**
+ * Cost parameters of Argon2id.
+ *
+ * The defaults follow the OWASP recommendation (19 MiB, 2 passes, 1 lane).
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Argon2Params {
+    pub memory_kib: u32,
+    pub iterations: u32,
+    pub parallelism: u32,
+}
+
+impl Default for Argon2Params {
+    fn default() -> Self {
+        Self {
+            memory_kib: 19 * 1024,
+            iterations: 2,
+            parallelism: 1,
+        }
+    }
+}
+
+impl Argon2Params {
+    /// Checks that Argon2 accepts the parameters.
+    pub fn validate(&self) -> Result<(), String> {
+        self.to_argon2().map(|_| ())
+    }
+
+    /// Returns the description recorded in the header of blobs sealed with the derived key.
+    pub fn kdf(&self) -> Kdf {
+        Kdf::Argon2id {
+            memory_kib: self.memory_kib,
+            iterations: self.iterations,
+            parallelism: self.parallelism,
+        }
+    }
+
+    fn to_argon2(self) -> Result<Argon2<'static>, String> {
+        let params = Params::new(self.memory_kib, self.iterations, self.parallelism, Some(32))
+            .map_err(|e| format!("Invalid Argon2id parameters: {}", e))?;
+        Ok(Argon2::new(Algorithm::Argon2id, Version::V0x13, params))
+    }
+}
+
+/**
+ * Salt and parameters used to derive the key of a user, stored in the users table.
+ */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UserKdf {
+    pub salt: Vec<u8>,
+    pub params: Argon2Params,
+}
+
+impl UserKdf {
+    /**
+     * Creates a fresh random salt for the given parameters.
+     *
+     * @param params - The Argon2id parameters.
+     * @return A new UserKdf instance.
+     */
+    pub fn generate(params: Argon2Params) -> Self {
+        Self {
+            salt: generate_salt(),
+            params,
+        }
+    }
+
+    /**
+     * Derives the user key from the password.
+     *
+     * @param password - The password of the user.
+     * @return Result<SecretKey, String> - The 256-bit key or an error message.
+     */
+    pub fn derive(&self, password: &str) -> Result<SecretKey, String> {
+        derive_key_argon2id(password, &self.salt, &self.params).map(SecretKey::new)
+    }
+}
+
+// Function that derives an AES-256 key from a password and a salt with Argon2id
+pub fn derive_key_argon2id(
+    password: &str,
+    salt: &[u8],
+    params: &Argon2Params,
+) -> Result<Vec<u8>, String> {
+    let mut key = vec![0; 32];
+    params
+        .to_argon2()?
+        .hash_password_into(password.as_bytes(), salt, &mut key)
+        .map_err(|e| format!("Argon2id derivation failed: {}", e))?;
+    Ok(key)
+}
+
+// Function that generates a random salt for a new user
+pub fn generate_salt() -> Vec<u8> {
+    let rng = SystemRandom::new();
+    let mut salt = vec![0; USER_SALT_LEN];
+    rng.fill(&mut salt).expect("Failed to generate salt");
+    salt
+}
+
 // Function that derives an AES-256 key from a password, a salt, and an iteration count
 pub fn derive_key(password: &str, salt: &[u8], iterations: u32) -> Vec<u8> {
     // Initialize a vector of 32 bytes (256 bits) to store the derived key
@@ -1,5 +1,15 @@
+pub mod keypair;
+
 pub mod keys_password;
 
-pub mod crypted_key;
+pub mod cipher;
+
+pub mod container;
+
+pub mod chunked;
+
+pub mod recovery_key;
+
+pub mod secret_key;
 
-pub mod decrypted_key;
+pub mod vault_key;
@@ -0,0 +1,197 @@
+// Account recovery key, the way back in after a forgotten password
+//
+// A random 256-bit key is shown once to the user, grouped in base32. The
+// server keeps two blobs per account:
+// - the escrow: the user key sealed under a key derived from the recovery
+//   key, opened when the user recovers the account;
+// - the wrap: the recovery key sealed under the user key, so the escrow can
+//   be sealed again when the user key changes while the password does not
+//   (e.g. Argon2id parameter upgrades).
+// Both are bound to the user ID, so blobs copied to another account do not open.
+use crate::backend::aes_keys::cipher::gcm::{generate_nonce, AesGcm, NONCE_LEN, TAG_LEN};
+use crate::backend::aes_keys::secret_key::SecretKey;
+use crate::backend::auth::base32::{base32_decode, base32_encode};
+use ring::hmac;
+use ring::rand::{SecureRandom, SystemRandom};
+use zeroize::Zeroizing;
+
+// Size of a recovery key in bytes
+pub const RECOVERY_KEY_LEN: usize = 32;
+// Characters per group when the key is shown
+const GROUP_LEN: usize = 4;
+
+/**
+ * Recovery key of an account, always exactly 256 bits.
+ */
+pub struct RecoveryKey(SecretKey);
+
+impl RecoveryKey {
+    /**
+     * Draws a fresh key from the system CSPRNG.
+     *
+     * @return A new RecoveryKey instance.
+     */
+    pub fn generate() -> Self {
+        let mut key = vec![0u8; RECOVERY_KEY_LEN];
+        SystemRandom::new()
+            .fill(&mut key)
+            .expect("Failed to generate recovery key");
+        Self(SecretKey::new(key))
+    }
+
+    /**
+     * Reads a key typed by the user, ignoring case, spaces and dashes.
+     *
+     * @param text - The key as shown by `display`.
+     * @return Result<Self, String> - The key, or an error if it is malformed.
+     */
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let bytes = Zeroizing::new(base32_decode(text)?);
+        if bytes.len() != RECOVERY_KEY_LEN {
+            return Err("Invalid recovery key".to_string());
+        }
+        Ok(Self(SecretKey::from_slice(&bytes)))
+    }
+
+    /**
+     * Formats the key for the user, in dash-separated groups of base32.
+     *
+     * @return Zeroizing<String> - The key, e.g. `ABCD-EFGH-...`.
+     */
+    pub fn display(&self) -> Zeroizing<String> {
+        let encoded = Zeroizing::new(base32_encode(self.0.as_bytes()));
+        let groups: Vec<&str> = encoded
+            .as_bytes()
+            .chunks(GROUP_LEN)
+            .map(|group| std::str::from_utf8(group).unwrap())
+            .collect();
+        Zeroizing::new(groups.join("-"))
+    }
+
+    // Key of the escrow, kept apart from the recovery key itself
+    fn escrow_cipher(&self) -> AesGcm {
+        let key = hmac::Key::new(hmac::HMAC_SHA256, self.0.as_bytes());
+        let escrow_key = Zeroizing::new(
+            hmac::sign(&key, b"vaultify recovery escrow")
+                .as_ref()
+                .to_vec(),
+        );
+        AesGcm::new(&escrow_key)
+    }
+
+    /**
+     * Seals a user key under this recovery key.
+     *
+     * @param user_id - The ID of the user.
+     * @param user_key - The current user key.
+     * @return Vec<u8> - The escrow to store.
+     */
+    pub fn escrow(&self, user_id: u32, user_key: &[u8]) -> Vec<u8> {
+        seal(&self.escrow_cipher(), &aad(b"escrow", user_id), user_key)
+    }
+
+    /**
+     * Opens an escrow sealed by `escrow`.
+     *
+     * @param user_id - The ID of the user.
+     * @param escrow - The stored escrow.
+     * @return Result<SecretKey, String> - The user key, or an error if this is not the right recovery key.
+     */
+    pub fn open_escrow(&self, user_id: u32, escrow: &[u8]) -> Result<SecretKey, String> {
+        open(&self.escrow_cipher(), &aad(b"escrow", user_id), escrow)
+            .map(SecretKey::new)
+            .map_err(|_| "Invalid recovery key".to_string())
+    }
+
+    /**
+     * Seals this recovery key under a user key.
+     *
+     * @param user_id - The ID of the user.
+     * @param user_key - The current user key.
+     * @return Vec<u8> - The wrap to store.
+     */
+    pub fn wrap(&self, user_id: u32, user_key: &[u8]) -> Vec<u8> {
+        seal(
+            &AesGcm::new(user_key),
+            &aad(b"wrap", user_id),
+            self.0.as_bytes(),
+        )
+    }
+
+    /**
+     * Opens a wrap sealed by `wrap`.
+     *
+     * @param user_id - The ID of the user.
+     * @param user_key - The user key the wrap was sealed with.
+     * @param wrapped - The stored wrap.
+     * @return Result<Self, String> - The recovery key or an error message.
+     */
+    pub fn unwrap(user_id: u32, user_key: &[u8], wrapped: &[u8]) -> Result<Self, String> {
+        let bytes = Zeroizing::new(open(
+            &AesGcm::new(user_key),
+            &aad(b"wrap", user_id),
+            wrapped,
+        )?);
+        if bytes.len() != RECOVERY_KEY_LEN {
+            return Err("Invalid recovery key".to_string());
+        }
+        Ok(Self(SecretKey::from_slice(&bytes)))
+    }
+}
+
+// Authenticated data binding a blob to its purpose and its user
+fn aad(purpose: &[u8], user_id: u32) -> Vec<u8> {
+    let mut aad = purpose.to_vec();
+    aad.extend_from_slice(&user_id.to_be_bytes());
+    aad
+}
+
+// nonce || ciphertext || tag
+fn seal(cipher: &AesGcm, aad: &[u8], data: &[u8]) -> Vec<u8> {
+    let nonce = generate_nonce();
+    let mut output = nonce.to_vec();
+    output.extend(cipher.seal(&nonce, aad, data));
+    output
+}
+
+fn open(cipher: &AesGcm, aad: &[u8], data: &[u8]) -> Result<Vec<u8>, String> {
+    if data.len() < NONCE_LEN + TAG_LEN {
+        return Err("Ciphertext too short".to_string());
+    }
+    let (nonce, sealed) = data.split_at(NONCE_LEN);
+    cipher.open(&nonce.try_into().unwrap(), aad, sealed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn display_round_trip() {
+        let key = RecoveryKey::generate();
+        let shown = key.display();
+        assert_eq!(shown.len(), 52 + 12);
+        let typed = shown.to_lowercase().replace('-', " ");
+        assert!(RecoveryKey::parse(&typed).unwrap().0 == key.0);
+        assert!(RecoveryKey::parse(&shown[..shown.len() - 5]).is_err());
+    }
+
+    #[test]
+    fn escrow_opens_only_with_the_right_key_and_user() {
+        let key = RecoveryKey::generate();
+        let escrow = key.escrow(3, &[5u8; 32]);
+        assert_eq!(key.open_escrow(3, &escrow).unwrap().as_bytes(), &[5u8; 32]);
+        assert!(key.open_escrow(4, &escrow).is_err());
+        assert!(RecoveryKey::generate().open_escrow(3, &escrow).is_err());
+    }
+
+    #[test]
+    fn wrap_round_trip() {
+        let key = RecoveryKey::generate();
+        let wrapped = key.wrap(3, &[6u8; 32]);
+        assert!(RecoveryKey::unwrap(3, &[6u8; 32], &wrapped).unwrap().0 == key.0);
+        assert!(RecoveryKey::unwrap(3, &[7u8; 32], &wrapped).is_err());
+        // A wrap is not an escrow
+        assert!(key.open_escrow(3, &wrapped).is_err());
+    }
+}
@@ -0,0 +1,102 @@
+// Heap buffer for key material that is wiped when it is no longer needed
+//
+// The bytes are zeroed when the value is dropped, and can be wiped earlier
+// with `wipe`, e.g. when a session or a vault falls out of cache while a
+// handler still holds a reference to it. The type is deliberately neither
+// `Debug` nor `Serialize`, so a key cannot end up in a log line or a response.
+use zeroize::Zeroize;
+
+pub struct SecretKey(Vec<u8>);
+
+impl SecretKey {
+    /**
+     * Takes ownership of key material without copying it.
+     *
+     * @param bytes - The key bytes.
+     * @return A new SecretKey instance.
+     */
+    pub fn new(bytes: Vec<u8>) -> Self {
+        Self(bytes)
+    }
+
+    /**
+     * Copies key material into a new secret.
+     *
+     * @param bytes - The key bytes, the caller remains responsible for wiping them.
+     * @return A new SecretKey instance.
+     */
+    pub fn from_slice(bytes: &[u8]) -> Self {
+        Self(bytes.to_vec())
+    }
+
+    /// Returns the raw bytes of the key.
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+
+    /// Returns the length of the key in bytes, 0 once wiped.
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    /// Returns true if the key is empty, which is the case once wiped.
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Zeroes the key and leaves it empty, so it can never be used by mistake afterwards.
+    pub fn wipe(&mut self) {
+        self.0.zeroize();
+    }
+}
+
+impl Clone for SecretKey {
+    fn clone(&self) -> Self {
+        Self::from_slice(&self.0)
+    }
+}
+
+impl PartialEq for SecretKey {
+    // Compares without leaking the position of the first difference
+    fn eq(&self, other: &Self) -> bool {
+        self.0.len() == other.0.len()
+            && self
+                .0
+                .iter()
+                .zip(other.0.iter())
+                .fold(0u8, |acc, (x, y)| acc | (x ^ y))
+                == 0
+    }
+}
+
+impl Eq for SecretKey {}
+
+impl Drop for SecretKey {
+    fn drop(&mut self) {
+        self.0.zeroize();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wipe_leaves_the_key_empty() {
+        let mut key = SecretKey::new(vec![0x42; 32]);
+        assert_eq!(key.len(), 32);
+        key.wipe();
+        assert!(key.is_empty());
+        assert_eq!(key.as_bytes(), &[] as &[u8]);
+    }
+
+    #[test]
+    fn clones_are_independent() {
+        let key = SecretKey::from_slice(&[7u8; 32]);
+        let mut copy = key.clone();
+        assert!(copy == key);
+        copy.wipe();
+        assert_eq!(key.as_bytes(), &[7u8; 32]);
+        assert!(copy != key);
+    }
+}
@@ -0,0 +1,175 @@
+// 256-bit key protecting the content of a vault
+//
+// Every member of a vault holds a copy of the key in `.vault/users/<id>.json`,
+// sealed with their own user key. The plaintext of that file is the JSON
+// array of the key bytes, kept for compatibility with existing vaults.
+use crate::backend::aes_keys::container::{self, Kdf};
+use crate::backend::aes_keys::secret_key::SecretKey;
+use ring::rand::{SecureRandom, SystemRandom};
+use std::fmt;
+use zeroize::Zeroizing;
+
+// Size of a vault key in bytes
+pub const VAULT_KEY_LEN: usize = 32;
+
+/**
+ * Key of a vault, always exactly 256 bits.
+ *
+ * The bytes live in a `SecretKey`, so they are wiped on drop and never
+ * printed by `Debug`.
+ */
+#[derive(Clone, PartialEq, Eq)]
+pub struct VaultKey(SecretKey);
+
+impl VaultKey {
+    /**
+     * Draws a fresh key from the system CSPRNG.
+     *
+     * @return A new VaultKey instance.
+     */
+    pub fn generate() -> Self {
+        let rng = SystemRandom::new();
+        let mut key = vec![0u8; VAULT_KEY_LEN];
+        rng.fill(&mut key).expect("Failed to generate vault key");
+        Self(SecretKey::new(key))
+    }
+
+    /**
+     * Builds a key from raw bytes.
+     *
+     * @param bytes - The 32 bytes of the key.
+     * @return Result<Self, String> - The key, or an error if the length is wrong.
+     */
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, String> {
+        if bytes.len() != VAULT_KEY_LEN {
+            return Err(format!(
+                "Vault key must be {} bytes, got {}",
+                VAULT_KEY_LEN,
+                bytes.len()
+            ));
+        }
+        Ok(Self(SecretKey::from_slice(bytes)))
+    }
+
+    /// Returns the raw bytes of the key.
+    pub fn as_bytes(&self) -> &[u8] {
+        self.0.as_bytes()
+    }
+
+    /// Zeroes the key, called when the vault falls out of cache.
+    pub fn wipe(&mut self) {
+        self.0.wipe();
+    }
+
+    /// Returns true once the key has been wiped and must not be used anymore.
+    pub fn is_wiped(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /**
+     * Seals the key for a member of the vault.
+     *
+     * @param user_key - The key of the member.
+     * @param user_kdf - How `user_key` was derived, recorded in the header.
+     * @return Vec<u8> - The content of the member's key file.
+     */
+    pub fn wrap(&self, user_key: &[u8], user_kdf: Kdf) -> Vec<u8> {
+        let content = Zeroizing::new(
+            serde_json::to_vec(self.as_bytes()).expect("a byte slice always serializes"),
+        );
+        container::seal(&content, user_key, user_kdf)
+    }
+
+    /**
+     * Opens a member's key file.
+     *
+     * @param data - The content of the key file.
+     * @param user_key - The key of the member.
+     * @return Result<Self, String> - The vault key or an error message.
+     */
+    pub fn unwrap_key(data: &[u8], user_key: &[u8]) -> Result<Self, String> {
+        let content = Zeroizing::new(container::open(data, user_key)?);
+        let bytes: Zeroizing<Vec<u8>> = Zeroizing::new(
+            serde_json::from_slice(&content).map_err(|e| format!("Invalid key file: {}", e))?,
+        );
+        Self::from_bytes(&bytes)
+    }
+}
+
+impl fmt::Debug for VaultKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("VaultKey(..)")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn generated_keys_are_distinct() {
+        let a = VaultKey::generate();
+        let b = VaultKey::generate();
+        assert_eq!(a.as_bytes().len(), VAULT_KEY_LEN);
+        assert_ne!(a, b);
+    }
+
+    #[test]
+    fn from_bytes_checks_the_length() {
+        assert!(VaultKey::from_bytes(&[7u8; VAULT_KEY_LEN]).is_ok());
+        assert!(VaultKey::from_bytes(&[7u8; 31]).is_err());
+        assert!(VaultKey::from_bytes(&[7u8; 33]).is_err());
+        assert!(VaultKey::from_bytes(&[]).is_err());
+    }
+
+    #[test]
+    fn wrap_round_trip() {
+        let key = VaultKey::generate();
+        let user_key = [1u8; 32];
+        let wrapped = key.wrap(&user_key, Kdf::None);
+        assert_eq!(VaultKey::unwrap_key(&wrapped, &user_key).unwrap(), key);
+    }
+
+    #[test]
+    fn unwrap_with_the_wrong_user_key_fails() {
+        let wrapped = VaultKey::generate().wrap(&[1u8; 32], Kdf::None);
+        assert!(VaultKey::unwrap_key(&wrapped, &[2u8; 32]).is_err());
+    }
+
+    #[test]
+    fn reads_key_files_written_as_json_arrays() {
+        let user_key = [3u8; 32];
+        let bytes: Vec<u8> = (0..32).collect();
+        let legacy = container::seal(
+            serde_json::to_string(&bytes).unwrap().as_bytes(),
+            &user_key,
+            Kdf::None,
+        );
+        let key = VaultKey::unwrap_key(&legacy, &user_key).unwrap();
+        assert_eq!(key.as_bytes(), bytes.as_slice());
+    }
+
+    #[test]
+    fn rejects_key_files_with_a_short_key() {
+        let user_key = [4u8; 32];
+        let short = container::seal(b"[1,2,3]", &user_key, Kdf::None);
+        assert!(VaultKey::unwrap_key(&short, &user_key).is_err());
+    }
+
+    #[test]
+    fn debug_does_not_print_the_key() {
+        let key = VaultKey::from_bytes(&[0xAB; VAULT_KEY_LEN]).unwrap();
+        let printed = format!("{:?}", key);
+        assert_eq!(printed, "VaultKey(..)");
+        assert!(!printed.contains("171"));
+    }
+
+    #[test]
+    fn wipe_marks_the_key_unusable() {
+        let mut key = VaultKey::generate();
+        assert!(!key.is_wiped());
+        key.wipe();
+        assert!(key.is_wiped());
+        assert!(key.as_bytes().is_empty());
+    }
+}
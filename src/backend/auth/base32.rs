@@ -0,0 +1,82 @@
+// Base32 without padding (RFC 4648), for secrets people copy by hand
+//
+// Used for the otpauth secrets of authenticator apps and for recovery keys.
+// Decoding ignores case, spaces and dashes, so grouped or lowercase input
+// is accepted as typed.
+
+// The RFC 4648 alphabet, no 0/1/8/9 to avoid confusion with O/I/B/g
+pub const ALPHABET: &[u8; 32] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+
+// Function that encodes bytes in unpadded base32
+pub fn base32_encode(data: &[u8]) -> String {
+    let mut output = String::with_capacity(data.len().div_ceil(5) * 8);
+    let (mut buffer, mut bits) = (0u32, 0);
+    for &byte in data {
+        buffer = (buffer << 8) | byte as u32;
+        bits += 8;
+        while bits >= 5 {
+            bits -= 5;
+            output.push(ALPHABET[((buffer >> bits) & 31) as usize] as char);
+        }
+    }
+    if bits > 0 {
+        output.push(ALPHABET[((buffer << (5 - bits)) & 31) as usize] as char);
+    }
+    output
+}
+
+// Function that decodes unpadded base32, skipping spaces and dashes
+pub fn base32_decode(text: &str) -> Result<Vec<u8>, String> {
+    let mut output = Vec::with_capacity(text.len() * 5 / 8);
+    let (mut buffer, mut bits) = (0u32, 0);
+    for c in text.chars().filter(|c| !c.is_whitespace() && *c != '-') {
+        let value = ALPHABET
+            .iter()
+            .position(|&a| a as char == c.to_ascii_uppercase())
+            .ok_or_else(|| format!("Invalid base32 character {:?}", c))?;
+        buffer = (buffer << 5) | value as u32;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            output.push((buffer >> bits) as u8);
+        }
+    }
+    // Leftover bits are padding, a whole leftover character is a truncated input
+    if bits >= 5 || buffer & ((1 << bits) - 1) != 0 {
+        return Err("Truncated base32 input".to_string());
+    }
+    Ok(output)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // RFC 4648 section 10, without padding
+    const VECTORS: [(&str, &str); 7] = [
+        ("", ""),
+        ("f", "MY"),
+        ("fo", "MZXQ"),
+        ("foo", "MZXW6"),
+        ("foob", "MZXW6YQ"),
+        ("fooba", "MZXW6YTB"),
+        ("foobar", "MZXW6YTBOI"),
+    ];
+
+    #[test]
+    fn rfc_vectors() {
+        for (data, encoded) in VECTORS {
+            assert_eq!(base32_encode(data.as_bytes()), encoded);
+            assert_eq!(base32_decode(encoded).unwrap(), data.as_bytes());
+        }
+    }
+
+    #[test]
+    fn decode_accepts_typed_input() {
+        assert_eq!(base32_decode("mzxw-6ytb oi").unwrap(), b"foobar");
+        assert!(base32_decode("MZXW1").is_err());
+        assert!(base32_decode("MZX").is_err());
+        assert!(base32_decode("MZ").is_err());
+        assert!(base32_decode("M3").is_err());
+    }
+}
@@ -1,8 +1,10 @@
-use lettre::transport::smtp::authentication::Credentials;
-use lettre::{Message, SmtpTransport, Transport};
+use crate::backend::server_manager::global_manager::MAILER;
 use rand::Rng; // For handling current date and time
 use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
+// How long a code stays valid after it was sent
+pub const CODE_VALIDITY: Duration = Duration::from_secs(600);
+
 pub struct Timecode {
     pub code: String,
     pub time: SystemTime, // Current system time (standard)
@@ -20,11 +22,22 @@ impl Timecode {
 
     pub fn is_valid(&self) -> bool {
         match SystemTime::now().duration_since(self.time) {
-            Ok(duration) => duration < Duration::from_secs(600),
+            Ok(duration) => duration < CODE_VALIDITY,
             Err(_) => false, // In case system clock changed
         }
     }
 
+    // Compares a submitted code without leaking how many digits are right
+    pub fn matches(&self, code: &str) -> bool {
+        self.code.len() == code.len()
+            && self
+                .code
+                .bytes()
+                .zip(code.bytes())
+                .fold(0u8, |acc, (x, y)| acc | (x ^ y))
+                == 0
+    }
+
     pub fn timestamp(&self) -> Option<u64> {
         match self.time.duration_since(UNIX_EPOCH) {
             Ok(dur) => Some(dur.as_secs()),
@@ -41,31 +54,84 @@ pub fn generate_code() -> String {
         .collect()
 }
 
-// Function to send an email containing the verification code
+// Function to send an email containing the verification code, through the configured mailer
 pub fn send_email(to: &str, code: &str) -> Result<(), Box<dyn std::error::Error>> {
-    // Create the email message with subject and body
-    let email = Message::builder()
-        .from("vaultify.do.not.reply@gmail.com".parse()?) // Sender address
-        .to(to.parse()?) // Recipient address
-        .subject("Your 2FA verification code") // Email subject
-        .body(format!("Here is your verification code : {}", code))?; // Email body
-
-    // Set up the credentials (email + app-specific password)
-    let creds = Credentials::new(
-        "vaultify.do.not.reply@gmail.com".to_string(),
-        "rvsf dykg gzpw patc".to_string(), // App password from Gmail
-    );
-
-    // Set up the SMTP transport to send the email through Gmail
-    let mailer = SmtpTransport::relay("smtp.gmail.com")?
-        .credentials(creds)
-        .build();
-
-    // Send the email
-    mailer.send(&email)?;
+    MAILER.send(
+        to,
+        "Your 2FA verification code",
+        &format!("Here is your verification code : {}", code),
+    )?;
     Ok(())
 }
 
+// Function to send the code confirming the address of a new account
+pub fn send_verification_code(email_address: &str) -> Result<Timecode, Box<dyn std::error::Error>> {
+    let code = generate_code();
+    MAILER.send(
+        email_address,
+        "Confirm your Vaultify account",
+        &format!(
+            "Welcome to Vaultify! Here is the code confirming your email address : {}",
+            code
+        ),
+    )?;
+    Ok(Timecode::new(code, email_address.to_string()))
+}
+
+// Longest address accepted, RFC 5321 limits a path to 256 octets including the brackets
+const MAX_EMAIL_LEN: usize = 254;
+// Longest local part, before the '@'
+const MAX_LOCAL_LEN: usize = 64;
+
+// Function that returns the form emails are stored and looked up in: trimmed and lowercased.
+// Only ASCII letters are lowered, the same as SQLite's lower()
+pub fn canonical_email(email: &str) -> String {
+    email.trim().to_ascii_lowercase()
+}
+
+/**
+ * Checks the syntax of an email address and returns its canonical form.
+ *
+ * Accepts the common `local@domain.tld` addresses: a dot-atom local part and
+ * a domain of at least two labels made of letters, digits and hyphens. Quoted
+ * local parts, IP literals and non-ASCII addresses are refused.
+ *
+ * @param email - The address as typed by the user.
+ * @return Result<String, String> - The canonical address, or an error message.
+ */
+pub fn normalize_email(email: &str) -> Result<String, String> {
+    let email = canonical_email(email);
+    let invalid = || format!("Invalid email address {:?}", email);
+    if email.len() > MAX_EMAIL_LEN {
+        return Err(invalid());
+    }
+
+    let (local, domain) = email.split_once('@').ok_or_else(invalid)?;
+    let local_ok = !local.is_empty()
+        && local.len() <= MAX_LOCAL_LEN
+        && local.split('.').all(|atom| {
+            !atom.is_empty()
+                && atom
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || "!#$%&'*+/=?^_`{|}~-".contains(c))
+        });
+    let labels: Vec<&str> = domain.split('.').collect();
+    let domain_ok = labels.len() >= 2
+        && labels.iter().all(|label| {
+            !label.is_empty()
+                && label.len() <= 63
+                && !label.starts_with('-')
+                && !label.ends_with('-')
+                && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+        });
+
+    if local_ok && domain_ok {
+        Ok(email)
+    } else {
+        Err(invalid())
+    }
+}
+
 // This is the main function that handles everything:
 // it generates the code, sends it by email, and returns a Timecode struct
 pub fn final_send(email_address: &str) -> Result<Timecode, Box<dyn std::error::Error>> {
@@ -74,9 +140,46 @@ pub fn final_send(email_address: &str) -> Result<Timecode, Box<dyn std::error::E
     // Try to send the email and handle errors if they occur
     if let Err(e) = send_email(email_address, &code) {
         eprintln!("Error while sending email: {}", e);
-        return Err(e); // Forward the error
+        Err(e) // Forward the error
     } else {
         // If the email is sent successfully, return the Timecode struct
         Ok(Timecode::new(code, email_address.to_string()))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn normalizes_case_and_whitespace() {
+        assert_eq!(
+            normalize_email("  Jane.Doe+vault@Example.COM\n").unwrap(),
+            "jane.doe+vault@example.com"
+        );
+        assert_eq!(canonical_email(" A@B.io "), "a@b.io");
+    }
+
+    #[test]
+    fn rejects_malformed_addresses() {
+        for email in [
+            "",
+            "plain",
+            "@example.com",
+            "user@",
+            "user@localhost",
+            "a@b@example.com",
+            ".user@example.com",
+            "us..er@example.com",
+            "user@-example.com",
+            "user@example..com",
+            "us er@example.com",
+            "usér@example.com",
+            "user@exa_mple.com",
+        ] {
+            assert!(normalize_email(email).is_err(), "{:?} accepted", email);
+        }
+        assert!(normalize_email(&format!("{}@example.com", "a".repeat(65))).is_err());
+        assert!(normalize_email(&format!("{}@example.com", "a".repeat(64))).is_ok());
+    }
+}
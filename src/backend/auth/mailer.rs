@@ -0,0 +1,196 @@
+// Outgoing mail transports
+//
+// Everything that sends a mail goes through the `Mailer` trait, the transport
+// is chosen by the `mail` section of the server configuration. The maildir
+// transport is the default so a fresh server never relays anything before an
+// SMTP server has been configured.
+use crate::backend::server_manager::config::{MailConfig, MailTransport, SmtpConfig, TlsMode};
+use lettre::message::Mailbox;
+use lettre::transport::smtp::authentication::Credentials;
+use lettre::{Message, SmtpTransport, Transport};
+use rand::Rng;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/**
+ * Something able to deliver a plain text mail.
+ */
+pub trait Mailer: Send + Sync {
+    /**
+     * Sends a mail.
+     *
+     * @param to - The recipient address.
+     * @param subject - The subject line.
+     * @param body - The plain text body.
+     * @return Result<(), String> - Ok if the mail was handed over, an error message otherwise.
+     */
+    fn send(&self, to: &str, subject: &str, body: &str) -> Result<(), String>;
+}
+
+// Builds the message shared by every transport
+fn build_message(from: &Mailbox, to: &str, subject: &str, body: &str) -> Result<Message, String> {
+    let to: Mailbox = to
+        .parse()
+        .map_err(|e| format!("Invalid recipient {:?}: {}", to, e))?;
+    Message::builder()
+        .from(from.clone())
+        .to(to)
+        .subject(subject)
+        .body(body.to_string())
+        .map_err(|e| format!("Failed to build the mail: {}", e))
+}
+
+/**
+ * Sends mails through an SMTP server.
+ */
+pub struct SmtpMailer {
+    from: Mailbox,
+    transport: SmtpTransport,
+}
+
+impl SmtpMailer {
+    /**
+     * Prepares the connection to the SMTP server, nothing is sent yet.
+     *
+     * @param from - The sender.
+     * @param config - The SMTP server settings.
+     * @return Result<Self, String> - The mailer or an error message.
+     */
+    pub fn new(from: Mailbox, config: &SmtpConfig) -> Result<Self, String> {
+        let builder = match config.tls {
+            TlsMode::Tls => SmtpTransport::relay(&config.host),
+            TlsMode::StartTls => SmtpTransport::starttls_relay(&config.host),
+            TlsMode::None => Ok(SmtpTransport::builder_dangerous(&config.host)),
+        }
+        .map_err(|e| format!("Invalid SMTP server {:?}: {}", config.host, e))?;
+
+        let mut builder = builder.port(config.port);
+        if let Some((username, password)) = config.credentials() {
+            builder = builder.credentials(Credentials::new(username, password));
+        }
+        Ok(Self {
+            from,
+            transport: builder.build(),
+        })
+    }
+}
+
+impl Mailer for SmtpMailer {
+    fn send(&self, to: &str, subject: &str, body: &str) -> Result<(), String> {
+        let message = build_message(&self.from, to, subject, body)?;
+        self.transport
+            .send(&message)
+            .map(|_| ())
+            .map_err(|e| format!("Failed to send the mail to {}: {}", to, e))
+    }
+}
+
+/**
+ * Drops mails in a local maildir instead of sending them, for offline
+ * machines and development.
+ */
+pub struct MaildirMailer {
+    from: Mailbox,
+    root: PathBuf,
+}
+
+impl MaildirMailer {
+    /**
+     * Creates the `tmp`, `new` and `cur` folders of the maildir if needed.
+     *
+     * @param from - The sender.
+     * @param root - The maildir folder.
+     * @return Result<Self, String> - The mailer or an error message.
+     */
+    pub fn new(from: Mailbox, root: &Path) -> Result<Self, String> {
+        for folder in ["tmp", "new", "cur"] {
+            let path = root.join(folder);
+            fs::create_dir_all(&path).map_err(|e| format!("Failed to create {:?}: {}", path, e))?;
+        }
+        Ok(Self {
+            from,
+            root: root.to_path_buf(),
+        })
+    }
+}
+
+impl Mailer for MaildirMailer {
+    fn send(&self, to: &str, subject: &str, body: &str) -> Result<(), String> {
+        let message = build_message(&self.from, to, subject, body)?;
+
+        // Written in tmp/ then moved to new/ so readers never see half a mail
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let name = format!(
+            "{}.{}_{:016x}.vaultify",
+            time.as_secs(),
+            time.subsec_micros(),
+            rand::rng().random::<u64>()
+        );
+        let tmp = self.root.join("tmp").join(&name);
+        fs::write(&tmp, message.formatted())
+            .map_err(|e| format!("Failed to write {:?}: {}", tmp, e))?;
+        let new = self.root.join("new").join(&name);
+        fs::rename(&tmp, &new).map_err(|e| format!("Failed to move {:?}: {}", tmp, e))
+    }
+}
+
+/**
+ * Builds the mailer selected by the configuration.
+ *
+ * @param config - The mail settings.
+ * @param home - The directory the maildir path is relative to.
+ * @return Result<Box<dyn Mailer>, String> - The mailer or an error message.
+ */
+pub fn from_config(config: &MailConfig, home: &Path) -> Result<Box<dyn Mailer>, String> {
+    let from: Mailbox = config
+        .from
+        .parse()
+        .map_err(|e| format!("Invalid sender {:?}: {}", config.from, e))?;
+    Ok(match config.transport {
+        MailTransport::Smtp => Box::new(SmtpMailer::new(from, &config.smtp)?),
+        MailTransport::Maildir => Box::new(MaildirMailer::new(from, &home.join(&config.maildir))?),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn maildir_delivers_to_new() {
+        let home = std::env::temp_dir().join(format!("vaultify-mail-{}", uuid::Uuid::new_v4()));
+        let config = MailConfig::default();
+        let mailer = from_config(&config, &home).unwrap();
+        mailer
+            .send("user@example.com", "Code", "Here is your code : 123456")
+            .unwrap();
+
+        let maildir = home.join(&config.maildir);
+        assert_eq!(fs::read_dir(maildir.join("tmp")).unwrap().count(), 0);
+        let mails: Vec<_> = fs::read_dir(maildir.join("new"))
+            .unwrap()
+            .map(|entry| fs::read_to_string(entry.unwrap().path()).unwrap())
+            .collect();
+        assert_eq!(mails.len(), 1);
+        assert!(mails[0].contains("To: user@example.com"));
+        assert!(mails[0].contains("Subject: Code"));
+        assert!(mails[0].contains("123456"));
+
+        fs::remove_dir_all(&home).unwrap();
+    }
+
+    #[test]
+    fn rejects_invalid_addresses() {
+        let home = std::env::temp_dir().join(format!("vaultify-mail-{}", uuid::Uuid::new_v4()));
+        let mut config = MailConfig::default();
+        let mailer = from_config(&config, &home).unwrap();
+        assert!(mailer.send("not an address", "Code", "body").is_err());
+
+        config.from = "nobody".to_string();
+        assert!(from_config(&config, &home).is_err());
+        fs::remove_dir_all(&home).unwrap();
+    }
+}
@@ -1 +1,5 @@
+pub mod base32;
 pub mod email;
+pub mod mailer;
+pub mod qr;
+pub mod totp;
@@ -0,0 +1,531 @@
+// QR Code encoder (ISO/IEC 18004), enough to show an otpauth URI as an SVG
+//
+// Only byte mode and error correction level M are supported, which is what
+// authenticator apps scan. The smallest version (1 to 40) holding the data
+// is used and the mask with the lowest penalty is kept.
+
+// Error correction codewords per block at level M, indexed by version
+const ECC_CODEWORDS_PER_BLOCK: [usize; 41] = [
+    0, 10, 16, 26, 18, 24, 16, 18, 22, 22, 26, 30, 22, 22, 24, 24, 28, 28, 26, 26, 26, 26, 28, 28,
+    28, 28, 28, 28, 28, 28, 28, 28, 28, 28, 28, 28, 28, 28, 28, 28, 28,
+];
+// Error correction blocks at level M, indexed by version
+const NUM_BLOCKS: [usize; 41] = [
+    0, 1, 1, 1, 2, 2, 4, 4, 4, 5, 5, 5, 8, 9, 9, 10, 10, 11, 13, 14, 16, 17, 17, 18, 20, 21, 23,
+    25, 26, 28, 29, 31, 33, 35, 37, 38, 40, 43, 45, 47, 49,
+];
+// Format bits of level M
+const ECL_M_BITS: u32 = 0;
+// Light modules around the symbol required by the standard
+const QUIET_ZONE: usize = 4;
+
+/**
+ * Square grid of modules, true for dark.
+ */
+pub struct QrCode {
+    size: usize,
+    modules: Vec<bool>,
+    is_function: Vec<bool>,
+}
+
+impl QrCode {
+    /**
+     * Encodes bytes in the smallest symbol that holds them.
+     *
+     * @param data - The bytes to encode, e.g. a URI.
+     * @return Result<Self, String> - The symbol, or an error if the data does not fit in version 40.
+     */
+    pub fn encode(data: &[u8]) -> Result<Self, String> {
+        let version = (1..=40)
+            .find(|&version| data_bits(data.len(), version) <= num_data_codewords(version) * 8)
+            .ok_or_else(|| "Data too long for a QR code".to_string())?;
+
+        let mut qr = Self {
+            size: version * 4 + 17,
+            modules: vec![false; (version * 4 + 17).pow(2)],
+            is_function: vec![false; (version * 4 + 17).pow(2)],
+        };
+        qr.draw_function_patterns(version);
+        let codewords = add_ecc_and_interleave(&data_codewords(data, version), version);
+        qr.draw_codewords(&codewords);
+
+        // Keep the mask that is easiest to scan
+        let mut best = (u32::MAX, 0);
+        for mask in 0..8 {
+            qr.apply_mask(mask);
+            qr.draw_format_bits(mask);
+            let penalty = qr.penalty_score();
+            if penalty < best.0 {
+                best = (penalty, mask);
+            }
+            qr.apply_mask(mask); // XOR again to undo it
+        }
+        qr.apply_mask(best.1);
+        qr.draw_format_bits(best.1);
+        Ok(qr)
+    }
+
+    /// Number of modules on each side, without the quiet zone.
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Tells whether the module at column `x` and row `y` is dark.
+    pub fn get(&self, x: usize, y: usize) -> bool {
+        self.modules[y * self.size + x]
+    }
+
+    /**
+     * Renders the symbol as an SVG image, one unit per module.
+     *
+     * @return String - The SVG document, quiet zone included.
+     */
+    pub fn to_svg(&self) -> String {
+        let full = self.size + 2 * QUIET_ZONE;
+        let mut path = String::new();
+        for y in 0..self.size {
+            for x in 0..self.size {
+                if self.get(x, y) {
+                    path.push_str(&format!("M{},{}h1v1h-1z", x + QUIET_ZONE, y + QUIET_ZONE));
+                }
+            }
+        }
+        format!(
+            concat!(
+                "<svg xmlns=\"http://www.w3.org/2000/svg\" viewBox=\"0 0 {0} {0}\" ",
+                "shape-rendering=\"crispEdges\">",
+                "<rect width=\"100%\" height=\"100%\" fill=\"#FFFFFF\"/>",
+                "<path d=\"{1}\" fill=\"#000000\"/></svg>"
+            ),
+            full, path
+        )
+    }
+
+    fn set_function(&mut self, x: usize, y: usize, dark: bool) {
+        self.modules[y * self.size + x] = dark;
+        self.is_function[y * self.size + x] = true;
+    }
+
+    fn draw_function_patterns(&mut self, version: usize) {
+        let size = self.size;
+
+        // Timing patterns
+        for i in 0..size {
+            self.set_function(6, i, i % 2 == 0);
+            self.set_function(i, 6, i % 2 == 0);
+        }
+
+        // Finder patterns and their separators, in three corners
+        for (cx, cy) in [(3, 3), (size - 4, 3), (3, size - 4)] {
+            for dy in -4i32..=4 {
+                for dx in -4i32..=4 {
+                    let (x, y) = (cx as i32 + dx, cy as i32 + dy);
+                    if (0..size as i32).contains(&x) && (0..size as i32).contains(&y) {
+                        let distance = dx.abs().max(dy.abs());
+                        self.set_function(x as usize, y as usize, distance != 2 && distance != 4);
+                    }
+                }
+            }
+        }
+
+        // Alignment patterns, except where they would overlap a finder
+        let positions = alignment_positions(version);
+        let last = positions.len().saturating_sub(1);
+        for (i, &cx) in positions.iter().enumerate() {
+            for (j, &cy) in positions.iter().enumerate() {
+                let corner = (i == 0 && (j == 0 || j == last)) || (i == last && j == 0);
+                if corner {
+                    continue;
+                }
+                for dy in -2i32..=2 {
+                    for dx in -2i32..=2 {
+                        let distance = dx.abs().max(dy.abs());
+                        self.set_function(
+                            (cx as i32 + dx) as usize,
+                            (cy as i32 + dy) as usize,
+                            distance != 1,
+                        );
+                    }
+                }
+            }
+        }
+
+        // Reserve the format areas, drawn for real once the mask is known
+        self.draw_format_bits(0);
+
+        // Version information, from version 7 on
+        if version >= 7 {
+            let bits = version_bits(version);
+            for i in 0..18 {
+                let dark = (bits >> i) & 1 != 0;
+                let (a, b) = (size - 11 + i % 3, i / 3);
+                self.set_function(a, b, dark);
+                self.set_function(b, a, dark);
+            }
+        }
+    }
+
+    fn draw_format_bits(&mut self, mask: u32) {
+        let bits = format_bits(mask);
+        let bit = |i: u32| (bits >> i) & 1 != 0;
+        let size = self.size;
+
+        // First copy, around the top left finder
+        for i in 0..6 {
+            self.set_function(8, i, bit(i as u32));
+        }
+        self.set_function(8, 7, bit(6));
+        self.set_function(8, 8, bit(7));
+        self.set_function(7, 8, bit(8));
+        for i in 9..15 {
+            self.set_function(14 - i, 8, bit(i as u32));
+        }
+
+        // Second copy, split between the two other finders
+        for i in 0..8 {
+            self.set_function(size - 1 - i, 8, bit(i as u32));
+        }
+        for i in 8..15 {
+            self.set_function(8, size - 15 + i, bit(i as u32));
+        }
+        self.set_function(8, size - 8, true); // Always dark
+    }
+
+    // Places the codewords in the two-module wide zigzag, from the bottom right
+    fn draw_codewords(&mut self, codewords: &[u8]) {
+        let size = self.size;
+        let mut i = 0;
+        let mut right = size - 1;
+        loop {
+            if right == 6 {
+                right = 5; // Skip the vertical timing pattern
+            }
+            let upward = (right + 1) & 2 == 0;
+            for vertical in 0..size {
+                for x in [right, right - 1] {
+                    let y = if upward {
+                        size - 1 - vertical
+                    } else {
+                        vertical
+                    };
+                    if !self.is_function[y * size + x] && i < codewords.len() * 8 {
+                        self.modules[y * size + x] = (codewords[i / 8] >> (7 - i % 8)) & 1 != 0;
+                        i += 1;
+                    }
+                    // Remainder bits stay light
+                }
+            }
+            if right < 2 {
+                break;
+            }
+            right -= 2;
+        }
+    }
+
+    fn apply_mask(&mut self, mask: u32) {
+        for y in 0..self.size {
+            for x in 0..self.size {
+                let invert = match mask {
+                    0 => (x + y) % 2 == 0,
+                    1 => y % 2 == 0,
+                    2 => x % 3 == 0,
+                    3 => (x + y) % 3 == 0,
+                    4 => (x / 3 + y / 2) % 2 == 0,
+                    5 => x * y % 2 + x * y % 3 == 0,
+                    6 => (x * y % 2 + x * y % 3) % 2 == 0,
+                    _ => ((x + y) % 2 + x * y % 3) % 2 == 0,
+                };
+                let index = y * self.size + x;
+                if invert && !self.is_function[index] {
+                    self.modules[index] = !self.modules[index];
+                }
+            }
+        }
+    }
+
+    // Penalty rules N1 to N4 of the standard, lower is easier to scan
+    fn penalty_score(&self) -> u32 {
+        let size = self.size;
+        let mut score = 0;
+        let finder_like = [
+            [
+                true, false, true, true, true, false, true, false, false, false, false,
+            ],
+            [
+                false, false, false, false, true, false, true, true, true, false, true,
+            ],
+        ];
+
+        for transposed in [false, true] {
+            let get = |a: usize, b: usize| {
+                if transposed {
+                    self.get(b, a)
+                } else {
+                    self.get(a, b)
+                }
+            };
+            for line in 0..size {
+                // N1: runs of five or more modules of the same color
+                let mut run = 1;
+                for i in 1..size {
+                    if get(i, line) == get(i - 1, line) {
+                        run += 1;
+                        if run == 5 {
+                            score += 3;
+                        } else if run > 5 {
+                            score += 1;
+                        }
+                    } else {
+                        run = 1;
+                    }
+                }
+                // N3: patterns looking like a finder
+                for start in 0..size.saturating_sub(10) {
+                    for pattern in &finder_like {
+                        if (0..11).all(|k| get(start + k, line) == pattern[k]) {
+                            score += 40;
+                        }
+                    }
+                }
+            }
+        }
+
+        // N2: 2x2 blocks of the same color
+        for y in 0..size - 1 {
+            for x in 0..size - 1 {
+                let color = self.get(x, y);
+                if color == self.get(x + 1, y)
+                    && color == self.get(x, y + 1)
+                    && color == self.get(x + 1, y + 1)
+                {
+                    score += 3;
+                }
+            }
+        }
+
+        // N4: balance between dark and light modules
+        let dark = self.modules.iter().filter(|&&dark| dark).count();
+        let total = size * size;
+        let k = (dark * 20).abs_diff(total * 10).div_ceil(total) - 1;
+        score + 10 * k as u32
+    }
+}
+
+// Number of bits a byte mode segment of `len` bytes takes
+fn data_bits(len: usize, version: usize) -> usize {
+    let count_bits = if version <= 9 { 8 } else { 16 };
+    if len >= 1 << count_bits {
+        return usize::MAX;
+    }
+    4 + count_bits + 8 * len
+}
+
+// Modules left for data and error correction once the function patterns are drawn
+fn num_raw_data_modules(version: usize) -> usize {
+    let mut result = (16 * version + 128) * version + 64;
+    if version >= 2 {
+        let num_align = version / 7 + 2;
+        result -= (25 * num_align - 10) * num_align - 55;
+        if version >= 7 {
+            result -= 36;
+        }
+    }
+    result
+}
+
+fn num_data_codewords(version: usize) -> usize {
+    num_raw_data_modules(version) / 8 - ECC_CODEWORDS_PER_BLOCK[version] * NUM_BLOCKS[version]
+}
+
+fn alignment_positions(version: usize) -> Vec<usize> {
+    if version == 1 {
+        return Vec::new();
+    }
+    let size = version * 4 + 17;
+    let num_align = version / 7 + 2;
+    let step = (version * 8 + num_align * 3 + 5) / (num_align * 4 - 4) * 2;
+    let mut positions: Vec<usize> = (0..num_align - 1).map(|i| size - 7 - i * step).collect();
+    positions.push(6);
+    positions.reverse();
+    positions
+}
+
+// Mode, length, data, terminator and padding, as codewords
+fn data_codewords(data: &[u8], version: usize) -> Vec<u8> {
+    let capacity = num_data_codewords(version) * 8;
+    let count_bits = if version <= 9 { 8 } else { 16 };
+    let mut bits: Vec<bool> = Vec::with_capacity(capacity);
+    let mut push = |value: usize, len: usize| {
+        for i in (0..len).rev() {
+            bits.push((value >> i) & 1 != 0);
+        }
+    };
+    push(0b0100, 4); // Byte mode
+    push(data.len(), count_bits);
+    for &byte in data {
+        push(byte as usize, 8);
+    }
+    let terminator = 4.min(capacity - bits.len());
+    bits.extend(std::iter::repeat_n(false, terminator));
+    bits.resize(bits.len().div_ceil(8) * 8, false);
+
+    let mut codewords: Vec<u8> = bits
+        .chunks(8)
+        .map(|byte| byte.iter().fold(0u8, |acc, &bit| (acc << 1) | bit as u8))
+        .collect();
+    for pad in [0xEC, 0x11].into_iter().cycle() {
+        if codewords.len() >= capacity / 8 {
+            break;
+        }
+        codewords.push(pad);
+    }
+    codewords
+}
+
+// Splits the data in blocks, appends their error correction, and interleaves them
+fn add_ecc_and_interleave(data: &[u8], version: usize) -> Vec<u8> {
+    let num_blocks = NUM_BLOCKS[version];
+    let ecc_len = ECC_CODEWORDS_PER_BLOCK[version];
+    let raw_codewords = num_raw_data_modules(version) / 8;
+    let num_short_blocks = num_blocks - raw_codewords % num_blocks;
+    let short_block_len = raw_codewords / num_blocks;
+
+    let divisor = reed_solomon_divisor(ecc_len);
+    let mut blocks = Vec::with_capacity(num_blocks);
+    let mut offset = 0;
+    for i in 0..num_blocks {
+        let len = short_block_len - ecc_len + usize::from(i >= num_short_blocks);
+        let block_data = &data[offset..offset + len];
+        offset += len;
+        let mut block = block_data.to_vec();
+        if i < num_short_blocks {
+            block.push(0); // Placeholder, skipped when interleaving
+        }
+        block.extend(reed_solomon_remainder(block_data, &divisor));
+        blocks.push(block);
+    }
+
+    let mut result = Vec::with_capacity(raw_codewords);
+    for i in 0..blocks[0].len() {
+        for (j, block) in blocks.iter().enumerate() {
+            if i != short_block_len - ecc_len || j >= num_short_blocks {
+                result.push(block[i]);
+            }
+        }
+    }
+    result
+}
+
+// Generator polynomial of the given degree, highest coefficient (always 1) omitted
+fn reed_solomon_divisor(degree: usize) -> Vec<u8> {
+    let mut result = vec![0u8; degree];
+    result[degree - 1] = 1;
+    let mut root = 1u8;
+    for _ in 0..degree {
+        for j in 0..degree {
+            result[j] = gf_multiply(result[j], root);
+            if j + 1 < degree {
+                result[j] ^= result[j + 1];
+            }
+        }
+        root = gf_multiply(root, 0x02);
+    }
+    result
+}
+
+fn reed_solomon_remainder(data: &[u8], divisor: &[u8]) -> Vec<u8> {
+    let mut result = vec![0u8; divisor.len()];
+    for &byte in data {
+        let factor = byte ^ result.remove(0);
+        result.push(0);
+        for (coefficient, value) in divisor.iter().zip(result.iter_mut()) {
+            *value ^= gf_multiply(*coefficient, factor);
+        }
+    }
+    result
+}
+
+// Multiplication in GF(2^8) modulo x^8 + x^4 + x^3 + x^2 + 1
+fn gf_multiply(x: u8, y: u8) -> u8 {
+    let mut z: u16 = 0;
+    for i in (0..8).rev() {
+        z = (z << 1) ^ ((z >> 7) * 0x11D);
+        z ^= ((y as u16 >> i) & 1) * x as u16;
+    }
+    z as u8
+}
+
+// Error correction level and mask, protected by a BCH(15,5) code
+fn format_bits(mask: u32) -> u32 {
+    let data = (ECL_M_BITS << 3) | mask;
+    let mut remainder = data;
+    for _ in 0..10 {
+        remainder = (remainder << 1) ^ ((remainder >> 9) * 0x537);
+    }
+    ((data << 10) | remainder) ^ 0x5412
+}
+
+// Version number protected by a BCH(18,6) code
+fn version_bits(version: usize) -> u32 {
+    let mut remainder = version as u32;
+    for _ in 0..12 {
+        remainder = (remainder << 1) ^ ((remainder >> 11) * 0x1F25);
+    }
+    ((version as u32) << 12) | remainder
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // "HELLO WORLD" at 1-M, from the worked example of the standard
+    #[test]
+    fn reed_solomon_known_block() {
+        let data = [
+            32, 91, 11, 120, 209, 114, 220, 77, 67, 64, 236, 17, 236, 17, 236, 17,
+        ];
+        let ecc = reed_solomon_remainder(&data, &reed_solomon_divisor(10));
+        assert_eq!(ecc, [196, 35, 39, 119, 235, 215, 231, 226, 93, 23]);
+    }
+
+    #[test]
+    fn format_and_version_bits() {
+        assert_eq!(format_bits(0), 0b101010000010010);
+        assert_eq!(format_bits(5), 0b100000011001110);
+        assert_eq!(version_bits(7), 0x07C94);
+        assert_eq!(version_bits(40), 0x28C69);
+    }
+
+    #[test]
+    fn capacities_match_the_standard() {
+        // Data codewords at level M for a few versions
+        for (version, expected) in [(1, 16), (2, 28), (7, 124), (10, 216), (40, 2334)] {
+            assert_eq!(num_data_codewords(version), expected);
+        }
+        assert_eq!(alignment_positions(7), [6, 22, 38]);
+        assert_eq!(alignment_positions(32), [6, 34, 60, 86, 112, 138]);
+    }
+
+    #[test]
+    fn picks_the_smallest_version() {
+        assert_eq!(QrCode::encode(&[b'a'; 14]).unwrap().size(), 21);
+        assert_eq!(QrCode::encode(&[b'a'; 15]).unwrap().size(), 25);
+        assert!(QrCode::encode(&[b'a'; 2331]).is_ok());
+        assert!(QrCode::encode(&[b'a'; 2332]).is_err());
+    }
+
+    #[test]
+    fn draws_finders_and_timing() {
+        let qr = QrCode::encode(b"otpauth://totp/Vaultify:user%40example.com").unwrap();
+        let last = qr.size() - 1;
+        for (x, y) in [(0, 0), (last, 0), (0, last), (6, 6), (last - 6, 6)] {
+            assert!(qr.get(x, y));
+        }
+        assert!(!qr.get(7, 7));
+        for i in 8..qr.size() - 8 {
+            assert_eq!(qr.get(i, 6), i % 2 == 0);
+            assert_eq!(qr.get(6, i), i % 2 == 0);
+        }
+        assert!(qr.to_svg().starts_with("<svg"));
+    }
+}
@@ -0,0 +1,303 @@
+// Time-based one-time passwords (RFC 6238) for authenticator apps
+//
+// Codes are HOTP (RFC 4226) over HMAC-SHA1 with 6 digits and 30-second
+// steps, the parameters every authenticator app supports. The shared secrets
+// are stored encrypted with a server key, bound to the user they belong to.
+use crate::backend::aes_keys::cipher::gcm::{generate_nonce, AesGcm, NONCE_LEN, TAG_LEN};
+use crate::backend::auth::base32::{self, base32_encode};
+use crate::backend::server_manager::token::load_or_create_secret;
+use rand::Rng;
+use ring::hmac;
+use ring::rand::{SecureRandom, SystemRandom};
+use sha2::{Digest, Sha256};
+use std::path::Path;
+use zeroize::Zeroizing;
+
+// Seconds covered by one code
+pub const PERIOD: u64 = 30;
+// Digits of a code
+pub const DIGITS: u32 = 6;
+// Steps accepted before and after the current one, for clocks that drift
+pub const SKEW_STEPS: u64 = 1;
+// Size of the shared secret in bytes (160 bits, as RFC 4226 recommends)
+pub const SECRET_LEN: usize = 20;
+// Backup codes handed out when TOTP is enabled
+pub const BACKUP_CODE_COUNT: usize = 10;
+// Name shown in authenticator apps
+pub const ISSUER: &str = "Vaultify";
+
+// Function that generates a fresh shared secret
+pub fn generate_secret() -> Zeroizing<Vec<u8>> {
+    let mut secret = Zeroizing::new(vec![0u8; SECRET_LEN]);
+    SystemRandom::new()
+        .fill(&mut secret)
+        .expect("Failed to generate the TOTP secret");
+    secret
+}
+
+/**
+ * Computes an HOTP value (RFC 4226).
+ *
+ * @param secret - The shared secret.
+ * @param counter - The moving factor, the time step for TOTP.
+ * @param digits - The number of digits of the code.
+ * @return u32 - The code, to be zero-padded to `digits`.
+ */
+pub fn hotp(secret: &[u8], counter: u64, digits: u32) -> u32 {
+    let key = hmac::Key::new(hmac::HMAC_SHA1_FOR_LEGACY_USE_ONLY, secret);
+    let mac = hmac::sign(&key, &counter.to_be_bytes());
+    let mac = mac.as_ref();
+
+    // Dynamic truncation
+    let offset = (mac[mac.len() - 1] & 0x0F) as usize;
+    let value = u32::from_be_bytes([
+        mac[offset] & 0x7F,
+        mac[offset + 1],
+        mac[offset + 2],
+        mac[offset + 3],
+    ]);
+    value % 10u32.pow(digits)
+}
+
+// Function that returns the code of a time step, zero-padded
+pub fn code_at(secret: &[u8], step: u64) -> String {
+    format!(
+        "{:0width$}",
+        hotp(secret, step, DIGITS),
+        width = DIGITS as usize
+    )
+}
+
+/**
+ * Checks a code against the steps around the current time.
+ *
+ * @param secret - The shared secret.
+ * @param code - The code typed by the user.
+ * @param now - The current time, in seconds since the UNIX epoch.
+ * @param last_step - The step of the last accepted code, whose code and older ones are refused.
+ * @return Option<u64> - The step the code belongs to, None if it is wrong or already used.
+ */
+pub fn verify(secret: &[u8], code: &str, now: u64, last_step: Option<u64>) -> Option<u64> {
+    let code = code.trim();
+    if code.len() != DIGITS as usize {
+        return None;
+    }
+
+    let current = now / PERIOD;
+    // Every candidate is computed so the time taken does not tell which one matched
+    let mut matched = None;
+    for step in current.saturating_sub(SKEW_STEPS)..=current + SKEW_STEPS {
+        if constant_time_eq(code_at(secret, step).as_bytes(), code.as_bytes())
+            && last_step.is_none_or(|last| step > last)
+        {
+            matched = Some(step);
+        }
+    }
+    matched
+}
+
+/**
+ * Builds the `otpauth://` URI authenticator apps import, usually from a QR code.
+ *
+ * @param secret - The shared secret.
+ * @param account - The account name shown in the app, the user's email.
+ * @return String - The URI.
+ */
+pub fn otpauth_uri(secret: &[u8], account: &str) -> String {
+    format!(
+        "otpauth://totp/{issuer}:{account}?secret={secret}&issuer={issuer}&algorithm=SHA1&digits={digits}&period={period}",
+        issuer = percent_encode(ISSUER),
+        account = percent_encode(account),
+        secret = base32_encode(secret),
+        digits = DIGITS,
+        period = PERIOD,
+    )
+}
+
+// Percent-encodes everything but the unreserved characters of RFC 3986
+fn percent_encode(value: &str) -> String {
+    value
+        .bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                (byte as char).to_string()
+            }
+            _ => format!("%{:02X}", byte),
+        })
+        .collect()
+}
+
+// Compares two byte strings without stopping at the first difference
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len()
+        && a.iter()
+            .zip(b.iter())
+            .fold(0u8, |acc, (x, y)| acc | (x ^ y))
+            == 0
+}
+
+// Function that generates single-use backup codes, formatted as xxxxx-xxxxx
+pub fn generate_backup_codes() -> Vec<String> {
+    let mut rng = rand::rng();
+    (0..BACKUP_CODE_COUNT)
+        .map(|_| {
+            let chars: String = (0..10)
+                .map(|_| base32::ALPHABET[rng.random_range(0..32)].to_ascii_lowercase() as char)
+                .collect();
+            format!("{}-{}", &chars[..5], &chars[5..])
+        })
+        .collect()
+}
+
+// Function that hashes a backup code for storage, ignoring case, spaces and dashes
+pub fn hash_backup_code(code: &str) -> String {
+    let normalized: String = code
+        .chars()
+        .filter(|c| c.is_ascii_alphanumeric())
+        .map(|c| c.to_ascii_lowercase())
+        .collect();
+    Sha256::digest(normalized.as_bytes())
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+/**
+ * Server key encrypting the TOTP secrets at rest.
+ */
+pub struct TotpKey(AesGcm);
+
+impl TotpKey {
+    /**
+     * Builds a key from a secret.
+     *
+     * @param secret - The 256-bit secret.
+     * @return A new TotpKey instance.
+     */
+    pub fn new(secret: &[u8]) -> Self {
+        Self(AesGcm::new(secret))
+    }
+
+    /**
+     * Reads the key from disk, generating it on first use.
+     *
+     * @param path - The file holding the key.
+     * @return Result<Self, String> - The key or an error message.
+     */
+    pub fn load_or_create(path: &Path) -> Result<Self, String> {
+        Ok(Self::new(&load_or_create_secret(path)?))
+    }
+
+    /**
+     * Encrypts the secret of a user.
+     *
+     * The user ID is authenticated with it, so a secret copied to another
+     * row of the database does not decrypt.
+     *
+     * @param user_id - The ID of the user.
+     * @param secret - The shared secret.
+     * @return Vec<u8> - nonce || ciphertext || tag.
+     */
+    pub fn seal(&self, user_id: u32, secret: &[u8]) -> Vec<u8> {
+        let nonce = generate_nonce();
+        let mut output = nonce.to_vec();
+        output.extend(self.0.seal(&nonce, &user_id.to_be_bytes(), secret));
+        output
+    }
+
+    /**
+     * Decrypts a secret encrypted by `seal`.
+     *
+     * @param user_id - The ID of the user the secret belongs to.
+     * @param sealed - nonce || ciphertext || tag.
+     * @return Result<Zeroizing<Vec<u8>>, String> - The shared secret or an error message.
+     */
+    pub fn open(&self, user_id: u32, sealed: &[u8]) -> Result<Zeroizing<Vec<u8>>, String> {
+        if sealed.len() < NONCE_LEN + TAG_LEN {
+            return Err("TOTP secret too short".to_string());
+        }
+        let (nonce, data) = sealed.split_at(NONCE_LEN);
+        let nonce: [u8; NONCE_LEN] = nonce.try_into().unwrap();
+        self.0
+            .open(&nonce, &user_id.to_be_bytes(), data)
+            .map(Zeroizing::new)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RFC_SECRET: &[u8] = b"12345678901234567890";
+
+    // RFC 4226 appendix D
+    #[test]
+    fn hotp_vectors() {
+        let expected = [
+            755224, 287082, 359152, 969429, 338314, 254676, 287922, 162583, 399871, 520489,
+        ];
+        for (counter, &code) in expected.iter().enumerate() {
+            assert_eq!(hotp(RFC_SECRET, counter as u64, 6), code);
+        }
+    }
+
+    // RFC 6238 appendix B, SHA-1 rows
+    #[test]
+    fn totp_vectors() {
+        for (time, code) in [
+            (59, 94287082),
+            (1111111109, 7081804),
+            (1111111111, 14050471),
+            (1234567890, 89005924),
+            (2000000000, 69279037),
+            (20000000000, 65353130),
+        ] {
+            assert_eq!(hotp(RFC_SECRET, time / PERIOD, 8), code);
+        }
+    }
+
+    #[test]
+    fn verify_tolerates_skew_and_refuses_replays() {
+        let now = 1_700_000_000;
+        let step = now / PERIOD;
+        let code = code_at(RFC_SECRET, step - 1);
+        assert_eq!(verify(RFC_SECRET, &code, now, None), Some(step - 1));
+        assert_eq!(verify(RFC_SECRET, &code, now, Some(step - 1)), None);
+        assert_eq!(verify(RFC_SECRET, &code, now + 2 * PERIOD, None), None);
+        assert_eq!(
+            verify(RFC_SECRET, &code_at(RFC_SECRET, step + 1), now, Some(step)),
+            Some(step + 1)
+        );
+        assert_eq!(verify(RFC_SECRET, "12345", now, None), None);
+    }
+
+    #[test]
+    fn otpauth_uri_format() {
+        let uri = otpauth_uri(b"foobar", "user@example.com");
+        assert_eq!(
+            uri,
+            "otpauth://totp/Vaultify:user%40example.com?secret=MZXW6YTBOI&issuer=Vaultify&algorithm=SHA1&digits=6&period=30"
+        );
+    }
+
+    #[test]
+    fn backup_codes_are_normalized() {
+        let codes = generate_backup_codes();
+        assert_eq!(codes.len(), BACKUP_CODE_COUNT);
+        assert_eq!(codes[0].len(), 11);
+        assert_eq!(
+            hash_backup_code(&codes[0]),
+            hash_backup_code(&format!(" {} ", codes[0].to_uppercase().replace('-', "")))
+        );
+        assert_ne!(hash_backup_code(&codes[0]), hash_backup_code(&codes[1]));
+    }
+
+    #[test]
+    fn sealed_secrets_are_bound_to_the_user() {
+        let key = TotpKey::new(&[9u8; 32]);
+        let sealed = key.seal(4, RFC_SECRET);
+        assert_eq!(&key.open(4, &sealed).unwrap()[..], RFC_SECRET);
+        assert!(key.open(5, &sealed).is_err());
+        assert!(TotpKey::new(&[8u8; 32]).open(4, &sealed).is_err());
+    }
+}
@@ -12,5 +12,12 @@ pub const VAULTS_MATCHING: &str = ".vaultify/vault_matching.json";
 // Where the vault are stored
 pub const VAULTS_DATA: &str = "VaultsData/";
 pub const VAULTIFY_DATABASE: &str = ".vaultify/database.sqlite";
+pub const VAULTIFY_SERVER_CONFIG: &str = ".vaultify/config.json";
+// Secret signing the session tokens, generated on first start
+pub const VAULTIFY_TOKEN_KEY: &str = ".vaultify/token.key";
+// Secret encrypting the TOTP secrets of the users, generated on first start
+pub const VAULTIFY_TOTP_KEY: &str = ".vaultify/totp.key";
+// Secret sealing the vault keys of pending invitations, generated on first start
+pub const VAULTIFY_INVITATION_KEY: &str = ".vaultify/invitation.key";
 
 pub const PASSWORD: &str = "password.json";
@@ -1,21 +1,52 @@
-use crate::backend::aes_keys::keys_password::{derive_key, generate_salt_from_login};
+use crate::backend::aes_keys::container::{self, Kdf};
+use crate::backend::aes_keys::keypair::UserKeypair;
+use crate::backend::aes_keys::keys_password::{
+    derive_key, generate_salt_from_login, Argon2Params, UserKdf, USER_KEY_ITERATIONS,
+};
+use crate::backend::aes_keys::recovery_key::RecoveryKey;
+use crate::backend::aes_keys::secret_key::SecretKey;
+use crate::backend::aes_keys::vault_key::VaultKey;
+use crate::backend::auth::email::{canonical_email, final_send, normalize_email, Timecode};
+use crate::backend::server_manager::api_token_manager::{
+    delete_user_api_tokens, end_api_token_sessions,
+};
 use crate::backend::server_manager::global_manager::{
-    get_user_from_cookie, CONNECTION, EMAIL_TO_SESSION_KEY, PENDING_SHARE_CACHE, ROOT,
-    SESSION_CACHE,
+    get_user_from_cookie, issue_token, CONNECTION, PENDING_LOGIN_CACHE, SERVER_CONFIG,
+    SESSION_CACHE, VAULTS_CACHE,
+};
+use crate::backend::server_manager::invitation_manager::{
+    count_pending_invitations, delete_user_invitations,
+};
+use crate::backend::server_manager::lockout_manager::{
+    clear_login_failures, login_retry_after, record_login_failure,
+};
+use crate::backend::server_manager::pw_manager::get_passwords_path;
+use crate::backend::server_manager::token;
+use crate::backend::server_manager::totp_manager::{check_totp_code, is_totp_enabled};
+use crate::backend::server_manager::transfer_manager::delete_user_transfers;
+use crate::backend::server_manager::vault_manager::{
+    delete_vault_rows, remove_vault, set_vault_creator, VaultInfo,
+};
+use crate::backend::server_manager::verification_manager::{
+    is_email_verified, start_email_verification,
 };
-use crate::backend::server_manager::vault_manager::{create_vault, VaultInfo};
-use crate::backend::{VAULTS_DATA, VAULT_USERS_DIR};
 use actix_web::cookie::time::{Duration as Dudu, Duration, OffsetDateTime};
 use actix_web::cookie::{Cookie, SameSite};
-use actix_web::{web, HttpRequest, HttpResponse, Responder};
+use actix_web::http::header;
+use actix_web::{web, HttpRequest, HttpResponse, HttpResponseBuilder, Responder};
 use bcrypt::{hash, verify, DEFAULT_COST};
-use rusqlite::{params, Connection, Result};
+use rusqlite::{params, Connection, OptionalExtension, Result};
 use serde::{Deserialize, Serialize};
 use serde_json::json;
-use std::cmp::Ordering;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::fs;
+use std::path::PathBuf;
+use std::str::FromStr;
 use std::sync::{Arc, Mutex};
-use std::time::SystemTime;
+use std::time::{SystemTime, UNIX_EPOCH};
 use uuid::Uuid;
+use zeroize::Zeroizing;
 
 /**
  * Enum representing user permissions.
@@ -30,18 +61,68 @@ pub enum Perms {
 }
 
 impl Perms {
-    pub fn from_str(s: &str) -> Self {
+    /**
+     * Tells whether a member with these permissions may change the role of
+     * another member, or remove them. Admins manage the members below them,
+     * the Creator manages the Admins too, and nobody manages the Creator.
+     *
+     * @param member - The permissions of the other member.
+     * @return bool - True if the change is allowed.
+     */
+    pub fn can_manage(&self, member: &Perms) -> bool {
+        match self {
+            Self::Creator => member != &Self::Creator,
+            Self::Admin => member < &Self::Admin,
+            _ => false,
+        }
+    }
+
+    /**
+     * Tells whether a member with these permissions may give `perm` to a
+     * member who has `current`, None for someone joining the vault. Only the
+     * Creator grants Admin, and Creator only changes hands through an
+     * ownership transfer.
+     *
+     * @param perm - The permissions to give.
+     * @param current - The permissions the other member has now.
+     * @return bool - True if the change is allowed.
+     */
+    pub fn can_grant(&self, perm: &Perms, current: Option<&Perms>) -> bool {
+        let manages = match current {
+            Some(current) => self.can_manage(current),
+            None => self >= &Self::Admin,
+        };
+        manages && perm != &Self::Creator && (perm < &Self::Admin || self == &Self::Creator)
+    }
+}
+
+impl FromStr for Perms {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
         match s {
-            "Creator" => Self::Creator,
-            "Admin" => Self::Admin,
-            "Write" => Self::Write,
-            "Read" => Self::Read,
-            "NoLoad" => Self::NoLoad,
-            _ => Self::NoLoad,
+            "Creator" => Ok(Self::Creator),
+            "Admin" => Ok(Self::Admin),
+            "Write" => Ok(Self::Write),
+            "Read" => Ok(Self::Read),
+            "NoLoad" => Ok(Self::NoLoad),
+            _ => Err(format!("Unknown permission '{}'", s)),
         }
     }
 }
 
+impl fmt::Display for Perms {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Creator => "Creator",
+            Self::Admin => "Admin",
+            Self::Write => "Write",
+            Self::Read => "Read",
+            Self::NoLoad => "NoLoad",
+        })
+    }
+}
+
 /**
  * Struct representing the form data for creating a new user.
  */
@@ -58,6 +139,74 @@ pub struct CreateUserForm {
 pub struct LoginForm {
     username: String,
     password: String,
+    /// Code from the authenticator app, lets accounts with TOTP log in in one step
+    #[serde(default)]
+    code: Option<String>,
+}
+
+/**
+ * Struct representing the second step of a login with an email or TOTP code.
+ */
+#[derive(serde::Deserialize, Debug)]
+pub struct VerifyCodeForm {
+    challenge_id: String,
+    code: String,
+}
+
+/**
+ * Struct representing the form data for changing the password.
+ */
+#[derive(serde::Deserialize, Debug)]
+pub struct ChangePasswordForm {
+    old_password: String,
+    new_password: String,
+}
+
+/**
+ * Struct representing the form data for resetting a forgotten password with the recovery key.
+ */
+#[derive(serde::Deserialize, Debug)]
+pub struct RecoverAccountForm {
+    username: String,
+    recovery_key: String,
+    new_password: String,
+}
+
+/**
+ * Struct representing the form data for replacing the recovery key.
+ */
+#[derive(serde::Deserialize, Debug)]
+pub struct RecoveryKeyForm {
+    password: String,
+}
+
+/**
+ * Struct representing the form data for previewing the deletion of an account.
+ */
+#[derive(serde::Deserialize, Debug)]
+pub struct DeleteAccountPreviewForm {
+    /// Vault name (as given by `VaultInfo::get_name`) → email of the Admin
+    /// taking over a vault the user created; the others are deleted
+    #[serde(default)]
+    handover: HashMap<String, String>,
+}
+
+/**
+ * Struct representing the form data for deleting an account.
+ */
+#[derive(serde::Deserialize, Debug)]
+pub struct DeleteAccountForm {
+    password: String,
+    #[serde(default)]
+    handover: HashMap<String, String>,
+}
+
+/**
+ * Struct representing the form data for turning email codes on or off.
+ */
+#[derive(serde::Deserialize, Debug)]
+pub struct EmailTwoFactorForm {
+    enabled: bool,
 }
 
 #[derive(Serialize)]
@@ -95,15 +244,58 @@ impl JWT {
     }
 }
 
+/**
+ * Struct representing the device a session was opened from.
+ */
+#[derive(Clone, Debug, Default)]
+pub struct SessionClient {
+    pub ip: Option<String>,
+    pub user_agent: Option<String>,
+}
+
+// Longest user agent kept, the header is chosen by the client
+const MAX_USER_AGENT_LEN: usize = 256;
+
+impl SessionClient {
+    /**
+     * Reads the client address and user agent of a request.
+     *
+     * @param req - The HTTP request.
+     * @return A new SessionClient instance.
+     */
+    pub fn from_request(req: &HttpRequest) -> Self {
+        let user_agent = req
+            .headers()
+            .get(header::USER_AGENT)
+            .and_then(|value| value.to_str().ok())
+            .map(|value| value.chars().take(MAX_USER_AGENT_LEN).collect());
+        Self {
+            ip: client_ip(req),
+            user_agent,
+        }
+    }
+}
+
 /**
  * Struct representing a user session.
+ *
+ * A user has one session per login, each with its own copy of the user key
+ * and its own idle timeout.
  */
 #[derive(Clone)]
 pub struct Session {
     pub user_id: u32,
+    /// Identifies the session when it is listed to its user, unlike the
+    /// session ID it grants nothing
+    pub public_id: String,
     pub hash_pw: String,
-    pub user_key: Vec<u8>,
+    pub user_key: SecretKey,
+    pub user_kdf: Kdf,
+    pub created_at: SystemTime,
     pub last_activity: SystemTime,
+    pub client: SessionClient,
+    /// Names of the vaults this session loaded into `VAULTS_CACHE`.
+    pub loaded_vaults: HashSet<String>,
 }
 
 impl Session {
@@ -113,18 +305,64 @@ impl Session {
      * @param user_id - The ID of the user.
      * @param hash_pw - The hashed password of the user.
      * @param user_key - The user's encryption key.
+     * @param user_kdf - How the user's key was derived.
+     * @param client - The device the user logged in from.
      * @return A new Session instance.
      */
-    pub fn new(user_id: u32, hash_pw: &str, user_key: &[u8]) -> Self {
+    pub fn new(
+        user_id: u32,
+        hash_pw: &str,
+        user_key: SecretKey,
+        user_kdf: Kdf,
+        client: SessionClient,
+    ) -> Self {
+        let now = SystemTime::now();
         Self {
             user_id,
+            public_id: Uuid::new_v4().to_string(),
             hash_pw: hash_pw.to_string(),
-            user_key: user_key.to_vec(),
-            last_activity: SystemTime::now(),
+            user_key,
+            user_kdf,
+            created_at: now,
+            last_activity: now,
+            client,
+            loaded_vaults: HashSet::new(),
         }
     }
 }
 
+// Wrong codes after which a login challenge is dropped
+pub const MAX_CODE_ATTEMPTS: u32 = 5;
+
+/**
+ * Second factor a pending login waits for.
+ */
+pub enum SecondFactor {
+    /// Code sent by email
+    Email(Timecode),
+    /// Code from the authenticator app, or a backup code
+    Totp,
+}
+
+/**
+ * Login waiting for its second factor.
+ *
+ * The password was already checked and the user key unlocked, so the
+ * password itself is not kept. The key is wiped if the challenge fails or
+ * expires.
+ */
+pub struct PendingLogin {
+    pub user_id: u32,
+    pub email: String,
+    /// Device of the login, wrong codes count against its address too
+    pub client: SessionClient,
+    pub hash_pw: String,
+    pub factor: SecondFactor,
+    pub attempts: u32,
+    pub user_key: SecretKey,
+    pub user_kdf: Kdf,
+}
+
 /**
  * Initializes the connection to the database.
  *
@@ -136,31 +374,303 @@ pub fn init_db_connection(database_path: &str) -> Result<Connection> {
 }
 
 /**
- * Creates a new user in the database.
+ * Creates a new user in the database, its email not verified yet.
  *
  * @param conn - The database connection.
  * @param email - The user's email.
  * @param hash_password - The hashed password of the user.
+ * @param user_kdf - The salt and parameters used to derive the user's key.
  * @return A Result containing the ID of the created user.
  */
-pub fn create_user(conn: &Connection, email: &str, hash_password: &str) -> Result<u32> {
+pub fn create_user(
+    conn: &Connection,
+    email: &str,
+    hash_password: &str,
+    user_kdf: &UserKdf,
+) -> Result<u32> {
     conn.execute(
-        "INSERT INTO users (email, hash_password) VALUES (?, ?)",
-        params![email, hash_password],
+        "INSERT INTO users (email, hash_password, kdf_salt, kdf_memory_kib, kdf_iterations, kdf_parallelism, email_verified)
+         VALUES (?, ?, ?, ?, ?, ?, 0)",
+        params![
+            email,
+            hash_password,
+            user_kdf.salt,
+            user_kdf.params.memory_kib,
+            user_kdf.params.iterations,
+            user_kdf.params.parallelism
+        ],
     )?;
     Ok(conn.last_insert_rowid() as u32)
 }
 
+/**
+ * Retrieves the salt and parameters used to derive the key of a user.
+ *
+ * @param conn - The database connection.
+ * @param user_id - The ID of the user.
+ * @return A Result containing None for an account still using the legacy email salt.
+ */
+pub fn get_user_kdf(conn: &Connection, user_id: u32) -> Result<Option<UserKdf>> {
+    let mut stmt = conn.prepare(
+        "SELECT kdf_salt, kdf_memory_kib, kdf_iterations, kdf_parallelism
+         FROM users
+         WHERE id = ?",
+    )?;
+    let mut rows = stmt.query(params![user_id])?;
+    let row = match rows.next()? {
+        Some(row) => row,
+        None => return Ok(None),
+    };
+
+    let salt: Option<Vec<u8>> = row.get(0)?;
+    match salt {
+        Some(salt) => Ok(Some(UserKdf {
+            salt,
+            params: Argon2Params {
+                memory_kib: row.get(1)?,
+                iterations: row.get(2)?,
+                parallelism: row.get(3)?,
+            },
+        })),
+        None => Ok(None),
+    }
+}
+
+/**
+ * Records the salt and parameters used to derive the key of a user.
+ *
+ * @param conn - The database connection.
+ * @param user_id - The ID of the user.
+ * @param user_kdf - The new salt and parameters.
+ * @return A Result indicating success or failure.
+ */
+pub fn set_user_kdf(conn: &Connection, user_id: u32, user_kdf: &UserKdf) -> Result<()> {
+    conn.execute(
+        "UPDATE users
+         SET kdf_salt = ?, kdf_memory_kib = ?, kdf_iterations = ?, kdf_parallelism = ?,
+             legacy_salt_email = NULL
+         WHERE id = ?",
+        params![
+            user_kdf.salt,
+            user_kdf.params.memory_kib,
+            user_kdf.params.iterations,
+            user_kdf.params.parallelism,
+            user_id
+        ],
+    )?;
+    Ok(())
+}
+
+/**
+ * Tells whether a user confirms each login with a code sent by email.
+ *
+ * @param conn - The database connection.
+ * @param user_id - The ID of the user.
+ * @return A Result containing true if email codes are enabled.
+ */
+pub fn get_email_2fa(conn: &Connection, user_id: u32) -> Result<bool> {
+    conn.query_row(
+        "SELECT email_2fa FROM users WHERE id = ?",
+        params![user_id],
+        |row| row.get(0),
+    )
+}
+
+/**
+ * Turns email codes on or off for a user.
+ *
+ * @param conn - The database connection.
+ * @param user_id - The ID of the user.
+ * @param enabled - Whether logins need a code.
+ * @return A Result indicating success or failure.
+ */
+pub fn set_email_2fa(conn: &Connection, user_id: u32, enabled: bool) -> Result<()> {
+    conn.execute(
+        "UPDATE users SET email_2fa = ? WHERE id = ?",
+        params![enabled, user_id],
+    )?;
+    Ok(())
+}
+
+/**
+ * Stores the escrow and the wrap of a new recovery key, replacing the previous one.
+ *
+ * @param conn - The database connection.
+ * @param user_id - The ID of the user.
+ * @param recovery_key - The new recovery key.
+ * @param user_key - The current user key.
+ * @return A Result indicating success or failure.
+ */
+pub fn set_recovery_key(
+    conn: &Connection,
+    user_id: u32,
+    recovery_key: &RecoveryKey,
+    user_key: &[u8],
+) -> Result<()> {
+    conn.execute(
+        "UPDATE users SET recovery_escrow = ?, recovery_wrap = ? WHERE id = ?",
+        params![
+            recovery_key.escrow(user_id, user_key),
+            recovery_key.wrap(user_id, user_key),
+            user_id
+        ],
+    )?;
+    Ok(())
+}
+
+/**
+ * Retrieves the user key sealed under the recovery key of a user.
+ *
+ * @param conn - The database connection.
+ * @param user_id - The ID of the user.
+ * @return A Result containing None for accounts that never had a recovery key.
+ */
+pub fn get_recovery_escrow(conn: &Connection, user_id: u32) -> Result<Option<Vec<u8>>> {
+    conn.query_row(
+        "SELECT recovery_escrow FROM users WHERE id = ?",
+        params![user_id],
+        |row| row.get(0),
+    )
+}
+
+/**
+ * Seals the recovery key of a user again for a new user key, keeping the
+ * key the user wrote down valid. Does nothing for accounts without one.
+ *
+ * @param conn - The database connection.
+ * @param user_id - The ID of the user.
+ * @param old_key - The user key the recovery key is currently wrapped with.
+ * @param new_key - The new user key.
+ * @return Result<(), String> - An error message if the recovery key could not be opened or stored.
+ */
+fn reseal_recovery_key(
+    conn: &Connection,
+    user_id: u32,
+    old_key: &[u8],
+    new_key: &[u8],
+) -> std::result::Result<(), String> {
+    let wrapped: Option<Vec<u8>> = conn
+        .query_row(
+            "SELECT recovery_wrap FROM users WHERE id = ?",
+            params![user_id],
+            |row| row.get(0),
+        )
+        .map_err(|e| e.to_string())?;
+    let wrapped = match wrapped {
+        Some(wrapped) => wrapped,
+        None => return Ok(()),
+    };
+    let recovery_key = RecoveryKey::unwrap(user_id, old_key, &wrapped)?;
+    set_recovery_key(conn, user_id, &recovery_key, new_key).map_err(|e| e.to_string())
+}
+
+/**
+ * Stores the keypair of a user, the private key sealed under their user key.
+ *
+ * @param conn - The database connection.
+ * @param user_id - The ID of the user.
+ * @param keypair - The keypair.
+ * @param user_key - The current user key.
+ * @return A Result indicating success or failure.
+ */
+pub fn set_user_keypair(
+    conn: &Connection,
+    user_id: u32,
+    keypair: &UserKeypair,
+    user_key: &[u8],
+) -> Result<()> {
+    conn.execute(
+        "UPDATE users SET public_key = ?, private_key = ? WHERE id = ?",
+        params![
+            keypair.public_key().to_vec(),
+            keypair.wrap_private(user_id, user_key),
+            user_id
+        ],
+    )?;
+    Ok(())
+}
+
+/**
+ * Retrieves the public key of a user, to seal vault keys to them.
+ *
+ * @param conn - The database connection.
+ * @param user_id - The ID of the user.
+ * @return A Result containing None for accounts that have not logged in since keypairs exist.
+ */
+pub fn get_public_key(conn: &Connection, user_id: u32) -> Result<Option<Vec<u8>>> {
+    conn.query_row(
+        "SELECT public_key FROM users WHERE id = ?",
+        params![user_id],
+        |row| row.get(0),
+    )
+}
+
+/**
+ * Opens the keypair of a user.
+ *
+ * @param conn - The database connection.
+ * @param user_id - The ID of the user.
+ * @param user_key - The current user key.
+ * @return Result<Option<UserKeypair>, String> - The keypair, None if the user has none yet.
+ */
+pub fn get_user_keypair(
+    conn: &Connection,
+    user_id: u32,
+    user_key: &[u8],
+) -> std::result::Result<Option<UserKeypair>, String> {
+    let wrapped: Option<Vec<u8>> = conn
+        .query_row(
+            "SELECT private_key FROM users WHERE id = ?",
+            params![user_id],
+            |row| row.get(0),
+        )
+        .map_err(|e| e.to_string())?;
+    wrapped
+        .map(|wrapped| UserKeypair::unwrap_private(user_id, user_key, &wrapped))
+        .transpose()
+}
+
+// Gives a keypair to accounts created before keypairs existed
+fn ensure_user_keypair(
+    conn: &Connection,
+    user_id: u32,
+    user_key: &[u8],
+) -> std::result::Result<(), String> {
+    if get_public_key(conn, user_id)
+        .map_err(|e| e.to_string())?
+        .is_none()
+    {
+        set_user_keypair(conn, user_id, &UserKeypair::generate(), user_key)
+            .map_err(|e| e.to_string())?;
+    }
+    Ok(())
+}
+
+// Seals the private key of a user again after their user key changed
+fn reseal_private_key(
+    conn: &Connection,
+    user_id: u32,
+    old_key: &[u8],
+    new_key: &[u8],
+) -> std::result::Result<(), String> {
+    match get_user_keypair(conn, user_id, old_key)? {
+        Some(keypair) => {
+            set_user_keypair(conn, user_id, &keypair, new_key).map_err(|e| e.to_string())
+        }
+        None => Ok(()),
+    }
+}
+
 /**
  * Retrieves a user from the database by email.
  *
  * @param conn - The database connection.
- * @param email - The user's email.
+ * @param email - The user's email, compared in its canonical form.
  * @return A Result containing an Option with the user's ID and hashed password.
  */
 pub fn get_user_by_email(conn: &Connection, email: &str) -> Result<Option<(u32, String)>> {
     let mut stmt = conn.prepare("SELECT id, hash_password FROM users WHERE email = ?")?;
-    let mut rows = stmt.query(params![email])?;
+    let mut rows = stmt.query(params![canonical_email(email)])?;
     if let Some(row) = rows.next()? {
         Ok(Some((row.get(0)?, row.get(1)?)))
     } else {
@@ -168,6 +678,22 @@ pub fn get_user_by_email(conn: &Connection, email: &str) -> Result<Option<(u32,
     }
 }
 
+/**
+ * Retrieves the email of a user from the database.
+ *
+ * @param conn - The database connection.
+ * @param user_id - The ID of the user.
+ * @return A Result containing an Option with the user's email.
+ */
+pub fn get_user_email(conn: &Connection, user_id: u32) -> Result<Option<String>> {
+    let mut stmt = conn.prepare("SELECT email FROM users WHERE id = ?")?;
+    let mut rows = stmt.query(params![user_id])?;
+    match rows.next()? {
+        Some(row) => Ok(Some(row.get(0)?)),
+        None => Ok(None),
+    }
+}
+
 /**
  * Retrieves the vaults of a user from the database.
  *
@@ -177,7 +703,7 @@ pub fn get_user_by_email(conn: &Connection, email: &str) -> Result<Option<(u32,
  */
 pub fn get_user_vaults(conn: &Connection, user_id: u32) -> Result<Vec<VaultInfo>> {
     let mut stmt = conn.prepare(
-        "SELECT id, creator_id, name, date
+        "SELECT id, creator_id, name, date, origin_id
          FROM vaults
          WHERE id = ?",
     )?;
@@ -189,6 +715,7 @@ pub fn get_user_vaults(conn: &Connection, user_id: u32) -> Result<Vec<VaultInfo>
             creator_id: row.get(1)?,
             name: row.get(2)?,
             date: row.get(3)?,
+            origin_id: row.get(4)?,
         });
     }
 
@@ -196,141 +723,2654 @@ pub fn get_user_vaults(conn: &Connection, user_id: u32) -> Result<Vec<VaultInfo>
 }
 
 /**
- * Generates a unique session identifier.
- *
- * @return A String representing the session ID.
+ * Files replaced or moved aside by `swap_files`, whose previous versions are
+ * kept until the caller knows whether the change can be kept.
  */
-fn generate_session_id() -> String {
-    Uuid::new_v4().to_string()
+#[must_use = "the previous files must be dropped with commit() or restored with rollback()"]
+pub struct SwappedFiles {
+    // (file, copy of its previous content)
+    backups: Vec<(PathBuf, PathBuf)>,
+}
+
+impl SwappedFiles {
+    /**
+     * Keeps the new files and deletes the previous versions.
+     */
+    pub fn commit(self) {
+        for (_, backup) in &self.backups {
+            let removed = if backup.is_dir() {
+                fs::remove_dir_all(backup)
+            } else {
+                fs::remove_file(backup)
+            };
+            if let Err(e) = removed {
+                eprintln!("Failed to remove {}: {}", backup.display(), e);
+            }
+        }
+    }
+
+    /**
+     * Puts the previous versions back.
+     *
+     * @return Result<(), String> - An error message naming the files that could not be restored.
+     */
+    pub fn rollback(self) -> Result<(), String> {
+        restore_backups(&self.backups)
+    }
+}
+
+// Renames each backup over its file, newest first
+fn restore_backups(backups: &[(PathBuf, PathBuf)]) -> Result<(), String> {
+    let mut failed = Vec::new();
+    for (path, backup) in backups.iter().rev() {
+        if let Err(e) = fs::rename(backup, path) {
+            eprintln!("Failed to restore {}: {}", path.display(), e);
+            failed.push(path.display().to_string());
+        }
+    }
+    if failed.is_empty() {
+        Ok(())
+    } else {
+        Err(format!("Could not restore {}", failed.join(", ")))
+    }
 }
 
 /**
- * Endpoint to create a new user.
+ * Replaces files with new content and moves others aside, keeping the
+ * previous versions until the caller commits or rolls back.
  *
- * @param form - The form data containing the username and password.
- * @return An HTTP response indicating the result of the operation.
+ * The new content is written next to each file first, then everything is
+ * swapped in; if a swap fails the files already swapped are restored.
+ *
+ * @param replaced - Files and their new content.
+ * @param removed - Files or folders to delete; those already gone are skipped.
+ * @return Result<SwappedFiles, String> - The swapped files, or an error message if nothing was changed.
  */
-pub async fn create_user_query(form: web::Json<CreateUserForm>) -> HttpResponse {
-    let conn = CONNECTION.lock().unwrap();
-    let email = form.username.clone();
-    let pw = form.password.clone();
-
-    // Check if the user already exists
-    if let Ok(Some(_)) = get_user_by_email(&conn, &email) {
-        return HttpResponse::Conflict().json(json!({
-            "success": false,
-            "message": "Un utilisateur avec cet email existe déjà"
-        }));
+pub fn swap_files(
+    replaced: Vec<(PathBuf, Vec<u8>)>,
+    removed: Vec<PathBuf>,
+) -> Result<SwappedFiles, String> {
+    // Stage the new files
+    let staged: Vec<PathBuf> = replaced
+        .iter()
+        .map(|(path, _)| path.with_extension("rewrap"))
+        .collect();
+    for (tmp, (_, content)) in staged.iter().zip(replaced.iter()) {
+        if let Err(e) = fs::write(tmp, content) {
+            for tmp in &staged {
+                let _ = fs::remove_file(tmp);
+            }
+            return Err(format!("{}: {}", tmp.display(), e));
+        }
     }
 
-    // Hash the password
-    let hash_pw = match hash(&pw, DEFAULT_COST) {
-        Ok(hashed) => hashed,
-        Err(_) => {
-            return HttpResponse::InternalServerError().json(json!({
-                "success": false,
-                "message": "Erreur lors du hachage du mot de passe"
-            }))
+    // Swap them in, keeping the previous versions until the caller decides
+    let mut backups = Vec::with_capacity(staged.len() + removed.len());
+    for (tmp, (path, _)) in staged.iter().zip(replaced.iter()) {
+        let backup = path.with_extension("bak");
+        let swapped = fs::rename(path, &backup).and_then(|()| {
+            fs::rename(tmp, path).inspect_err(|_| {
+                let _ = fs::rename(&backup, path);
+            })
+        });
+        if let Err(e) = swapped {
+            for tmp in &staged {
+                let _ = fs::remove_file(tmp);
+            }
+            restore_backups(&backups)?;
+            return Err(format!("{}: {}", path.display(), e));
         }
-    };
+        backups.push((path.clone(), backup));
+    }
 
-    // Create the user in the database
-    let _ = match create_user(&conn, &email, &hash_pw) {
-        Ok(id) => id,
-        Err(_) => {
-            return HttpResponse::InternalServerError().json(json!({
-                "success": false,
-                "message": "Erreur lors de la création de l'utilisateur"
-            }))
+    // Removed files keep their extension aside, a key file and a handoff share their stem
+    for path in removed.into_iter().filter(|path| path.exists()) {
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        let backup = path.with_file_name(format!("{}.removed", name));
+        if let Err(e) = fs::rename(&path, &backup) {
+            restore_backups(&backups)?;
+            return Err(format!("{}: {}", path.display(), e));
         }
-    };
-
-    HttpResponse::Ok().json(json!({
-        "success": true,
-        "message": "Utilisateur créé avec succès"
-    }))
+        backups.push((path, backup));
+    }
+    Ok(SwappedFiles { backups })
 }
 
 /**
- * Endpoint to log in a user.
+ * Re-encrypts every file sealed with a user key: the key file of each vault
+ * of the user and the password list.
  *
- * @param form - The form data containing the username and password.
- * @return An HTTP response containing the JWT if the login is successful.
+ * All files are decrypted before anything is written, so a file that cannot
+ * be opened with `old_key` leaves the account untouched. The new files are
+ * swapped in with `swap_files`. Once this returns, the caller records the
+ * new key and then calls `commit`, or `rollback` if that failed.
+ *
+ * @param conn - The database connection.
+ * @param user_id - The ID of the user.
+ * @param old_key - The key the files are currently sealed with.
+ * @param new_key - The key to seal them with.
+ * @param new_kdf - How `new_key` was derived.
+ * @return Result<SwappedFiles, String> - The swapped files, or an error message if nothing was changed.
+ */
+pub fn rewrap_user_files(
+    conn: &Connection,
+    user_id: u32,
+    old_key: &[u8],
+    new_key: &[u8],
+    new_kdf: Kdf,
+) -> Result<SwappedFiles, String> {
+    let mut paths: Vec<PathBuf> = get_user_vaults(conn, user_id)
+        .map_err(|e| e.to_string())?
+        .iter()
+        .map(|vault| PathBuf::from(vault.get_key_path(user_id)))
+        .collect();
+    paths.push(get_passwords_path(user_id));
+
+    // Decrypt and seal everything in memory first
+    let mut rewrapped = Vec::new();
+    for path in paths {
+        let encrypted = match fs::read(&path) {
+            Ok(encrypted) => encrypted,
+            Err(_) => continue,
+        };
+        let plaintext = container::open(&encrypted, old_key)
+            .map(Zeroizing::new)
+            .map_err(|e| format!("{}: {}", path.display(), e))?;
+        rewrapped.push((path, container::seal(&plaintext, new_key, new_kdf)));
+    }
+    swap_files(rewrapped, Vec::new())
+}
+
+/**
+ * Derives the key of a user who just proved their password.
+ *
+ * Accounts still using the email-derived PBKDF2 salt, or Argon2id parameters
+ * other than the configured ones, are migrated on the way: a new random salt
+ * is drawn and every file sealed with the user key is re-encrypted.
+ *
+ * @param conn - The database connection.
+ * @param user_id - The ID of the user.
+ * @param email - The user's email, the salt of legacy accounts.
+ * @param password - The verified password.
+ * @return Result<(SecretKey, Kdf), String> - The user key and how it was derived.
+ */
+pub fn unlock_user_key(
+    conn: &Connection,
+    user_id: u32,
+    email: &str,
+    password: &str,
+) -> Result<(SecretKey, Kdf), String> {
+    let old_key = match get_user_kdf(conn, user_id).map_err(|e| e.to_string())? {
+        Some(user_kdf) if user_kdf.params == SERVER_CONFIG.kdf => {
+            let user_key = user_kdf.derive(password)?;
+            ensure_user_keypair(conn, user_id, user_key.as_bytes())?;
+            return Ok((user_key, user_kdf.params.kdf()));
+        }
+        _ => derive_user_key(conn, user_id, email, password)?,
+    };
+
+    let upgraded = UserKdf::generate(SERVER_CONFIG.kdf);
+    let new_key = upgraded.derive(password)?;
+    let files = rewrap_user_files(
+        conn,
+        user_id,
+        old_key.as_bytes(),
+        new_key.as_bytes(),
+        upgraded.params.kdf(),
+    )?;
+    let recorded = conn
+        .unchecked_transaction()
+        .map_err(|e| e.to_string())
+        .and_then(|tx| {
+            set_user_kdf(&tx, user_id, &upgraded).map_err(|e| e.to_string())?;
+            reseal_recovery_key(&tx, user_id, old_key.as_bytes(), new_key.as_bytes())?;
+            reseal_private_key(&tx, user_id, old_key.as_bytes(), new_key.as_bytes())?;
+            ensure_user_keypair(&tx, user_id, new_key.as_bytes())?;
+            // API tokens carry the old key
+            let tokens = delete_user_api_tokens(&tx, user_id).map_err(|e| e.to_string())?;
+            tx.commit().map_err(|e| e.to_string())?;
+            Ok(tokens)
+        });
+    let tokens = match recorded {
+        Ok(tokens) => tokens,
+        Err(e) => {
+            files.rollback()?;
+            return Err(e);
+        }
+    };
+    files.commit();
+    end_api_token_sessions(&tokens);
+    Ok((new_key, upgraded.params.kdf()))
+}
+
+/**
+ * Derives the current key of a user from their password, with the stored
+ * salt and parameters, or the email salt of legacy accounts.
+ *
+ * @param conn - The database connection.
+ * @param user_id - The ID of the user.
+ * @param email - The user's email, the salt of legacy accounts whose email was stored canonical.
+ * @param password - The verified password.
+ * @return Result<SecretKey, String> - The user key or an error message.
+ */
+pub fn derive_user_key(
+    conn: &Connection,
+    user_id: u32,
+    email: &str,
+    password: &str,
+) -> Result<SecretKey, String> {
+    match get_user_kdf(conn, user_id).map_err(|e| e.to_string())? {
+        Some(user_kdf) => user_kdf.derive(password),
+        None => {
+            let salt_email = get_legacy_salt_email(conn, user_id).map_err(|e| e.to_string())?;
+            Ok(SecretKey::new(derive_key(
+                password,
+                &generate_salt_from_login(salt_email.as_deref().unwrap_or(email)),
+                USER_KEY_ITERATIONS,
+            )))
+        }
+    }
+}
+
+// Email a legacy account was salted with, kept as stored before emails were
+// normalized; None once the account moved to a random salt
+fn get_legacy_salt_email(conn: &Connection, user_id: u32) -> Result<Option<String>> {
+    conn.query_row(
+        "SELECT legacy_salt_email FROM users WHERE id = ?",
+        params![user_id],
+        |row| row.get(0),
+    )
+    .optional()
+    .map(Option::flatten)
+}
+
+/**
+ * Credentials of a user after a password change.
+ */
+pub struct PasswordChange {
+    pub user_key: SecretKey,
+    pub user_kdf: Kdf,
+    pub hash_pw: String,
+    /// Replaces the previous recovery key, which no longer works.
+    pub recovery_key: RecoveryKey,
+}
+
+/**
+ * Replaces the password of a user and re-encrypts everything sealed with
+ * the key derived from it.
+ *
+ * The key files and the password list are swapped first; the new hash, salt
+ * and recovery key are then written in one transaction, and the files are
+ * restored if that fails, so the account is either fully on the old password
+ * or fully on the new one.
+ *
+ * @param conn - The database connection.
+ * @param user_id - The ID of the user.
+ * @param old_key - The current user key.
+ * @param new_password - The new password.
+ * @return Result<PasswordChange, String> - The new credentials or an error message.
+ */
+pub fn change_user_password(
+    conn: &mut Connection,
+    user_id: u32,
+    old_key: &SecretKey,
+    new_password: &str,
+) -> Result<PasswordChange, String> {
+    let hash_pw = hash(new_password, DEFAULT_COST).map_err(|e| e.to_string())?;
+    let new_kdf = UserKdf::generate(SERVER_CONFIG.kdf);
+    let new_key = new_kdf.derive(new_password)?;
+    let recovery_key = RecoveryKey::generate();
+
+    let files = rewrap_user_files(
+        conn,
+        user_id,
+        old_key.as_bytes(),
+        new_key.as_bytes(),
+        new_kdf.params.kdf(),
+    )?;
+    let recorded = conn
+        .transaction()
+        .map_err(|e| e.to_string())
+        .and_then(|tx| {
+            tx.execute(
+                "UPDATE users SET hash_password = ? WHERE id = ?",
+                params![hash_pw, user_id],
+            )
+            .map_err(|e| e.to_string())?;
+            set_user_kdf(&tx, user_id, &new_kdf).map_err(|e| e.to_string())?;
+            // A new password gets a new recovery key, so an old one written down
+            // next to a leaked password does not outlive the change
+            set_recovery_key(&tx, user_id, &recovery_key, new_key.as_bytes())
+                .map_err(|e| e.to_string())?;
+            reseal_private_key(&tx, user_id, old_key.as_bytes(), new_key.as_bytes())?;
+            // API tokens carry the old key, the sessions they opened end with the others
+            delete_user_api_tokens(&tx, user_id).map_err(|e| e.to_string())?;
+            tx.commit().map_err(|e| e.to_string())
+        });
+    if let Err(e) = recorded {
+        files.rollback()?;
+        return Err(e);
+    }
+    files.commit();
+    Ok(PasswordChange {
+        user_key: new_key,
+        user_kdf: new_kdf.params.kdf(),
+        hash_pw,
+        recovery_key,
+    })
+}
+
+/**
+ * Generates a unique session identifier.
+ *
+ * @return A String representing the session ID.
+ */
+fn generate_session_id() -> String {
+    Uuid::new_v4().to_string()
+}
+
+/**
+ * Endpoint to create a new user.
+ *
+ * The account cannot log in until its email address is confirmed with the
+ * code mailed to it (see `verification_manager`). The response carries the
+ * recovery key of the account, which is shown only this once.
+ *
+ * @param form - The form data containing the username and password.
+ * @return An HTTP response indicating the result of the operation.
  */
+pub async fn create_user_query(form: web::Json<CreateUserForm>) -> HttpResponse {
+    let email = match normalize_email(&form.username) {
+        Ok(email) => email,
+        Err(_) => {
+            return HttpResponse::BadRequest().json(json!({
+                "success": false,
+                "message": "Adresse email invalide"
+            }))
+        }
+    };
+    let pw = form.password.clone();
+    let mut conn = CONNECTION.lock().unwrap();
+
+    // Check if the user already exists
+    if let Ok(Some(_)) = get_user_by_email(&conn, &email) {
+        return HttpResponse::Conflict().json(json!({
+            "success": false,
+            "message": "Un utilisateur avec cet email existe déjà"
+        }));
+    }
+
+    // Hash the password
+    let hash_pw = match hash(&pw, DEFAULT_COST) {
+        Ok(hashed) => hashed,
+        Err(_) => {
+            return HttpResponse::InternalServerError().json(json!({
+                "success": false,
+                "message": "Erreur lors du hachage du mot de passe"
+            }))
+        }
+    };
+
+    // Derive the user key now, to seal it under the recovery key and seal the private key under it
+    let user_kdf = UserKdf::generate(SERVER_CONFIG.kdf);
+    let user_key = match user_kdf.derive(&pw) {
+        Ok(user_key) => user_key,
+        Err(e) => {
+            eprintln!("Failed to derive the key of {}: {}", email, e);
+            return HttpResponse::InternalServerError().json(json!({
+                "success": false,
+                "message": "Erreur lors de la création de l'utilisateur"
+            }));
+        }
+    };
+    let recovery_key = RecoveryKey::generate();
+
+    // Create the user in the database
+    let created = conn.transaction().and_then(|tx| {
+        let id = create_user(&tx, &email, &hash_pw, &user_kdf)?;
+        set_recovery_key(&tx, id, &recovery_key, user_key.as_bytes())?;
+        set_user_keypair(&tx, id, &UserKeypair::generate(), user_key.as_bytes())?;
+        tx.commit()
+    });
+    if created.is_err() {
+        return HttpResponse::InternalServerError().json(json!({
+            "success": false,
+            "message": "Erreur lors de la création de l'utilisateur"
+        }));
+    }
+    drop(conn);
+
+    // The account exists either way, a new code can be asked for if this one is lost
+    let code_sent = match start_email_verification(&email) {
+        Ok(()) => true,
+        Err(e) => {
+            eprintln!("Failed to send the verification code of {}: {}", email, e);
+            false
+        }
+    };
+
+    HttpResponse::Ok().json(json!({
+        "success": true,
+        "message": "Utilisateur créé avec succès",
+        "recovery_key": recovery_key.display().as_str(),
+        "verification_required": true,
+        "verification_sent": code_sent
+    }))
+}
 
-pub async fn login_user_query(form: web::Json<LoginForm>) -> impl Responder {
+/**
+ * Endpoint to log in a user.
+ *
+ * Accounts with TOTP or email codes enabled get a challenge ID instead of
+ * the JWT, to be completed with `verify_login_code_query`. Accounts with
+ * TOTP can also send their code along with the password. No session exists
+ * until the second factor was checked.
+ *
+ * Failed attempts are counted per account and per client address, and make
+ * the next attempts wait longer and longer (see `lockout_manager`).
+ *
+ * @param req - The HTTP request, for the client address.
+ * @param form - The form data containing the username, the password and an optional TOTP code.
+ * @return An HTTP response containing the JWT, or the challenge ID, if the password is correct.
+ */
+pub async fn login_user_query(req: HttpRequest, form: web::Json<LoginForm>) -> impl Responder {
     let conn = CONNECTION.lock().unwrap();
 
-    let email = form.username.clone();
+    let email = canonical_email(&form.username);
     let pw = form.password.clone();
+    let client = SessionClient::from_request(&req);
+    let ip = client.ip.clone();
+
+    match login_retry_after(&conn, &email, ip.as_deref(), token::now()) {
+        Ok(None) => {}
+        Ok(Some(wait)) => return too_many_attempts(wait),
+        Err(e) => {
+            eprintln!("Failed to read the failed logins of {}: {}", email, e);
+            return HttpResponse::InternalServerError().json("failed to log in");
+        }
+    }
 
-    let (user_id, hash_pw) = match get_user_by_email(&conn, &email).unwrap() {
-        Some((user_id, hash_pw)) => (user_id, hash_pw),
-        None => return HttpResponse::Unauthorized().json("invalid email or password"),
+    let (user_id, hash_pw) = match get_user_by_email(&conn, &email) {
+        Ok(Some((user_id, hash_pw))) => (user_id, hash_pw),
+        Ok(None) => return login_failed(&conn, &email, ip.as_deref(), "unknown account"),
+        Err(e) => {
+            eprintln!("Failed to read user {}: {}", email, e);
+            return HttpResponse::InternalServerError().json("failed to log in");
+        }
     };
 
-    if !verify(&pw, &hash_pw).unwrap() {
-        return HttpResponse::Unauthorized().json("invalid email or password");
+    if !verify(&pw, &hash_pw).unwrap_or(false) {
+        return login_failed(&conn, &email, ip.as_deref(), "wrong password");
     }
 
-    let session_key = match EMAIL_TO_SESSION_KEY.get(&email) {
-        Some(session_key) => session_key,
-        None => {
-            let session_id = generate_session_id();
-            let user_key = derive_key(&pw, &generate_salt_from_login(&email), 10000);
+    match is_email_verified(&conn, user_id) {
+        Ok(true) => {}
+        Ok(false) => {
+            return HttpResponse::Forbidden().json(json!({
+                "success": false,
+                "verification_required": true,
+                "message": "confirm your email address first"
+            }))
+        }
+        Err(e) => {
+            eprintln!("Failed to read the state of user {}: {}", user_id, e);
+            return HttpResponse::InternalServerError().json("failed to log in");
+        }
+    }
 
-            SESSION_CACHE.insert(
-                session_id.clone(),
-                Arc::new(Mutex::new(Session::new(user_id, &hash_pw, &user_key))),
-            );
+    let second_factor = match (
+        is_totp_enabled(&conn, user_id),
+        get_email_2fa(&conn, user_id),
+    ) {
+        // The authenticator app takes precedence, no mail is sent then
+        (Ok(true), _) => Some("totp"),
+        (Ok(false), Ok(true)) => Some("email"),
+        (Ok(false), Ok(false)) => None,
+        (Err(e), _) | (_, Err(e)) => {
+            eprintln!("Failed to read the 2FA setting of user {}: {}", user_id, e);
+            return HttpResponse::InternalServerError().json("failed to log in");
+        }
+    };
 
-            EMAIL_TO_SESSION_KEY.insert(email.clone(), session_id.clone());
+    if let (Some("totp"), Some(code)) = (second_factor, &form.code) {
+        match check_totp_code(&conn, user_id, code) {
+            Ok(true) => {}
+            Ok(false) => {
+                if let Err(e) = record_login_failure(
+                    &conn,
+                    &SERVER_CONFIG.login,
+                    &email,
+                    ip.as_deref(),
+                    "wrong TOTP code",
+                    token::now(),
+                ) {
+                    eprintln!("Failed to record a failed login of {}: {}", email, e);
+                }
+                return HttpResponse::Unauthorized().json("invalid code");
+            }
+            Err(e) => {
+                eprintln!("Failed to check the TOTP code of user {}: {}", user_id, e);
+                return HttpResponse::InternalServerError().json("failed to log in");
+            }
+        }
+    } else if let Some(method) = second_factor {
+        // Unlocked now, so the password does not have to be kept until the code comes back
+        let (user_key, user_kdf) = match unlock_user_key(&conn, user_id, &email, &pw) {
+            Ok(unlocked) => unlocked,
+            Err(e) => {
+                eprintln!("Failed to unlock the key of user {}: {}", user_id, e);
+                return HttpResponse::InternalServerError().json("failed to unlock account");
+            }
+        };
+        // Sending the mail can take a while, other requests need the database meanwhile
+        drop(conn);
 
-            if let Some(pending) = PENDING_SHARE_CACHE.get(&email) {
-                let mut pending = pending.lock().unwrap();
-                for (vault_info, vault_key) in pending.drain(..) {
-                    vault_info
-                        .save_key(vault_key.as_slice(), user_key.as_slice(), user_id)
-                        .unwrap();
-                    create_vault(&conn, &vault_info, user_id).unwrap();
+        let (factor, message) = if method == "email" {
+            match final_send(&email) {
+                Ok(timecode) => (SecondFactor::Email(timecode), "Verification code sent"),
+                Err(_) => {
+                    return HttpResponse::InternalServerError()
+                        .json("failed to send verification code")
                 }
             }
+        } else {
+            (
+                SecondFactor::Totp,
+                "Enter the code from your authenticator app",
+            )
+        };
+        let challenge_id = Uuid::new_v4().to_string();
+        PENDING_LOGIN_CACHE.insert(
+            challenge_id.clone(),
+            Arc::new(Mutex::new(PendingLogin {
+                user_id,
+                email,
+                client,
+                hash_pw,
+                factor,
+                attempts: 0,
+                user_key,
+                user_kdf,
+            })),
+        );
+        return HttpResponse::Ok().json(json!({
+            "success": true,
+            "two_factor": true,
+            "method": method,
+            "challenge_id": challenge_id,
+            "message": message
+        }));
+    }
+
+    if let Err(e) = clear_login_failures(&conn, &email) {
+        eprintln!("Failed to clear the failed logins of {}: {}", email, e);
+    }
+    let unlock = || unlock_user_key(&conn, user_id, &email, &pw);
+    match start_session(user_id, &hash_pw, client, unlock) {
+        Ok(session_id) => login_response(&conn, &session_id, user_id, &email),
+        Err(e) => {
+            eprintln!("Failed to unlock the key of user {}: {}", user_id, e);
+            HttpResponse::InternalServerError().json("failed to unlock account")
+        }
+    }
+}
+
+// Address of the client, from the connection itself so it cannot be forged with a header
+fn client_ip(req: &HttpRequest) -> Option<String> {
+    req.peer_addr().map(|addr| addr.ip().to_string())
+}
+
+// Response refusing a login until the backoff is over
+fn too_many_attempts(wait: u64) -> HttpResponse {
+    HttpResponse::TooManyRequests()
+        .insert_header(("Retry-After", wait.to_string()))
+        .json(json!({
+            "success": false,
+            "message": "too many failed attempts, try again later",
+            "retry_after": wait
+        }))
+}
 
-            session_id
+// Records a failed login and answers it, telling how long the next attempt has to wait
+fn login_failed(conn: &Connection, email: &str, ip: Option<&str>, reason: &str) -> HttpResponse {
+    refused_attempt(conn, email, ip, reason).json("invalid email or password")
+}
+
+// Counts a wrong password or recovery key against the account, as a failed login
+fn refused_attempt(
+    conn: &Connection,
+    email: &str,
+    ip: Option<&str>,
+    reason: &str,
+) -> HttpResponseBuilder {
+    let mut response = HttpResponse::Unauthorized();
+    match record_login_failure(conn, &SERVER_CONFIG.login, email, ip, reason, token::now()) {
+        Ok(0) => {}
+        Ok(wait) => {
+            response.insert_header(("Retry-After", wait.to_string()));
         }
+        Err(e) => eprintln!("Failed to record a failed login of {}: {}", email, e),
+    }
+    response
+}
+
+/**
+ * Endpoint for the second step of a login with an email or TOTP code.
+ *
+ * A challenge is dropped after `MAX_CODE_ATTEMPTS` wrong codes, once it
+ * expires, and once it succeeded.
+ *
+ * Wrong codes also count as failed logins of the account.
+ *
+ * @param form - The challenge ID returned by the login and the code received by email,
+ *               shown by the authenticator app, or one of the TOTP backup codes.
+ * @return An HTTP response containing the JWT if the code is correct.
+ */
+pub async fn verify_login_code_query(form: web::Json<VerifyCodeForm>) -> impl Responder {
+    let pending = match PENDING_LOGIN_CACHE.get(&form.challenge_id) {
+        Some(pending) => pending,
+        None => return HttpResponse::Unauthorized().json("unknown or expired challenge"),
     };
-    let jwt = JWT::new(&session_key, user_id, &email);
+    let mut pending = pending.lock().unwrap();
 
-    let cookie = Cookie::build("user_token", serde_json::to_string(&jwt).unwrap())
-        .http_only(true)
-        .secure(true) // Use secure(true) if you are in production (HTTPS)
-        .path("/")
-        .max_age(Dudu::days(7)) // The cookie expires after 7 days
-        .finish();
+    // An empty key means the challenge was used or dropped meanwhile
+    let expired = match &pending.factor {
+        SecondFactor::Email(timecode) => !timecode.is_valid(),
+        SecondFactor::Totp => false, // Only bounded by the lifetime of the cache entry
+    };
+    if pending.user_key.is_empty() || expired {
+        pending.user_key.wipe();
+        drop(pending);
+        PENDING_LOGIN_CACHE.invalidate(&form.challenge_id);
+        return HttpResponse::Unauthorized().json("unknown or expired challenge");
+    }
 
-    // Display the JWT to see if it is generated correctly
-    HttpResponse::Ok().cookie(cookie).json(json!({
+    let valid = match &pending.factor {
+        SecondFactor::Email(timecode) => timecode.matches(form.code.trim()),
+        SecondFactor::Totp => {
+            let conn = CONNECTION.lock().unwrap();
+            match check_totp_code(&conn, pending.user_id, &form.code) {
+                Ok(valid) => valid,
+                Err(e) => {
+                    eprintln!(
+                        "Failed to check the TOTP code of user {}: {}",
+                        pending.user_id, e
+                    );
+                    return HttpResponse::InternalServerError().json("failed to log in");
+                }
+            }
+        }
+    };
+    if !valid {
+        let conn = CONNECTION.lock().unwrap();
+        if let Err(e) = record_login_failure(
+            &conn,
+            &SERVER_CONFIG.login,
+            &pending.email,
+            pending.client.ip.as_deref(),
+            "wrong second factor code",
+            token::now(),
+        ) {
+            eprintln!(
+                "Failed to record a failed login of {}: {}",
+                pending.email, e
+            );
+        }
+        drop(conn);
+
+        pending.attempts += 1;
+        let remaining = MAX_CODE_ATTEMPTS.saturating_sub(pending.attempts);
+        if remaining == 0 {
+            pending.user_key.wipe();
+            drop(pending);
+            PENDING_LOGIN_CACHE.invalidate(&form.challenge_id);
+            return HttpResponse::TooManyRequests().json("too many wrong codes, log in again");
+        }
+        return HttpResponse::Unauthorized().json(json!({
+            "success": false,
+            "message": "invalid code",
+            "remaining_attempts": remaining
+        }));
+    }
+
+    // A code can only be used once
+    let user_key = std::mem::replace(&mut pending.user_key, SecretKey::new(Vec::new()));
+    let (user_id, user_kdf) = (pending.user_id, pending.user_kdf);
+    let hash_pw = pending.hash_pw.clone();
+    let email = pending.email.clone();
+    let client = pending.client.clone();
+    drop(pending);
+    PENDING_LOGIN_CACHE.invalidate(&form.challenge_id);
+
+    let conn = CONNECTION.lock().unwrap();
+    if let Err(e) = clear_login_failures(&conn, &email) {
+        eprintln!("Failed to clear the failed logins of {}: {}", email, e);
+    }
+    match start_session(user_id, &hash_pw, client, || Ok((user_key, user_kdf))) {
+        Ok(session_id) => login_response(&conn, &session_id, user_id, &email),
+        Err(e) => {
+            eprintln!("Failed to open the session of user {}: {}", user_id, e);
+            HttpResponse::InternalServerError().json("failed to log in")
+        }
+    }
+}
+
+/**
+ * Endpoint to turn email codes on or off for the logged-in user.
+ *
+ * @param req - The HTTP request carrying the session token.
+ * @param form - Whether logins need a code.
+ * @return An HTTP response indicating the result of the operation.
+ */
+pub async fn set_email_2fa_query(
+    req: HttpRequest,
+    form: web::Json<EmailTwoFactorForm>,
+) -> impl Responder {
+    let jwt = match get_user_from_cookie(&req) {
+        Some(jwt) => jwt,
+        None => return HttpResponse::Unauthorized().body("Invalid session"),
+    };
+
+    let conn = CONNECTION.lock().unwrap();
+    match set_email_2fa(&conn, jwt.id, form.enabled) {
+        Ok(()) => HttpResponse::Ok().json(json!({ "success": true, "enabled": form.enabled })),
+        Err(e) => {
+            eprintln!("Failed to update the 2FA setting of user {}: {}", jwt.id, e);
+            HttpResponse::InternalServerError().body("Failed to update the setting")
+        }
+    }
+}
+
+/**
+ * Endpoint to change the password of the logged-in user.
+ *
+ * Every vault key file and the password list are re-encrypted with the key
+ * derived from the new password. The other sessions of the account are
+ * ended, the current one keeps working with the new key.
+ *
+ * A wrong current password counts as a failed login of the account.
+ *
+ * @param req - The HTTP request carrying the session token.
+ * @param form - The current password and the new one.
+ * @return An HTTP response indicating the result of the operation.
+ */
+pub async fn change_password_query(
+    req: HttpRequest,
+    form: web::Json<ChangePasswordForm>,
+) -> impl Responder {
+    let jwt = match get_user_from_cookie(&req) {
+        Some(jwt) => jwt,
+        None => return HttpResponse::Unauthorized().body("Invalid session"),
+    };
+    if form.new_password.is_empty() {
+        return HttpResponse::BadRequest().body("The new password cannot be empty");
+    }
+
+    let ip = client_ip(&req);
+    let mut conn = CONNECTION.lock().unwrap();
+    // Guessing the current password from a stolen session is throttled like a login
+    match login_retry_after(&conn, &jwt.email, ip.as_deref(), token::now()) {
+        Ok(None) => {}
+        Ok(Some(wait)) => return too_many_attempts(wait),
+        Err(e) => {
+            eprintln!("Failed to read the failed logins of {}: {}", jwt.email, e);
+            return HttpResponse::InternalServerError().body("Failed to change the password");
+        }
+    }
+    let hash_pw = match get_user_by_email(&conn, &jwt.email) {
+        Ok(Some((user_id, hash_pw))) if user_id == jwt.id => hash_pw,
+        Ok(_) => return HttpResponse::Unauthorized().body("Invalid session"),
+        Err(e) => {
+            eprintln!("Failed to read user {}: {}", jwt.id, e);
+            return HttpResponse::InternalServerError().body("Failed to change the password");
+        }
+    };
+    if !verify(&form.old_password, &hash_pw).unwrap_or(false) {
+        return refused_attempt(&conn, &jwt.email, ip.as_deref(), "wrong current password")
+            .body("Wrong password");
+    }
+
+    let changed = derive_user_key(&conn, jwt.id, &jwt.email, &form.old_password)
+        .and_then(|old_key| change_user_password(&mut conn, jwt.id, &old_key, &form.new_password));
+    let changed = match changed {
+        Ok(changed) => changed,
+        Err(e) => {
+            eprintln!("Failed to change the password of user {}: {}", jwt.id, e);
+            return HttpResponse::InternalServerError().body("Failed to change the password");
+        }
+    };
+    drop(conn);
+
+    // Other sessions hold the old key, which no longer opens anything
+    let mut ended = 0;
+    for (session_id, session) in SESSION_CACHE.iter() {
+        if *session_id == jwt.session_id {
+            continue;
+        }
+        if session
+            .lock()
+            .is_ok_and(|session| session.user_id == jwt.id)
+        {
+            destroy_session(&session_id);
+            ended += 1;
+        }
+    }
+    if let Some(session) = SESSION_CACHE.get(&jwt.session_id) {
+        let mut session = session.lock().unwrap();
+        session.user_key.wipe();
+        session.user_key = changed.user_key;
+        session.user_kdf = changed.user_kdf;
+        session.hash_pw = changed.hash_pw;
+    }
+    drop_pending_logins(jwt.id);
+
+    HttpResponse::Ok().json(json!({
         "success": true,
-        "message": "Successful connection"
+        "sessions_ended": ended,
+        "recovery_key": changed.recovery_key.display().as_str()
     }))
 }
 
-pub async fn logout_user_query(req: HttpRequest) -> impl Responder {
-    let expired_cookie = Cookie::build("user_token", "")
-        .path("/")
-        .http_only(true)
-        .secure(true)
-        .same_site(SameSite::Lax)
-        .expires(OffsetDateTime::now_utc() - Duration::days(1)) // important
-        .max_age(Duration::seconds(0)) // important aussi
-        .finish();
+// Drops the logins of a user waiting for a second factor, they unlocked a key that changed
+fn drop_pending_logins(user_id: u32) {
+    let pending: Vec<String> = PENDING_LOGIN_CACHE
+        .iter()
+        .filter(|(_, pending)| {
+            pending
+                .lock()
+                .is_ok_and(|pending| pending.user_id == user_id)
+        })
+        .map(|(challenge_id, _)| challenge_id.to_string())
+        .collect();
+    for challenge_id in &pending {
+        PENDING_LOGIN_CACHE.invalidate(challenge_id);
+    }
+}
 
-    HttpResponse::Ok()
-        .cookie(expired_cookie)
-        .json(json!({ "success": true }))
+/**
+ * Endpoint to set a new password with the recovery key, for users who
+ * forgot theirs.
+ *
+ * The recovery key unseals the user key, everything is re-encrypted as for
+ * a password change, every session of the account is ended and a new
+ * recovery key replaces the one just used.
+ *
+ * Wrong recovery keys count as failed logins, throttled the same way.
+ *
+ * @param req - The HTTP request, for the client address.
+ * @param form - The email, the recovery key and the new password.
+ * @return An HTTP response with the new recovery key.
+ */
+pub async fn recover_account_query(
+    req: HttpRequest,
+    form: web::Json<RecoverAccountForm>,
+) -> impl Responder {
+    if form.new_password.is_empty() {
+        return HttpResponse::BadRequest().body("The new password cannot be empty");
+    }
+    let email = canonical_email(&form.username);
+    let ip = client_ip(&req);
+
+    let mut conn = CONNECTION.lock().unwrap();
+    match login_retry_after(&conn, &email, ip.as_deref(), token::now()) {
+        Ok(None) => {}
+        Ok(Some(wait)) => return too_many_attempts(wait),
+        Err(e) => {
+            eprintln!("Failed to read the failed logins of {}: {}", email, e);
+            return HttpResponse::InternalServerError().body("Failed to recover the account");
+        }
+    }
+    let refused = |conn: &Connection, reason| {
+        refused_attempt(conn, &email, ip.as_deref(), reason).body("Invalid email or recovery key")
+    };
+
+    let user_id = match get_user_by_email(&conn, &email) {
+        Ok(Some((user_id, _))) => user_id,
+        Ok(None) => return refused(&conn, "recovery of an unknown account"),
+        Err(e) => {
+            eprintln!("Failed to read user {}: {}", email, e);
+            return HttpResponse::InternalServerError().body("Failed to recover the account");
+        }
+    };
+    let escrow = match get_recovery_escrow(&conn, user_id) {
+        Ok(Some(escrow)) => escrow,
+        Ok(None) => return refused(&conn, "recovery without a recovery key"),
+        Err(e) => {
+            eprintln!("Failed to read the recovery key of user {}: {}", user_id, e);
+            return HttpResponse::InternalServerError().body("Failed to recover the account");
+        }
+    };
+    let old_key = match RecoveryKey::parse(&form.recovery_key)
+        .and_then(|recovery_key| recovery_key.open_escrow(user_id, &escrow))
+    {
+        Ok(old_key) => old_key,
+        Err(_) => return refused(&conn, "wrong recovery key"),
+    };
+    if let Err(e) = clear_login_failures(&conn, &email) {
+        eprintln!("Failed to clear the failed logins of {}: {}", email, e);
+    }
+
+    let changed = match change_user_password(&mut conn, user_id, &old_key, &form.new_password) {
+        Ok(changed) => changed,
+        Err(e) => {
+            eprintln!("Failed to recover the account of user {}: {}", user_id, e);
+            return HttpResponse::InternalServerError().body("Failed to recover the account");
+        }
+    };
+    drop(conn);
+    eprintln!(
+        "User {} reset their password with the recovery key",
+        user_id
+    );
+
+    let sessions = destroy_user_sessions(user_id);
+    drop_pending_logins(user_id);
+
+    HttpResponse::Ok().json(json!({
+        "success": true,
+        "sessions_ended": sessions,
+        "recovery_key": changed.recovery_key.display().as_str()
+    }))
+}
+
+/**
+ * Endpoint to replace the recovery key of the logged-in user, e.g. when it
+ * was lost or for accounts created before recovery keys existed.
+ *
+ * @param req - The HTTP request carrying the session token.
+ * @param form - The current password, to confirm the change.
+ * @return An HTTP response with the new recovery key, shown only this once.
+ */
+pub async fn regenerate_recovery_key_query(
+    req: HttpRequest,
+    form: web::Json<RecoveryKeyForm>,
+) -> impl Responder {
+    let jwt = match get_user_from_cookie(&req) {
+        Some(jwt) => jwt,
+        None => return HttpResponse::Unauthorized().body("Invalid session"),
+    };
+
+    let conn = CONNECTION.lock().unwrap();
+    let hash_pw = match get_user_by_email(&conn, &jwt.email) {
+        Ok(Some((user_id, hash_pw))) if user_id == jwt.id => hash_pw,
+        Ok(_) => return HttpResponse::Unauthorized().body("Invalid session"),
+        Err(e) => {
+            eprintln!("Failed to read user {}: {}", jwt.id, e);
+            return HttpResponse::InternalServerError().body("Failed to create a recovery key");
+        }
+    };
+    if !verify(&form.password, &hash_pw).unwrap_or(false) {
+        return HttpResponse::Unauthorized().body("Wrong password");
+    }
+    let session = match SESSION_CACHE.get(&jwt.session_id) {
+        Some(session) => session,
+        None => return HttpResponse::Unauthorized().body("Invalid session"),
+    };
+
+    let recovery_key = RecoveryKey::generate();
+    let stored = {
+        let session = session.lock().unwrap();
+        set_recovery_key(&conn, jwt.id, &recovery_key, session.user_key.as_bytes())
+    };
+    match stored {
+        Ok(()) => HttpResponse::Ok().json(json!({
+            "success": true,
+            "recovery_key": recovery_key.display().as_str()
+        })),
+        Err(e) => {
+            eprintln!("Failed to store the recovery key of user {}: {}", jwt.id, e);
+            HttpResponse::InternalServerError().body("Failed to create a recovery key")
+        }
+    }
+}
+
+/**
+ * Opens a new session for a user whose credentials were checked. Every login
+ * gets its own session, so the user can be logged in on several devices.
+ *
+ * @param user_id - The ID of the user.
+ * @param hash_pw - The hashed password of the user.
+ * @param client - The device the user logged in from.
+ * @param unlock - Returns the user key.
+ * @return Result<String, String> - The session ID or an error message.
+ */
+fn start_session(
+    user_id: u32,
+    hash_pw: &str,
+    client: SessionClient,
+    unlock: impl FnOnce() -> std::result::Result<(SecretKey, Kdf), String>,
+) -> std::result::Result<String, String> {
+    let session_id = generate_session_id();
+    let (user_key, user_kdf) = unlock()?;
+
+    SESSION_CACHE.insert(
+        session_id.clone(),
+        Arc::new(Mutex::new(Session::new(
+            user_id, hash_pw, user_key, user_kdf, client,
+        ))),
+    );
+
+    Ok(session_id)
+}
+
+// Response setting the cookie with a freshly signed token, and telling the
+// user about the invitations waiting for them
+fn login_response(conn: &Connection, session_id: &str, user_id: u32, email: &str) -> HttpResponse {
+    let jwt = JWT::new(session_id, user_id, email);
+
+    let cookie = Cookie::build("user_token", issue_token(&jwt))
+        .http_only(true)
+        .secure(true) // Use secure(true) if you are in production (HTTPS)
+        .same_site(SameSite::Lax)
+        .path("/")
+        // The cookie lives as long as the token it carries
+        .max_age(Dudu::seconds(
+            SERVER_CONFIG
+                .session
+                .token_lifetime_secs
+                .min(i64::MAX as u64) as i64,
+        ))
+        .finish();
+
+    let invitations = count_pending_invitations(conn, user_id).unwrap_or_else(|e| {
+        eprintln!("Failed to count the invitations of user {}: {}", user_id, e);
+        0
+    });
+
+    HttpResponse::Ok().cookie(cookie).json(json!({
+        "success": true,
+        "message": "Successful connection",
+        "pending_invitations": invitations
+    }))
+}
+
+/**
+ * Ends a session on the server.
+ *
+ * The session leaves `SESSION_CACHE`, the user key is wiped, and the vaults
+ * it loaded are unloaded unless another live session still uses them.
+ *
+ * @param session_id - The session to end.
+ */
+pub fn destroy_session(session_id: &str) {
+    let session = match SESSION_CACHE.get(session_id) {
+        Some(session) => session,
+        None => return,
+    };
+    SESSION_CACHE.invalidate(session_id);
+
+    let loaded_vaults = match session.lock() {
+        Ok(mut session) => {
+            session.user_key.wipe();
+            std::mem::take(&mut session.loaded_vaults)
+        }
+        Err(_) => return,
+    };
+    unload_unused_vaults(loaded_vaults);
+}
+
+/**
+ * Ends every session of a user.
+ *
+ * @param user_id - The ID of the user.
+ * @return usize - The number of sessions ended.
+ */
+pub fn destroy_user_sessions(user_id: u32) -> usize {
+    let session_ids: Vec<String> = SESSION_CACHE
+        .iter()
+        .filter(|(_, session)| {
+            session
+                .lock()
+                .map(|session| session.user_id == user_id)
+                .unwrap_or(false)
+        })
+        .map(|(session_id, _)| session_id.to_string())
+        .collect();
+
+    for session_id in &session_ids {
+        destroy_session(session_id);
+    }
+    session_ids.len()
+}
+
+/**
+ * Struct representing the form data for ending one session.
+ */
+#[derive(serde::Deserialize, Debug)]
+pub struct RevokeSessionForm {
+    /// Public ID of the session, as listed by `list_sessions_query`
+    id: String,
+}
+
+// Seconds since the UNIX epoch, for the JSON responses
+fn unix_secs(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or(0)
+}
+
+/**
+ * Endpoint listing the live sessions of the logged-in user, most recently
+ * active first.
+ *
+ * @param req - The HTTP request carrying the session token.
+ * @return An HTTP response with the sessions, the one making the request marked as current.
+ */
+pub async fn list_sessions_query(req: HttpRequest) -> impl Responder {
+    let jwt = match get_user_from_cookie(&req) {
+        Some(jwt) => jwt,
+        None => return HttpResponse::Unauthorized().body("Invalid session"),
+    };
+
+    let mut sessions: Vec<(SystemTime, serde_json::Value)> = SESSION_CACHE
+        .iter()
+        .filter_map(|(session_id, session)| {
+            let session = session.lock().ok()?;
+            if session.user_id != jwt.id {
+                return None;
+            }
+            Some((
+                session.last_activity,
+                json!({
+                    "id": session.public_id,
+                    "created_at": unix_secs(session.created_at),
+                    "last_activity": unix_secs(session.last_activity),
+                    "ip": session.client.ip,
+                    "user_agent": session.client.user_agent,
+                    "current": *session_id == jwt.session_id,
+                }),
+            ))
+        })
+        .collect();
+    sessions.sort_by_key(|(last_activity, _)| std::cmp::Reverse(*last_activity));
+    let sessions: Vec<_> = sessions.into_iter().map(|(_, session)| session).collect();
+
+    HttpResponse::Ok().json(json!({ "success": true, "sessions": sessions }))
+}
+
+/**
+ * Endpoint ending one session of the logged-in user, e.g. on a lost phone.
+ *
+ * @param req - The HTTP request carrying the session token.
+ * @param form - The public ID of the session to end.
+ * @return An HTTP response, clearing the cookie if the current session was ended.
+ */
+pub async fn revoke_session_query(
+    req: HttpRequest,
+    form: web::Json<RevokeSessionForm>,
+) -> impl Responder {
+    let jwt = match get_user_from_cookie(&req) {
+        Some(jwt) => jwt,
+        None => return HttpResponse::Unauthorized().body("Invalid session"),
+    };
+
+    let session_id = SESSION_CACHE.iter().find_map(|(session_id, session)| {
+        session
+            .lock()
+            .ok()
+            .filter(|session| session.user_id == jwt.id && session.public_id == form.id)
+            .map(|_| session_id.to_string())
+    });
+    let session_id = match session_id {
+        Some(session_id) => session_id,
+        None => return HttpResponse::NotFound().body("Unknown session"),
+    };
+    destroy_session(&session_id);
+
+    let mut response = HttpResponse::Ok();
+    let current = session_id == jwt.session_id;
+    if current {
+        response.cookie(expired_token_cookie());
+    }
+    response.json(json!({ "success": true, "current": current }))
+}
+
+// Drops the given vaults from the cache, except those another live session loaded
+fn unload_unused_vaults(vault_names: HashSet<String>) {
+    let mut in_use = HashSet::new();
+    for (_, session) in SESSION_CACHE.iter() {
+        if let Ok(session) = session.lock() {
+            in_use.extend(session.loaded_vaults.iter().cloned());
+        }
+    }
+
+    // The eviction listener of VAULTS_CACHE wipes the vault keys
+    for name in vault_names.difference(&in_use) {
+        VAULTS_CACHE.invalidate(name);
+    }
+}
+
+// Cookie replacing the session token in the browser
+fn expired_token_cookie() -> Cookie<'static> {
+    Cookie::build("user_token", "")
+        .path("/")
+        .http_only(true)
+        .secure(true)
+        .same_site(SameSite::Lax)
+        .expires(OffsetDateTime::now_utc() - Duration::days(1)) // important
+        .max_age(Duration::seconds(0)) // important aussi
+        .finish()
+}
+
+/**
+ * Endpoint to log out: ends the session and clears the cookie.
+ *
+ * @param req - The HTTP request carrying the session token.
+ * @return An HTTP response clearing the cookie.
+ */
+pub async fn logout_user_query(req: HttpRequest) -> impl Responder {
+    if let Some(jwt) = get_user_from_cookie(&req) {
+        destroy_session(&jwt.session_id);
+    }
+
+    HttpResponse::Ok()
+        .cookie(expired_token_cookie())
+        .json(json!({ "success": true }))
+}
+
+/**
+ * Endpoint to log out everywhere: ends every session of the account.
+ *
+ * @param req - The HTTP request carrying the session token.
+ * @return An HTTP response with the number of sessions ended.
+ */
+pub async fn logout_everywhere_query(req: HttpRequest) -> impl Responder {
+    let jwt = match get_user_from_cookie(&req) {
+        Some(jwt) => jwt,
+        None => return HttpResponse::Unauthorized().body("Invalid session"),
+    };
+
+    let sessions = destroy_user_sessions(jwt.id);
+    HttpResponse::Ok()
+        .cookie(expired_token_cookie())
+        .json(json!({ "success": true, "sessions": sessions }))
+}
+
+/**
+ * What happens to a vault when one of its members deletes their account.
+ */
+#[derive(Serialize, Debug, PartialEq)]
+#[serde(tag = "action", rename_all = "lowercase")]
+pub enum VaultFate {
+    /// The user is only a member: their access is removed
+    Leave,
+    /// The user created the vault and hands it over to one of its Admins
+    Transfer { to: String },
+    /// The user created the vault and nobody takes it over
+    Delete,
+}
+
+/**
+ * A vault of an account about to be deleted, and what will become of it.
+ */
+#[derive(Serialize)]
+pub struct VaultDeletion {
+    pub vault: VaultInfo,
+    #[serde(flatten)]
+    pub fate: VaultFate,
+    /// Emails of the Admins a vault the user created can be handed over to
+    pub admins: Vec<String>,
+    #[serde(skip)]
+    heir: Option<u32>,
+    // None when the key file of the user does not open, only the database row is left then
+    #[serde(skip)]
+    vault_key: Option<VaultKey>,
+}
+
+/**
+ * Works out what deleting an account does to each of its vaults.
+ *
+ * Vaults the user created are deleted unless `handover` names one of their
+ * Admins to take them over; the user simply leaves the other vaults.
+ *
+ * @param conn - The database connection.
+ * @param user_id - The ID of the user.
+ * @param user_key - The user key, to open the vaults.
+ * @param user_kdf - How `user_key` was derived.
+ * @param handover - Vault name → email of the Admin taking it over.
+ * @return Result<Vec<VaultDeletion>, String> - The plan, or an error message if the handover is not possible.
+ */
+pub fn plan_account_deletion(
+    conn: &Connection,
+    user_id: u32,
+    user_key: &[u8],
+    user_kdf: Kdf,
+    handover: &HashMap<String, String>,
+) -> std::result::Result<Vec<VaultDeletion>, String> {
+    let vaults = get_user_vaults(conn, user_id).map_err(|e| e.to_string())?;
+    let keypair = get_user_keypair(conn, user_id, user_key)?;
+    let mut plan = Vec::with_capacity(vaults.len());
+    for vault in vaults {
+        let vault_key = match vault.open_key(user_id, user_key, user_kdf, keypair.as_ref()) {
+            Ok(vault_key) => vault_key,
+            // Without the key nobody can be made Creator, and deleting it is not ours to guess
+            Err(e) if vault.creator_id == user_id => {
+                eprintln!(
+                    "Key file of vault {} failed to open: {}",
+                    vault.get_name(),
+                    e
+                );
+                return Err(format!(
+                    "The key of vault '{}' does not open, it can neither be handed over nor deleted",
+                    vault.name
+                ));
+            }
+            Err(e) => {
+                eprintln!(
+                    "Key file of vault {} failed to open: {}",
+                    vault.get_name(),
+                    e
+                );
+                plan.push(VaultDeletion {
+                    vault,
+                    fate: VaultFate::Leave,
+                    admins: Vec::new(),
+                    heir: None,
+                    vault_key: None,
+                });
+                continue;
+            }
+        };
+        let perms = vault.get_perms(&vault_key)?;
+        if perms.get(&user_id) != Some(&Perms::Creator) {
+            plan.push(VaultDeletion {
+                vault,
+                fate: VaultFate::Leave,
+                admins: Vec::new(),
+                heir: None,
+                vault_key: Some(vault_key),
+            });
+            continue;
+        }
+
+        let mut admins = Vec::new();
+        for (&id, perm) in &perms {
+            if *perm == Perms::Admin {
+                if let Some(email) = get_user_email(conn, id).map_err(|e| e.to_string())? {
+                    admins.push((id, email));
+                }
+            }
+        }
+        admins.sort_by(|a, b| a.1.cmp(&b.1));
+
+        let (fate, heir) = match handover.get(&vault.get_name()) {
+            Some(email) => match admins.iter().find(|(_, admin)| admin == email) {
+                Some((id, _)) => (VaultFate::Transfer { to: email.clone() }, Some(*id)),
+                None => {
+                    return Err(format!(
+                        "{} is not an Admin of vault '{}'",
+                        email, vault.name
+                    ))
+                }
+            },
+            None => (VaultFate::Delete, None),
+        };
+        plan.push(VaultDeletion {
+            vault,
+            fate,
+            admins: admins.into_iter().map(|(_, email)| email).collect(),
+            heir,
+            vault_key: Some(vault_key),
+        });
+    }
+
+    for name in handover.keys() {
+        if !plan
+            .iter()
+            .any(|entry| entry.vault.get_name() == *name && entry.fate != VaultFate::Leave)
+        {
+            return Err(format!("Vault {} was not created by this account", name));
+        }
+    }
+    Ok(plan)
+}
+
+/**
+ * Deletes an account following a plan from `plan_account_deletion`.
+ *
+ * The new permissions of the vaults the user leaves are staged and the files
+ * going away (deleted vaults, key files, the password list) are moved aside.
+ * The user, their vault rows, backup codes, API tokens, invitations and
+ * transfers are then removed in one transaction; the staged files are kept
+ * once it is committed, and put back if it fails.
+ *
+ * @param conn - The database connection.
+ * @param user_id - The ID of the user.
+ * @param plan - What to do with each vault.
+ * @return Result<(), String> - An error message if the account could not be deleted, nothing was changed then.
+ */
+pub fn delete_account(
+    conn: &mut Connection,
+    user_id: u32,
+    plan: &[VaultDeletion],
+) -> std::result::Result<(), String> {
+    let mut replaced = Vec::new();
+    let mut removed = Vec::new();
+    for entry in plan {
+        let vault = &entry.vault;
+        if entry.fate == VaultFate::Delete {
+            removed.push(PathBuf::from(vault.get_path()));
+            continue;
+        }
+        if let Some(vault_key) = &entry.vault_key {
+            replaced.push(vault.perms_without_member(vault_key, user_id, entry.heir)?);
+        }
+        removed.extend(vault.get_member_files(user_id));
+    }
+    removed.push(get_passwords_path(user_id));
+    let files = swap_files(replaced, removed)?;
+
+    let recorded = conn
+        .transaction()
+        .map_err(|e| e.to_string())
+        .and_then(|tx| {
+            for entry in plan {
+                let vault = &entry.vault;
+                if entry.fate == VaultFate::Delete {
+                    delete_vault_rows(&tx, vault).map_err(|e| e.to_string())?;
+                    continue;
+                }
+                if let Some(heir) = entry.heir {
+                    set_vault_creator(&tx, vault, heir).map_err(|e| e.to_string())?;
+                }
+                if let Err(e) = remove_vault(&tx, vault, user_id) {
+                    eprintln!(
+                        "Vault row of {} for user {}: {}",
+                        vault.get_name(),
+                        user_id,
+                        e
+                    );
+                }
+            }
+            tx.execute(
+                "DELETE FROM totp_backup_codes WHERE user_id = ?",
+                params![user_id],
+            )
+            .map_err(|e| e.to_string())?;
+            delete_user_api_tokens(&tx, user_id).map_err(|e| e.to_string())?;
+            delete_user_invitations(&tx, user_id).map_err(|e| e.to_string())?;
+            delete_user_transfers(&tx, user_id).map_err(|e| e.to_string())?;
+            tx.execute("DELETE FROM vaults WHERE id = ?", params![user_id])
+                .map_err(|e| e.to_string())?;
+            tx.execute("DELETE FROM users WHERE id = ?", params![user_id])
+                .map_err(|e| e.to_string())?;
+            tx.commit().map_err(|e| e.to_string())
+        });
+    if let Err(e) = recorded {
+        files.rollback()?;
+        return Err(e);
+    }
+    files.commit();
+
+    for entry in plan {
+        // Members who loaded the vault reload the new permissions, or find it gone
+        VAULTS_CACHE.invalidate(&entry.vault.get_name());
+        if entry.fate != VaultFate::Delete {
+            if let Err(e) = entry.vault.release_rekey_links(user_id) {
+                eprintln!("Forward links of vault {}: {}", entry.vault.get_name(), e);
+            }
+        }
+    }
+    Ok(())
+}
+
+// User key and derivation of a session, cloned so the session lock is not held
+fn session_user_key(session_id: &str) -> Option<(SecretKey, Kdf)> {
+    let session = SESSION_CACHE.get(session_id)?;
+    let session = session.lock().ok()?;
+    if session.user_key.is_empty() {
+        return None;
+    }
+    Some((session.user_key.clone(), session.user_kdf))
+}
+
+/**
+ * Endpoint showing what deleting the account would do to each vault,
+ * before the user confirms it.
+ *
+ * @param req - The HTTP request carrying the session token.
+ * @param form - The vaults to hand over, and to whom.
+ * @return An HTTP response with the fate of every vault.
+ */
+pub async fn delete_account_preview_query(
+    req: HttpRequest,
+    form: web::Json<DeleteAccountPreviewForm>,
+) -> impl Responder {
+    let jwt = match get_user_from_cookie(&req) {
+        Some(jwt) => jwt,
+        None => return HttpResponse::Unauthorized().body("Invalid session"),
+    };
+    let (user_key, user_kdf) = match session_user_key(&jwt.session_id) {
+        Some(key) => key,
+        None => return HttpResponse::Unauthorized().body("Invalid session"),
+    };
+
+    let conn = CONNECTION.lock().unwrap();
+    match plan_account_deletion(&conn, jwt.id, user_key.as_bytes(), user_kdf, &form.handover) {
+        Ok(plan) => HttpResponse::Ok().json(json!({ "success": true, "vaults": plan })),
+        Err(e) => HttpResponse::BadRequest().body(e),
+    }
+}
+
+/**
+ * Endpoint to delete the account of the logged-in user.
+ *
+ * Vaults the user created are handed over to the Admins named in the form
+ * or deleted with everything in them, the user leaves the others. Every
+ * session of the account is ended.
+ *
+ * @param req - The HTTP request carrying the session token.
+ * @param form - The password, to confirm, and the vaults to hand over.
+ * @return An HTTP response with what was done to each vault.
+ */
+pub async fn delete_account_query(
+    req: HttpRequest,
+    form: web::Json<DeleteAccountForm>,
+) -> impl Responder {
+    let jwt = match get_user_from_cookie(&req) {
+        Some(jwt) => jwt,
+        None => return HttpResponse::Unauthorized().body("Invalid session"),
+    };
+
+    let mut conn = CONNECTION.lock().unwrap();
+    let hash_pw = match get_user_by_email(&conn, &jwt.email) {
+        Ok(Some((user_id, hash_pw))) if user_id == jwt.id => hash_pw,
+        Ok(_) => return HttpResponse::Unauthorized().body("Invalid session"),
+        Err(e) => {
+            eprintln!("Failed to read user {}: {}", jwt.id, e);
+            return HttpResponse::InternalServerError().body("Failed to delete the account");
+        }
+    };
+    if !verify(&form.password, &hash_pw).unwrap_or(false) {
+        return HttpResponse::Unauthorized().body("Wrong password");
+    }
+    let (user_key, user_kdf) = match session_user_key(&jwt.session_id) {
+        Some(key) => key,
+        None => return HttpResponse::Unauthorized().body("Invalid session"),
+    };
+
+    let plan =
+        match plan_account_deletion(&conn, jwt.id, user_key.as_bytes(), user_kdf, &form.handover) {
+            Ok(plan) => plan,
+            Err(e) => return HttpResponse::BadRequest().body(e),
+        };
+    if let Err(e) = delete_account(&mut conn, jwt.id, &plan) {
+        eprintln!("Failed to delete the account of user {}: {}", jwt.id, e);
+        return HttpResponse::InternalServerError().body("Failed to delete the account");
+    }
+    drop(conn);
+    eprintln!("User {} deleted their account", jwt.id);
+
+    destroy_user_sessions(jwt.id);
+    drop_pending_logins(jwt.id);
+
+    HttpResponse::Ok()
+        .cookie(expired_token_cookie())
+        .json(json!({ "success": true, "vaults": plan }))
 }
 
 #[derive(Deserialize)]
 pub struct VaultForm {
     pub(crate) name: String, // The name must match the `name` attribute of the HTML form
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::auth::email::CODE_VALIDITY;
+    use crate::backend::auth::totp;
+    use crate::backend::server_manager::global_manager::{migrate_database, TOTP_KEY};
+    use crate::backend::server_manager::test_support::{
+        create_account, create_test_vault, create_user, log_in, respond, TestAccount, TestResponse,
+        TestUser,
+    };
+    use crate::backend::server_manager::vault_manager::get_vault;
+    use crate::backend::VAULT_USERS_DIR;
+    use actix_web::http::StatusCode;
+    use actix_web::test::TestRequest;
+    use std::path::Path;
+
+    const PASSWORD: &str = "correct horse battery staple";
+    const LEGACY_KDF: Kdf = Kdf::Pbkdf2Sha256 {
+        iterations: USER_KEY_ITERATIONS,
+    };
+
+    // An account of the releases before Argon2id, with one vault and a password list
+    fn legacy_account(conn: &Connection, id: u32, email: &str) -> (SecretKey, VaultInfo, VaultKey) {
+        conn.execute(
+            "INSERT INTO users (id, email, hash_password) VALUES (?, ?, '')",
+            params![id, email],
+        )
+        .unwrap();
+        legacy_files(conn, id, email)
+    }
+
+    // The files of a legacy account, sealed with the key salted with `email`
+    fn legacy_files(conn: &Connection, id: u32, email: &str) -> (SecretKey, VaultInfo, VaultKey) {
+        let user_key = SecretKey::new(derive_key(
+            PASSWORD,
+            &generate_salt_from_login(email),
+            USER_KEY_ITERATIONS,
+        ));
+
+        let vault = VaultInfo::new(id, "tests", 1_700_000_000);
+        vault.create_path().unwrap();
+        conn.execute(
+            "INSERT INTO vaults (id, creator_id, name, date) VALUES (?1, ?1, ?2, ?3)",
+            params![id, vault.name, vault.date],
+        )
+        .unwrap();
+        let vault_key = VaultKey::generate();
+        vault
+            .save_key(&vault_key, user_key.as_bytes(), LEGACY_KDF, id)
+            .unwrap();
+
+        let passwords = get_passwords_path(id);
+        fs::create_dir_all(passwords.parent().unwrap()).unwrap();
+        fs::write(
+            &passwords,
+            container::seal(b"[]", user_key.as_bytes(), LEGACY_KDF),
+        )
+        .unwrap();
+        (user_key, vault, vault_key)
+    }
+
+    fn user_files(vault: &VaultInfo, id: u32) -> [PathBuf; 2] {
+        [
+            PathBuf::from(vault.get_key_path(id)),
+            get_passwords_path(id),
+        ]
+    }
+
+    fn assert_no_leftovers(files: &[PathBuf]) {
+        for path in files {
+            for extension in ["rewrap", "bak"] {
+                assert!(!path.with_extension(extension).exists(), "{:?}", path);
+            }
+        }
+    }
+
+    #[test]
+    fn legacy_accounts_move_to_argon2id_on_login() {
+        let conn = Connection::open_in_memory().unwrap();
+        migrate_database(&conn).unwrap();
+        let id = 1401;
+        let email = "kdf-migration@example.com";
+        let (legacy_key, vault, vault_key) = legacy_account(&conn, id, email);
+
+        let (user_key, user_kdf) = unlock_user_key(&conn, id, email, PASSWORD).unwrap();
+        assert_eq!(user_kdf, SERVER_CONFIG.kdf.kdf());
+        assert_ne!(user_key.as_bytes(), legacy_key.as_bytes());
+        let recorded = get_user_kdf(&conn, id).unwrap().unwrap();
+        assert_eq!(recorded.params, SERVER_CONFIG.kdf);
+        assert_eq!(
+            derive_user_key(&conn, id, email, PASSWORD)
+                .unwrap()
+                .as_bytes(),
+            user_key.as_bytes()
+        );
+
+        // Both files are sealed with the new key and record how it was derived
+        let opened = vault
+            .open_key(id, user_key.as_bytes(), user_kdf, None)
+            .unwrap();
+        assert_eq!(opened.as_bytes(), vault_key.as_bytes());
+        let passwords = fs::read(get_passwords_path(id)).unwrap();
+        assert_eq!(
+            container::Header::parse(&passwords).unwrap().unwrap().kdf,
+            user_kdf
+        );
+        assert_eq!(
+            container::open(&passwords, user_key.as_bytes()).unwrap(),
+            b"[]"
+        );
+        assert!(container::open(&passwords, legacy_key.as_bytes()).is_err());
+        assert_no_leftovers(&user_files(&vault, id));
+        assert!(get_public_key(&conn, id).unwrap().is_some());
+
+        // Migrated once, the next login derives the same key
+        let (again, _) = unlock_user_key(&conn, id, email, PASSWORD).unwrap();
+        assert_eq!(again.as_bytes(), user_key.as_bytes());
+    }
+
+    #[test]
+    fn legacy_mixed_case_accounts_log_in_after_emails_are_normalized() {
+        let conn = Connection::open_in_memory().unwrap();
+        // The users table of the first releases, emails stored as typed
+        conn.execute_batch(
+            "CREATE TABLE users (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                email TEXT NOT NULL UNIQUE,
+                hash_password TEXT NOT NULL
+            );",
+        )
+        .unwrap();
+        let id = 1404;
+        let stored = " Legacy.Mixed@Example.COM";
+        let hash_pw = hash(PASSWORD, 4).unwrap();
+        conn.execute(
+            "INSERT INTO users (id, email, hash_password) VALUES (?, ?, ?)",
+            params![id, stored, hash_pw],
+        )
+        .unwrap();
+        for (twin, email) in [(1405, "Twin@example.com"), (1406, "twin@Example.com ")] {
+            conn.execute(
+                "INSERT INTO users (id, email, hash_password) VALUES (?, ?, '')",
+                params![twin, email],
+            )
+            .unwrap();
+        }
+
+        migrate_database(&conn).unwrap();
+        let (legacy_key, vault, vault_key) = legacy_files(&conn, id, stored);
+        // A second start changes nothing
+        migrate_database(&conn).unwrap();
+
+        // Login: canonical lookup, password check, then the key
+        let email = canonical_email("legacy.mixed@example.com ");
+        let (user_id, hash_pw) = get_user_by_email(&conn, &email).unwrap().unwrap();
+        assert_eq!(user_id, id);
+        assert!(verify(PASSWORD, &hash_pw).unwrap());
+        assert_eq!(
+            derive_user_key(&conn, id, &email, PASSWORD)
+                .unwrap()
+                .as_bytes(),
+            legacy_key.as_bytes()
+        );
+        let (user_key, user_kdf) = unlock_user_key(&conn, id, &email, PASSWORD).unwrap();
+        let opened = vault
+            .open_key(id, user_key.as_bytes(), user_kdf, None)
+            .unwrap();
+        assert_eq!(opened.as_bytes(), vault_key.as_bytes());
+        assert_eq!(get_legacy_salt_email(&conn, id).unwrap(), None);
+
+        // Addresses that would collide are left as stored
+        for (twin, email) in [(1405, "Twin@example.com"), (1406, "twin@Example.com ")] {
+            assert_eq!(get_user_email(&conn, twin).unwrap().unwrap(), email);
+        }
+    }
+
+    #[test]
+    fn a_failed_swap_leaves_the_account_on_its_old_key() {
+        let conn = Connection::open_in_memory().unwrap();
+        migrate_database(&conn).unwrap();
+        let id = 1402;
+        let email = "kdf-rollback@example.com";
+        let (legacy_key, vault, vault_key) = legacy_account(&conn, id, email);
+
+        // The key file is swapped first, the password list cannot be moved aside
+        let blocker = get_passwords_path(id).with_extension("bak");
+        fs::create_dir_all(blocker.join("busy")).unwrap();
+        assert!(unlock_user_key(&conn, id, email, PASSWORD).is_err());
+        fs::remove_dir_all(&blocker).unwrap();
+
+        assert!(get_user_kdf(&conn, id).unwrap().is_none());
+        let opened = vault
+            .open_key(id, legacy_key.as_bytes(), LEGACY_KDF, None)
+            .unwrap();
+        assert_eq!(opened.as_bytes(), vault_key.as_bytes());
+        let passwords = fs::read(get_passwords_path(id)).unwrap();
+        assert_eq!(
+            container::open(&passwords, legacy_key.as_bytes()).unwrap(),
+            b"[]"
+        );
+        assert_no_leftovers(&user_files(&vault, id));
+    }
+
+    #[test]
+    fn rollback_restores_the_previous_files() {
+        let conn = Connection::open_in_memory().unwrap();
+        migrate_database(&conn).unwrap();
+        let id = 1403;
+        let (old_key, vault, _) = legacy_account(&conn, id, "kdf-restore@example.com");
+        let files = user_files(&vault, id);
+        let before: Vec<Vec<u8>> = files.iter().map(|path| fs::read(path).unwrap()).collect();
+
+        let new_key = SecretKey::new(vec![9u8; 32]);
+        let rewrapped =
+            rewrap_user_files(&conn, id, old_key.as_bytes(), new_key.as_bytes(), Kdf::None)
+                .unwrap();
+        for path in &files {
+            let content = fs::read(path).unwrap();
+            assert!(container::open(&content, new_key.as_bytes()).is_ok());
+            assert!(path.with_extension("bak").exists());
+        }
+
+        rewrapped.rollback().unwrap();
+        let after: Vec<Vec<u8>> = files.iter().map(|path| fs::read(path).unwrap()).collect();
+        assert_eq!(after, before);
+        assert_no_leftovers(&files);
+
+        // Nothing is written for a file the old key does not open
+        let wrong_key = SecretKey::new(vec![8u8; 32]);
+        assert!(rewrap_user_files(
+            &conn,
+            id,
+            wrong_key.as_bytes(),
+            new_key.as_bytes(),
+            Kdf::None
+        )
+        .is_err());
+        let after: Vec<Vec<u8>> = files.iter().map(|path| fs::read(path).unwrap()).collect();
+        assert_eq!(after, before);
+        assert_no_leftovers(&files);
+        assert!(Path::new(&vault.get_path()).exists());
+    }
+
+    #[test]
+    fn roles_follow_the_hierarchy() {
+        use Perms::*;
+        assert!(Creator.can_grant(&Admin, Some(&Read)));
+        assert!(Creator.can_grant(&Read, Some(&Admin)));
+        assert!(!Creator.can_grant(&Creator, Some(&Admin)));
+        assert!(!Creator.can_grant(&Admin, Some(&Creator)));
+
+        assert!(Admin.can_grant(&Write, Some(&Read)));
+        assert!(Admin.can_grant(&Write, None));
+        assert!(!Admin.can_grant(&Admin, Some(&Write)));
+        assert!(!Admin.can_grant(&Admin, None));
+        assert!(!Admin.can_grant(&Read, Some(&Admin)));
+        assert!(!Admin.can_manage(&Admin));
+
+        assert!(!Write.can_grant(&Read, Some(&NoLoad)));
+        assert!(!Write.can_grant(&Read, None));
+        assert!(!Write.can_manage(&Read));
+    }
+
+    fn client(ip: &str, user_agent: &str) -> SessionClient {
+        SessionClient {
+            ip: Some(ip.to_string()),
+            user_agent: Some(user_agent.to_string()),
+        }
+    }
+
+    #[actix_web::test]
+    async fn logging_out_ends_the_session_and_wipes_its_key() {
+        let account = create_account("logout@example.com", PASSWORD);
+        let login = log_in(&account.email, PASSWORD, &client("192.0.2.10", "laptop")).await;
+        assert_eq!(login.status, StatusCode::OK);
+        let user = login.user();
+        let session = user.session();
+        assert!(get_user_from_cookie(&user.request()).is_some());
+
+        let req = user.request();
+        let logout = respond(logout_user_query(req.clone()).await, &req).await;
+        assert_eq!(logout.status, StatusCode::OK);
+        assert!(SESSION_CACHE.get(&user.session_id).is_none());
+        assert!(session.lock().unwrap().user_key.is_empty());
+
+        // The token is still signed and unexpired, but names a session that is gone
+        assert!(get_user_from_cookie(&user.request()).is_none());
+        let req = user.request();
+        let sessions = respond(list_sessions_query(req.clone()).await, &req).await;
+        assert_eq!(sessions.status, StatusCode::UNAUTHORIZED);
+    }
+
+    #[actix_web::test]
+    async fn logging_out_everywhere_ends_every_session_of_the_account() {
+        let account = create_account("logout-everywhere@example.com", PASSWORD);
+        let other = create_account("logout-bystander@example.com", PASSWORD);
+        let laptop = log_in(&account.email, PASSWORD, &client("192.0.2.11", "laptop"))
+            .await
+            .user();
+        let phone = log_in(&account.email, PASSWORD, &client("192.0.2.12", "phone"))
+            .await
+            .user();
+        let bystander = log_in(&other.email, PASSWORD, &client("192.0.2.13", "laptop"))
+            .await
+            .user();
+        let sessions = [laptop.session(), phone.session()];
+
+        let req = phone.request();
+        let logout = respond(logout_everywhere_query(req.clone()).await, &req).await;
+        assert_eq!(logout.status, StatusCode::OK);
+        assert_eq!(logout.body["sessions"], 2);
+
+        for (user, session) in [&laptop, &phone].into_iter().zip(&sessions) {
+            assert!(get_user_from_cookie(&user.request()).is_none());
+            assert!(session.lock().unwrap().user_key.is_empty());
+        }
+        // Other accounts keep their sessions
+        assert!(get_user_from_cookie(&bystander.request()).is_some());
+        assert!(!bystander.session().lock().unwrap().user_key.is_empty());
+
+        let req = laptop.request();
+        let again = respond(logout_everywhere_query(req.clone()).await, &req).await;
+        assert_eq!(again.status, StatusCode::UNAUTHORIZED);
+    }
+
+    // Turns on TOTP for an account, returning the secret of its authenticator app
+    fn enable_totp(user_id: u32) -> Zeroizing<Vec<u8>> {
+        let secret = totp::generate_secret();
+        CONNECTION
+            .lock()
+            .unwrap()
+            .execute(
+                "UPDATE users SET totp_secret = ?, totp_enabled = 1 WHERE id = ?",
+                params![TOTP_KEY.seal(user_id, &secret), user_id],
+            )
+            .unwrap();
+        secret
+    }
+
+    fn current_code(secret: &[u8]) -> String {
+        totp::code_at(secret, token::now() / totp::PERIOD)
+    }
+
+    async fn submit_code(challenge_id: &str, code: &str) -> TestResponse {
+        let req = TestRequest::default().to_http_request();
+        let form = VerifyCodeForm {
+            challenge_id: challenge_id.to_string(),
+            code: code.to_string(),
+        };
+        respond(verify_login_code_query(web::Json(form)).await, &req).await
+    }
+
+    fn sessions_of(user_id: u32) -> usize {
+        SESSION_CACHE
+            .iter()
+            .filter(|(_, session)| session.lock().unwrap().user_id == user_id)
+            .count()
+    }
+
+    #[actix_web::test]
+    async fn a_second_factor_holds_the_login_until_the_code_is_checked() {
+        let account = create_account("2fa-login@example.com", PASSWORD);
+        let secret = enable_totp(account.id);
+
+        let login = log_in(&account.email, PASSWORD, &SessionClient::default()).await;
+        assert_eq!(login.status, StatusCode::OK);
+        assert_eq!(login.body["two_factor"], true);
+        assert_eq!(login.body["method"], "totp");
+        assert!(login.cookie.is_none());
+        assert_eq!(sessions_of(account.id), 0);
+        let challenge_id = login.body["challenge_id"].as_str().unwrap();
+
+        let verified = submit_code(challenge_id, &current_code(&secret)).await;
+        assert_eq!(verified.status, StatusCode::OK);
+        let user = verified.user();
+        assert_eq!(user.id, account.id);
+        assert!(get_user_from_cookie(&user.request()).is_some());
+        assert!(!user.user_key.is_empty());
+        assert!(PENDING_LOGIN_CACHE.get(challenge_id).is_none());
+
+        // A challenge opens one session only
+        let replayed = submit_code(challenge_id, &current_code(&secret)).await;
+        assert_eq!(replayed.status, StatusCode::UNAUTHORIZED);
+        assert!(replayed.cookie.is_none());
+        assert_eq!(sessions_of(account.id), 1);
+    }
+
+    #[actix_web::test]
+    async fn wrong_codes_drop_the_challenge_after_the_last_attempt() {
+        let account = create_account("2fa-attempts@example.com", PASSWORD);
+        let secret = enable_totp(account.id);
+        let login = log_in(&account.email, PASSWORD, &SessionClient::default()).await;
+        let challenge_id = login.body["challenge_id"].as_str().unwrap();
+        let pending = PENDING_LOGIN_CACHE.get(challenge_id).unwrap();
+
+        for remaining in (1..MAX_CODE_ATTEMPTS).rev() {
+            let wrong = submit_code(challenge_id, "not-a-code").await;
+            assert_eq!(wrong.status, StatusCode::UNAUTHORIZED);
+            assert_eq!(wrong.body["remaining_attempts"], remaining);
+        }
+        let last = submit_code(challenge_id, "not-a-code").await;
+        assert_eq!(last.status, StatusCode::TOO_MANY_REQUESTS);
+        assert!(pending.lock().unwrap().user_key.is_empty());
+
+        let late = submit_code(challenge_id, &current_code(&secret)).await;
+        assert_eq!(late.status, StatusCode::UNAUTHORIZED);
+        assert_eq!(sessions_of(account.id), 0);
+
+        // The wrong codes counted as failed logins of the account
+        let conn = CONNECTION.lock().unwrap();
+        assert!(login_retry_after(&conn, &account.email, None, token::now())
+            .unwrap()
+            .is_some());
+    }
+
+    #[actix_web::test]
+    async fn expired_email_codes_are_refused() {
+        let account = create_account("2fa-expired@example.com", PASSWORD);
+        enable_totp(account.id);
+        let login = log_in(&account.email, PASSWORD, &SessionClient::default()).await;
+        let challenge_id = login.body["challenge_id"].as_str().unwrap();
+        let pending = PENDING_LOGIN_CACHE.get(challenge_id).unwrap();
+        // As if the code had been mailed before the validity window
+        pending.lock().unwrap().factor = SecondFactor::Email(Timecode {
+            code: "123456".to_string(),
+            time: SystemTime::now() - CODE_VALIDITY,
+            email: account.email.clone(),
+        });
+
+        let expired = submit_code(challenge_id, "123456").await;
+        assert_eq!(expired.status, StatusCode::UNAUTHORIZED);
+        assert!(expired.cookie.is_none());
+        assert!(pending.lock().unwrap().user_key.is_empty());
+        assert!(PENDING_LOGIN_CACHE.get(challenge_id).is_none());
+        assert_eq!(sessions_of(account.id), 0);
+    }
+
+    #[actix_web::test]
+    async fn email_codes_open_a_session_once() {
+        let account = create_account("2fa-email@example.com", PASSWORD);
+        enable_totp(account.id);
+        let login = log_in(&account.email, PASSWORD, &SessionClient::default()).await;
+        let challenge_id = login.body["challenge_id"].as_str().unwrap();
+        PENDING_LOGIN_CACHE
+            .get(challenge_id)
+            .unwrap()
+            .lock()
+            .unwrap()
+            .factor =
+            SecondFactor::Email(Timecode::new("654321".to_string(), account.email.clone()));
+
+        let wrong = submit_code(challenge_id, "123456").await;
+        assert_eq!(wrong.status, StatusCode::UNAUTHORIZED);
+        let verified = submit_code(challenge_id, " 654321 ").await;
+        assert_eq!(verified.status, StatusCode::OK);
+        assert_eq!(verified.user().id, account.id);
+
+        let replayed = submit_code(challenge_id, "654321").await;
+        assert_eq!(replayed.status, StatusCode::UNAUTHORIZED);
+        assert_eq!(sessions_of(account.id), 1);
+    }
+
+    async fn change_password(
+        user: &TestUser,
+        old_password: &str,
+        new_password: &str,
+    ) -> TestResponse {
+        let req = user.request();
+        let form = ChangePasswordForm {
+            old_password: old_password.to_string(),
+            new_password: new_password.to_string(),
+        };
+        respond(
+            change_password_query(req.clone(), web::Json(form)).await,
+            &req,
+        )
+        .await
+    }
+
+    #[actix_web::test]
+    async fn changing_the_password_rewraps_every_secret_of_the_account() {
+        const NEW_PASSWORD: &str = "new horse battery staple";
+        let account = create_account("change-password@example.com", PASSWORD);
+        let laptop = log_in(&account.email, PASSWORD, &SessionClient::default())
+            .await
+            .user();
+        let phone = log_in(&account.email, PASSWORD, &SessionClient::default())
+            .await
+            .user();
+        let (vault, vault_key) = create_test_vault(&laptop, &[]);
+        let passwords = get_passwords_path(account.id);
+        fs::create_dir_all(passwords.parent().unwrap()).unwrap();
+        fs::write(
+            &passwords,
+            container::seal(b"[]", laptop.user_key.as_bytes(), Kdf::None),
+        )
+        .unwrap();
+        let old_key = laptop.user_key.clone();
+
+        let changed = change_password(&laptop, PASSWORD, NEW_PASSWORD).await;
+        assert_eq!(changed.status, StatusCode::OK);
+        assert_eq!(changed.body["sessions_ended"], 1);
+
+        let new_key = {
+            let conn = CONNECTION.lock().unwrap();
+            let new_key = derive_user_key(&conn, account.id, &account.email, NEW_PASSWORD).unwrap();
+            let new_kdf = get_user_kdf(&conn, account.id)
+                .unwrap()
+                .unwrap()
+                .params
+                .kdf();
+            assert!(new_key != old_key);
+
+            let opened = vault
+                .open_key(account.id, new_key.as_bytes(), new_kdf, None)
+                .unwrap();
+            assert_eq!(opened.as_bytes(), vault_key.as_bytes());
+            assert!(vault
+                .open_key(account.id, old_key.as_bytes(), new_kdf, None)
+                .is_err());
+            let sealed = fs::read(&passwords).unwrap();
+            assert_eq!(container::open(&sealed, new_key.as_bytes()).unwrap(), b"[]");
+            assert!(container::open(&sealed, old_key.as_bytes()).is_err());
+            assert_no_leftovers(&user_files(&vault, account.id));
+
+            assert!(get_user_keypair(&conn, account.id, new_key.as_bytes())
+                .unwrap()
+                .is_some());
+            assert!(get_user_keypair(&conn, account.id, old_key.as_bytes()).is_err());
+
+            // The recovery key shown once replaces the previous one
+            let escrow = get_recovery_escrow(&conn, account.id).unwrap().unwrap();
+            assert!(account
+                .recovery_key
+                .open_escrow(account.id, &escrow)
+                .is_err());
+            let recovery_key =
+                RecoveryKey::parse(changed.body["recovery_key"].as_str().unwrap()).unwrap();
+            assert!(recovery_key.open_escrow(account.id, &escrow).unwrap() == new_key);
+            new_key
+        };
+
+        // The current session moved to the new key, the other one ended
+        assert!(*laptop.session().lock().unwrap().user_key.as_bytes() == *new_key.as_bytes());
+        assert!(get_user_from_cookie(&phone.request()).is_none());
+
+        let old = log_in(&account.email, PASSWORD, &SessionClient::default()).await;
+        assert_eq!(old.status, StatusCode::UNAUTHORIZED);
+        let new = log_in(&account.email, NEW_PASSWORD, &SessionClient::default()).await;
+        assert_eq!(new.status, StatusCode::OK);
+        assert!(new.user().user_key == new_key);
+    }
+
+    #[actix_web::test]
+    async fn wrong_current_passwords_count_as_failed_logins() {
+        let account = create_account("change-password-guess@example.com", PASSWORD);
+        let user = log_in(&account.email, PASSWORD, &SessionClient::default())
+            .await
+            .user();
+
+        let guess = change_password(&user, "guess", "new password").await;
+        assert_eq!(guess.status, StatusCode::UNAUTHORIZED);
+        {
+            let conn = CONNECTION.lock().unwrap();
+            let guesses: u32 = conn
+                .query_row(
+                    "SELECT COUNT(*) FROM login_audit WHERE email = ? AND reason = 'wrong current password'",
+                    params![account.email],
+                    |row| row.get(0),
+                )
+                .unwrap();
+            assert_eq!(guesses, 1);
+
+            // Once the account is backing off, even the right password has to wait
+            for _ in 0..6 {
+                record_login_failure(
+                    &conn,
+                    &SERVER_CONFIG.login,
+                    &account.email,
+                    None,
+                    "test",
+                    token::now(),
+                )
+                .unwrap();
+            }
+        }
+        let throttled = change_password(&user, PASSWORD, "new password").await;
+        assert_eq!(throttled.status, StatusCode::TOO_MANY_REQUESTS);
+        let conn = CONNECTION.lock().unwrap();
+        let (_, hash_pw) = get_user_by_email(&conn, &account.email).unwrap().unwrap();
+        assert!(verify(PASSWORD, &hash_pw).unwrap());
+    }
+
+    async fn recover(email: &str, recovery_key: &str, new_password: &str) -> TestResponse {
+        let req = TestRequest::default()
+            .peer_addr("198.51.100.20:443".parse().unwrap())
+            .to_http_request();
+        let form = RecoverAccountForm {
+            username: email.to_string(),
+            recovery_key: recovery_key.to_string(),
+            new_password: new_password.to_string(),
+        };
+        respond(
+            recover_account_query(req.clone(), web::Json(form)).await,
+            &req,
+        )
+        .await
+    }
+
+    fn failed_logins(email: &str, reason: &str) -> u32 {
+        CONNECTION
+            .lock()
+            .unwrap()
+            .query_row(
+                "SELECT COUNT(*) FROM login_audit WHERE email = ? AND reason = ?",
+                params![email, reason],
+                |row| row.get(0),
+            )
+            .unwrap()
+    }
+
+    #[actix_web::test]
+    async fn recovering_an_account_sets_the_new_password() {
+        const NEW_PASSWORD: &str = "recovered horse battery staple";
+        let account = create_account("recover@example.com", PASSWORD);
+        let session = log_in(&account.email, PASSWORD, &SessionClient::default())
+            .await
+            .user();
+
+        let wrong = recover(
+            &account.email,
+            &RecoveryKey::generate().display(),
+            NEW_PASSWORD,
+        )
+        .await;
+        assert_eq!(wrong.status, StatusCode::UNAUTHORIZED);
+        assert_eq!(failed_logins(&account.email, "wrong recovery key"), 1);
+
+        let recovered = recover(
+            &account.email,
+            &account.recovery_key.display(),
+            NEW_PASSWORD,
+        )
+        .await;
+        assert_eq!(recovered.status, StatusCode::OK);
+        assert_eq!(recovered.body["sessions_ended"], 1);
+        assert!(get_user_from_cookie(&session.request()).is_none());
+
+        // The recovery key was spent with the old password
+        let replayed = recover(&account.email, &account.recovery_key.display(), "other").await;
+        assert_eq!(replayed.status, StatusCode::UNAUTHORIZED);
+        let login = log_in(&account.email, NEW_PASSWORD, &SessionClient::default()).await;
+        assert_eq!(login.status, StatusCode::OK);
+    }
+
+    #[actix_web::test]
+    async fn wrong_recovery_keys_are_throttled_like_logins() {
+        let account = create_account("recover-guess@example.com", PASSWORD);
+        let unknown = "recover-nobody@example.com";
+        for _ in 0..4 {
+            let guess = recover(unknown, &RecoveryKey::generate().display(), "x").await;
+            assert_eq!(guess.status, StatusCode::UNAUTHORIZED);
+        }
+        assert_eq!(failed_logins(unknown, "recovery of an unknown account"), 4);
+
+        {
+            let conn = CONNECTION.lock().unwrap();
+            for _ in 0..7 {
+                record_login_failure(
+                    &conn,
+                    &SERVER_CONFIG.login,
+                    &account.email,
+                    None,
+                    "test",
+                    token::now(),
+                )
+                .unwrap();
+            }
+        }
+        let throttled = recover(
+            &account.email,
+            &account.recovery_key.display(),
+            "new password",
+        )
+        .await;
+        assert_eq!(throttled.status, StatusCode::TOO_MANY_REQUESTS);
+        let login = log_in(&account.email, PASSWORD, &SessionClient::default()).await;
+        assert_eq!(login.status, StatusCode::TOO_MANY_REQUESTS);
+        let conn = CONNECTION.lock().unwrap();
+        let (_, hash_pw) = get_user_by_email(&conn, &account.email).unwrap().unwrap();
+        assert!(verify(PASSWORD, &hash_pw).unwrap());
+    }
+
+    // An account with a password list, the Creator of one vault and a member of another
+    struct Leaving {
+        account: TestAccount,
+        user: TestUser,
+        admin: TestUser,
+        owned: (VaultInfo, VaultKey),
+        joined: (VaultInfo, VaultKey),
+    }
+
+    async fn leaving_account(name: &str) -> Leaving {
+        let account = create_account(&format!("{}@example.com", name), PASSWORD);
+        let user = log_in(&account.email, PASSWORD, &SessionClient::default())
+            .await
+            .user();
+        let admin = create_user(&format!("{}-admin@example.com", name), true);
+        let writer = create_user(&format!("{}-writer@example.com", name), true);
+        let owned = create_test_vault(&user, &[(&admin, Perms::Admin), (&writer, Perms::Write)]);
+        let joined = create_test_vault(&writer, &[(&user, Perms::Admin)]);
+
+        let passwords = get_passwords_path(account.id);
+        fs::create_dir_all(passwords.parent().unwrap()).unwrap();
+        let user_kdf = user.session().lock().unwrap().user_kdf;
+        fs::write(
+            &passwords,
+            container::seal(b"[]", user.user_key.as_bytes(), user_kdf),
+        )
+        .unwrap();
+        Leaving {
+            account,
+            user,
+            admin,
+            owned,
+            joined,
+        }
+    }
+
+    async fn preview_deletion(user: &TestUser, handover: &[(&VaultInfo, &str)]) -> TestResponse {
+        let req = user.request();
+        let form = DeleteAccountPreviewForm {
+            handover: handover
+                .iter()
+                .map(|(vault, email)| (vault.get_name(), email.to_string()))
+                .collect(),
+        };
+        respond(
+            delete_account_preview_query(req.clone(), web::Json(form)).await,
+            &req,
+        )
+        .await
+    }
+
+    async fn delete(user: &TestUser, handover: &[(&VaultInfo, &str)]) -> TestResponse {
+        let req = user.request();
+        let form = DeleteAccountForm {
+            password: PASSWORD.to_string(),
+            handover: handover
+                .iter()
+                .map(|(vault, email)| (vault.get_name(), email.to_string()))
+                .collect(),
+        };
+        respond(
+            delete_account_query(req.clone(), web::Json(form)).await,
+            &req,
+        )
+        .await
+    }
+
+    fn fate_of<'a>(response: &'a TestResponse, vault: &VaultInfo) -> &'a serde_json::Value {
+        response.body["vaults"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|entry| entry["vault"]["date"] == vault.date)
+            .unwrap()
+    }
+
+    // Nothing staged is left behind next to the files of a vault
+    fn assert_no_staged_files(vault: &VaultInfo) {
+        let path = PathBuf::from(vault.get_path());
+        let parent = path.parent().unwrap();
+        let removed = parent.join(format!("{}.removed", vault.get_name()));
+        assert!(!removed.exists());
+        for dir in [path.join(".vault"), path.join(VAULT_USERS_DIR)] {
+            for entry in fs::read_dir(dir).unwrap() {
+                let name = entry.unwrap().file_name().into_string().unwrap();
+                assert!(
+                    !name.ends_with(".rewrap")
+                        && !name.ends_with(".bak")
+                        && !name.ends_with(".removed"),
+                    "{}",
+                    name
+                );
+            }
+        }
+    }
+
+    fn user_exists(user_id: u32) -> bool {
+        CONNECTION
+            .lock()
+            .unwrap()
+            .query_row(
+                "SELECT COUNT(*) FROM users WHERE id = ?",
+                params![user_id],
+                |row| row.get::<_, u32>(0),
+            )
+            .unwrap()
+            == 1
+    }
+
+    #[actix_web::test]
+    async fn the_deletion_preview_shows_the_fate_of_every_vault() {
+        let leaving = leaving_account("delete-preview").await;
+        let (owned, _) = &leaving.owned;
+        let (joined, _) = &leaving.joined;
+
+        let preview = preview_deletion(&leaving.user, &[]).await;
+        assert_eq!(preview.status, StatusCode::OK);
+        assert_eq!(fate_of(&preview, owned)["action"], "delete");
+        assert_eq!(
+            fate_of(&preview, owned)["admins"],
+            json!([leaving.admin.email])
+        );
+        assert_eq!(fate_of(&preview, joined)["action"], "leave");
+
+        let handed_over = preview_deletion(&leaving.user, &[(owned, &leaving.admin.email)]).await;
+        assert_eq!(fate_of(&handed_over, owned)["action"], "transfer");
+        assert_eq!(fate_of(&handed_over, owned)["to"], leaving.admin.email);
+
+        // Only an Admin of a vault the user created can take it over
+        let writer = format!("{}-writer@example.com", "delete-preview");
+        let refused = preview_deletion(&leaving.user, &[(owned, &writer)]).await;
+        assert_eq!(refused.status, StatusCode::BAD_REQUEST);
+        let refused = preview_deletion(&leaving.user, &[(joined, &leaving.admin.email)]).await;
+        assert_eq!(refused.status, StatusCode::BAD_REQUEST);
+        assert!(user_exists(leaving.account.id));
+    }
+
+    #[actix_web::test]
+    async fn a_vault_handed_over_on_deletion_keeps_its_members() {
+        let leaving = leaving_account("delete-handover").await;
+        let (owned, owned_key) = &leaving.owned;
+        let (joined, joined_key) = &leaving.joined;
+        let id = leaving.account.id;
+
+        let deleted = delete(&leaving.user, &[(owned, &leaving.admin.email)]).await;
+        assert_eq!(deleted.status, StatusCode::OK);
+        assert!(!user_exists(id));
+        assert!(get_user_from_cookie(&leaving.user.request()).is_none());
+
+        let perms = owned.get_perms(owned_key).unwrap();
+        assert_eq!(perms.get(&leaving.admin.id), Some(&Perms::Creator));
+        assert!(!perms.contains_key(&id));
+        let conn = CONNECTION.lock().unwrap();
+        let current = get_vault(&conn, owned).unwrap().unwrap();
+        assert_eq!(current.creator_id, leaving.admin.id);
+        assert!(get_user_vaults(&conn, id).unwrap().is_empty());
+        drop(conn);
+
+        assert!(!joined.get_perms(joined_key).unwrap().contains_key(&id));
+        for vault in [owned, joined] {
+            assert!(!Path::new(&vault.get_key_path(id)).exists());
+            assert_no_staged_files(vault);
+        }
+        assert!(!get_passwords_path(id).exists());
+    }
+
+    #[actix_web::test]
+    async fn vaults_nobody_takes_over_are_deleted_with_the_account() {
+        let leaving = leaving_account("delete-vaults").await;
+        let (owned, _) = &leaving.owned;
+        let id = leaving.account.id;
+
+        let deleted = delete(&leaving.user, &[]).await;
+        assert_eq!(deleted.status, StatusCode::OK);
+        assert_eq!(fate_of(&deleted, owned)["action"], "delete");
+        assert!(!Path::new(&owned.get_path()).exists());
+        assert!(!PathBuf::from(owned.get_path())
+            .with_file_name(format!("{}.removed", owned.get_name()))
+            .exists());
+        let conn = CONNECTION.lock().unwrap();
+        assert!(get_vault(&conn, owned).unwrap().is_none());
+        assert!(get_user_vaults(&conn, leaving.admin.id).unwrap().is_empty());
+        assert!(!get_passwords_path(id).exists());
+    }
+
+    #[actix_web::test]
+    async fn a_creator_vault_whose_key_does_not_open_blocks_the_deletion() {
+        let leaving = leaving_account("delete-broken-key").await;
+        let (owned, _) = &leaving.owned;
+        fs::write(owned.get_key_path(leaving.account.id), b"garbage").unwrap();
+
+        let preview = preview_deletion(&leaving.user, &[]).await;
+        assert_eq!(preview.status, StatusCode::BAD_REQUEST);
+        let deleted = delete(&leaving.user, &[]).await;
+        assert_eq!(deleted.status, StatusCode::BAD_REQUEST);
+        assert!(user_exists(leaving.account.id));
+        assert!(Path::new(&owned.get_path()).exists());
+    }
+
+    #[actix_web::test]
+    async fn a_failed_deletion_leaves_the_account_as_it_was() {
+        let leaving = leaving_account("delete-failed").await;
+        let (owned, owned_key) = &leaving.owned;
+        let (joined, joined_key) = &leaving.joined;
+        let id = leaving.account.id;
+        let before: Vec<Vec<u8>> = [owned, joined]
+            .iter()
+            .map(|vault| fs::read(vault.get_key_path(id)).unwrap())
+            .collect();
+        // The transaction fails on its last statement, after every vault row was handled
+        CONNECTION
+            .lock()
+            .unwrap()
+            .execute_batch(&format!(
+                "CREATE TEMP TRIGGER keep_user_{id} BEFORE DELETE ON users WHEN old.id = {id}
+                 BEGIN SELECT RAISE(ABORT, 'kept by the test'); END;"
+            ))
+            .unwrap();
+
+        let deleted = delete(&leaving.user, &[(owned, &leaving.admin.email)]).await;
+        CONNECTION
+            .lock()
+            .unwrap()
+            .execute_batch(&format!("DROP TRIGGER keep_user_{id};"))
+            .unwrap();
+        assert_eq!(deleted.status, StatusCode::INTERNAL_SERVER_ERROR);
+
+        assert!(user_exists(id));
+        assert!(get_user_from_cookie(&leaving.user.request()).is_some());
+        assert_eq!(
+            owned.get_perms(owned_key).unwrap().get(&id),
+            Some(&Perms::Creator)
+        );
+        assert_eq!(
+            joined.get_perms(joined_key).unwrap().get(&id),
+            Some(&Perms::Admin)
+        );
+        let conn = CONNECTION.lock().unwrap();
+        assert_eq!(get_vault(&conn, owned).unwrap().unwrap().creator_id, id);
+        assert_eq!(get_user_vaults(&conn, id).unwrap().len(), 2);
+        drop(conn);
+        for (vault, key_file) in [owned, joined].into_iter().zip(before) {
+            assert_eq!(fs::read(vault.get_key_path(id)).unwrap(), key_file);
+            assert_no_staged_files(vault);
+        }
+        assert!(get_passwords_path(id).exists());
+    }
+
+    async fn sessions(user: &TestUser) -> TestResponse {
+        let req = user.request();
+        respond(list_sessions_query(req.clone()).await, &req).await
+    }
+
+    async fn revoke(user: &TestUser, public_id: &str) -> TestResponse {
+        let req = user.request();
+        let form = RevokeSessionForm {
+            id: public_id.to_string(),
+        };
+        respond(
+            revoke_session_query(req.clone(), web::Json(form)).await,
+            &req,
+        )
+        .await
+    }
+
+    #[actix_web::test]
+    async fn each_device_keeps_a_session_of_its_own() {
+        let account = create_account("sessions@example.com", PASSWORD);
+        let laptop = log_in(&account.email, PASSWORD, &client("192.0.2.20", "laptop"))
+            .await
+            .user();
+        let phone = log_in(&account.email, PASSWORD, &client("192.0.2.21", "phone"))
+            .await
+            .user();
+        assert_ne!(laptop.session_id, phone.session_id);
+        assert!(laptop.user_key == phone.user_key);
+
+        // Ending one session does not touch the key of the other
+        let req = laptop.request();
+        respond(logout_user_query(req.clone()).await, &req).await;
+        assert!(!phone.session().lock().unwrap().user_key.is_empty());
+        assert!(get_user_from_cookie(&phone.request()).is_some());
+    }
+
+    #[actix_web::test]
+    async fn sessions_are_listed_with_their_device() {
+        let account = create_account("sessions-list@example.com", PASSWORD);
+        let laptop = log_in(&account.email, PASSWORD, &client("192.0.2.22", "laptop"))
+            .await
+            .user();
+        let phone = log_in(&account.email, PASSWORD, &client("192.0.2.23", "phone"))
+            .await
+            .user();
+
+        let listed = sessions(&phone).await;
+        assert_eq!(listed.status, StatusCode::OK);
+        let listed = listed.body["sessions"].as_array().unwrap().clone();
+        assert_eq!(listed.len(), 2);
+        for (user, ip, user_agent) in [
+            (&laptop, "192.0.2.22", "laptop"),
+            (&phone, "192.0.2.23", "phone"),
+        ] {
+            let public_id = user.session().lock().unwrap().public_id.clone();
+            let session = listed
+                .iter()
+                .find(|session| session["id"] == public_id)
+                .unwrap();
+            assert_eq!(session["ip"], ip);
+            assert_eq!(session["user_agent"], user_agent);
+            assert_eq!(session["current"], user.session_id == phone.session_id);
+            assert!(session["created_at"].as_u64().unwrap() > 0);
+            assert!(session["last_activity"].as_u64() >= session["created_at"].as_u64());
+            // The list never gives away what the cookie holds
+            assert_ne!(session["id"], user.session_id.as_str());
+        }
+    }
+
+    #[actix_web::test]
+    async fn revoking_a_session_leaves_the_others_working() {
+        let account = create_account("sessions-revoke@example.com", PASSWORD);
+        let laptop = log_in(&account.email, PASSWORD, &client("192.0.2.24", "laptop"))
+            .await
+            .user();
+        let phone = log_in(&account.email, PASSWORD, &client("192.0.2.25", "phone"))
+            .await
+            .user();
+        let lost = phone.session();
+        let public_id = lost.lock().unwrap().public_id.clone();
+
+        let revoked = revoke(&laptop, &public_id).await;
+        assert_eq!(revoked.status, StatusCode::OK);
+        assert_eq!(revoked.body["current"], false);
+        assert!(get_user_from_cookie(&phone.request()).is_none());
+        assert!(lost.lock().unwrap().user_key.is_empty());
+        assert!(get_user_from_cookie(&laptop.request()).is_some());
+        assert_eq!(
+            sessions(&laptop).await.body["sessions"]
+                .as_array()
+                .unwrap()
+                .len(),
+            1
+        );
+
+        let again = revoke(&laptop, &public_id).await;
+        assert_eq!(again.status, StatusCode::NOT_FOUND);
+    }
+
+    #[actix_web::test]
+    async fn sessions_of_other_accounts_cannot_be_revoked() {
+        let account = create_account("sessions-owner@example.com", PASSWORD);
+        let other = create_account("sessions-intruder@example.com", PASSWORD);
+        let victim = log_in(&account.email, PASSWORD, &client("192.0.2.26", "laptop"))
+            .await
+            .user();
+        let intruder = log_in(&other.email, PASSWORD, &client("192.0.2.27", "laptop"))
+            .await
+            .user();
+        let public_id = victim.session().lock().unwrap().public_id.clone();
+
+        let revoked = revoke(&intruder, &public_id).await;
+        assert_eq!(revoked.status, StatusCode::NOT_FOUND);
+        assert!(get_user_from_cookie(&victim.request()).is_some());
+        assert!(!victim.session().lock().unwrap().user_key.is_empty());
+        let listed = sessions(&intruder).await;
+        assert_eq!(listed.body["sessions"].as_array().unwrap().len(), 1);
+    }
+}
@@ -0,0 +1,685 @@
+// Personal API tokens, for scripts and clients that cannot use the login form
+//
+// A token reads `vfy_<id>_<secret>`: the ID finds its row, only the SHA-256 of
+// the secret is stored. The user key cannot be derived without the password,
+// so a copy of it is sealed under a key derived from the secret when the token
+// is created; deleting the row deletes that copy with it. Tokens wrap the user
+// key of the moment, so anything replacing that key revokes them.
+//
+// A request with `Authorization: Bearer <token>` gets a session of its own in
+// `SESSION_CACHE`, every handler then works as with the cookie. The scope of
+// the token limits the routes it can reach, the account routes need a login.
+use crate::backend::aes_keys::container::{self, Kdf};
+use crate::backend::aes_keys::secret_key::SecretKey;
+use crate::backend::auth::base32::{base32_decode, base32_encode};
+use crate::backend::server_manager::account_manager::{
+    destroy_session, get_user_by_email, get_user_email, get_user_kdf, Session, SessionClient, JWT,
+};
+use crate::backend::server_manager::global_manager::{
+    get_user_from_cookie, CONNECTION, SESSION_CACHE,
+};
+use crate::backend::server_manager::token;
+use actix_web::http::{header, Method};
+use actix_web::{web, HttpRequest, HttpResponse, Responder};
+use bcrypt::verify;
+use ring::hmac;
+use ring::rand::{SecureRandom, SystemRandom};
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use std::sync::{Arc, Mutex};
+use uuid::Uuid;
+use zeroize::Zeroizing;
+
+// Prefix of every token, so leaked ones are easy to spot
+const TOKEN_PREFIX: &str = "vfy_";
+// Size of the secret part in bytes
+const SECRET_LEN: usize = 32;
+// Lifetime of a token when none is asked for, and the longest one allowed
+const DEFAULT_LIFETIME_DAYS: u64 = 90;
+const MAX_LIFETIME_DAYS: u64 = 365;
+// Longest label kept
+const MAX_LABEL_LEN: usize = 100;
+
+/**
+ * What a token may do, each scope includes the ones before it.
+ */
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "lowercase")]
+pub enum TokenScope {
+    /// List vaults, read file trees, download files
+    Read,
+    /// Also create vaults, upload, rename and remove files
+    Write,
+    /// Also share, unshare and delete vaults
+    Admin,
+}
+
+impl TokenScope {
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::Read => "read",
+            Self::Write => "write",
+            Self::Admin => "admin",
+        }
+    }
+
+    fn parse(scope: &str) -> Option<Self> {
+        match scope {
+            "read" => Some(Self::Read),
+            "write" => Some(Self::Write),
+            "admin" => Some(Self::Admin),
+            _ => None,
+        }
+    }
+}
+
+/**
+ * Returns the scope a token needs for a route.
+ *
+ * @param method - The HTTP method of the request.
+ * @param path - The path of the request.
+ * @return Option<TokenScope> - The scope, None for routes that need a login (the account settings).
+ */
+pub fn required_scope(method: &Method, path: &str) -> Option<TokenScope> {
+    let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+    match segments[0] {
+        "account" | "login" | "logout" | "logout-all" | "create-user" => return None,
+        _ => {}
+    }
+    if method == Method::GET {
+        return Some(TokenScope::Read);
+    }
+    Some(match (segments[0], segments.last().copied()) {
+        ("load-vault", _) => TokenScope::Read,
+        ("vaults", Some("tree" | "download")) => TokenScope::Read,
+        ("create-vault", _) => TokenScope::Write,
+        (
+            "vaults",
+            Some("create-folder" | "rename-item" | "remove-folder" | "remove-file" | "upload"),
+        ) => TokenScope::Write,
+        // Sharing, deleting, and whatever is added later
+        _ => TokenScope::Admin,
+    })
+}
+
+// Session holding the user key of a token while it is used
+fn token_session_id(token_id: &str) -> String {
+    format!("api-{}", token_id)
+}
+
+fn hash_secret(secret: &[u8]) -> String {
+    Sha256::digest(secret)
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+// Key sealing the copy of the user key, distinct from the stored hash
+fn wrapping_key(secret: &[u8]) -> Zeroizing<Vec<u8>> {
+    let key = hmac::Key::new(hmac::HMAC_SHA256, secret);
+    Zeroizing::new(
+        hmac::sign(&key, b"vaultify api token key")
+            .as_ref()
+            .to_vec(),
+    )
+}
+
+// Splits `vfy_<id>_<secret>`
+fn parse_token(token: &str) -> Option<(&str, Zeroizing<Vec<u8>>)> {
+    let (id, secret) = token.strip_prefix(TOKEN_PREFIX)?.split_once('_')?;
+    let secret = Zeroizing::new(base32_decode(secret).ok()?);
+    (secret.len() == SECRET_LEN).then_some((id, secret))
+}
+
+// Compares two byte strings without stopping at the first difference
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len()
+        && a.iter()
+            .zip(b.iter())
+            .fold(0u8, |acc, (x, y)| acc | (x ^ y))
+            == 0
+}
+
+/**
+ * Creates the table of the API tokens.
+ *
+ * @param conn - The database connection.
+ * @return A Result indicating success or failure.
+ */
+pub fn create_api_token_table(conn: &Connection) -> rusqlite::Result<()> {
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS api_tokens (
+            id TEXT PRIMARY KEY,
+            user_id INTEGER NOT NULL,
+            label TEXT NOT NULL,
+            scope TEXT NOT NULL,
+            secret_hash TEXT NOT NULL,
+            wrapped_key BLOB NOT NULL,
+            created_at INTEGER NOT NULL,
+            expires_at INTEGER NOT NULL,
+            last_used INTEGER,
+            FOREIGN KEY (user_id) REFERENCES users(id)
+        )",
+        [],
+    )?;
+    Ok(())
+}
+
+/**
+ * Deletes every API token of a user, e.g. when their user key changes.
+ *
+ * @param conn - The database connection.
+ * @param user_id - The ID of the user.
+ * @return A Result containing the IDs of the deleted tokens.
+ */
+pub fn delete_user_api_tokens(conn: &Connection, user_id: u32) -> rusqlite::Result<Vec<String>> {
+    let mut stmt = conn.prepare("SELECT id FROM api_tokens WHERE user_id = ?")?;
+    let ids = stmt
+        .query_map(params![user_id], |row| row.get(0))?
+        .collect::<rusqlite::Result<Vec<String>>>()?;
+    conn.execute("DELETE FROM api_tokens WHERE user_id = ?", params![user_id])?;
+    Ok(ids)
+}
+
+/**
+ * Ends the sessions opened by API tokens that were deleted.
+ *
+ * @param token_ids - The IDs of the tokens.
+ */
+pub fn end_api_token_sessions(token_ids: &[String]) {
+    for token_id in token_ids {
+        destroy_session(&token_session_id(token_id));
+    }
+}
+
+// Row of a token, as needed to authenticate a request
+struct TokenRow {
+    user_id: u32,
+    label: String,
+    scope: String,
+    secret_hash: String,
+    wrapped_key: Vec<u8>,
+    expires_at: u64,
+}
+
+/**
+ * Authenticates a request from its `Authorization: Bearer` API token.
+ *
+ * @param req - The HTTP request.
+ * @return Option<JWT> - The identity of the token owner, None if the token is invalid,
+ *                       expired, or its scope does not cover the route.
+ */
+pub fn authenticate_api_token(req: &HttpRequest) -> Option<JWT> {
+    let value = req.headers().get(header::AUTHORIZATION)?.to_str().ok()?;
+    let token = value.strip_prefix("Bearer ")?.trim();
+    let (token_id, secret) = parse_token(token)?;
+
+    let conn = CONNECTION.lock().unwrap();
+    let row = conn
+        .query_row(
+            "SELECT user_id, label, scope, secret_hash, wrapped_key, expires_at
+             FROM api_tokens WHERE id = ?",
+            params![token_id],
+            |row| {
+                Ok(TokenRow {
+                    user_id: row.get(0)?,
+                    label: row.get(1)?,
+                    scope: row.get(2)?,
+                    secret_hash: row.get(3)?,
+                    wrapped_key: row.get(4)?,
+                    expires_at: row.get::<_, i64>(5)? as u64,
+                })
+            },
+        )
+        .optional()
+        .inspect_err(|e| eprintln!("Failed to read API token {}: {}", token_id, e))
+        .ok()??;
+    if !constant_time_eq(hash_secret(&secret).as_bytes(), row.secret_hash.as_bytes()) {
+        eprintln!("Rejected API token {}: wrong secret", token_id);
+        return None;
+    }
+    let now = token::now();
+    if row.expires_at <= now {
+        eprintln!("Rejected API token {}: expired", token_id);
+        return None;
+    }
+    let scope = TokenScope::parse(&row.scope)?;
+    match required_scope(req.method(), req.path()) {
+        Some(required) if scope >= required => {}
+        _ => {
+            eprintln!(
+                "Rejected API token {}: {} {} is out of its scope",
+                token_id,
+                req.method(),
+                req.path()
+            );
+            return None;
+        }
+    }
+    let _ = conn.execute(
+        "UPDATE api_tokens SET last_used = ? WHERE id = ?",
+        params![now as i64, token_id],
+    );
+
+    let email = get_user_email(&conn, row.user_id).ok()??;
+    let session_id = token_session_id(token_id);
+    let live = SESSION_CACHE.get(&session_id).is_some_and(|session| {
+        session
+            .lock()
+            .is_ok_and(|session| session.user_id == row.user_id && !session.user_key.is_empty())
+    });
+    if !live {
+        let user_key = match container::open(&row.wrapped_key, &wrapping_key(&secret)) {
+            Ok(user_key) => SecretKey::new(user_key),
+            Err(e) => {
+                eprintln!("Key of API token {} failed to open: {}", token_id, e);
+                return None;
+            }
+        };
+        // Tokens are deleted whenever the user key changes, so the current parameters derived it
+        let user_kdf = get_user_kdf(&conn, row.user_id).ok()??.params.kdf();
+        let (_, hash_pw) = get_user_by_email(&conn, &email).ok()??;
+        let client = SessionClient {
+            ip: req.peer_addr().map(|addr| addr.ip().to_string()),
+            user_agent: Some(format!("API token: {}", row.label)),
+        };
+        SESSION_CACHE.insert(
+            session_id.clone(),
+            Arc::new(Mutex::new(Session::new(
+                row.user_id,
+                &hash_pw,
+                user_key,
+                user_kdf,
+                client,
+            ))),
+        );
+    }
+    Some(JWT::new(&session_id, row.user_id, &email))
+}
+
+/**
+ * Struct representing the form data for creating an API token.
+ */
+#[derive(Deserialize, Debug)]
+pub struct CreateApiTokenForm {
+    password: String,
+    label: String,
+    scope: TokenScope,
+    /// Days before the token expires, 90 if left out
+    expires_in_days: Option<u64>,
+}
+
+/**
+ * Struct representing the form data for revoking an API token.
+ */
+#[derive(Deserialize, Debug)]
+pub struct RevokeApiTokenForm {
+    id: String,
+}
+
+/**
+ * Endpoint creating an API token for the logged-in user.
+ *
+ * The token itself is only in this response, the server keeps its hash.
+ *
+ * @param req - The HTTP request carrying the session token.
+ * @param form - The password, to confirm, and the label, scope and lifetime of the token.
+ * @return An HTTP response with the token.
+ */
+pub async fn create_api_token_query(
+    req: HttpRequest,
+    form: web::Json<CreateApiTokenForm>,
+) -> impl Responder {
+    let jwt = match get_user_from_cookie(&req) {
+        Some(jwt) => jwt,
+        None => return HttpResponse::Unauthorized().body("Invalid session"),
+    };
+    let label = form.label.trim();
+    if label.is_empty() || label.chars().count() > MAX_LABEL_LEN {
+        return HttpResponse::BadRequest().body("The label must have 1 to 100 characters");
+    }
+    let days = form.expires_in_days.unwrap_or(DEFAULT_LIFETIME_DAYS);
+    if days == 0 || days > MAX_LIFETIME_DAYS {
+        return HttpResponse::BadRequest().body("Tokens last from 1 to 365 days");
+    }
+
+    let conn = CONNECTION.lock().unwrap();
+    match get_user_by_email(&conn, &jwt.email) {
+        Ok(Some((user_id, hash_pw))) if user_id == jwt.id => {
+            if !verify(&form.password, &hash_pw).unwrap_or(false) {
+                return HttpResponse::Unauthorized().body("Wrong password");
+            }
+        }
+        Ok(_) => return HttpResponse::Unauthorized().body("Invalid session"),
+        Err(e) => {
+            eprintln!("Failed to read user {}: {}", jwt.id, e);
+            return HttpResponse::InternalServerError().body("Failed to create the token");
+        }
+    }
+    let session = match SESSION_CACHE.get(&jwt.session_id) {
+        Some(session) => session,
+        None => return HttpResponse::Unauthorized().body("Invalid session"),
+    };
+
+    let mut secret = Zeroizing::new(vec![0u8; SECRET_LEN]);
+    SystemRandom::new()
+        .fill(&mut secret)
+        .expect("Failed to generate the API token");
+    let token_id = Uuid::new_v4().simple().to_string();
+    let wrapped_key = {
+        let session = session.lock().unwrap();
+        container::seal(
+            session.user_key.as_bytes(),
+            &wrapping_key(&secret),
+            Kdf::None,
+        )
+    };
+    let now = token::now();
+    let expires_at = now + days * 24 * 3600;
+
+    let inserted = conn.execute(
+        "INSERT INTO api_tokens (id, user_id, label, scope, secret_hash, wrapped_key, created_at, expires_at)
+         VALUES (?, ?, ?, ?, ?, ?, ?, ?)",
+        params![
+            token_id,
+            jwt.id,
+            label,
+            form.scope.as_str(),
+            hash_secret(&secret),
+            wrapped_key,
+            now as i64,
+            expires_at as i64
+        ],
+    );
+    if let Err(e) = inserted {
+        eprintln!("Failed to store an API token of user {}: {}", jwt.id, e);
+        return HttpResponse::InternalServerError().body("Failed to create the token");
+    }
+
+    let token = Zeroizing::new(format!(
+        "{}{}_{}",
+        TOKEN_PREFIX,
+        token_id,
+        base32_encode(&secret)
+    ));
+    HttpResponse::Ok().json(json!({
+        "success": true,
+        "id": token_id,
+        "token": token.as_str(),
+        "scope": form.scope,
+        "expires_at": expires_at
+    }))
+}
+
+/**
+ * Endpoint listing the API tokens of the logged-in user, without their secrets.
+ *
+ * @param req - The HTTP request carrying the session token.
+ * @return An HTTP response with the tokens.
+ */
+pub async fn list_api_tokens_query(req: HttpRequest) -> impl Responder {
+    let jwt = match get_user_from_cookie(&req) {
+        Some(jwt) => jwt,
+        None => return HttpResponse::Unauthorized().body("Invalid session"),
+    };
+
+    let conn = CONNECTION.lock().unwrap();
+    let tokens = conn
+        .prepare(
+            "SELECT id, label, scope, created_at, expires_at, last_used
+             FROM api_tokens WHERE user_id = ? ORDER BY created_at DESC",
+        )
+        .and_then(|mut stmt| {
+            stmt.query_map(params![jwt.id], |row| {
+                Ok(json!({
+                    "id": row.get::<_, String>(0)?,
+                    "label": row.get::<_, String>(1)?,
+                    "scope": row.get::<_, String>(2)?,
+                    "created_at": row.get::<_, i64>(3)?,
+                    "expires_at": row.get::<_, i64>(4)?,
+                    "last_used": row.get::<_, Option<i64>>(5)?,
+                }))
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()
+        });
+    match tokens {
+        Ok(tokens) => HttpResponse::Ok().json(json!({ "success": true, "tokens": tokens })),
+        Err(e) => {
+            eprintln!("Failed to list the API tokens of user {}: {}", jwt.id, e);
+            HttpResponse::InternalServerError().body("Failed to list the tokens")
+        }
+    }
+}
+
+/**
+ * Endpoint revoking an API token of the logged-in user. The copy of the user
+ * key it carried is deleted with it.
+ *
+ * @param req - The HTTP request carrying the session token.
+ * @param form - The ID of the token.
+ * @return An HTTP response indicating the result of the operation.
+ */
+pub async fn revoke_api_token_query(
+    req: HttpRequest,
+    form: web::Json<RevokeApiTokenForm>,
+) -> impl Responder {
+    let jwt = match get_user_from_cookie(&req) {
+        Some(jwt) => jwt,
+        None => return HttpResponse::Unauthorized().body("Invalid session"),
+    };
+
+    let deleted = {
+        let conn = CONNECTION.lock().unwrap();
+        conn.execute(
+            "DELETE FROM api_tokens WHERE id = ? AND user_id = ?",
+            params![form.id, jwt.id],
+        )
+    };
+    match deleted {
+        Ok(0) => HttpResponse::NotFound().body("Unknown token"),
+        Ok(_) => {
+            end_api_token_sessions(std::slice::from_ref(&form.id));
+            HttpResponse::Ok().json(json!({ "success": true }))
+        }
+        Err(e) => {
+            eprintln!("Failed to revoke an API token of user {}: {}", jwt.id, e);
+            HttpResponse::InternalServerError().body("Failed to revoke the token")
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::server_manager::account_manager::change_password_query;
+    use crate::backend::server_manager::test_support::{create_account, log_in, respond, TestUser};
+    use actix_web::http::StatusCode;
+    use actix_web::test::TestRequest;
+
+    const PASSWORD: &str = "token horse battery staple";
+
+    async fn create_token(user: &TestUser, scope: &str) -> (String, String) {
+        let req = user.request();
+        let form = serde_json::from_value(json!({
+            "password": PASSWORD,
+            "label": "backup script",
+            "scope": scope,
+        }))
+        .unwrap();
+        let created = respond(
+            create_api_token_query(req.clone(), web::Json(form)).await,
+            &req,
+        )
+        .await;
+        assert_eq!(created.status, StatusCode::OK);
+        (
+            created.body["id"].as_str().unwrap().to_string(),
+            created.body["token"].as_str().unwrap().to_string(),
+        )
+    }
+
+    fn bearer(token: &str, method: Method, path: &str) -> HttpRequest {
+        TestRequest::default()
+            .method(method)
+            .uri(path)
+            .insert_header((header::AUTHORIZATION, format!("Bearer {}", token)))
+            .to_http_request()
+    }
+
+    async fn logged_in(email: &str) -> TestUser {
+        let account = create_account(email, PASSWORD);
+        log_in(&account.email, PASSWORD, &SessionClient::default())
+            .await
+            .user()
+    }
+
+    #[test]
+    fn scopes_follow_the_routes() {
+        let post = Method::POST;
+        assert_eq!(
+            required_scope(&Method::GET, "/vaults"),
+            Some(TokenScope::Read)
+        );
+        assert_eq!(required_scope(&post, "/load-vault"), Some(TokenScope::Read));
+        assert_eq!(
+            required_scope(&post, "/vaults/1_17/download"),
+            Some(TokenScope::Read)
+        );
+        assert_eq!(
+            required_scope(&post, "/vaults/1_17/upload"),
+            Some(TokenScope::Write)
+        );
+        assert_eq!(
+            required_scope(&post, "/share-vault"),
+            Some(TokenScope::Admin)
+        );
+        assert_eq!(
+            required_scope(&post, "/something-new"),
+            Some(TokenScope::Admin)
+        );
+        assert_eq!(required_scope(&post, "/account/api-tokens"), None);
+        assert_eq!(required_scope(&Method::GET, "/account/sessions"), None);
+        assert!(TokenScope::Admin > TokenScope::Write && TokenScope::Write > TokenScope::Read);
+    }
+
+    #[test]
+    fn tokens_parse_back() {
+        let secret = [7u8; SECRET_LEN];
+        let token = format!("{}abc123_{}", TOKEN_PREFIX, base32_encode(&secret));
+        let (id, parsed) = parse_token(&token).unwrap();
+        assert_eq!(id, "abc123");
+        assert_eq!(&parsed[..], &secret);
+        assert!(parse_token(&token[..token.len() - 4]).is_none());
+        assert!(parse_token("abc123_AAAA").is_none());
+    }
+
+    #[actix_web::test]
+    async fn a_token_opens_a_session_with_the_user_key() {
+        let user = logged_in("api-token@example.com").await;
+        let (token_id, token) = create_token(&user, "read").await;
+
+        let jwt = get_user_from_cookie(&bearer(&token, Method::GET, "/vaults")).unwrap();
+        assert_eq!(jwt.id, user.id);
+        assert_eq!(jwt.session_id, token_session_id(&token_id));
+        let session = SESSION_CACHE.get(&jwt.session_id).unwrap();
+        let session = session.lock().unwrap();
+        assert!(session.user_key == user.user_key);
+        assert_eq!(
+            session.client.user_agent.as_deref(),
+            Some("API token: backup script")
+        );
+        drop(session);
+
+        // The secret is checked, not just the ID
+        let forged = format!(
+            "{}{}_{}",
+            TOKEN_PREFIX,
+            token_id,
+            base32_encode(&[1u8; SECRET_LEN])
+        );
+        assert!(get_user_from_cookie(&bearer(&forged, Method::GET, "/vaults")).is_none());
+    }
+
+    #[actix_web::test]
+    async fn revoked_and_expired_tokens_are_refused() {
+        let user = logged_in("api-token-revoked@example.com").await;
+        let (revoked_id, revoked) = create_token(&user, "read").await;
+        let (expired_id, expired) = create_token(&user, "read").await;
+        assert!(get_user_from_cookie(&bearer(&revoked, Method::GET, "/vaults")).is_some());
+
+        let req = user.request();
+        let form = RevokeApiTokenForm {
+            id: revoked_id.clone(),
+        };
+        let response = respond(
+            revoke_api_token_query(req.clone(), web::Json(form)).await,
+            &req,
+        )
+        .await;
+        assert_eq!(response.status, StatusCode::OK);
+        assert!(get_user_from_cookie(&bearer(&revoked, Method::GET, "/vaults")).is_none());
+        assert!(SESSION_CACHE.get(&token_session_id(&revoked_id)).is_none());
+
+        CONNECTION
+            .lock()
+            .unwrap()
+            .execute(
+                "UPDATE api_tokens SET expires_at = ? WHERE id = ?",
+                params![token::now() as i64, expired_id],
+            )
+            .unwrap();
+        assert!(get_user_from_cookie(&bearer(&expired, Method::GET, "/vaults")).is_none());
+    }
+
+    #[actix_web::test]
+    async fn tokens_only_reach_the_routes_of_their_scope() {
+        let user = logged_in("api-token-scope@example.com").await;
+        let (_, read) = create_token(&user, "read").await;
+        let (_, write) = create_token(&user, "write").await;
+        let upload = "/vaults/1_17/upload";
+
+        assert!(get_user_from_cookie(&bearer(&read, Method::POST, "/load-vault")).is_some());
+        assert!(get_user_from_cookie(&bearer(&read, Method::POST, upload)).is_none());
+        assert!(get_user_from_cookie(&bearer(&write, Method::POST, upload)).is_some());
+        assert!(get_user_from_cookie(&bearer(&write, Method::POST, "/share-vault")).is_none());
+        // Account settings need a login, whatever the scope
+        let account_route = bearer(&write, Method::GET, "/account/api-tokens");
+        assert!(get_user_from_cookie(&account_route).is_none());
+    }
+
+    #[actix_web::test]
+    async fn changing_the_password_revokes_the_tokens() {
+        let user = logged_in("api-token-password@example.com").await;
+        let (token_id, token) = create_token(&user, "read").await;
+        assert!(get_user_from_cookie(&bearer(&token, Method::GET, "/vaults")).is_some());
+
+        let req = user.request();
+        let form = serde_json::from_value(json!({
+            "old_password": PASSWORD,
+            "new_password": "new token horse battery staple",
+        }))
+        .unwrap();
+        let changed = respond(
+            change_password_query(req.clone(), web::Json(form)).await,
+            &req,
+        )
+        .await;
+        assert_eq!(changed.status, StatusCode::OK);
+
+        // The token carried the old user key
+        assert!(get_user_from_cookie(&bearer(&token, Method::GET, "/vaults")).is_none());
+        assert!(SESSION_CACHE.get(&token_session_id(&token_id)).is_none());
+        let left: u32 = CONNECTION
+            .lock()
+            .unwrap()
+            .query_row(
+                "SELECT COUNT(*) FROM api_tokens WHERE user_id = ?",
+                params![user.id],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(left, 0);
+    }
+}
@@ -0,0 +1,220 @@
+use crate::backend::aes_keys::keys_password::Argon2Params;
+use crate::backend::server_manager::global_manager::ROOT;
+use crate::backend::VAULTIFY_SERVER_CONFIG;
+use serde::{Deserialize, Serialize};
+use std::fs;
+
+/// Server settings read from `.vaultify/config.json`, missing fields keep their defaults.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+#[serde(default)]
+pub struct ServerConfig {
+    /// Argon2id parameters given to new user keys.
+    pub kdf: Argon2Params,
+    /// Lifetime of the signed session tokens.
+    pub session: SessionConfig,
+    /// How verification codes and notifications are sent.
+    pub mail: MailConfig,
+    /// Limits on failed logins.
+    pub login: LoginConfig,
+}
+
+/// Session token settings.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct SessionConfig {
+    /// Seconds before a token expires, the cookie is kept as long.
+    pub token_lifetime_secs: u64,
+}
+
+impl Default for SessionConfig {
+    fn default() -> Self {
+        Self {
+            token_lifetime_secs: 7 * 24 * 3600,
+        }
+    }
+}
+
+/// Failed login limits, counted per account and per client IP address.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct LoginConfig {
+    pub account: LockoutPolicy,
+    pub ip: LockoutPolicy,
+    /// Seconds without a failure after which a counter starts over.
+    pub forget_after_secs: u64,
+}
+
+impl Default for LoginConfig {
+    fn default() -> Self {
+        Self {
+            account: LockoutPolicy {
+                free_attempts: 3,
+                max_backoff_secs: 300,
+                lockout_after: 10,
+                lockout_secs: 900,
+            },
+            ip: LockoutPolicy {
+                free_attempts: 10,
+                max_backoff_secs: 300,
+                lockout_after: 50,
+                lockout_secs: 3600,
+            },
+            forget_after_secs: 24 * 3600,
+        }
+    }
+}
+
+/// How failed logins slow down the next attempts.
+///
+/// The first `free_attempts` failures cost nothing, each one after that
+/// doubles the wait before the next attempt (1 s, 2 s, 4 s, ...) up to
+/// `max_backoff_secs`, and `lockout_after` failures lock logins out for
+/// `lockout_secs`.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct LockoutPolicy {
+    pub free_attempts: u32,
+    pub max_backoff_secs: u64,
+    pub lockout_after: u32,
+    pub lockout_secs: u64,
+}
+
+impl LockoutPolicy {
+    /// Returns how long logins wait after the given number of consecutive failures.
+    pub fn delay(&self, failures: u32) -> u64 {
+        if failures >= self.lockout_after {
+            self.lockout_secs
+        } else if failures > self.free_attempts {
+            let doublings = (failures - self.free_attempts - 1).min(63);
+            1u64.checked_shl(doublings)
+                .unwrap_or(u64::MAX)
+                .min(self.max_backoff_secs)
+        } else {
+            0
+        }
+    }
+}
+
+/// Outgoing mail settings.
+///
+/// The SMTP credentials can be left out of the file and given through the
+/// `VAULTIFY_SMTP_USERNAME` and `VAULTIFY_SMTP_PASSWORD` environment variables,
+/// which take precedence.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct MailConfig {
+    /// Where mails go: an SMTP server, or a local maildir for offline machines.
+    pub transport: MailTransport,
+    /// Sender address, e.g. `Vaultify <no-reply@example.com>`.
+    pub from: String,
+    pub smtp: SmtpConfig,
+    /// Maildir receiving the mails of the `maildir` transport, relative to the home directory.
+    pub maildir: String,
+}
+
+impl Default for MailConfig {
+    fn default() -> Self {
+        Self {
+            transport: MailTransport::Maildir,
+            from: "Vaultify <no-reply@localhost>".to_string(),
+            smtp: SmtpConfig::default(),
+            maildir: ".vaultify/mail/".to_string(),
+        }
+    }
+}
+
+/// Transport used to deliver mails.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum MailTransport {
+    Smtp,
+    Maildir,
+}
+
+/// Connection security of the SMTP server.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum TlsMode {
+    /// Implicit TLS, usually port 465
+    Tls,
+    /// Plain connection upgraded with STARTTLS, usually port 587
+    StartTls,
+    /// No encryption, only for a relay on the same machine
+    None,
+}
+
+/// SMTP server settings.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct SmtpConfig {
+    pub host: String,
+    pub port: u16,
+    pub tls: TlsMode,
+    pub username: Option<String>,
+    pub password: Option<String>,
+}
+
+impl Default for SmtpConfig {
+    fn default() -> Self {
+        Self {
+            host: "localhost".to_string(),
+            port: 587,
+            tls: TlsMode::StartTls,
+            username: None,
+            password: None,
+        }
+    }
+}
+
+impl SmtpConfig {
+    /// Returns the username and password, the environment overriding the file.
+    pub fn credentials(&self) -> Option<(String, String)> {
+        let username = std::env::var("VAULTIFY_SMTP_USERNAME")
+            .ok()
+            .or_else(|| self.username.clone())?;
+        let password = std::env::var("VAULTIFY_SMTP_PASSWORD")
+            .ok()
+            .or_else(|| self.password.clone())?;
+        Some((username, password))
+    }
+}
+
+impl ServerConfig {
+    /// Loads the configuration file, falling back to the defaults if it is missing or invalid.
+    pub fn load() -> Self {
+        let path = ROOT.join(VAULTIFY_SERVER_CONFIG);
+        let mut config = match fs::read_to_string(&path) {
+            Ok(content) => match serde_json::from_str::<ServerConfig>(&content) {
+                Ok(config) => config,
+                Err(e) => {
+                    eprintln!("Invalid server configuration {:?}: {}", path, e);
+                    ServerConfig::default()
+                }
+            },
+            Err(_) => ServerConfig::default(),
+        };
+
+        if let Err(e) = config.kdf.validate() {
+            eprintln!("{}, using the default KDF parameters", e);
+            config.kdf = Argon2Params::default();
+        }
+        if config.session.token_lifetime_secs == 0 {
+            eprintln!("Session tokens cannot have a zero lifetime, using the default");
+            config.session = SessionConfig::default();
+        }
+        config
+    }
+
+    /// Writes the configuration file if it does not exist yet, so it can be edited.
+    pub fn write_default_if_missing(&self) {
+        let path = ROOT.join(VAULTIFY_SERVER_CONFIG);
+        if path.exists() {
+            return;
+        }
+        match serde_json::to_string_pretty(self) {
+            Ok(content) => fs::write(&path, content).unwrap_or_else(|why| {
+                eprintln!("Error writing the server configuration: {:?}", why);
+            }),
+            Err(e) => eprintln!("Error serializing the server configuration: {}", e),
+        }
+    }
+}
@@ -1,21 +1,26 @@
-use crate::backend::aes_keys::crypted_key::encrypt;
-use crate::backend::aes_keys::decrypted_key::decrypt;
+use crate::backend::aes_keys::chunked::{ChunkedReader, ChunkedWriter, DEFAULT_CHUNK_SIZE};
+use crate::backend::aes_keys::cipher::gcm::{NONCE_LEN, TAG_LEN};
+use crate::backend::aes_keys::container::{self, Cipher, Header, HEADER_LEN};
 use crate::backend::server_manager::account_manager::Perms;
 use crate::backend::server_manager::file_manager::file_tree::FileType;
 use crate::backend::server_manager::file_manager::file_tree::*;
 use crate::backend::server_manager::global_manager::{get_user_from_cookie, VAULTS_CACHE};
-use crate::backend::server_manager::vault_manager::{load_vault, VaultInfo, VaultsCache};
+use crate::backend::server_manager::vault_manager::{load_vault, VaultInfo};
 use actix_multipart::Multipart;
+use actix_web::http::header;
 use actix_web::web::Bytes;
 use actix_web::{web, HttpRequest, HttpResponse, Responder};
-use futures_util::{stream, StreamExt};
+use futures_util::{stream, Stream, StreamExt};
 use serde::Deserialize;
 use sha2::{Digest, Sha256};
 use std::fs;
-use std::fs::File;
-use std::io::{BufReader, Read};
+use std::io::{Read, Seek, SeekFrom};
+use std::path::Path;
+use uuid::Uuid;
 
-const BUFFER_SIZE: usize = 4 * 1024 * 1024;
+// Size of the plaintext sealed in each piece of a file uploaded before the
+// chunked format, only needed to read those files back
+const LEGACY_BUFFER_SIZE: usize = 4 * 1024 * 1024;
 
 pub async fn get_file_tree_query(req: HttpRequest, path: web::Path<String>) -> impl Responder {
     let _jwt = match get_user_from_cookie(&req) {
@@ -36,14 +41,6 @@ pub async fn get_file_tree_query(req: HttpRequest, path: web::Path<String>) -> i
 }
 
 /// Payload for creating a new folder
-#[derive(Deserialize)]
-pub struct CreateFolderPayload {
-    /// Full path where the new folder should be created (e.g. "Documents/Projects")
-    path: String,
-    /// Name of the new folder to create
-    name: String,
-}
-
 #[derive(Deserialize)]
 pub struct CreateFolderRequest {
     pub vault_info: VaultInfo,
@@ -92,26 +89,13 @@ pub async fn create_folder_query(
 
     target_dir.add_dir(&data.name);
 
-    match vault_info.save_file_tree(
-        vault_cache.vault_key.as_slice(),
-        vault_cache.vault_file_tree.clone(),
-    ) {
+    match vault_info.save_file_tree(&vault_cache.vault_key, vault_cache.vault_file_tree.clone()) {
         Ok(_) => HttpResponse::Ok().finish(),
         Err(_) => HttpResponse::InternalServerError().finish(),
     }
 }
 
 /// Payload for renaming a file or directory
-#[derive(Deserialize)]
-pub struct RenamePayload {
-    /// Path to the parent directory of the item
-    path: String,
-    /// Current name of the item to rename
-    old_name: String,
-    /// New name for the item
-    new_name: String,
-}
-
 #[derive(Deserialize)]
 pub struct RenameRequest {
     pub vault_info: VaultInfo,
@@ -172,10 +156,7 @@ pub async fn rename_item_query(
     }
 
     // Sauvegarde l'arborescence modifiée
-    match vault_info.save_file_tree(
-        vault_cache.vault_key.as_slice(),
-        vault_cache.vault_file_tree.clone(),
-    ) {
+    match vault_info.save_file_tree(&vault_cache.vault_key, vault_cache.vault_file_tree.clone()) {
         Ok(_) => HttpResponse::Ok().json(vault_cache.vault_file_tree.to_public()),
         Err(_) => HttpResponse::InternalServerError().finish(),
     }
@@ -234,10 +215,7 @@ pub async fn remove_folder_query(
         Err(e) => return HttpResponse::InternalServerError().body(e),
     }
 
-    match vault_info.save_file_tree(
-        vault_cache.vault_key.as_slice(),
-        vault_cache.vault_file_tree.clone(),
-    ) {
+    match vault_info.save_file_tree(&vault_cache.vault_key, vault_cache.vault_file_tree.clone()) {
         Ok(_) => HttpResponse::Ok().json(vault_cache.vault_file_tree.to_public()),
         Err(_) => HttpResponse::InternalServerError().finish(),
     }
@@ -252,7 +230,6 @@ pub struct RemoveFileRequest {
 }
 
 /// Handler to remove a file
-
 pub async fn remove_file_query(
     req: HttpRequest,
     payload: web::Json<RemoveFileRequest>,
@@ -297,10 +274,7 @@ pub async fn remove_file_query(
         Err(e) => return HttpResponse::InternalServerError().body(e),
     }
 
-    match vault_info.save_file_tree(
-        vault_cache.vault_key.as_slice(),
-        vault_cache.vault_file_tree.clone(),
-    ) {
+    match vault_info.save_file_tree(&vault_cache.vault_key, vault_cache.vault_file_tree.clone()) {
         Ok(_) => HttpResponse::Ok().json(vault_cache.vault_file_tree.to_public()),
         Err(_) => HttpResponse::InternalServerError().finish(),
     }
@@ -310,16 +284,10 @@ pub async fn remove_file_query(
 pub async fn upload_file_query(req: HttpRequest, mut payload: Multipart) -> impl Responder {
     use futures_util::TryStreamExt;
     use serde_json;
-    use std::io::Write;
 
     let mut vault_info_opt: Option<VaultInfo> = None;
     let mut upload_path = String::new();
     let mut upload_file_name = String::new(); // Nom d’origine
-    let mut secure_file_name = String::new(); // Nom sécurisé
-
-    let mut file: Option<fs::File> = None;
-    let mut vault_key: Vec<u8> = Vec::new();
-    let mut buffer: Vec<u8> = Vec::with_capacity(BUFFER_SIZE);
 
     while let Ok(Some(mut field)) = payload.try_next().await {
         let content_disposition = field.content_disposition();
@@ -364,30 +332,23 @@ pub async fn upload_file_query(req: HttpRequest, mut payload: Multipart) -> impl
                 None => return HttpResponse::Unauthorized().body("Unauthorized"),
             };
 
-            {
+            // The lease keeps a key rotation from starting until the file is written
+            let (vault_key, _key_lease) = {
                 let vault_cache_locked = vault_cache.lock().unwrap();
-                if vault_cache_locked.vault_key.is_empty() {
-                    return HttpResponse::Unauthorized().body("Unauthorized");
-                }
                 if !vault_cache_locked.perms.contains_key(&jwt.id)
                     || vault_cache_locked.perms.get(&jwt.id).unwrap() < &Perms::Write
                 {
                     return HttpResponse::Unauthorized().body("Unauthorized");
                 }
-                vault_key = vault_cache_locked.vault_key.clone();
-            }
+                match vault_cache_locked.lease_key() {
+                    Some(lease) => lease,
+                    None => return HttpResponse::Unauthorized().body("Unauthorized"),
+                }
+            };
 
             // NOM SÉCURISÉ
-            secure_file_name = generate_secure_filename(&upload_file_name, &jwt.id.to_string());
-
-            let mut full_path = vault_info.get_path();
-            if !upload_path.is_empty() {
-                full_path = format!(
-                    "{}/{}",
-                    full_path.trim_end_matches('/'),
-                    upload_path.trim_start_matches('/')
-                );
-            }
+            let secure_file_name = generate_secure_filename(&upload_file_name, &jwt.id.to_string());
+
             let file_path = format!("{}{}", vault_info.get_path(), secure_file_name);
 
             if let Some(parent) = std::path::Path::new(&file_path).parent() {
@@ -397,32 +358,44 @@ pub async fn upload_file_query(req: HttpRequest, mut payload: Multipart) -> impl
                 }
             }
 
-            file = match fs::File::create(&file_path) {
-                Ok(f) => Some(f),
+            // The upload is written next to the target and only replaces it once
+            // complete, a failed re-upload leaves the stored file as it was
+            let staged_path = format!("{}.{}.upload", file_path, Uuid::new_v4());
+            let file = match fs::File::create(&staged_path) {
+                Ok(f) => f,
                 Err(_) => return HttpResponse::InternalServerError().body("Internal server error"),
             };
 
-            while let Some(chunk) = field.next().await {
-                let chunk = chunk.unwrap();
-                buffer.extend_from_slice(&chunk);
-
-                if buffer.len() >= BUFFER_SIZE {
-                    let encrypted = encrypt(&buffer, &vault_key);
-                    if let Err(_) = file.as_mut().unwrap().write_all(&encrypted) {
-                        let _ = fs::remove_file(&file_path);
+            // The upload is encrypted segment by segment as it arrives
+            let mut writer =
+                match ChunkedWriter::new(file, vault_key.as_bytes(), DEFAULT_CHUNK_SIZE) {
+                    Ok(writer) => writer,
+                    Err(_) => {
+                        let _ = fs::remove_file(&staged_path);
                         return HttpResponse::InternalServerError().body("Write failed");
                     }
-                    buffer.clear();
-                }
-            }
-
-            if !buffer.is_empty() {
-                let encrypted = encrypt(&buffer, &vault_key);
-                if let Err(_) = file.as_mut().unwrap().write_all(&encrypted) {
-                    let _ = fs::remove_file(&file_path);
+                };
+            while let Some(chunk) = field.next().await {
+                let chunk = match chunk {
+                    Ok(chunk) => chunk,
+                    Err(_) => {
+                        let _ = fs::remove_file(&staged_path);
+                        return HttpResponse::BadRequest().body("Upload interrupted");
+                    }
+                };
+                if writer.write(&chunk).is_err() {
+                    let _ = fs::remove_file(&staged_path);
                     return HttpResponse::InternalServerError().body("Write failed");
                 }
             }
+            if writer
+                .finish()
+                .and_then(|_| fs::rename(&staged_path, &file_path))
+                .is_err()
+            {
+                let _ = fs::remove_file(&staged_path);
+                return HttpResponse::InternalServerError().body("Write failed");
+            }
 
             // File tree update (on garde le nom *original* ici)
             {
@@ -444,10 +417,11 @@ pub async fn upload_file_query(req: HttpRequest, mut payload: Multipart) -> impl
                     secure_file_name.clone(),
                     "File".to_string(),
                 );
-                if let Err(_) = vault_info.save_file_tree(
-                    vault_cache.vault_key.as_slice(),
-                    vault_cache.vault_file_tree.clone(),
-                ) {
+                // A rotation started meanwhile re-encrypts the tree once the lease ends
+                if vault_info
+                    .save_file_tree(&vault_key, vault_cache.vault_file_tree.clone())
+                    .is_err()
+                {
                     return HttpResponse::InternalServerError().body("Failed to save file tree");
                 }
             }
@@ -473,6 +447,9 @@ pub struct DownloadFileQuery {
 }
 
 /// Endpoint for downloading an encrypted file
+///
+/// Chunked files are decrypted segment by segment while streaming, and a
+/// `Range: bytes=start-end` header only decrypts the segments it covers.
 pub async fn download_file_query(
     req: HttpRequest,
     json: web::Json<DownloadFileQuery>,
@@ -482,11 +459,17 @@ pub async fn download_file_query(
     let path = json.path.clone();
 
     // Authenticate the user
-    let jwt = match get_user_from_cookie(&req) {
+    let _jwt = match get_user_from_cookie(&req) {
         Some(jwt) => jwt,
         None => return HttpResponse::Unauthorized().finish(),
     };
 
+    let range = req
+        .headers()
+        .get(header::RANGE)
+        .and_then(|value| value.to_str().ok())
+        .map(|value| value.to_string());
+
     // Authenticate the user
     if load_vault(req, web::Json(vault_info.clone()))
         .await
@@ -502,8 +485,12 @@ pub async fn download_file_query(
     };
 
     // Get file node from enum variant
-    let (binary_file_name, original_file_name, vault_key) = {
+    let (binary_file_name, original_file_name, (vault_key, _key_lease)) = {
         let vault_cache = cache.lock().unwrap();
+        let lease = match vault_cache.lease_key() {
+            Some(lease) => lease,
+            None => return HttpResponse::NotFound().finish(),
+        };
 
         let dir = match vault_cache.vault_file_tree.get_directory_from_path(&path) {
             Ok(d) => d,
@@ -518,45 +505,394 @@ pub async fn download_file_query(
         (
             file_node.binary_file_name.clone(),
             file_node.file_name.clone(),
-            vault_cache.vault_key.clone(),
+            lease,
         )
     };
 
-    // Construct the full disk path
-    let full_path = format!(
-        "{}/{}/{}",
-        vault_info.get_path(),
-        path.trim_matches('/'),
-        binary_file_name
-    );
+    // Binary files are stored at the root of the vault, like upload writes them
+    let full_path = format!("{}{}", vault_info.get_path(), binary_file_name);
 
     let mut file = match std::fs::File::open(&full_path) {
         Ok(f) => f,
         Err(_) => return HttpResponse::NotFound().body("Failed to open file"),
     };
 
+    let mut header_bytes = [0u8; HEADER_LEN];
+    let is_chunked = file.read_exact(&mut header_bytes).is_ok()
+        && matches!(
+            Header::parse(&header_bytes),
+            Ok(Some(Header {
+                cipher: Cipher::Aes256GcmChunked,
+                ..
+            }))
+        );
+
+    if !is_chunked {
+        if file.seek(SeekFrom::Start(0)).is_err() {
+            return HttpResponse::InternalServerError().body("Failed to read file");
+        }
+        // The lease is held until the file is written back as a chunked
+        // container, a key rotation waits for it
+        return download_legacy_file(
+            file,
+            vault_key.as_bytes(),
+            &full_path,
+            &binary_file_name,
+            &original_file_name,
+        );
+    }
+
+    // The lease ends with this handler, the response streams from the open
+    // file, which a key rotation swapping it afterwards does not affect
+    let reader = match ChunkedReader::open(file, vault_key.as_bytes()) {
+        Ok(reader) => reader,
+        Err(e) => {
+            eprintln!("File {} failed to open: {}", binary_file_name, e);
+            return HttpResponse::InternalServerError().body("Failed to decrypt");
+        }
+    };
+    let total = reader.len();
+
+    let (start, end) = match &range {
+        None => (0, total),
+        Some(range) => match parse_range(range, total) {
+            Some(bounds) => bounds,
+            None => {
+                return HttpResponse::RangeNotSatisfiable()
+                    .insert_header((header::CONTENT_RANGE, format!("bytes */{}", total)))
+                    .finish()
+            }
+        },
+    };
+
+    let mut response = if range.is_some() {
+        let mut response = HttpResponse::PartialContent();
+        response.insert_header((
+            header::CONTENT_RANGE,
+            format!("bytes {}-{}/{}", start, end - 1, total),
+        ));
+        response
+    } else {
+        HttpResponse::Ok()
+    };
+
+    response
+        .content_type(get_mime_type_or_bin(&original_file_name))
+        .insert_header(content_disposition(&original_file_name))
+        .insert_header((header::ACCEPT_RANGES, "bytes"))
+        .no_chunking(end - start)
+        .streaming(stream_segments(reader, binary_file_name, start, end))
+}
+
+// Decrypts a file written before the chunked format, entirely in memory, and
+// writes it back in the chunked format
+fn download_legacy_file(
+    mut file: fs::File,
+    vault_key: &[u8],
+    full_path: &str,
+    binary_file_name: &str,
+    original_file_name: &str,
+) -> HttpResponse {
     let mut encrypted_contents = Vec::new();
-    if let Err(_) = file.read_to_end(&mut encrypted_contents) {
+    if file.read_to_end(&mut encrypted_contents).is_err() {
         return HttpResponse::InternalServerError().body("Failed to read file");
     }
 
-    let decrypted = match decrypt(encrypted_contents.as_slice(), vault_key.as_slice()) {
-        Ok(data) => data,
-        Err(_) => return HttpResponse::InternalServerError().body("Failed to decrypt"),
+    let decrypted = match decrypt_legacy_file(&encrypted_contents, vault_key) {
+        Ok(decrypted) => decrypted,
+        Err(e) => {
+            eprintln!("File {} failed to decrypt: {}", binary_file_name, e);
+            return HttpResponse::InternalServerError().body("Failed to decrypt");
+        }
     };
+    if let Err(e) = upgrade_legacy_file(full_path, &decrypted, vault_key) {
+        eprintln!("Failed to upgrade file {}: {}", binary_file_name, e);
+    }
 
     HttpResponse::Ok()
-        .content_type(get_mime_type_or_bin(&original_file_name))
-        .insert_header((
-            "Content-Disposition",
-            format!("attachment; filename=\"{}\"", original_file_name),
-        ))
+        .content_type(get_mime_type_or_bin(original_file_name))
+        .insert_header(content_disposition(original_file_name))
         .body(decrypted)
 }
 
+// Replaces a legacy file with its chunked version, staged under a unique name
+// as another download may upgrade it at the same time
+fn upgrade_legacy_file(full_path: &str, plaintext: &[u8], vault_key: &[u8]) -> Result<(), String> {
+    let staged = format!("{}.{}.upgrade", full_path, Uuid::new_v4());
+    let result = fs::File::create(&staged)
+        .and_then(|output| {
+            let mut writer = ChunkedWriter::new(output, vault_key, DEFAULT_CHUNK_SIZE)?;
+            writer.write(plaintext)?;
+            writer.finish()?;
+            Ok(())
+        })
+        .and_then(|()| fs::rename(&staged, full_path));
+    if result.is_err() {
+        let _ = fs::remove_file(&staged);
+    }
+    result.map_err(|e| e.to_string())
+}
+
+/**
+ * Decrypts a file written before the chunked format.
+ *
+ * Each sealed piece carries its own header and tag, older files are one blob.
+ *
+ * @param encrypted_contents - The whole encrypted file.
+ * @param vault_key - The key of the vault.
+ * @return Result<Vec<u8>, String> - The plaintext or an error message.
+ */
+pub fn decrypt_legacy_file(encrypted_contents: &[u8], vault_key: &[u8]) -> Result<Vec<u8>, String> {
+    let piece_size = match Header::parse(encrypted_contents)? {
+        Some(header) if header.chunk_size > 0 => HEADER_LEN + header.chunk_size as usize + TAG_LEN,
+        Some(_) => encrypted_contents.len().max(1),
+        None if encrypted_contents.len() > LEGACY_BUFFER_SIZE + NONCE_LEN + TAG_LEN => {
+            NONCE_LEN + LEGACY_BUFFER_SIZE + TAG_LEN
+        }
+        None => encrypted_contents.len().max(1),
+    };
+    let mut decrypted = Vec::with_capacity(encrypted_contents.len());
+    for piece in encrypted_contents.chunks(piece_size) {
+        decrypted.extend_from_slice(&container::open(piece, vault_key)?);
+    }
+    Ok(decrypted)
+}
+
+/**
+ * Re-encrypts a stored file under another key, always in the chunked format.
+ *
+ * Chunked files are processed one segment at a time, legacy files in memory.
+ *
+ * @param source - The encrypted file.
+ * @param destination - Where the re-encrypted file is written.
+ * @param old_key - The key the file is encrypted with.
+ * @param new_key - The key to encrypt it with.
+ * @return Result<(), String> - An error message if the file could not be re-encrypted.
+ */
+pub fn reencrypt_file(
+    source: &Path,
+    destination: &Path,
+    old_key: &[u8],
+    new_key: &[u8],
+) -> Result<(), String> {
+    let mut file = fs::File::open(source).map_err(|e| e.to_string())?;
+    let output = fs::File::create(destination).map_err(|e| e.to_string())?;
+    let mut writer =
+        ChunkedWriter::new(output, new_key, DEFAULT_CHUNK_SIZE).map_err(|e| e.to_string())?;
+
+    let mut header_bytes = [0u8; HEADER_LEN];
+    let is_chunked = file.read_exact(&mut header_bytes).is_ok()
+        && matches!(
+            Header::parse(&header_bytes),
+            Ok(Some(Header {
+                cipher: Cipher::Aes256GcmChunked,
+                ..
+            }))
+        );
+
+    if is_chunked {
+        let mut reader = ChunkedReader::open(file, old_key)?;
+        for index in 0..reader.segment_count() {
+            let segment = reader.read_segment(index)?;
+            writer.write(&segment).map_err(|e| e.to_string())?;
+        }
+    } else {
+        let mut encrypted_contents = Vec::new();
+        file.seek(SeekFrom::Start(0)).map_err(|e| e.to_string())?;
+        file.read_to_end(&mut encrypted_contents)
+            .map_err(|e| e.to_string())?;
+        writer
+            .write(&decrypt_legacy_file(&encrypted_contents, old_key)?)
+            .map_err(|e| e.to_string())?;
+    }
+    writer.finish().map_err(|e| e.to_string())?;
+    Ok(())
+}
+
+// Streams the decrypted bytes in [start, end), one segment at a time
+fn stream_segments(
+    reader: ChunkedReader<fs::File>,
+    binary_file_name: String,
+    start: u64,
+    end: u64,
+) -> impl Stream<Item = Result<Bytes, actix_web::Error>> {
+    let first = start / reader.chunk_size();
+    stream::unfold(Some((reader, first)), move |state| {
+        let binary_file_name = binary_file_name.clone();
+        async move {
+            let (mut reader, index) = state?;
+            let segment_start = index * reader.chunk_size();
+            if segment_start >= end {
+                return None;
+            }
+            match reader.read_segment(index) {
+                Ok(segment) => {
+                    let from = start.saturating_sub(segment_start) as usize;
+                    let to = (end - segment_start).min(segment.len() as u64) as usize;
+                    let bytes = Bytes::copy_from_slice(&segment[from..to]);
+                    Some((Ok(bytes), Some((reader, index + 1))))
+                }
+                Err(e) => {
+                    // The response is cut short, the client sees an incomplete body
+                    eprintln!("File {} failed to decrypt: {}", binary_file_name, e);
+                    let error = actix_web::error::ErrorInternalServerError("Failed to decrypt");
+                    Some((Err(error), None))
+                }
+            }
+        }
+    })
+}
+
+// Parses a single `bytes=start-end` range into a half-open interval
+fn parse_range(range: &str, total: u64) -> Option<(u64, u64)> {
+    let spec = range.trim().strip_prefix("bytes=")?;
+    if spec.contains(',') {
+        return None;
+    }
+    let (start, end) = spec.split_once('-')?;
+    let (start, end) = match (start.trim(), end.trim()) {
+        ("", suffix) => {
+            let suffix: u64 = suffix.parse().ok()?;
+            (total.saturating_sub(suffix), total)
+        }
+        (start, "") => (start.parse().ok()?, total),
+        (start, end) => {
+            let end: u64 = end.parse().ok()?;
+            (start.parse().ok()?, end.saturating_add(1).min(total))
+        }
+    };
+    if start >= end {
+        return None;
+    }
+    Some((start, end))
+}
+
+fn content_disposition(original_file_name: &str) -> (header::HeaderName, String) {
+    (
+        header::CONTENT_DISPOSITION,
+        format!("attachment; filename=\"{}\"", original_file_name),
+    )
+}
+
 fn get_mime_type_or_bin(filename: &str) -> String {
     mime_guess::from_path(filename)
         .first_raw()
         .unwrap_or("application/octet-stream")
         .to_string()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::server_manager::test_support::{
+        create_test_vault, create_user, status, TestUser,
+    };
+    use actix_web::error::PayloadError;
+    use actix_web::http::header::{HeaderMap, HeaderValue};
+    use actix_web::http::StatusCode;
+
+    const BOUNDARY: &str = "vaultify-test-boundary";
+
+    // Sends a file to `upload_file_query`, the body is cut after `sent` bytes
+    // of its content if given
+    async fn upload(
+        user: &TestUser,
+        vault: &VaultInfo,
+        content: &[u8],
+        sent: Option<usize>,
+    ) -> StatusCode {
+        let mut body = format!(
+            "--{b}\r\nContent-Disposition: form-data; name=\"vault_info\"\r\n\r\n{}\r\n\
+             --{b}\r\nContent-Disposition: form-data; name=\"path\"\r\n\r\n\r\n\
+             --{b}\r\nContent-Disposition: form-data; name=\"file\"; filename=\"notes.txt\"\r\n\r\n",
+            serde_json::to_string(vault).unwrap(),
+            b = BOUNDARY
+        )
+        .into_bytes();
+        body.extend_from_slice(&content[..sent.unwrap_or(content.len())]);
+        if sent.is_none() {
+            body.extend_from_slice(format!("\r\n--{}--\r\n", BOUNDARY).as_bytes());
+        }
+        let mut chunks: Vec<Result<Bytes, PayloadError>> = body
+            .chunks(256)
+            .map(|chunk| Ok(Bytes::copy_from_slice(chunk)))
+            .collect();
+        if sent.is_some() {
+            chunks.push(Err(PayloadError::Incomplete(None)));
+        }
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            header::CONTENT_TYPE,
+            HeaderValue::from_str(&format!("multipart/form-data; boundary={}", BOUNDARY)).unwrap(),
+        );
+        let req = user.request();
+        // Each chunk arrives on its own poll, as from a socket
+        let chunks = stream::iter(chunks).then(|chunk| async {
+            actix_web::rt::task::yield_now().await;
+            chunk
+        });
+        let payload = Multipart::new(&headers, chunks);
+        status(upload_file_query(req.clone(), payload).await, &req)
+    }
+
+    #[actix_web::test]
+    async fn a_failed_upload_keeps_the_stored_file() {
+        let owner = create_user("reupload@example.com", true);
+        let (vault, vault_key) = create_test_vault(&owner, &[]);
+        let original = vec![1u8; 3 * DEFAULT_CHUNK_SIZE as usize / 2];
+        assert_eq!(
+            upload(&owner, &vault, &original, None).await,
+            StatusCode::OK
+        );
+
+        let replacement = vec![2u8; original.len()];
+        let status = upload(&owner, &vault, &replacement, Some(1000)).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+
+        let name = generate_secure_filename("notes.txt", &owner.id.to_string());
+        let file = fs::File::open(format!("{}{}", vault.get_path(), name)).unwrap();
+        let mut reader = ChunkedReader::open(file, vault_key.as_bytes()).unwrap();
+        assert_eq!(reader.read_range(0, reader.len()).unwrap(), original);
+        let staged = fs::read_dir(vault.get_path())
+            .unwrap()
+            .filter(|entry| {
+                entry
+                    .as_ref()
+                    .unwrap()
+                    .path()
+                    .extension()
+                    .is_some_and(|ext| ext == "upload")
+            })
+            .count();
+        assert_eq!(staged, 0);
+    }
+
+    #[test]
+    fn parses_single_ranges() {
+        assert_eq!(parse_range("bytes=0-99", 1000), Some((0, 100)));
+        assert_eq!(parse_range("bytes=10-10", 1000), Some((10, 11)));
+        assert_eq!(parse_range(" bytes= 5 - 9 ", 1000), Some((5, 10)));
+        // Suffix ranges, the last N bytes
+        assert_eq!(parse_range("bytes=-100", 1000), Some((900, 1000)));
+        assert_eq!(parse_range("bytes=-5000", 1000), Some((0, 1000)));
+        // Open-ended ranges
+        assert_eq!(parse_range("bytes=900-", 1000), Some((900, 1000)));
+        assert_eq!(parse_range("bytes=0-", 1000), Some((0, 1000)));
+        // An end past the file is clamped to it
+        assert_eq!(parse_range("bytes=990-5000", 1000), Some((990, 1000)));
+    }
+
+    // Every None is answered with 416 Range Not Satisfiable
+    #[test]
+    fn rejects_unsatisfiable_ranges() {
+        assert_eq!(parse_range("bytes=0-1,5-9", 1000), None);
+        assert_eq!(parse_range("bytes=1000-", 1000), None);
+        assert_eq!(parse_range("bytes=2000-3000", 1000), None);
+        assert_eq!(parse_range("bytes=10-5", 1000), None);
+        assert_eq!(parse_range("bytes=-0", 1000), None);
+        assert_eq!(parse_range("bytes=-10", 0), None);
+        assert_eq!(parse_range("bytes=a-b", 1000), None);
+        assert_eq!(parse_range("items=0-10", 1000), None);
+        assert_eq!(parse_range("bytes=10", 1000), None);
+    }
+}
@@ -173,7 +173,7 @@ impl Directory {
 ///
 /// @file_type - .jpeg, .png, .pdf ...
 #[derive(Serialize, Deserialize, Debug, Clone)]
-struct PubFileNode {
+pub struct PubFileNode {
     file_name: String,
     file_type: String,
 }
@@ -189,7 +189,7 @@ impl PubFileNode {
 
 /// A Public FileType can be either a File or a Directory
 #[derive(Serialize, Deserialize, Debug, Clone)]
-enum PubFileType {
+pub enum PubFileType {
     File(PubFileNode),
     Dir(PubDirectory),
 }
@@ -230,7 +230,6 @@ fn generate_unique_name(existing: &HashMap<String, FileType>, base: &str) -> Str
 }
 
 use std::fs;
-use std::path::Path;
 
 /// Recursively removes a directory from memory and deletes all files from disk
 pub fn remove_directory_recursively(
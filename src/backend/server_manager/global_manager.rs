@@ -1,59 +1,185 @@
-use crate::backend::server_manager::account_manager::{init_db_connection, Session, JWT};
-use crate::backend::server_manager::vault_manager::{VaultInfo, VaultsCache};
-use crate::backend::{VAULTIFY_CONFIG, VAULTIFY_DATABASE};
+use crate::backend::auth::email::{canonical_email, CODE_VALIDITY};
+use crate::backend::auth::mailer::{self, MaildirMailer, Mailer};
+use crate::backend::auth::totp::TotpKey;
+use crate::backend::server_manager::account_manager::{
+    init_db_connection, PendingLogin, Session, JWT,
+};
+use crate::backend::server_manager::api_token_manager::{
+    authenticate_api_token, create_api_token_table,
+};
+use crate::backend::server_manager::config::{MailConfig, ServerConfig};
+use crate::backend::server_manager::invitation_manager::{create_invitation_table, InvitationKey};
+use crate::backend::server_manager::lockout_manager::create_lockout_tables;
+use crate::backend::server_manager::rotation_manager::RotationStatus;
+use crate::backend::server_manager::token::{self, TokenKey};
+use crate::backend::server_manager::transfer_manager::create_transfer_table;
+use crate::backend::server_manager::vault_manager::VaultsCache;
+use crate::backend::server_manager::verification_manager::PendingVerification;
+use crate::backend::{
+    VAULTIFY_CONFIG, VAULTIFY_DATABASE, VAULTIFY_INVITATION_KEY, VAULTIFY_TOKEN_KEY,
+    VAULTIFY_TOTP_KEY,
+};
+use actix_web::http::header;
 use actix_web::HttpRequest;
 use lazy_static::lazy_static;
 use moka::notification::RemovalCause;
 use moka::sync::Cache;
-use rusqlite::Connection;
-use std::collections::HashMap;
+use rusqlite::{params, Connection};
+use std::collections::BTreeMap;
 use std::fs;
 use std::sync::{Arc, Mutex};
-use std::time::Duration;
+use std::time::{Duration, SystemTime};
 
 lazy_static! {
-    pub static ref EMAIL_TO_SESSION_KEY: Cache<String, String> = {
-        Cache::builder().build()
-    };
-
     /// Root directory path for the application.
-    pub static ref ROOT: std::path::PathBuf = dirs::home_dir().expect("Could not find home dir");
+    pub static ref ROOT: std::path::PathBuf = root_dir();
 
-    /// Global cache for user sessions.
+    /// Global cache for user sessions, the user key is wiped when a session is evicted.
     pub static ref SESSION_CACHE: Cache<String, Arc<Mutex<Session>>> = {
         Cache::builder()
             .time_to_idle(Duration::from_secs(1800))
-            .eviction_listener(move |session_key: Arc<String>, _session, cause| {
+            .eviction_listener(move |_session_key, session: Arc<Mutex<Session>>, cause| {
         if cause != RemovalCause::Replaced {
-            let  _ = EMAIL_TO_SESSION_KEY.invalidate_entries_if(move |_email, key| key == session_key.deref());
+            // A handler holding the lock drops its reference soon after, the key is wiped on drop then
+            if let Ok(mut session) = session.try_lock() {
+                session.user_key.wipe();
+            }
         }
     })
             .build()
     };
 
-    /// Global cache for vault
+    /// Global cache for vault, the vault key is wiped when a vault is evicted.
     pub static ref VAULTS_CACHE: Cache<String, Arc<Mutex<VaultsCache>>> = {
         Cache::builder()
         .time_to_idle(Duration::from_secs(1800))
+        .eviction_listener(|_name, vault: Arc<Mutex<VaultsCache>>, cause| {
+            if cause != RemovalCause::Replaced {
+                if let Ok(mut vault) = vault.try_lock() {
+                    vault.vault_key.wipe();
+                }
+            }
+        })
         .build()
     };
 
-    /// Pending share cache
-    pub static ref PENDING_SHARE_CACHE: Cache<String, Arc<Mutex<Vec<(VaultInfo, Vec<u8>)>>>> = {
+    /// Progress of the key rotations, by vault name; a running one refreshes its entry.
+    pub static ref ROTATION_CACHE: Cache<String, Arc<Mutex<RotationStatus>>> = {
+        Cache::builder().time_to_idle(Duration::from_secs(3600)).build()
+    };
+
+    /// Logins waiting for their email code, the unlocked user key is wiped when they expire.
+    pub static ref PENDING_LOGIN_CACHE: Cache<String, Arc<Mutex<PendingLogin>>> = {
         Cache::builder()
-        .time_to_idle(Duration::from_secs(86400))
+        .time_to_live(CODE_VALIDITY)
+        .eviction_listener(|_challenge_id, pending: Arc<Mutex<PendingLogin>>, cause| {
+            if cause != RemovalCause::Replaced {
+                if let Ok(mut pending) = pending.try_lock() {
+                    pending.user_key.wipe();
+                }
+            }
+        })
         .build()
     };
 
+    /// Verification codes of new accounts, keyed by email.
+    pub static ref PENDING_VERIFICATION_CACHE: Cache<String, Arc<Mutex<PendingVerification>>> = {
+        Cache::builder().time_to_live(CODE_VALIDITY).build()
+    };
+
+    /// Server configuration, read once at startup.
+    pub static ref SERVER_CONFIG: ServerConfig = ServerConfig::load();
+
+    /// Key signing the session tokens, created on first start.
+    pub static ref TOKEN_KEY: TokenKey = TokenKey::load_or_create(&ROOT.join(VAULTIFY_TOKEN_KEY))
+        .expect("Failed to load the session token key");
+
+    /// Key encrypting the TOTP secrets in the database, created on first start.
+    pub static ref TOTP_KEY: TotpKey = TotpKey::load_or_create(&ROOT.join(VAULTIFY_TOTP_KEY))
+        .expect("Failed to load the TOTP key");
+
+    /// Key sealing the vault keys of pending invitations, created on first start.
+    pub static ref INVITATION_KEY: InvitationKey =
+        InvitationKey::load_or_create(&ROOT.join(VAULTIFY_INVITATION_KEY))
+            .expect("Failed to load the invitation key");
+
+    /// Transport of the outgoing mails, falls back to the local maildir if the
+    /// configured one cannot be set up.
+    pub static ref MAILER: Box<dyn Mailer> = mailer::from_config(&SERVER_CONFIG.mail, &ROOT)
+        .unwrap_or_else(|e| {
+            eprintln!("{}, mails will be written to the default maildir", e);
+            let default = MailConfig::default();
+            Box::new(
+                MaildirMailer::new(default.from.parse().unwrap(), &ROOT.join(default.maildir))
+                    .expect("Failed to create the default maildir"),
+            )
+        });
+
     /**
      * Global database connection.
      */
     pub static ref CONNECTION: Arc<Mutex<Connection>> = Arc::new(Mutex::new(init_db_connection(&format!("{}/{}", ROOT.to_str().unwrap(), VAULTIFY_DATABASE)).unwrap()));
 }
 
+#[cfg(not(test))]
+fn root_dir() -> std::path::PathBuf {
+    dirs::home_dir().expect("Could not find home dir")
+}
+
+// Unit tests keep their files out of the home directory, in a directory of
+// their own so a previous run leaves no accounts behind
+#[cfg(test)]
+fn root_dir() -> std::path::PathBuf {
+    let started = SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos();
+    std::env::temp_dir().join(format!("vaultify-test-{}-{}", std::process::id(), started))
+}
+
+/**
+ * Authenticates a request from its `user_token` cookie.
+ *
+ * The token must carry a valid signature, must not be expired, and must name
+ * a live session that belongs to the user id it claims.
+ *
+ * @param req - The HTTP request.
+ * @return Option<JWT> - The content of the token, None if the request is not authenticated.
+ */
 pub fn get_user_from_cookie(req: &HttpRequest) -> Option<JWT> {
-    req.cookie("user_token")
-        .and_then(|cookie| serde_json::from_str(cookie.value()).ok())
+    if req.headers().contains_key(header::AUTHORIZATION) {
+        return authenticate_api_token(req);
+    }
+    let cookie = req.cookie("user_token")?;
+    let jwt = match TOKEN_KEY.verify(cookie.value(), token::now()) {
+        Ok(jwt) => jwt,
+        Err(e) => {
+            eprintln!("Rejected session token: {}", e);
+            return None;
+        }
+    };
+
+    let session = SESSION_CACHE.get(&jwt.session_id)?;
+    let mut session = session.lock().ok()?;
+    if session.user_id != jwt.id {
+        eprintln!(
+            "Session token for user {} names a session of user {}",
+            jwt.id, session.user_id
+        );
+        return None;
+    }
+    session.last_activity = SystemTime::now();
+    Some(jwt)
+}
+
+/**
+ * Signs a token for the `user_token` cookie.
+ *
+ * @param jwt - The content of the token.
+ * @return String - The signed token, valid for the configured lifetime.
+ */
+pub fn issue_token(jwt: &JWT) -> String {
+    TOKEN_KEY.sign(jwt, token::now(), SERVER_CONFIG.session.token_lifetime_secs)
 }
 
 pub async fn is_vault_in_cache(name: &str) -> bool {
@@ -68,6 +194,11 @@ pub fn init_server_config() {
     fs::create_dir_all(&config_path).unwrap_or_else(|why| {
         eprintln!("Error creating the configuration directory: {:?}", why);
     });
+    SERVER_CONFIG.write_default_if_missing();
+    lazy_static::initialize(&TOKEN_KEY);
+    lazy_static::initialize(&TOTP_KEY);
+    lazy_static::initialize(&INVITATION_KEY);
+    lazy_static::initialize(&MAILER);
 
     let database_path = ROOT.join(VAULTIFY_DATABASE);
     if let Some(parent_dir) = database_path.parent() {
@@ -77,7 +208,16 @@ pub fn init_server_config() {
     }
 
     let conn = init_db_connection(database_path.to_str().unwrap()).unwrap();
+    migrate_database(&conn).unwrap();
+}
 
+/**
+ * Creates the tables, and brings those of an older release up to date.
+ *
+ * @param conn - The database connection.
+ * @return A Result indicating success or failure.
+ */
+pub fn migrate_database(conn: &Connection) -> rusqlite::Result<()> {
     // Create the users table
     conn.execute(
         "CREATE TABLE IF NOT EXISTS users (
@@ -86,8 +226,59 @@ pub fn init_server_config() {
             hash_password TEXT NOT NULL
         )",
         [],
-    )
-    .unwrap();
+    )?;
+
+    // Per-user salt and Argon2id parameters, NULL for accounts not migrated yet
+    add_column_if_missing(conn, "users", "kdf_salt", "BLOB")?;
+    add_column_if_missing(conn, "users", "kdf_memory_kib", "INTEGER")?;
+    add_column_if_missing(conn, "users", "kdf_iterations", "INTEGER")?;
+    add_column_if_missing(conn, "users", "kdf_parallelism", "INTEGER")?;
+
+    // Accounts that confirm each login with a code sent by email
+    add_column_if_missing(conn, "users", "email_2fa", "INTEGER NOT NULL DEFAULT 0")?;
+
+    // Authenticator app: sealed secret, enabled once a first code was checked, last used time step
+    add_column_if_missing(conn, "users", "totp_secret", "BLOB")?;
+    add_column_if_missing(conn, "users", "totp_enabled", "INTEGER NOT NULL DEFAULT 0")?;
+    add_column_if_missing(conn, "users", "totp_last_step", "INTEGER")?;
+
+    // Accounts created before email verification existed count as verified
+    add_column_if_missing(
+        conn,
+        "users",
+        "email_verified",
+        "INTEGER NOT NULL DEFAULT 1",
+    )?;
+
+    // Legacy user keys are salted with the email as stored at signup, it is
+    // kept aside before the email is normalized
+    add_column_if_missing(conn, "users", "legacy_salt_email", "TEXT")?;
+    conn.execute(
+        "UPDATE users SET legacy_salt_email = email
+         WHERE kdf_salt IS NULL AND legacy_salt_email IS NULL",
+        [],
+    )?;
+    // Emails are looked up lowercased, older releases stored them as typed
+    normalize_stored_emails(conn)?;
+
+    // User key sealed under the recovery key, and the recovery key sealed under the user key
+    add_column_if_missing(conn, "users", "recovery_escrow", "BLOB")?;
+    add_column_if_missing(conn, "users", "recovery_wrap", "BLOB")?;
+
+    // X25519 keypair, the private key sealed under the user key; NULL until the next login
+    add_column_if_missing(conn, "users", "public_key", "BLOB")?;
+    add_column_if_missing(conn, "users", "private_key", "BLOB")?;
+
+    // Single-use TOTP backup codes, hashed
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS totp_backup_codes (
+            user_id INTEGER NOT NULL,
+            code_hash TEXT NOT NULL,
+            FOREIGN KEY (user_id) REFERENCES users(id)
+            PRIMARY KEY (user_id, code_hash)
+        )",
+        [],
+    )?;
 
     // Create the vaults table with a foreign key to users
     conn.execute(
@@ -100,6 +291,86 @@ pub fn init_server_config() {
             PRIMARY KEY (id, creator_id, date)
         )",
         [],
-    )
-    .unwrap();
+    )?;
+
+    // Creator the vault was made by, set once it changes hands
+    add_column_if_missing(conn, "vaults", "origin_id", "INTEGER")?;
+
+    // Failed login counters and their audit log
+    create_lockout_tables(conn)?;
+    create_api_token_table(conn)?;
+    create_invitation_table(conn)?;
+    create_transfer_table(conn)?;
+    add_column_if_missing(
+        conn,
+        "vault_invitations",
+        "key_seal",
+        "TEXT NOT NULL DEFAULT 'server'",
+    )?;
+    Ok(())
+}
+
+// Brings the emails stored by older releases to their canonical form. Accounts
+// whose addresses only differ by case or spaces would end up with the same
+// one: they are left as stored and reported on every start, and only the one
+// already in canonical form, if any, can log in until the others are renamed
+fn normalize_stored_emails(conn: &Connection) -> rusqlite::Result<()> {
+    let mut stmt = conn.prepare("SELECT id, email FROM users ORDER BY id")?;
+    let users = stmt
+        .query_map([], |row| {
+            Ok((row.get::<_, u32>(0)?, row.get::<_, String>(1)?))
+        })?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+    let mut by_canonical: BTreeMap<String, Vec<(u32, String)>> = BTreeMap::new();
+    for (id, email) in users {
+        by_canonical
+            .entry(canonical_email(&email))
+            .or_default()
+            .push((id, email));
+    }
+
+    let tx = conn.unchecked_transaction()?;
+    for (canonical, accounts) in &by_canonical {
+        match accounts.as_slice() {
+            [(_, email)] if email == canonical => {}
+            [(id, _)] => {
+                tx.execute(
+                    "UPDATE users SET email = ? WHERE id = ?",
+                    params![canonical, id],
+                )?;
+            }
+            _ => eprintln!(
+                "Accounts {:?} only differ by case or spaces from {}, rename them so they can log in",
+                accounts, canonical
+            ),
+        }
+    }
+    tx.commit()
+}
+
+/**
+ * Adds a column to a table created by an older release.
+ *
+ * @param conn - The database connection.
+ * @param table - The table to migrate.
+ * @param column - The name of the column.
+ * @param definition - The type and constraints of the column.
+ */
+pub fn add_column_if_missing(
+    conn: &Connection,
+    table: &str,
+    column: &str,
+    definition: &str,
+) -> rusqlite::Result<()> {
+    let mut stmt = conn.prepare(&format!("PRAGMA table_info({})", table))?;
+    let columns = stmt
+        .query_map([], |row| row.get::<_, String>(1))?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+    if !columns.iter().any(|name| name == column) {
+        conn.execute(
+            &format!("ALTER TABLE {} ADD COLUMN {} {}", table, column, definition),
+            [],
+        )?;
+    }
+    Ok(())
 }
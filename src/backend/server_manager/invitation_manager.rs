@@ -0,0 +1,887 @@
+// Vault invitations
+//
+// Sharing a vault no longer adds it to the recipient's vaults: it records an
+// invitation they accept or decline, and the inviter can cancel it until then.
+// Invitations live in the database so they survive a restart. The vault key
+// they carry is sealed to the public key of the recipient, and only their
+// private key, opened with their user key, gets it back when they accept.
+// Accounts that have not logged in since keypairs exist have no public key
+// yet: their invitations wait without a key, and the first member to open the
+// vault once the recipient logged in and got a keypair seals it to them. Keys
+// sealed with the server key by earlier releases still open, and are sealed to
+// the recipient the same way.
+use crate::backend::aes_keys::cipher::gcm::{generate_nonce, AesGcm, NONCE_LEN, TAG_LEN};
+use crate::backend::aes_keys::keypair::seal_to;
+use crate::backend::aes_keys::vault_key::VaultKey;
+use crate::backend::server_manager::account_manager::{
+    get_public_key, get_user_email, get_user_keypair, Perms,
+};
+use crate::backend::server_manager::global_manager::{
+    get_user_from_cookie, CONNECTION, INVITATION_KEY, SESSION_CACHE, VAULTS_CACHE,
+};
+use crate::backend::server_manager::rotation_manager::{is_rotating, ROTATION_IN_PROGRESS};
+use crate::backend::server_manager::token::{self, load_or_create_secret};
+use crate::backend::server_manager::vault_manager::{create_vault, get_vault, VaultInfo};
+use actix_web::{web, HttpRequest, HttpResponse, Responder};
+use rusqlite::types::Type;
+use rusqlite::{params, Connection, OptionalExtension, Row};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::path::Path;
+use uuid::Uuid;
+use zeroize::Zeroizing;
+
+/**
+ * Server key that sealed the vault keys of invitations to accounts without a
+ * keypair, before they waited for one. Kept to open those.
+ */
+pub struct InvitationKey(AesGcm);
+
+impl InvitationKey {
+    /**
+     * Builds a key from a secret.
+     *
+     * @param secret - The 256-bit secret.
+     * @return A new InvitationKey instance.
+     */
+    pub fn new(secret: &[u8]) -> Self {
+        Self(AesGcm::new(secret))
+    }
+
+    /**
+     * Reads the key from disk, generating it on first use.
+     *
+     * @param path - The file holding the key.
+     * @return Result<Self, String> - The key or an error message.
+     */
+    pub fn load_or_create(path: &Path) -> Result<Self, String> {
+        Ok(Self::new(&load_or_create_secret(path)?))
+    }
+
+    /**
+     * Seals the vault key of an invitation.
+     *
+     * The invitation ID and the recipient are authenticated with it, so a key
+     * copied to another invitation does not open.
+     *
+     * @param invitation_id - The ID of the invitation.
+     * @param invitee_id - The ID of the recipient.
+     * @param vault_key - The key of the vault.
+     * @return Vec<u8> - nonce || ciphertext || tag.
+     */
+    pub fn seal(&self, invitation_id: &str, invitee_id: u32, vault_key: &VaultKey) -> Vec<u8> {
+        let nonce = generate_nonce();
+        let mut output = nonce.to_vec();
+        output.extend(self.0.seal(
+            &nonce,
+            &Self::aad(invitation_id, invitee_id),
+            vault_key.as_bytes(),
+        ));
+        output
+    }
+
+    /**
+     * Opens a vault key sealed by `seal`.
+     *
+     * @param invitation_id - The ID of the invitation.
+     * @param invitee_id - The ID of the recipient.
+     * @param sealed - nonce || ciphertext || tag.
+     * @return Result<VaultKey, String> - The key of the vault or an error message.
+     */
+    pub fn open(
+        &self,
+        invitation_id: &str,
+        invitee_id: u32,
+        sealed: &[u8],
+    ) -> Result<VaultKey, String> {
+        if sealed.len() < NONCE_LEN + TAG_LEN {
+            return Err("Invitation key too short".to_string());
+        }
+        let (nonce, data) = sealed.split_at(NONCE_LEN);
+        let nonce: [u8; NONCE_LEN] = nonce.try_into().unwrap();
+        let key = Zeroizing::new(self.0.open(
+            &nonce,
+            &Self::aad(invitation_id, invitee_id),
+            data,
+        )?);
+        VaultKey::from_bytes(&key)
+    }
+
+    fn aad(invitation_id: &str, invitee_id: u32) -> Vec<u8> {
+        let mut aad = invitation_id.as_bytes().to_vec();
+        aad.extend(invitee_id.to_be_bytes());
+        aad
+    }
+}
+
+/**
+ * How the vault key of a new invitation is sealed.
+ */
+pub enum InvitationSeal<'a> {
+    /// To the public key of the recipient
+    Recipient(&'a [u8]),
+    /// Not yet, the recipient has no keypair: see `seal_pending_invitations`
+    Pending,
+}
+
+impl InvitationSeal<'_> {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Self::Recipient(_) => "recipient",
+            Self::Pending => "pending",
+        }
+    }
+
+    fn seal(&self, vault_key: &VaultKey) -> Result<Vec<u8>, String> {
+        match self {
+            Self::Recipient(public_key) => seal_to(public_key, vault_key.as_bytes()),
+            Self::Pending => Ok(Vec::new()),
+        }
+    }
+}
+
+/**
+ * An invitation to join a vault.
+ */
+#[derive(Serialize, Debug)]
+pub struct Invitation {
+    pub id: String,
+    pub vault: VaultInfo,
+    pub inviter_id: u32,
+    pub invitee_id: u32,
+    pub perm: Perms,
+    pub created_at: u64,
+    /// Whether the vault key is sealed to the recipient's public key, or with the server key
+    #[serde(skip)]
+    pub sealed_to_recipient: bool,
+    /// Whether the vault key waits for the recipient to have a keypair
+    #[serde(skip)]
+    pub awaiting_key: bool,
+}
+
+/**
+ * Struct representing the form data naming an invitation.
+ */
+#[derive(Deserialize, Debug)]
+pub struct InvitationForm {
+    id: String,
+}
+
+// `vault_creator_id` is the user who made the vault, see `VaultInfo::get_origin_id`
+const INVITATION_COLUMNS: &str =
+    "id, vault_creator_id, vault_name, vault_date, inviter_id, invitee_id, perm, created_at, key_seal";
+
+fn invitation_from_row(row: &Row) -> rusqlite::Result<Invitation> {
+    let perm: String = row.get(6)?;
+    Ok(Invitation {
+        id: row.get(0)?,
+        vault: VaultInfo::new(
+            row.get(1)?,
+            &row.get::<_, String>(2)?,
+            row.get::<_, i64>(3)? as u64,
+        ),
+        inviter_id: row.get(4)?,
+        invitee_id: row.get(5)?,
+        perm: perm.parse().map_err(|e: String| {
+            rusqlite::Error::FromSqlConversionFailure(6, Type::Text, e.into())
+        })?,
+        created_at: row.get::<_, i64>(7)? as u64,
+        sealed_to_recipient: row.get::<_, String>(8)? == "recipient",
+        awaiting_key: row.get::<_, String>(8)? == "pending",
+    })
+}
+
+/**
+ * Creates the table of the pending invitations.
+ *
+ * @param conn - The database connection.
+ * @return A Result indicating success or failure.
+ */
+pub fn create_invitation_table(conn: &Connection) -> rusqlite::Result<()> {
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS vault_invitations (
+            id TEXT PRIMARY KEY,
+            vault_creator_id INTEGER NOT NULL,
+            vault_name TEXT NOT NULL,
+            vault_date INTEGER NOT NULL,
+            inviter_id INTEGER NOT NULL,
+            invitee_id INTEGER NOT NULL,
+            perm TEXT NOT NULL,
+            wrapped_key BLOB NOT NULL,
+            created_at INTEGER NOT NULL,
+            key_seal TEXT NOT NULL DEFAULT 'server',
+            UNIQUE (vault_creator_id, vault_date, invitee_id),
+            FOREIGN KEY (inviter_id) REFERENCES users(id),
+            FOREIGN KEY (invitee_id) REFERENCES users(id)
+        )",
+        [],
+    )?;
+    Ok(())
+}
+
+/**
+ * Invites a user to a vault, replacing any invitation they have to it.
+ *
+ * @param conn - The database connection.
+ * @param seal - How to seal the vault key.
+ * @param vault - The vault.
+ * @param inviter_id - The ID of the member sending the invitation.
+ * @param invitee_id - The ID of the recipient.
+ * @param perm - The permissions the recipient gets on accepting.
+ * @param vault_key - The key of the vault.
+ * @return A Result containing the ID of the invitation.
+ */
+pub fn create_invitation(
+    conn: &Connection,
+    seal: InvitationSeal,
+    vault: &VaultInfo,
+    inviter_id: u32,
+    invitee_id: u32,
+    perm: &Perms,
+    vault_key: &VaultKey,
+) -> rusqlite::Result<String> {
+    let id = Uuid::new_v4().to_string();
+    let sealed = seal
+        .seal(vault_key)
+        .map_err(|e| rusqlite::Error::ToSqlConversionFailure(e.into()))?;
+    conn.execute(
+        "INSERT INTO vault_invitations
+            (id, vault_creator_id, vault_name, vault_date, inviter_id, invitee_id, perm, wrapped_key, created_at, key_seal)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)
+         ON CONFLICT (vault_creator_id, vault_date, invitee_id) DO UPDATE
+         SET id = ?1, vault_name = ?3, inviter_id = ?5, perm = ?7, wrapped_key = ?8, created_at = ?9, key_seal = ?10",
+        params![
+            id,
+            vault.get_origin_id(),
+            vault.name,
+            vault.date as i64,
+            inviter_id,
+            invitee_id,
+            perm.to_string(),
+            sealed,
+            token::now() as i64,
+            seal.as_str()
+        ],
+    )?;
+    Ok(id)
+}
+
+/**
+ * Reads an invitation with its sealed vault key.
+ *
+ * @param conn - The database connection.
+ * @param id - The ID of the invitation.
+ * @return A Result containing the invitation and its sealed key, None if there is none.
+ */
+pub fn get_invitation(
+    conn: &Connection,
+    id: &str,
+) -> rusqlite::Result<Option<(Invitation, Vec<u8>)>> {
+    conn.query_row(
+        &format!(
+            "SELECT {}, wrapped_key FROM vault_invitations WHERE id = ?",
+            INVITATION_COLUMNS
+        ),
+        params![id],
+        |row| Ok((invitation_from_row(row)?, row.get(9)?)),
+    )
+    .optional()
+}
+
+/**
+ * Lists the invitations sent to a user, or by them.
+ *
+ * @param conn - The database connection.
+ * @param user_id - The ID of the user.
+ * @param received - Whether to list the invitations the user received or the ones they sent.
+ * @return A Result containing the invitations, most recent first.
+ */
+pub fn list_invitations(
+    conn: &Connection,
+    user_id: u32,
+    received: bool,
+) -> rusqlite::Result<Vec<Invitation>> {
+    let column = if received { "invitee_id" } else { "inviter_id" };
+    let mut stmt = conn.prepare(&format!(
+        "SELECT {} FROM vault_invitations WHERE {} = ? ORDER BY created_at DESC",
+        INVITATION_COLUMNS, column
+    ))?;
+    let invitations = stmt.query_map(params![user_id], invitation_from_row)?;
+    invitations.collect()
+}
+
+/**
+ * Deletes an invitation.
+ *
+ * @param conn - The database connection.
+ * @param id - The ID of the invitation.
+ * @return A Result containing whether the invitation existed.
+ */
+pub fn delete_invitation(conn: &Connection, id: &str) -> rusqlite::Result<bool> {
+    let deleted = conn.execute("DELETE FROM vault_invitations WHERE id = ?", params![id])?;
+    Ok(deleted > 0)
+}
+
+/**
+ * Deletes the invitations to a vault, e.g. when it is deleted.
+ *
+ * @param conn - The database connection.
+ * @param vault - The vault.
+ * @return A Result indicating success or failure.
+ */
+pub fn delete_vault_invitations(conn: &Connection, vault: &VaultInfo) -> rusqlite::Result<()> {
+    conn.execute(
+        "DELETE FROM vault_invitations WHERE vault_creator_id = ? AND vault_date = ?",
+        params![vault.get_origin_id(), vault.date as i64],
+    )?;
+    Ok(())
+}
+
+/**
+ * Seals the vault key of the invitations to a vault again, after the key was rotated.
+ *
+ * @param conn - The database connection.
+ * @param vault - The vault.
+ * @param vault_key - The new key of the vault.
+ * @return A Result indicating success or failure.
+ */
+pub fn reseal_vault_invitations(
+    conn: &Connection,
+    vault: &VaultInfo,
+    vault_key: &VaultKey,
+) -> rusqlite::Result<()> {
+    let mut stmt = conn.prepare(
+        "SELECT id, invitee_id FROM vault_invitations WHERE vault_creator_id = ? AND vault_date = ?",
+    )?;
+    let invitations = stmt
+        .query_map(params![vault.get_origin_id(), vault.date as i64], |row| {
+            Ok((row.get::<_, String>(0)?, row.get::<_, u32>(1)?))
+        })?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+    for (id, invitee_id) in invitations {
+        let public_key = get_public_key(conn, invitee_id)?;
+        let seal = match &public_key {
+            Some(public_key) => InvitationSeal::Recipient(public_key),
+            None => InvitationSeal::Pending,
+        };
+        let sealed = seal
+            .seal(vault_key)
+            .map_err(|e| rusqlite::Error::ToSqlConversionFailure(e.into()))?;
+        conn.execute(
+            "UPDATE vault_invitations SET wrapped_key = ?, key_seal = ? WHERE id = ?",
+            params![sealed, seal.as_str(), id],
+        )?;
+    }
+    Ok(())
+}
+
+/**
+ * Seals the vault key of the invitations to a vault whose recipients had no
+ * keypair when they were invited, or got the key under the server key, and
+ * have a keypair now.
+ *
+ * @param conn - The database connection.
+ * @param vault - The vault.
+ * @param vault_key - The current key of the vault.
+ * @return A Result containing the number of invitations sealed.
+ */
+pub fn seal_pending_invitations(
+    conn: &Connection,
+    vault: &VaultInfo,
+    vault_key: &VaultKey,
+) -> rusqlite::Result<usize> {
+    let mut stmt = conn.prepare(
+        "SELECT i.id, u.public_key FROM vault_invitations i
+         JOIN users u ON u.id = i.invitee_id
+         WHERE i.vault_creator_id = ? AND i.vault_date = ? AND i.key_seal != 'recipient'
+           AND u.public_key IS NOT NULL",
+    )?;
+    let invitations = stmt
+        .query_map(params![vault.get_origin_id(), vault.date as i64], |row| {
+            Ok((row.get::<_, String>(0)?, row.get::<_, Vec<u8>>(1)?))
+        })?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+    for (id, public_key) in &invitations {
+        let seal = InvitationSeal::Recipient(public_key);
+        let sealed = seal
+            .seal(vault_key)
+            .map_err(|e| rusqlite::Error::ToSqlConversionFailure(e.into()))?;
+        conn.execute(
+            "UPDATE vault_invitations SET wrapped_key = ?, key_seal = ? WHERE id = ?",
+            params![sealed, seal.as_str(), id],
+        )?;
+    }
+    Ok(invitations.len())
+}
+
+/**
+ * Deletes the invitations a user sent or received, e.g. when their account is deleted.
+ *
+ * @param conn - The database connection.
+ * @param user_id - The ID of the user.
+ * @return A Result indicating success or failure.
+ */
+pub fn delete_user_invitations(conn: &Connection, user_id: u32) -> rusqlite::Result<()> {
+    conn.execute(
+        "DELETE FROM vault_invitations WHERE inviter_id = ?1 OR invitee_id = ?1",
+        params![user_id],
+    )?;
+    Ok(())
+}
+
+// Invitation as listed to its recipient or its sender, with the other party's email
+fn describe(conn: &Connection, invitation: &Invitation, received: bool) -> serde_json::Value {
+    let other = if received {
+        invitation.inviter_id
+    } else {
+        invitation.invitee_id
+    };
+    let email = get_user_email(conn, other).ok().flatten();
+    json!({
+        "id": invitation.id,
+        "vault": invitation.vault,
+        "perm": invitation.perm,
+        "created_at": invitation.created_at,
+        "awaiting_key": invitation.awaiting_key,
+        if received { "inviter" } else { "invitee" }: email,
+    })
+}
+
+/**
+ * Endpoint listing the pending invitations of the logged-in user, the ones
+ * they received and the ones they sent.
+ *
+ * @param req - The HTTP request carrying the session token.
+ * @return An HTTP response with the invitations.
+ */
+pub async fn list_invitations_query(req: HttpRequest) -> impl Responder {
+    let jwt = match get_user_from_cookie(&req) {
+        Some(jwt) => jwt,
+        None => return HttpResponse::Unauthorized().body("Invalid session"),
+    };
+
+    let conn = CONNECTION.lock().unwrap();
+    let listed = list_invitations(&conn, jwt.id, true)
+        .and_then(|received| Ok((received, list_invitations(&conn, jwt.id, false)?)));
+    match listed {
+        Ok((received, sent)) => HttpResponse::Ok().json(json!({
+            "success": true,
+            "received": received.iter().map(|i| describe(&conn, i, true)).collect::<Vec<_>>(),
+            "sent": sent.iter().map(|i| describe(&conn, i, false)).collect::<Vec<_>>(),
+        })),
+        Err(e) => {
+            eprintln!("Failed to list the invitations of user {}: {}", jwt.id, e);
+            HttpResponse::InternalServerError().body("Failed to list the invitations")
+        }
+    }
+}
+
+/**
+ * Endpoint accepting an invitation: the vault is added to the vaults of the
+ * logged-in user with the permissions it offered.
+ *
+ * The invitation is void if the inviter is no longer an admin of the vault.
+ *
+ * @param req - The HTTP request carrying the session token.
+ * @param form - The ID of the invitation.
+ * @return An HTTP response indicating the result of the operation.
+ */
+pub async fn accept_invitation_query(
+    req: HttpRequest,
+    form: web::Json<InvitationForm>,
+) -> impl Responder {
+    let jwt = match get_user_from_cookie(&req) {
+        Some(jwt) => jwt,
+        None => return HttpResponse::Unauthorized().body("Invalid session"),
+    };
+    let (user_key, user_kdf) = match SESSION_CACHE.get(&jwt.session_id) {
+        Some(session) => {
+            let session = session.lock().unwrap();
+            (session.user_key.clone(), session.user_kdf)
+        }
+        None => return HttpResponse::Unauthorized().body("Invalid session"),
+    };
+
+    let conn = CONNECTION.lock().unwrap();
+    let (invitation, sealed) = match get_invitation(&conn, &form.id) {
+        Ok(Some((invitation, sealed))) if invitation.invitee_id == jwt.id => (invitation, sealed),
+        Ok(_) => return HttpResponse::NotFound().body("Unknown invitation"),
+        Err(e) => {
+            eprintln!("Failed to read invitation {}: {}", form.id, e);
+            return HttpResponse::InternalServerError().body("Failed to accept the invitation");
+        }
+    };
+    // The vault may have changed hands since the invitation was sent
+    let vault = match get_vault(&conn, &invitation.vault) {
+        Ok(Some(vault)) => vault,
+        Ok(None) => {
+            let _ = delete_invitation(&conn, &invitation.id);
+            return HttpResponse::Gone().body("The vault no longer exists");
+        }
+        Err(e) => {
+            eprintln!("Vault of invitation {}: {}", invitation.id, e);
+            return HttpResponse::InternalServerError().body("Failed to accept the invitation");
+        }
+    };
+    let vault = &vault;
+    // Members joining now would miss the new key
+    if is_rotating(&vault.get_name()) {
+        return HttpResponse::Conflict().body(ROTATION_IN_PROGRESS);
+    }
+    let mut lease = None;
+    let opened = if invitation.awaiting_key {
+        // Only a loaded vault has the key until a member seals it to the recipient
+        let cache = VAULTS_CACHE.get(&vault.get_name());
+        match cache.and_then(|cache| cache.lock().unwrap().lease_key()) {
+            Some((vault_key, key_lease)) => {
+                lease = Some(key_lease);
+                Ok(vault_key)
+            }
+            None => {
+                return HttpResponse::Conflict()
+                    .body("The invitation is waiting for a member to open the vault")
+            }
+        }
+    } else if invitation.sealed_to_recipient {
+        get_user_keypair(&conn, jwt.id, user_key.as_bytes()).and_then(|keypair| {
+            let keypair = keypair.ok_or("No keypair to open the invitation")?;
+            VaultKey::from_bytes(&keypair.open_sealed(&sealed)?)
+        })
+    } else {
+        INVITATION_KEY.open(&invitation.id, jwt.id, &sealed)
+    };
+    let vault_key = match opened {
+        Ok(vault_key) => vault.follow_rekey_links(vault_key).0,
+        Err(e) => {
+            eprintln!("Key of invitation {} failed to open: {}", invitation.id, e);
+            return HttpResponse::InternalServerError().body("Failed to accept the invitation");
+        }
+    };
+
+    // Permissions of the loaded vault are the current ones
+    let cache = VAULTS_CACHE.get(&vault.get_name());
+    let mut cached = cache.as_ref().map(|cache| cache.lock().unwrap());
+    let mut perms = match &cached {
+        Some(cached) => cached.perms.clone(),
+        None => match vault.get_perms(&vault_key) {
+            Ok(perms) => perms,
+            Err(e) => {
+                eprintln!("Vault of invitation {}: {}", invitation.id, e);
+                let _ = delete_invitation(&conn, &invitation.id);
+                return HttpResponse::Gone().body("The vault no longer exists");
+            }
+        },
+    };
+    // The inviter may have lost the right to give this role since
+    if perms
+        .get(&invitation.inviter_id)
+        .is_none_or(|perm| !perm.can_grant(&invitation.perm, None))
+    {
+        let _ = delete_invitation(&conn, &invitation.id);
+        return HttpResponse::Gone().body("The invitation was withdrawn");
+    }
+    if perms.contains_key(&jwt.id) {
+        let _ = delete_invitation(&conn, &invitation.id);
+        return HttpResponse::Conflict().body("You are already a member of this vault");
+    }
+
+    perms.insert(jwt.id, invitation.perm.clone());
+    let joined = vault
+        .save_key(&vault_key, user_key.as_bytes(), user_kdf, jwt.id)
+        .and_then(|()| vault.set_perms(&vault_key, &perms))
+        .map_err(str::to_string)
+        .and_then(|()| create_vault(&conn, vault, jwt.id).map_err(|e| e.to_string()));
+    if let Err(e) = joined {
+        eprintln!(
+            "Failed to add user {} to vault {}: {}",
+            jwt.id,
+            vault.get_name(),
+            e
+        );
+        return HttpResponse::InternalServerError().body("Failed to accept the invitation");
+    }
+    if let Some(cached) = cached.as_mut() {
+        cached.perms = perms;
+    }
+    if let Err(e) = delete_invitation(&conn, &invitation.id) {
+        eprintln!("Failed to delete invitation {}: {}", invitation.id, e);
+    }
+    drop(lease);
+
+    HttpResponse::Ok().json(json!({
+        "success": true,
+        "message": format!("Vault '{}' added to your vaults", vault.name),
+        "vault": vault,
+        "perm": invitation.perm,
+    }))
+}
+
+/**
+ * Endpoint declining an invitation received by the logged-in user.
+ *
+ * @param req - The HTTP request carrying the session token.
+ * @param form - The ID of the invitation.
+ * @return An HTTP response indicating the result of the operation.
+ */
+pub async fn decline_invitation_query(
+    req: HttpRequest,
+    form: web::Json<InvitationForm>,
+) -> impl Responder {
+    drop_invitation(req, &form.id, true)
+}
+
+/**
+ * Endpoint cancelling an invitation sent by the logged-in user.
+ *
+ * @param req - The HTTP request carrying the session token.
+ * @param form - The ID of the invitation.
+ * @return An HTTP response indicating the result of the operation.
+ */
+pub async fn cancel_invitation_query(
+    req: HttpRequest,
+    form: web::Json<InvitationForm>,
+) -> impl Responder {
+    drop_invitation(req, &form.id, false)
+}
+
+// Deletes an invitation received by the user, or sent by them
+fn drop_invitation(req: HttpRequest, id: &str, received: bool) -> HttpResponse {
+    let jwt = match get_user_from_cookie(&req) {
+        Some(jwt) => jwt,
+        None => return HttpResponse::Unauthorized().body("Invalid session"),
+    };
+
+    let conn = CONNECTION.lock().unwrap();
+    let owned = get_invitation(&conn, id).map(|invitation| {
+        invitation.is_some_and(|(invitation, _)| {
+            let party = if received {
+                invitation.invitee_id
+            } else {
+                invitation.inviter_id
+            };
+            party == jwt.id
+        })
+    });
+    match owned.and_then(|owned| Ok(owned && delete_invitation(&conn, id)?)) {
+        Ok(true) => HttpResponse::Ok().json(json!({ "success": true })),
+        Ok(false) => HttpResponse::NotFound().body("Unknown invitation"),
+        Err(e) => {
+            eprintln!("Failed to delete invitation {}: {}", id, e);
+            HttpResponse::InternalServerError().body("Failed to delete the invitation")
+        }
+    }
+}
+
+/**
+ * Number of invitations waiting for a user's answer.
+ *
+ * @param conn - The database connection.
+ * @param user_id - The ID of the user.
+ * @return A Result containing the number of invitations.
+ */
+pub fn count_pending_invitations(conn: &Connection, user_id: u32) -> rusqlite::Result<u32> {
+    conn.query_row(
+        "SELECT count(*) FROM vault_invitations WHERE invitee_id = ?",
+        params![user_id],
+        |row| row.get(0),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::aes_keys::container::Kdf;
+    use crate::backend::aes_keys::keypair::UserKeypair;
+    use crate::backend::server_manager::account_manager::set_user_keypair;
+    use crate::backend::server_manager::test_support::{
+        create_test_vault, create_user, respond, status, TestUser,
+    };
+    use crate::backend::server_manager::vault_manager::{load_vault, share_vault_query};
+    use actix_web::http::StatusCode;
+
+    async fn accept(user: &TestUser, id: &str) -> StatusCode {
+        let req = user.request();
+        let form = web::Json(InvitationForm { id: id.to_string() });
+        status(accept_invitation_query(req.clone(), form).await, &req)
+    }
+
+    #[test]
+    fn sealed_keys_are_bound_to_the_invitation() {
+        let key = InvitationKey::new(&[3u8; 32]);
+        let vault_key = VaultKey::generate();
+        let sealed = key.seal("inv-1", 7, &vault_key);
+        assert_eq!(
+            key.open("inv-1", 7, &sealed).unwrap().as_bytes(),
+            vault_key.as_bytes()
+        );
+        assert!(key.open("inv-2", 7, &sealed).is_err());
+        assert!(key.open("inv-1", 8, &sealed).is_err());
+        assert!(InvitationKey::new(&[4u8; 32])
+            .open("inv-1", 7, &sealed)
+            .is_err());
+    }
+
+    #[test]
+    fn a_new_invitation_replaces_the_previous_one() {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(
+            "CREATE TABLE users (id INTEGER PRIMARY KEY, public_key BLOB);
+             INSERT INTO users (id) VALUES (1), (2);",
+        )
+        .unwrap();
+        create_invitation_table(&conn).unwrap();
+        let vault = VaultInfo::new(1, "team", 1_700_000_000);
+        let vault_key = VaultKey::generate();
+
+        let first = create_invitation(
+            &conn,
+            InvitationSeal::Pending,
+            &vault,
+            1,
+            2,
+            &Perms::Read,
+            &vault_key,
+        )
+        .unwrap();
+        let (invitation, sealed) = get_invitation(&conn, &first).unwrap().unwrap();
+        assert!(invitation.awaiting_key);
+        assert!(sealed.is_empty());
+
+        let recipient = UserKeypair::generate();
+        let second = create_invitation(
+            &conn,
+            InvitationSeal::Recipient(&recipient.public_key()),
+            &vault,
+            1,
+            2,
+            &Perms::Write,
+            &vault_key,
+        )
+        .unwrap();
+        assert!(get_invitation(&conn, &first).unwrap().is_none());
+        let (invitation, sealed) = get_invitation(&conn, &second).unwrap().unwrap();
+        assert_eq!(invitation.perm, Perms::Write);
+        assert!(invitation.sealed_to_recipient);
+        assert_eq!(
+            &recipient.open_sealed(&sealed).unwrap()[..],
+            vault_key.as_bytes()
+        );
+        assert_eq!(count_pending_invitations(&conn, 2).unwrap(), 1);
+        assert_eq!(list_invitations(&conn, 1, false).unwrap().len(), 1);
+
+        delete_vault_invitations(&conn, &vault).unwrap();
+        assert_eq!(count_pending_invitations(&conn, 2).unwrap(), 0);
+    }
+
+    #[test]
+    fn waiting_invitations_are_sealed_once_the_recipient_has_a_keypair() {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(
+            "CREATE TABLE users (id INTEGER PRIMARY KEY, public_key BLOB);
+             INSERT INTO users (id) VALUES (1), (2), (3);",
+        )
+        .unwrap();
+        create_invitation_table(&conn).unwrap();
+        let vault = VaultInfo::new(1, "team", 1_700_000_000);
+        let vault_key = VaultKey::generate();
+        let pending = create_invitation(
+            &conn,
+            InvitationSeal::Pending,
+            &vault,
+            1,
+            2,
+            &Perms::Read,
+            &vault_key,
+        )
+        .unwrap();
+        // Sealed with the server key by an earlier release
+        let key = InvitationKey::new(&[3u8; 32]);
+        let legacy = create_invitation(
+            &conn,
+            InvitationSeal::Pending,
+            &vault,
+            1,
+            3,
+            &Perms::Read,
+            &vault_key,
+        )
+        .unwrap();
+        conn.execute(
+            "UPDATE vault_invitations SET wrapped_key = ?, key_seal = 'server' WHERE id = ?",
+            params![key.seal(&legacy, 3, &vault_key), legacy],
+        )
+        .unwrap();
+
+        assert_eq!(
+            seal_pending_invitations(&conn, &vault, &vault_key).unwrap(),
+            0
+        );
+
+        let recipients = [UserKeypair::generate(), UserKeypair::generate()];
+        for (id, recipient) in [2, 3].iter().zip(&recipients) {
+            conn.execute(
+                "UPDATE users SET public_key = ? WHERE id = ?",
+                params![recipient.public_key(), id],
+            )
+            .unwrap();
+        }
+        assert_eq!(
+            seal_pending_invitations(&conn, &vault, &vault_key).unwrap(),
+            2
+        );
+        for (id, recipient) in [pending, legacy].iter().zip(&recipients) {
+            let (invitation, sealed) = get_invitation(&conn, id).unwrap().unwrap();
+            assert!(invitation.sealed_to_recipient);
+            assert!(!invitation.awaiting_key);
+            assert_eq!(
+                &recipient.open_sealed(&sealed).unwrap()[..],
+                vault_key.as_bytes()
+            );
+        }
+        assert_eq!(
+            seal_pending_invitations(&conn, &vault, &vault_key).unwrap(),
+            0
+        );
+    }
+
+    #[actix_web::test]
+    async fn invitations_wait_for_the_recipient_to_have_a_keypair() {
+        let owner = create_user("invite-wait-owner@example.com", true);
+        // Logged in before keypairs existed
+        let recipient = create_user("invite-wait-recipient@example.com", true);
+        let (vault, vault_key) = create_test_vault(&owner, &[]);
+
+        let req = owner.request();
+        let form = web::Json((vault.clone(), recipient.email.clone(), "Read".to_string()));
+        let shared = respond(share_vault_query(req.clone(), form).await, &req).await;
+        assert_eq!(shared.status, StatusCode::OK);
+        let id = shared.body["invitation_id"].as_str().unwrap().to_string();
+        let awaiting_key = || {
+            let conn = CONNECTION.lock().unwrap();
+            get_invitation(&conn, &id).unwrap().unwrap().0.awaiting_key
+        };
+        assert!(awaiting_key());
+
+        // Nobody holds the key of the vault
+        VAULTS_CACHE.invalidate(&vault.get_name());
+        assert_eq!(accept(&recipient, &id).await, StatusCode::CONFLICT);
+
+        // The next login gives them a keypair, the next load seals the key to it
+        let keypair = UserKeypair::generate();
+        {
+            let conn = CONNECTION.lock().unwrap();
+            set_user_keypair(&conn, recipient.id, &keypair, recipient.user_key.as_bytes()).unwrap();
+        }
+        assert!(load_vault(owner.request(), web::Json(vault.clone()))
+            .await
+            .is_ok());
+        assert!(!awaiting_key());
+
+        VAULTS_CACHE.invalidate(&vault.get_name());
+        assert_eq!(accept(&recipient, &id).await, StatusCode::OK);
+        let opened = vault
+            .open_key(recipient.id, recipient.user_key.as_bytes(), Kdf::None, None)
+            .unwrap();
+        assert_eq!(opened.as_bytes(), vault_key.as_bytes());
+    }
+}
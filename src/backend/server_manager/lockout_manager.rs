@@ -0,0 +1,388 @@
+// Brute-force protection of the login
+//
+// Failed logins are counted per account and per client IP address in the
+// database, so restarting the server does not hand out fresh attempts. Each
+// failure pushes back the next allowed attempt as set by the `login` section
+// of the server configuration, and is written to the audit log.
+use crate::backend::server_manager::config::{LockoutPolicy, LoginConfig};
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::Serialize;
+
+/**
+ * What a failure counter is about.
+ */
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LockoutScope {
+    /// Failures on an email address, whether an account exists for it or not
+    Account,
+    /// Failures from a client IP address, on any account
+    Ip,
+}
+
+impl LockoutScope {
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::Account => "account",
+            Self::Ip => "ip",
+        }
+    }
+
+    fn policy(self, config: &LoginConfig) -> &LockoutPolicy {
+        match self {
+            Self::Account => &config.account,
+            Self::Ip => &config.ip,
+        }
+    }
+}
+
+/**
+ * A failure counter, as listed for admins.
+ */
+#[derive(Serialize, Debug)]
+pub struct LockoutEntry {
+    pub scope: String,
+    pub subject: String,
+    pub failures: u32,
+    pub last_failure: u64,
+    pub locked_until: u64,
+}
+
+/**
+ * A failed login, as recorded in the audit log.
+ */
+#[derive(Serialize, Debug)]
+pub struct FailedLogin {
+    pub time: u64,
+    pub email: String,
+    pub ip: Option<String>,
+    pub reason: String,
+}
+
+// Counters are keyed on the email as typed, up to case and surrounding spaces
+fn account_subject(email: &str) -> String {
+    email.trim().to_lowercase()
+}
+
+/**
+ * Creates the failure counters and the audit log.
+ *
+ * @param conn - The database connection.
+ * @return A Result indicating success or failure.
+ */
+pub fn create_lockout_tables(conn: &Connection) -> rusqlite::Result<()> {
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS login_failures (
+            scope TEXT NOT NULL,
+            subject TEXT NOT NULL,
+            failures INTEGER NOT NULL,
+            last_failure INTEGER NOT NULL,
+            locked_until INTEGER NOT NULL,
+            PRIMARY KEY (scope, subject)
+        )",
+        [],
+    )?;
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS login_audit (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            time INTEGER NOT NULL,
+            email TEXT NOT NULL,
+            ip TEXT,
+            reason TEXT NOT NULL
+        )",
+        [],
+    )?;
+    Ok(())
+}
+
+/**
+ * Tells whether a login may be attempted now.
+ *
+ * @param conn - The database connection.
+ * @param email - The email the login is for.
+ * @param ip - The client address, if known.
+ * @param now - The current time, in seconds since the UNIX epoch.
+ * @return A Result containing None if the login may go on, or the seconds to wait before the next attempt.
+ */
+pub fn login_retry_after(
+    conn: &Connection,
+    email: &str,
+    ip: Option<&str>,
+    now: u64,
+) -> rusqlite::Result<Option<u64>> {
+    let mut locked_until = get_locked_until(conn, LockoutScope::Account, &account_subject(email))?;
+    if let Some(ip) = ip {
+        locked_until = locked_until.max(get_locked_until(conn, LockoutScope::Ip, ip)?);
+    }
+    Ok((locked_until > now).then(|| locked_until - now))
+}
+
+fn get_locked_until(
+    conn: &Connection,
+    scope: LockoutScope,
+    subject: &str,
+) -> rusqlite::Result<u64> {
+    let locked_until: Option<i64> = conn
+        .query_row(
+            "SELECT locked_until FROM login_failures WHERE scope = ? AND subject = ?",
+            params![scope.as_str(), subject],
+            |row| row.get(0),
+        )
+        .optional()?;
+    Ok(locked_until.unwrap_or(0) as u64)
+}
+
+/**
+ * Counts a failed login against the account and the client address, and
+ * writes it to the audit log.
+ *
+ * @param conn - The database connection.
+ * @param config - The failed login limits.
+ * @param email - The email the login was for.
+ * @param ip - The client address, if known.
+ * @param reason - Why the login failed, for the audit log.
+ * @param now - The current time, in seconds since the UNIX epoch.
+ * @return A Result containing the seconds to wait before the next attempt.
+ */
+pub fn record_login_failure(
+    conn: &Connection,
+    config: &LoginConfig,
+    email: &str,
+    ip: Option<&str>,
+    reason: &str,
+    now: u64,
+) -> rusqlite::Result<u64> {
+    let tx = conn.unchecked_transaction()?;
+    let mut wait = bump_counter(
+        &tx,
+        config,
+        LockoutScope::Account,
+        &account_subject(email),
+        now,
+    )?;
+    if let Some(ip) = ip {
+        wait = wait.max(bump_counter(&tx, config, LockoutScope::Ip, ip, now)?);
+    }
+    tx.execute(
+        "INSERT INTO login_audit (time, email, ip, reason) VALUES (?, ?, ?, ?)",
+        params![now as i64, email, ip, reason],
+    )?;
+    tx.commit()?;
+
+    eprintln!(
+        "Failed login for {} from {}: {}",
+        email,
+        ip.unwrap_or("unknown address"),
+        reason
+    );
+    Ok(wait)
+}
+
+// Adds a failure to a counter and returns the wait it imposes
+fn bump_counter(
+    conn: &Connection,
+    config: &LoginConfig,
+    scope: LockoutScope,
+    subject: &str,
+    now: u64,
+) -> rusqlite::Result<u64> {
+    let previous: Option<(u32, i64)> = conn
+        .query_row(
+            "SELECT failures, last_failure FROM login_failures WHERE scope = ? AND subject = ?",
+            params![scope.as_str(), subject],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )
+        .optional()?;
+    let failures = match previous {
+        Some((failures, last_failure))
+            if now.saturating_sub(last_failure as u64) < config.forget_after_secs =>
+        {
+            failures.saturating_add(1)
+        }
+        _ => 1,
+    };
+
+    let wait = scope.policy(config).delay(failures);
+    conn.execute(
+        "INSERT INTO login_failures (scope, subject, failures, last_failure, locked_until)
+         VALUES (?1, ?2, ?3, ?4, ?5)
+         ON CONFLICT (scope, subject) DO UPDATE
+         SET failures = ?3, last_failure = ?4, locked_until = ?5",
+        params![
+            scope.as_str(),
+            subject,
+            failures,
+            now as i64,
+            now.saturating_add(wait) as i64
+        ],
+    )?;
+    Ok(wait)
+}
+
+/**
+ * Clears the failures of an account after a successful login. The counter of
+ * the client address is kept, a valid login does not vouch for the others.
+ *
+ * @param conn - The database connection.
+ * @param email - The email of the account.
+ * @return A Result indicating success or failure.
+ */
+pub fn clear_login_failures(conn: &Connection, email: &str) -> rusqlite::Result<()> {
+    reset_lockout(conn, LockoutScope::Account, email).map(|_| ())
+}
+
+/**
+ * Drops a failure counter, lifting its lockout.
+ *
+ * @param conn - The database connection.
+ * @param scope - Whether `subject` is an email or an IP address.
+ * @param subject - The email or the IP address.
+ * @return A Result containing whether a counter was dropped.
+ */
+pub fn reset_lockout(
+    conn: &Connection,
+    scope: LockoutScope,
+    subject: &str,
+) -> rusqlite::Result<bool> {
+    let subject = match scope {
+        LockoutScope::Account => account_subject(subject),
+        LockoutScope::Ip => subject.trim().to_string(),
+    };
+    let deleted = conn.execute(
+        "DELETE FROM login_failures WHERE scope = ? AND subject = ?",
+        params![scope.as_str(), subject],
+    )?;
+    Ok(deleted > 0)
+}
+
+/**
+ * Lists the failure counters, most recent failure first.
+ *
+ * @param conn - The database connection.
+ * @return A Result containing the counters.
+ */
+pub fn list_lockouts(conn: &Connection) -> rusqlite::Result<Vec<LockoutEntry>> {
+    let mut stmt = conn.prepare(
+        "SELECT scope, subject, failures, last_failure, locked_until
+         FROM login_failures ORDER BY last_failure DESC",
+    )?;
+    let entries = stmt.query_map([], |row| {
+        Ok(LockoutEntry {
+            scope: row.get(0)?,
+            subject: row.get(1)?,
+            failures: row.get(2)?,
+            last_failure: row.get::<_, i64>(3)? as u64,
+            locked_until: row.get::<_, i64>(4)? as u64,
+        })
+    })?;
+    entries.collect()
+}
+
+/**
+ * Reads the most recent failed logins from the audit log.
+ *
+ * @param conn - The database connection.
+ * @param email - Only the failures for this email, if given.
+ * @param limit - The maximum number of entries.
+ * @return A Result containing the failures, most recent first.
+ */
+pub fn get_failed_logins(
+    conn: &Connection,
+    email: Option<&str>,
+    limit: u32,
+) -> rusqlite::Result<Vec<FailedLogin>> {
+    let mut stmt = conn.prepare(
+        "SELECT time, email, ip, reason FROM login_audit
+         WHERE ?1 IS NULL OR lower(email) = lower(?1)
+         ORDER BY id DESC LIMIT ?2",
+    )?;
+    let entries = stmt.query_map(params![email.map(str::trim), limit], |row| {
+        Ok(FailedLogin {
+            time: row.get::<_, i64>(0)? as u64,
+            email: row.get(1)?,
+            ip: row.get(2)?,
+            reason: row.get(3)?,
+        })
+    })?;
+    entries.collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> LoginConfig {
+        LoginConfig {
+            account: LockoutPolicy {
+                free_attempts: 2,
+                max_backoff_secs: 8,
+                lockout_after: 6,
+                lockout_secs: 600,
+            },
+            ..LoginConfig::default()
+        }
+    }
+
+    #[test]
+    fn delay_backs_off_then_locks_out() {
+        let policy = config().account;
+        let delays: Vec<u64> = (0..=7).map(|failures| policy.delay(failures)).collect();
+        assert_eq!(delays, [0, 0, 0, 1, 2, 4, 600, 600]);
+
+        let capped = LockoutPolicy {
+            lockout_after: u32::MAX,
+            ..policy
+        };
+        assert_eq!(capped.delay(5), 4);
+        assert_eq!(capped.delay(6), 8);
+        assert_eq!(capped.delay(1000), 8);
+    }
+
+    #[test]
+    fn failures_are_counted_per_account_and_address() {
+        let conn = Connection::open_in_memory().unwrap();
+        create_lockout_tables(&conn).unwrap();
+        let config = config();
+        let now = 1_000_000;
+
+        for _ in 0..3 {
+            record_login_failure(
+                &conn,
+                &config,
+                "A@x.io",
+                Some("10.0.0.1"),
+                "wrong password",
+                now,
+            )
+            .unwrap();
+        }
+        assert_eq!(
+            login_retry_after(&conn, "a@x.io ", None, now).unwrap(),
+            Some(1)
+        );
+        assert_eq!(
+            login_retry_after(&conn, "a@x.io", None, now + 1).unwrap(),
+            None
+        );
+        // The address is below its own limit
+        assert_eq!(
+            login_retry_after(&conn, "b@x.io", Some("10.0.0.1"), now).unwrap(),
+            None
+        );
+        assert_eq!(
+            get_failed_logins(&conn, Some("a@x.io"), 10).unwrap().len(),
+            3
+        );
+
+        // Old failures are forgotten
+        let later = now + config.forget_after_secs;
+        assert_eq!(
+            record_login_failure(&conn, &config, "a@x.io", None, "wrong password", later).unwrap(),
+            0
+        );
+
+        assert!(reset_lockout(&conn, LockoutScope::Ip, "10.0.0.1").unwrap());
+        clear_login_failures(&conn, "a@x.io").unwrap();
+        assert!(list_lockouts(&conn).unwrap().is_empty());
+        assert_eq!(get_failed_logins(&conn, None, 10).unwrap().len(), 4);
+    }
+}
@@ -1,6 +1,17 @@
 pub mod account_manager;
+pub mod api_token_manager;
+pub mod config;
 
 pub mod file_manager;
 pub mod global_manager;
+pub mod invitation_manager;
+pub mod lockout_manager;
 pub mod pw_manager;
+pub mod rotation_manager;
+#[cfg(test)]
+pub mod test_support;
+pub mod token;
+pub mod totp_manager;
+pub mod transfer_manager;
 pub mod vault_manager;
+pub mod verification_manager;
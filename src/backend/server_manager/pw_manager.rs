@@ -1,13 +1,11 @@
-use crate::backend::aes_keys::crypted_key::encrypt;
-use crate::backend::aes_keys::decrypted_key::decrypt;
+use crate::backend::aes_keys::container::{self, Kdf};
 use crate::backend::VAULTS_DATA;
-use actix_web::{web, HttpResponse, Responder};
+use actix_web::{web, HttpRequest, HttpResponse, Responder};
 use serde::{Deserialize, Serialize};
 use std::fs;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
-use crate::backend::server_manager::account_manager::JWT;
-use crate::backend::server_manager::global_manager::{ROOT, SESSION_CACHE};
+use crate::backend::server_manager::global_manager::{get_user_from_cookie, ROOT, SESSION_CACHE};
 
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct PasswordEntry {
@@ -17,51 +15,85 @@ pub struct PasswordEntry {
     pub password: String,
 }
 
-fn get_passwords_path(user_id: u32) -> PathBuf {
+/// Returns the path of the encrypted password list of a user.
+pub fn get_passwords_path(user_id: u32) -> PathBuf {
     let mut path = ROOT.clone();
     path.push(format!("{}{}password.json", VAULTS_DATA, user_id));
     path
 }
 
-pub async fn get_user_passwords(user: web::Json<JWT>) -> impl Responder {
+/// Reads and decrypts the password list, a missing file is an empty list.
+/// A legacy file is written back as a container.
+fn read_passwords(
+    path: &Path,
+    user_key: &[u8],
+    user_kdf: Kdf,
+) -> Result<Vec<PasswordEntry>, String> {
+    let encrypted_data = match fs::read(path) {
+        Ok(data) => data,
+        Err(_) => return Ok(Vec::new()),
+    };
+    let decrypted_data = container::open(&encrypted_data, user_key)?;
+    let passwords = serde_json::from_slice(&decrypted_data).map_err(|e| e.to_string())?;
+    if container::is_legacy(&encrypted_data) {
+        if let Err(e) = fs::write(path, container::seal(&decrypted_data, user_key, user_kdf)) {
+            eprintln!("Failed to upgrade {}: {}", path.display(), e);
+        }
+    }
+    Ok(passwords)
+}
+
+pub async fn get_user_passwords(req: HttpRequest) -> impl Responder {
+    let user = match get_user_from_cookie(&req) {
+        Some(jwt) => jwt,
+        None => return HttpResponse::Unauthorized().body("Invalid session"),
+    };
     let path = get_passwords_path(user.id);
 
     if let Some(session) = SESSION_CACHE.get(&user.session_id) {
         let session = session.lock().unwrap();
-        if let Ok(encrypted_data) = fs::read(&path) {
-            if let Ok(decrypted_data) = decrypt(&encrypted_data, session.user_key.as_slice()) {
-                if let Ok(passwords) = serde_json::from_slice::<Vec<PasswordEntry>>(&decrypted_data)
-                {
-                    return HttpResponse::Ok().json(passwords);
-                }
+        if session.user_key.is_empty() {
+            return HttpResponse::Unauthorized().body("Invalid session");
+        }
+        match read_passwords(&path, session.user_key.as_bytes(), session.user_kdf) {
+            Ok(passwords) => HttpResponse::Ok().json(passwords),
+            Err(e) => {
+                eprintln!("Password file of user {} failed to decrypt: {}", user.id, e);
+                HttpResponse::InternalServerError().body("Failed to decrypt passwords")
             }
         }
-        HttpResponse::Ok().json(Vec::<PasswordEntry>::new())
     } else {
         HttpResponse::Unauthorized().body("Invalid session")
     }
 }
 
-pub async fn add_user_password(data: web::Json<(JWT, PasswordEntry)>) -> impl Responder {
-    let (jwt, new_entry) = data.into_inner();
+pub async fn add_user_password(req: HttpRequest, data: web::Json<PasswordEntry>) -> impl Responder {
+    let jwt = match get_user_from_cookie(&req) {
+        Some(jwt) => jwt,
+        None => return HttpResponse::Unauthorized().body("Invalid session"),
+    };
+    let new_entry = data.into_inner();
     let path = get_passwords_path(jwt.id);
 
     if let Some(session) = SESSION_CACHE.get(&jwt.session_id) {
         let session = session.lock().unwrap();
-        let mut passwords: Vec<PasswordEntry> = if let Ok(encrypted_data) = fs::read(&path) {
-            if let Ok(decrypted_data) = decrypt(&encrypted_data, session.user_key.as_slice()) {
-                serde_json::from_slice(&decrypted_data).unwrap_or_default()
-            } else {
-                Vec::new()
-            }
-        } else {
-            Vec::new()
-        };
+        if session.user_key.is_empty() {
+            return HttpResponse::Unauthorized().body("Invalid session");
+        }
+        let mut passwords =
+            match read_passwords(&path, session.user_key.as_bytes(), session.user_kdf) {
+                Ok(passwords) => passwords,
+                Err(e) => {
+                    eprintln!("Password file of user {} failed to decrypt: {}", jwt.id, e);
+                    return HttpResponse::InternalServerError().body("Failed to decrypt passwords");
+                }
+            };
 
         passwords.push(new_entry);
 
         let json_data = serde_json::to_vec(&passwords).unwrap();
-        let encrypted_data = encrypt(&json_data, session.user_key.as_slice());
+        let encrypted_data =
+            container::seal(&json_data, session.user_key.as_bytes(), session.user_kdf);
         fs::write(&path, &encrypted_data).unwrap();
         HttpResponse::Ok().body("Password added")
     } else {
@@ -69,26 +101,36 @@ pub async fn add_user_password(data: web::Json<(JWT, PasswordEntry)>) -> impl Re
     }
 }
 
-pub async fn remove_user_password(data: web::Json<(JWT, PasswordEntry)>) -> impl Responder {
-    let (jwt, password_to_remove) = data.into_inner();
+pub async fn remove_user_password(
+    req: HttpRequest,
+    data: web::Json<PasswordEntry>,
+) -> impl Responder {
+    let jwt = match get_user_from_cookie(&req) {
+        Some(jwt) => jwt,
+        None => return HttpResponse::Unauthorized().body("Invalid session"),
+    };
+    let password_to_remove = data.into_inner();
     let path = get_passwords_path(jwt.id);
 
     if let Some(session) = SESSION_CACHE.get(&jwt.session_id) {
         let session = session.lock().unwrap();
-        let mut passwords: Vec<PasswordEntry> = if let Ok(encrypted_data) = fs::read(&path) {
-            if let Ok(decrypted_data) = decrypt(&encrypted_data, session.user_key.as_slice()) {
-                serde_json::from_slice(&decrypted_data).unwrap_or_default()
-            } else {
-                Vec::new()
-            }
-        } else {
-            Vec::new()
-        };
+        if session.user_key.is_empty() {
+            return HttpResponse::Unauthorized().body("Invalid session");
+        }
+        let mut passwords =
+            match read_passwords(&path, session.user_key.as_bytes(), session.user_kdf) {
+                Ok(passwords) => passwords,
+                Err(e) => {
+                    eprintln!("Password file of user {} failed to decrypt: {}", jwt.id, e);
+                    return HttpResponse::InternalServerError().body("Failed to decrypt passwords");
+                }
+            };
 
         passwords.retain(|entry| entry != &password_to_remove);
 
         let json_data = serde_json::to_vec(&passwords).unwrap();
-        let encrypted_data = encrypt(&json_data, session.user_key.as_slice());
+        let encrypted_data =
+            container::seal(&json_data, session.user_key.as_bytes(), session.user_kdf);
         fs::write(&path, &encrypted_data).unwrap();
         HttpResponse::Ok().body("Password removed")
     } else {
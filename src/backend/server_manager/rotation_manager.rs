@@ -0,0 +1,425 @@
+// Rotation of the vault key, e.g. after a member was removed
+//
+// A removed member may have kept the vault key. Rotating moves the vault to a
+// fresh key that the old one does not lead to: the permissions, the file tree
+// and every stored file are re-encrypted in the background while the vault is
+// closed. The new key is left to every member, sealed to their public key, and
+// they pick it up on their next load. Accounts without a keypair yet wait for
+// a member to load the vault after they got one, see `save_key_handoff`.
+// Pending invitations are sealed again under it.
+use crate::backend::aes_keys::vault_key::VaultKey;
+use crate::backend::server_manager::account_manager::{get_public_key, get_user_vaults, Perms};
+use crate::backend::server_manager::global_manager::{
+    get_user_from_cookie, CONNECTION, ROTATION_CACHE, VAULTS_CACHE,
+};
+use crate::backend::server_manager::invitation_manager::reseal_vault_invitations;
+use crate::backend::server_manager::token;
+use crate::backend::server_manager::vault_manager::{load_vault, VaultInfo, VaultsCache};
+use actix_web::{web, HttpRequest, HttpResponse, Responder};
+use serde::Serialize;
+use serde_json::json;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Error of `load_vault` while the key of the vault is rotated.
+pub const ROTATION_IN_PROGRESS: &str = "Key rotation in progress";
+
+// Longest wait for the requests still using the old key
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(60);
+
+/**
+ * Stage of a key rotation.
+ */
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum RotationState {
+    Running,
+    Done,
+    Failed,
+}
+
+/**
+ * Progress of the key rotation of a vault.
+ */
+#[derive(Serialize, Clone, Debug)]
+pub struct RotationStatus {
+    pub state: RotationState,
+    /// Files re-encrypted so far, out of `total`
+    pub done: usize,
+    pub total: usize,
+    pub started_by: u32,
+    pub started_at: u64,
+    pub error: Option<String>,
+}
+
+/**
+ * Tells whether the key of a vault is being rotated.
+ *
+ * @param vault_name - The internal name of the vault.
+ * @return bool - True while the rotation runs.
+ */
+pub fn is_rotating(vault_name: &str) -> bool {
+    ROTATION_CACHE
+        .get(vault_name)
+        .is_some_and(|status| status.lock().unwrap().state == RotationState::Running)
+}
+
+/**
+ * Starts rotating the key of a loaded vault in the background.
+ *
+ * The vault is taken out of `VAULTS_CACHE` and its key wiped, it cannot be
+ * loaded again until the rotation ends; requests holding a lease on the key
+ * are waited for, and the rotation is abandoned if they do not finish in time.
+ *
+ * @param vault - The loaded vault, locked by the caller.
+ * @param admin_id - The ID of the member starting the rotation.
+ * @param public_keys - Every member with their public key, None for accounts without a keypair.
+ * @return Result<(), String> - An error message if a rotation of the vault is already running.
+ */
+pub fn start_key_rotation(
+    vault: &mut VaultsCache,
+    admin_id: u32,
+    public_keys: Vec<(u32, Option<Vec<u8>>)>,
+) -> Result<(), String> {
+    let name = vault.info.get_name();
+    // The caller holds the vault, a second Admin waits for it and sees this one
+    if is_rotating(&name) {
+        return Err(ROTATION_IN_PROGRESS.to_string());
+    }
+    let status = Arc::new(Mutex::new(RotationStatus {
+        state: RotationState::Running,
+        done: 0,
+        total: 0,
+        started_by: admin_id,
+        started_at: token::now(),
+        error: None,
+    }));
+    ROTATION_CACHE.insert(name.clone(), status.clone());
+    VAULTS_CACHE.invalidate(&name);
+
+    // Requests that get the vault after this find no key to use
+    let info = vault.info.clone();
+    let old_key = vault.vault_key.clone();
+    vault.vault_key.wipe();
+    let key_users = vault.key_users.clone();
+    thread::spawn(move || {
+        let result = rotate(
+            &info,
+            &old_key,
+            &key_users,
+            &public_keys,
+            &status,
+            DRAIN_TIMEOUT,
+        );
+        let mut status = status.lock().unwrap();
+        match result {
+            Ok(()) => status.state = RotationState::Done,
+            Err(e) => {
+                eprintln!("Key rotation of vault {} failed: {}", name, e);
+                status.state = RotationState::Failed;
+                status.error = Some(e);
+            }
+        }
+    });
+    Ok(())
+}
+
+// Re-encrypts the vault under a new key and leaves it to every member
+fn rotate(
+    info: &VaultInfo,
+    old_key: &VaultKey,
+    key_users: &AtomicUsize,
+    public_keys: &[(u32, Option<Vec<u8>>)],
+    status: &Arc<Mutex<RotationStatus>>,
+    drain_timeout: Duration,
+) -> Result<(), String> {
+    // Requests that leased the key before it was wiped finish with it
+    let deadline = Instant::now() + drain_timeout;
+    while key_users.load(Ordering::SeqCst) > 0 {
+        if Instant::now() > deadline {
+            return Err("The vault is still in use, the rotation was abandoned".to_string());
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+
+    let perms = info.get_perms(old_key)?;
+    let new_key = VaultKey::generate();
+    let name = info.get_name();
+    let result = info.rotate_key(
+        old_key,
+        &new_key,
+        || {
+            for (id, public_key) in public_keys {
+                if perms.contains_key(id) {
+                    info.save_key_handoff(&new_key, *id, public_key.as_deref())?;
+                }
+            }
+            info.remove_stale_handoffs(&perms)?;
+            let conn = CONNECTION.lock().unwrap();
+            reseal_vault_invitations(&conn, info, &new_key).map_err(|e| e.to_string())
+        },
+        |done, total| {
+            {
+                let mut status = status.lock().unwrap();
+                status.done = done;
+                status.total = total;
+            }
+            // Keeps the entry from expiring while a large vault is re-encrypted
+            ROTATION_CACHE.insert(name.clone(), status.clone());
+        },
+    );
+    // A swap rolled back leaves the vault on the old key, and so must the
+    // invitations; the handoffs are ignored once they do not open the vault
+    if result.is_err() && !info.has_pending_swap() {
+        let conn = CONNECTION.lock().unwrap();
+        if let Err(e) = reseal_vault_invitations(&conn, info, old_key) {
+            eprintln!("Invitations of vault {} failed to reseal: {}", name, e);
+        }
+    }
+    result
+}
+
+/**
+ * Endpoint rotating the key of a vault, open to its Admins. The rotation runs
+ * in the background, its progress is read from `rotation_status_query`.
+ *
+ * @param req - The HTTP request carrying the session token.
+ * @param vault_id - The internal name of the vault.
+ * @return An HTTP response indicating whether the rotation started.
+ */
+pub async fn rotate_key_query(req: HttpRequest, vault_id: web::Path<String>) -> impl Responder {
+    let jwt = match get_user_from_cookie(&req) {
+        Some(jwt) => jwt,
+        None => return HttpResponse::Unauthorized().body("Invalid session"),
+    };
+    // The vault comes from the membership of the caller, not from the client
+    let vault_info = {
+        let conn = CONNECTION.lock().unwrap();
+        get_user_vaults(&conn, jwt.id).map(|vaults| {
+            vaults
+                .into_iter()
+                .find(|vault| vault.get_name() == *vault_id)
+        })
+    };
+    let vault_info = match vault_info {
+        Ok(Some(vault_info)) => vault_info,
+        Ok(None) => return HttpResponse::NotFound().body("Unknown vault"),
+        Err(e) => {
+            eprintln!("Failed to list the vaults of user {}: {}", jwt.id, e);
+            return HttpResponse::InternalServerError().body("Failed to get vault");
+        }
+    };
+    let name = vault_info.get_name();
+    if is_rotating(&name) {
+        return HttpResponse::Conflict().body(ROTATION_IN_PROGRESS);
+    }
+    match load_vault(req, web::Json(vault_info)).await {
+        Ok(_) => {}
+        Err(ROTATION_IN_PROGRESS) => return HttpResponse::Conflict().body(ROTATION_IN_PROGRESS),
+        Err(_) => return HttpResponse::InternalServerError().body("Failed to get vault"),
+    }
+
+    let conn = CONNECTION.lock().unwrap();
+    let cache = match VAULTS_CACHE.get(&name) {
+        Some(cache) => cache,
+        None => return HttpResponse::InternalServerError().body("Failed to get vault"),
+    };
+    let mut vault = cache.lock().unwrap();
+    if vault
+        .perms
+        .get(&jwt.id)
+        .is_none_or(|perm| perm < &Perms::Admin)
+    {
+        return HttpResponse::Unauthorized()
+            .body("You do not have permission to rotate the key of this vault");
+    }
+
+    let public_keys = vault
+        .perms
+        .keys()
+        .map(|id| get_public_key(&conn, *id).map(|public_key| (*id, public_key)))
+        .collect::<rusqlite::Result<Vec<_>>>();
+    let public_keys = match public_keys {
+        Ok(public_keys) => public_keys,
+        Err(e) => {
+            eprintln!("Failed to read the public keys of vault {}: {}", name, e);
+            return HttpResponse::InternalServerError().body("Failed to rotate the key");
+        }
+    };
+    drop(conn);
+
+    match start_key_rotation(&mut vault, jwt.id, public_keys) {
+        Ok(()) => HttpResponse::Accepted().json(json!({
+            "success": true,
+            "message": "Key rotation started, the vault is closed until it ends",
+            "status": format!("/vaults/{}/rotate-key", name),
+        })),
+        Err(e) => HttpResponse::Conflict().body(e),
+    }
+}
+
+/**
+ * Endpoint reporting the progress of the last key rotation of a vault to its members.
+ *
+ * @param req - The HTTP request carrying the session token.
+ * @param vault_id - The internal name of the vault.
+ * @return An HTTP response with the status of the rotation.
+ */
+pub async fn rotation_status_query(
+    req: HttpRequest,
+    vault_id: web::Path<String>,
+) -> impl Responder {
+    let jwt = match get_user_from_cookie(&req) {
+        Some(jwt) => jwt,
+        None => return HttpResponse::Unauthorized().body("Invalid session"),
+    };
+    let member = {
+        let conn = CONNECTION.lock().unwrap();
+        get_user_vaults(&conn, jwt.id)
+            .map(|vaults| vaults.iter().any(|vault| vault.get_name() == *vault_id))
+    };
+    match member {
+        Ok(true) => {}
+        Ok(false) => return HttpResponse::NotFound().body("Unknown vault"),
+        Err(e) => {
+            eprintln!("Failed to list the vaults of user {}: {}", jwt.id, e);
+            return HttpResponse::InternalServerError().body("Failed to get vault");
+        }
+    }
+
+    match ROTATION_CACHE.get(vault_id.as_str()) {
+        Some(status) => HttpResponse::Ok().json(&*status.lock().unwrap()),
+        None => HttpResponse::NotFound().body("No key rotation for this vault"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::aes_keys::keypair::UserKeypair;
+    use crate::backend::server_manager::account_manager::set_user_keypair;
+    use crate::backend::server_manager::test_support::{
+        create_test_vault, create_user, status, TestUser,
+    };
+    use actix_web::http::StatusCode;
+
+    async fn rotate_as(user: &TestUser, vault: &VaultInfo) -> StatusCode {
+        let req = user.request();
+        status(
+            rotate_key_query(req.clone(), web::Path::from(vault.get_name())).await,
+            &req,
+        )
+    }
+
+    #[actix_web::test]
+    async fn only_admins_of_the_vault_rotate_its_key() {
+        let owner = create_user("rotate-owner@example.com", true);
+        let reader = create_user("rotate-reader@example.com", true);
+        let stranger = create_user("rotate-stranger@example.com", true);
+        let (vault, _) = create_test_vault(&owner, &[(&reader, Perms::Read)]);
+        // A stranger owning a vault of their own cannot reach this one
+        create_test_vault(&stranger, &[]);
+
+        assert_eq!(rotate_as(&stranger, &vault).await, StatusCode::NOT_FOUND);
+        assert_eq!(rotate_as(&reader, &vault).await, StatusCode::UNAUTHORIZED);
+        assert!(!is_rotating(&vault.get_name()));
+    }
+
+    #[actix_web::test]
+    async fn a_rotation_hands_the_new_key_to_every_member() {
+        let owner = create_user("rotate-done@example.com", true);
+        let member = create_user("rotate-member@example.com", true);
+        let (vault, old_key) = create_test_vault(&owner, &[(&member, Perms::Write)]);
+        let name = vault.get_name();
+        // The owner logged in before keypairs existed
+        {
+            let conn = CONNECTION.lock().unwrap();
+            set_user_keypair(
+                &conn,
+                member.id,
+                &UserKeypair::generate(),
+                member.user_key.as_bytes(),
+            )
+            .unwrap();
+        }
+
+        assert_eq!(rotate_as(&owner, &vault).await, StatusCode::ACCEPTED);
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while is_rotating(&name) {
+            assert!(Instant::now() < deadline, "rotation still running");
+            thread::sleep(Duration::from_millis(20));
+        }
+        assert_eq!(
+            ROTATION_CACHE.get(&name).unwrap().lock().unwrap().state,
+            RotationState::Done
+        );
+
+        assert!(load_vault(member.request(), web::Json(vault.clone()))
+            .await
+            .is_ok());
+        let new_key = VAULTS_CACHE
+            .get(&name)
+            .unwrap()
+            .lock()
+            .unwrap()
+            .vault_key
+            .clone();
+        assert!(new_key != old_key);
+        assert!(vault.get_perms(&new_key).is_ok());
+        assert!(vault.get_perms(&old_key).is_err());
+        assert!(!vault.has_key_handoff(member.id));
+        assert_eq!(vault.get_unsealed_handoffs(), [owner.id]);
+
+        // Their next login gives them a keypair, the next load seals the key to it
+        {
+            let conn = CONNECTION.lock().unwrap();
+            set_user_keypair(
+                &conn,
+                owner.id,
+                &UserKeypair::generate(),
+                owner.user_key.as_bytes(),
+            )
+            .unwrap();
+        }
+        assert!(load_vault(member.request(), web::Json(vault.clone()))
+            .await
+            .is_ok());
+        assert!(vault.get_unsealed_handoffs().is_empty());
+        VAULTS_CACHE.invalidate(&name);
+        assert!(load_vault(owner.request(), web::Json(vault.clone()))
+            .await
+            .is_ok());
+        assert!(!vault.has_key_handoff(owner.id));
+    }
+
+    #[test]
+    fn a_rotation_is_abandoned_while_the_key_is_leased() {
+        let owner = create_user("rotate-leased@example.com", true);
+        let (vault, old_key) = create_test_vault(&owner, &[]);
+        let status = Arc::new(Mutex::new(RotationStatus {
+            state: RotationState::Running,
+            done: 0,
+            total: 0,
+            started_by: owner.id,
+            started_at: token::now(),
+            error: None,
+        }));
+
+        let result = rotate(
+            &vault,
+            &old_key,
+            &AtomicUsize::new(1),
+            &[(owner.id, None)],
+            &status,
+            Duration::from_millis(200),
+        );
+
+        assert!(result.is_err());
+        assert!(!vault.has_pending_swap());
+        assert!(!vault.has_key_handoff(owner.id));
+        assert!(vault.get_perms(&old_key).is_ok());
+        assert!(vault.get_file_tree(&old_key).is_ok());
+    }
+}
@@ -0,0 +1,270 @@
+// Fixtures for the tests of the endpoints: a server rooted in a temporary
+// directory (see `global_manager::ROOT`), accounts with an open session and
+// vaults on disk. Tests run in parallel against the same database, so each
+// one uses addresses of its own.
+use crate::backend::aes_keys::container::Kdf;
+use crate::backend::aes_keys::keypair::UserKeypair;
+use crate::backend::aes_keys::keys_password::UserKdf;
+use crate::backend::aes_keys::recovery_key::RecoveryKey;
+use crate::backend::aes_keys::secret_key::SecretKey;
+use crate::backend::aes_keys::vault_key::VaultKey;
+use crate::backend::server_manager::account_manager::{
+    self, login_user_query, set_recovery_key, set_user_keypair, Perms, Session, SessionClient, JWT,
+};
+use crate::backend::server_manager::file_manager::file_tree::Directory;
+use crate::backend::server_manager::global_manager::{
+    init_server_config, issue_token, CONNECTION, SERVER_CONFIG, SESSION_CACHE, TOKEN_KEY,
+};
+use crate::backend::server_manager::token;
+use crate::backend::server_manager::vault_manager::{create_vault, VaultInfo};
+use actix_web::body::to_bytes;
+use actix_web::cookie::Cookie;
+use actix_web::http::{header, StatusCode};
+use actix_web::test::TestRequest;
+use actix_web::{web, HttpRequest, Responder};
+use rusqlite::params;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, Once};
+use uuid::Uuid;
+
+static INIT: Once = Once::new();
+
+// Keeps the vaults of one user apart, their name comes from their date
+static NEXT_DATE: AtomicU64 = AtomicU64::new(0);
+
+/// Writes the configuration and the keys, and creates the database.
+pub fn init_server() {
+    INIT.call_once(init_server_config);
+}
+
+/// A user with an open session.
+pub struct TestUser {
+    pub id: u32,
+    pub email: String,
+    pub user_key: SecretKey,
+    pub session_id: String,
+    cookie: Cookie<'static>,
+}
+
+impl TestUser {
+    /// Builds a request carrying the session of the user.
+    pub fn request(&self) -> HttpRequest {
+        TestRequest::default()
+            .cookie(self.cookie.clone())
+            .to_http_request()
+    }
+
+    /**
+     * The user behind a session token set by a login.
+     *
+     * @param cookie - The `user_token` cookie of the login response.
+     * @return TestUser - The logged in user, with the key of the session.
+     */
+    pub fn from_cookie(cookie: Cookie<'static>) -> TestUser {
+        let jwt = TOKEN_KEY.verify(cookie.value(), token::now()).unwrap();
+        let user_key = SESSION_CACHE
+            .get(&jwt.session_id)
+            .unwrap()
+            .lock()
+            .unwrap()
+            .user_key
+            .clone();
+        TestUser {
+            id: jwt.id,
+            email: jwt.email,
+            user_key,
+            session_id: jwt.session_id,
+            cookie,
+        }
+    }
+
+    /// The session of the user, as the server holds it.
+    pub fn session(&self) -> Arc<Mutex<Session>> {
+        SESSION_CACHE.get(&self.session_id).unwrap()
+    }
+}
+
+/// An account as `create_user_query` makes it, its email address confirmed.
+pub struct TestAccount {
+    pub id: u32,
+    pub email: String,
+    pub password: String,
+    pub recovery_key: RecoveryKey,
+}
+
+/**
+ * Creates an account with a password, a keypair and a recovery key.
+ *
+ * @param email - The canonical email of the account, unique to the test.
+ * @param password - Its password.
+ * @return TestAccount - The account, logged out.
+ */
+pub fn create_account(email: &str, password: &str) -> TestAccount {
+    init_server();
+    let user_kdf = UserKdf::generate(SERVER_CONFIG.kdf);
+    let user_key = user_kdf.derive(password).unwrap();
+    let recovery_key = RecoveryKey::generate();
+    // The lowest bcrypt cost, passwords are checked often
+    let hash_pw = bcrypt::hash(password, 4).unwrap();
+
+    let conn = CONNECTION.lock().unwrap();
+    let id = account_manager::create_user(&conn, email, &hash_pw, &user_kdf).unwrap();
+    set_recovery_key(&conn, id, &recovery_key, user_key.as_bytes()).unwrap();
+    set_user_keypair(&conn, id, &UserKeypair::generate(), user_key.as_bytes()).unwrap();
+    conn.execute(
+        "UPDATE users SET email_verified = 1 WHERE id = ?",
+        params![id],
+    )
+    .unwrap();
+    TestAccount {
+        id,
+        email: email.to_string(),
+        password: password.to_string(),
+        recovery_key,
+    }
+}
+
+/// Response of an endpoint, with its body as JSON and the session cookie it set.
+pub struct TestResponse {
+    pub status: StatusCode,
+    /// Bodies that are not JSON are read as a JSON string
+    pub body: Value,
+    pub cookie: Option<Cookie<'static>>,
+}
+
+impl TestResponse {
+    /// The user logged in by the response.
+    pub fn user(&self) -> TestUser {
+        TestUser::from_cookie(self.cookie.clone().expect("no session cookie"))
+    }
+}
+
+/**
+ * Runs the response of an endpoint.
+ *
+ * @param responder - What the endpoint returned.
+ * @param req - The request it answered.
+ * @return TestResponse - The status, the body and the session cookie.
+ */
+pub async fn respond(responder: impl Responder, req: &HttpRequest) -> TestResponse {
+    let response = responder.respond_to(req).map_into_boxed_body();
+    let status = response.status();
+    let cookie = response
+        .cookies()
+        .find(|cookie| cookie.name() == "user_token" && !cookie.value().is_empty())
+        .map(|cookie| cookie.into_owned());
+    let body = to_bytes(response.into_body())
+        .await
+        .ok()
+        .unwrap_or_default();
+    let body = serde_json::from_slice(&body)
+        .unwrap_or_else(|_| Value::String(String::from_utf8_lossy(&body).into_owned()));
+    TestResponse {
+        status,
+        body,
+        cookie,
+    }
+}
+
+/**
+ * Logs in through `login_user_query`.
+ *
+ * @param email - The email of the account.
+ * @param password - The password tried.
+ * @param client - The device logging in, its address and user agent.
+ * @return TestResponse - The response of the login.
+ */
+pub async fn log_in(email: &str, password: &str, client: &SessionClient) -> TestResponse {
+    let mut req = TestRequest::default();
+    if let Some(ip) = &client.ip {
+        req = req.peer_addr(format!("{}:443", ip).parse().unwrap());
+    }
+    if let Some(user_agent) = &client.user_agent {
+        req = req.insert_header((header::USER_AGENT, user_agent.as_str()));
+    }
+    let req = req.to_http_request();
+    let form = serde_json::from_value(json!({ "username": email, "password": password })).unwrap();
+    respond(login_user_query(req.clone(), web::Json(form)).await, &req).await
+}
+
+/**
+ * Creates an account and opens a session for it.
+ *
+ * @param email - The canonical email of the account, unique to the test.
+ * @param verified - Whether the email address is confirmed.
+ * @return TestUser - The logged in user.
+ */
+pub fn create_user(email: &str, verified: bool) -> TestUser {
+    init_server();
+    let id = {
+        let conn = CONNECTION.lock().unwrap();
+        conn.execute(
+            "INSERT INTO users (email, hash_password, email_verified) VALUES (?, '', ?)",
+            params![email, verified],
+        )
+        .unwrap();
+        conn.last_insert_rowid() as u32
+    };
+    let user_key = SecretKey::new(VaultKey::generate().as_bytes().to_vec());
+    let session_id = Uuid::new_v4().to_string();
+    SESSION_CACHE.insert(
+        session_id.clone(),
+        Arc::new(Mutex::new(Session::new(
+            id,
+            "",
+            user_key.clone(),
+            Kdf::None,
+            SessionClient::default(),
+        ))),
+    );
+    let jwt = JWT::new(&session_id, id, email);
+    TestUser {
+        id,
+        email: email.to_string(),
+        user_key,
+        session_id,
+        cookie: Cookie::new("user_token", issue_token(&jwt)),
+    }
+}
+
+/**
+ * Creates a vault on disk and in the database, as `create_vault_query` does.
+ *
+ * @param owner - Its Creator.
+ * @param members - The other members, with their role.
+ * @return (VaultInfo, VaultKey) - The vault and its key.
+ */
+pub fn create_test_vault(
+    owner: &TestUser,
+    members: &[(&TestUser, Perms)],
+) -> (VaultInfo, VaultKey) {
+    let date = token::now() + NEXT_DATE.fetch_add(1, Ordering::SeqCst);
+    let info = VaultInfo::new(owner.id, "tests", date);
+    info.create_path().unwrap();
+    let vault_key = VaultKey::generate();
+
+    let mut perms = HashMap::new();
+    perms.insert(owner.id, Perms::Creator);
+    for (member, perm) in members {
+        perms.insert(member.id, perm.clone());
+    }
+    info.set_perms(&vault_key, &perms).unwrap();
+    info.save_file_tree(&vault_key, Directory::new("root".to_string()))
+        .unwrap();
+
+    let conn = CONNECTION.lock().unwrap();
+    for user in std::iter::once(owner).chain(members.iter().map(|(member, _)| *member)) {
+        let user_kdf = user.session().lock().unwrap().user_kdf;
+        info.save_key(&vault_key, user.user_key.as_bytes(), user_kdf, user.id)
+            .unwrap();
+        create_vault(&conn, &info, user.id).unwrap();
+    }
+    (info, vault_key)
+}
+
+/// Status of the response of an endpoint.
+pub fn status(responder: impl Responder, req: &HttpRequest) -> StatusCode {
+    responder.respond_to(req).status()
+}
@@ -0,0 +1,264 @@
+// Signed session tokens (JWT with HS256, RFC 7519)
+//
+// The `user_token` cookie holds `header.claims.signature`, each part in
+// unpadded base64url. The claims are the fields of `JWT` plus `iat` and
+// `exp`, the signature is HMAC-SHA256 under a 256-bit server secret that is
+// generated on first start. A client can read its token but any change to it
+// invalidates the signature.
+use crate::backend::server_manager::account_manager::JWT;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use ring::hmac;
+use ring::rand::{SecureRandom, SystemRandom};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+use zeroize::Zeroizing;
+
+// Only algorithm accepted, a token naming any other one (e.g. "none") is rejected
+const ALGORITHM: &str = "HS256";
+// Size of the server secrets in bytes
+const SECRET_LEN: usize = 32;
+// Tolerated clock difference for tokens issued "in the future", in seconds
+const CLOCK_SKEW: u64 = 60;
+
+#[derive(Serialize, Deserialize)]
+struct Header {
+    alg: String,
+    typ: String,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Claims {
+    #[serde(flatten)]
+    jwt: JWT,
+    iat: u64,
+    exp: u64,
+}
+
+/// Returns the current time in seconds since the UNIX epoch.
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or(0)
+}
+
+/**
+ * Key signing and verifying the session tokens.
+ */
+pub struct TokenKey(hmac::Key);
+
+impl TokenKey {
+    /**
+     * Builds a key from a secret.
+     *
+     * @param secret - The secret bytes.
+     * @return A new TokenKey instance.
+     */
+    pub fn new(secret: &[u8]) -> Self {
+        Self(hmac::Key::new(hmac::HMAC_SHA256, secret))
+    }
+
+    /**
+     * Reads the secret from disk, generating it on first use.
+     *
+     * @param path - The file holding the secret.
+     * @return Result<Self, String> - The key or an error message.
+     */
+    pub fn load_or_create(path: &Path) -> Result<Self, String> {
+        Ok(Self::new(&load_or_create_secret(path)?))
+    }
+
+    /**
+     * Issues a signed token.
+     *
+     * @param jwt - The content of the token.
+     * @param now - The current time, in seconds since the UNIX epoch.
+     * @param lifetime - Seconds before the token expires.
+     * @return String - The encoded token.
+     */
+    pub fn sign(&self, jwt: &JWT, now: u64, lifetime: u64) -> String {
+        let header = Header {
+            alg: ALGORITHM.to_string(),
+            typ: "JWT".to_string(),
+        };
+        let claims = Claims {
+            jwt: jwt.clone(),
+            iat: now,
+            exp: now.saturating_add(lifetime),
+        };
+        let signing_input = format!(
+            "{}.{}",
+            URL_SAFE_NO_PAD.encode(serde_json::to_vec(&header).unwrap()),
+            URL_SAFE_NO_PAD.encode(serde_json::to_vec(&claims).unwrap()),
+        );
+        let signature = hmac::sign(&self.0, signing_input.as_bytes());
+        format!(
+            "{}.{}",
+            signing_input,
+            URL_SAFE_NO_PAD.encode(signature.as_ref())
+        )
+    }
+
+    /**
+     * Checks the signature and the validity period of a token.
+     *
+     * @param token - The encoded token.
+     * @param now - The current time, in seconds since the UNIX epoch.
+     * @return Result<JWT, String> - The content of the token or why it was rejected.
+     */
+    pub fn verify(&self, token: &str, now: u64) -> Result<JWT, String> {
+        let mut parts = token.split('.');
+        let (header, claims, signature) = match (parts.next(), parts.next(), parts.next()) {
+            (Some(header), Some(claims), Some(signature)) if parts.next().is_none() => {
+                (header, claims, signature)
+            }
+            _ => return Err("Malformed token".to_string()),
+        };
+
+        // The signature is checked before anything in the token is trusted
+        let signature = URL_SAFE_NO_PAD
+            .decode(signature)
+            .map_err(|_| "Malformed signature".to_string())?;
+        let signing_input = &token[..header.len() + 1 + claims.len()];
+        hmac::verify(&self.0, signing_input.as_bytes(), &signature)
+            .map_err(|_| "Invalid signature".to_string())?;
+
+        let header: Header = decode_part(header)?;
+        if header.alg != ALGORITHM {
+            return Err(format!("Unsupported algorithm {}", header.alg));
+        }
+        let claims: Claims = decode_part(claims)?;
+        if claims.exp <= now {
+            return Err("Expired token".to_string());
+        }
+        if claims.iat > now + CLOCK_SKEW {
+            return Err("Token issued in the future".to_string());
+        }
+        Ok(claims.jwt)
+    }
+}
+
+/**
+ * Reads a 256-bit server secret, generating it on first use.
+ *
+ * The file is created readable by the server user only.
+ *
+ * @param path - The file holding the secret.
+ * @return Result<Zeroizing<Vec<u8>>, String> - The secret or an error message.
+ */
+pub fn load_or_create_secret(path: &Path) -> Result<Zeroizing<Vec<u8>>, String> {
+    if path.exists() {
+        let secret = Zeroizing::new(
+            fs::read(path).map_err(|e| format!("Failed to read {:?}: {}", path, e))?,
+        );
+        if secret.len() != SECRET_LEN {
+            return Err(format!("{:?} must hold {} bytes", path, SECRET_LEN));
+        }
+        return Ok(secret);
+    }
+
+    let mut secret = Zeroizing::new(vec![0u8; SECRET_LEN]);
+    SystemRandom::new()
+        .fill(&mut secret)
+        .map_err(|_| format!("Failed to generate the secret {:?}", path))?;
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent).map_err(|e| format!("Failed to create {:?}: {}", parent, e))?;
+    }
+    write_private(path, &secret).map_err(|e| format!("Failed to write {:?}: {}", path, e))?;
+    Ok(secret)
+}
+
+// Decodes one base64url JSON part of a token
+fn decode_part<T: for<'de> Deserialize<'de>>(part: &str) -> Result<T, String> {
+    let bytes = URL_SAFE_NO_PAD
+        .decode(part)
+        .map_err(|_| "Malformed token".to_string())?;
+    serde_json::from_slice(&bytes).map_err(|e| format!("Malformed token: {}", e))
+}
+
+// Writes a file only the server user can read
+#[cfg(unix)]
+fn write_private(path: &Path, content: &[u8]) -> std::io::Result<()> {
+    use std::io::Write;
+    use std::os::unix::fs::OpenOptionsExt;
+    fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(path)?
+        .write_all(content)
+}
+
+#[cfg(not(unix))]
+fn write_private(path: &Path, content: &[u8]) -> std::io::Result<()> {
+    fs::write(path, content)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NOW: u64 = 1_700_000_000;
+
+    fn token(key: &TokenKey) -> String {
+        key.sign(&JWT::new("session", 7, "user@example.com"), NOW, 3600)
+    }
+
+    #[test]
+    fn round_trip() {
+        let key = TokenKey::new(&[1u8; SECRET_LEN]);
+        let jwt = key.verify(&token(&key), NOW + 10).unwrap();
+        assert_eq!(jwt.session_id, "session");
+        assert_eq!(jwt.id, 7);
+        assert_eq!(jwt.email, "user@example.com");
+        assert!(jwt.loaded_vault.is_none());
+    }
+
+    #[test]
+    fn rejects_edited_claims() {
+        let key = TokenKey::new(&[1u8; SECRET_LEN]);
+        let token = token(&key);
+        let parts: Vec<&str> = token.split('.').collect();
+        let claims = String::from_utf8(URL_SAFE_NO_PAD.decode(parts[1]).unwrap()).unwrap();
+        assert!(claims.contains("\"id\":7"));
+        let forged = URL_SAFE_NO_PAD.encode(claims.replace("\"id\":7", "\"id\":1"));
+        let forged = format!("{}.{}.{}", parts[0], forged, parts[2]);
+        assert_eq!(key.verify(&forged, NOW).unwrap_err(), "Invalid signature");
+    }
+
+    #[test]
+    fn rejects_tokens_signed_with_another_key() {
+        let token = token(&TokenKey::new(&[1u8; SECRET_LEN]));
+        assert!(TokenKey::new(&[2u8; SECRET_LEN])
+            .verify(&token, NOW)
+            .is_err());
+    }
+
+    #[test]
+    fn rejects_unsigned_tokens() {
+        let key = TokenKey::new(&[1u8; SECRET_LEN]);
+        let token = token(&key);
+        let parts: Vec<&str> = token.split('.').collect();
+        let header = URL_SAFE_NO_PAD.encode(r#"{"alg":"none","typ":"JWT"}"#);
+        assert!(key
+            .verify(&format!("{}.{}.", header, parts[1]), NOW)
+            .is_err());
+        assert!(key
+            .verify(&format!("{}.{}", parts[0], parts[1]), NOW)
+            .is_err());
+        assert!(key.verify("{\"id\":1}", NOW).is_err());
+    }
+
+    #[test]
+    fn checks_the_validity_period() {
+        let key = TokenKey::new(&[1u8; SECRET_LEN]);
+        let token = token(&key);
+        assert!(key.verify(&token, NOW + 3599).is_ok());
+        assert_eq!(key.verify(&token, NOW + 3600).unwrap_err(), "Expired token");
+        assert!(key.verify(&token, NOW - CLOCK_SKEW).is_ok());
+        assert!(key.verify(&token, NOW - CLOCK_SKEW - 1).is_err());
+    }
+}
@@ -0,0 +1,337 @@
+use crate::backend::auth::base32::base32_encode;
+use crate::backend::auth::qr::QrCode;
+use crate::backend::auth::totp::{self, generate_backup_codes, hash_backup_code};
+use crate::backend::server_manager::global_manager::{get_user_from_cookie, CONNECTION, TOTP_KEY};
+use crate::backend::server_manager::token;
+use actix_web::{web, HttpRequest, HttpResponse, Responder};
+use rusqlite::{params, Connection, OptionalExtension};
+use serde_json::json;
+use zeroize::Zeroizing;
+
+/**
+ * Struct representing a code typed from an authenticator app, or a backup code.
+ */
+#[derive(serde::Deserialize, Debug)]
+pub struct TotpCodeForm {
+    code: String,
+}
+
+// TOTP state of a user: sealed secret, whether it is enabled, last accepted step
+struct TotpRow {
+    secret: Option<Vec<u8>>,
+    enabled: bool,
+    last_step: Option<u64>,
+}
+
+fn get_totp_row(conn: &Connection, user_id: u32) -> rusqlite::Result<Option<TotpRow>> {
+    conn.query_row(
+        "SELECT totp_secret, totp_enabled, totp_last_step FROM users WHERE id = ?",
+        params![user_id],
+        |row| {
+            Ok(TotpRow {
+                secret: row.get(0)?,
+                enabled: row.get(1)?,
+                last_step: row.get::<_, Option<i64>>(2)?.map(|step| step as u64),
+            })
+        },
+    )
+    .optional()
+}
+
+/**
+ * Tells whether a user confirms each login with an authenticator app.
+ *
+ * @param conn - The database connection.
+ * @param user_id - The ID of the user.
+ * @return rusqlite::Result<bool> - True once TOTP was enabled with a valid code.
+ */
+pub fn is_totp_enabled(conn: &Connection, user_id: u32) -> rusqlite::Result<bool> {
+    Ok(get_totp_row(conn, user_id)?.is_some_and(|row| row.enabled))
+}
+
+// Decrypts the secret of a user, enrolled or not yet confirmed
+fn open_secret(user_id: u32, row: &TotpRow) -> Result<Zeroizing<Vec<u8>>, String> {
+    match &row.secret {
+        Some(sealed) => TOTP_KEY.open(user_id, sealed),
+        None => Err("TOTP is not set up".to_string()),
+    }
+}
+
+/**
+ * Checks a code from the authenticator app and records its time step, so
+ * the same code cannot be used twice.
+ *
+ * @param conn - The database connection.
+ * @param user_id - The ID of the user.
+ * @param code - The 6-digit code.
+ * @return Result<bool, String> - Whether the code was accepted, or an error message.
+ */
+fn check_app_code(conn: &Connection, user_id: u32, code: &str) -> Result<bool, String> {
+    let row = match get_totp_row(conn, user_id).map_err(|e| e.to_string())? {
+        Some(row) => row,
+        None => return Ok(false),
+    };
+    let secret = open_secret(user_id, &row)?;
+    let step = match totp::verify(&secret, code, token::now(), row.last_step) {
+        Some(step) => step,
+        None => return Ok(false),
+    };
+
+    // Conditional so two requests racing with the same code cannot both pass
+    let updated = conn
+        .execute(
+            "UPDATE users SET totp_last_step = ?1
+             WHERE id = ?2 AND (totp_last_step IS NULL OR totp_last_step < ?1)",
+            params![step as i64, user_id],
+        )
+        .map_err(|e| e.to_string())?;
+    Ok(updated == 1)
+}
+
+/**
+ * Checks the second factor of a user with TOTP enabled: a code from the
+ * authenticator app, or one of the backup codes, which is then spent.
+ *
+ * @param conn - The database connection.
+ * @param user_id - The ID of the user.
+ * @param code - The code typed by the user.
+ * @return Result<bool, String> - Whether the code was accepted, or an error message.
+ */
+pub fn check_totp_code(conn: &Connection, user_id: u32, code: &str) -> Result<bool, String> {
+    let code = code.trim();
+    if code.len() == totp::DIGITS as usize && code.bytes().all(|c| c.is_ascii_digit()) {
+        return check_app_code(conn, user_id, code);
+    }
+
+    let spent = conn
+        .execute(
+            "DELETE FROM totp_backup_codes WHERE user_id = ? AND code_hash = ?",
+            params![user_id, hash_backup_code(code)],
+        )
+        .map_err(|e| e.to_string())?;
+    if spent == 1 {
+        eprintln!("User {} used a TOTP backup code", user_id);
+    }
+    Ok(spent == 1)
+}
+
+// Replaces the backup codes of a user, returning the new ones in clear; run in a transaction
+fn replace_backup_codes(tx: &Connection, user_id: u32) -> rusqlite::Result<Vec<String>> {
+    let codes = generate_backup_codes();
+    tx.execute(
+        "DELETE FROM totp_backup_codes WHERE user_id = ?",
+        params![user_id],
+    )?;
+    for code in &codes {
+        tx.execute(
+            "INSERT INTO totp_backup_codes (user_id, code_hash) VALUES (?, ?)",
+            params![user_id, hash_backup_code(code)],
+        )?;
+    }
+    Ok(codes)
+}
+
+/**
+ * Endpoint to start enrolling an authenticator app.
+ *
+ * A new secret is drawn and stored, but logins only ask for codes once
+ * `totp_enable_query` received a valid one.
+ *
+ * @param req - The HTTP request carrying the session token.
+ * @return An HTTP response with the secret, the otpauth URI and its QR code as SVG.
+ */
+pub async fn totp_setup_query(req: HttpRequest) -> impl Responder {
+    let jwt = match get_user_from_cookie(&req) {
+        Some(jwt) => jwt,
+        None => return HttpResponse::Unauthorized().body("Invalid session"),
+    };
+
+    let conn = CONNECTION.lock().unwrap();
+    match is_totp_enabled(&conn, jwt.id) {
+        Ok(false) => {}
+        Ok(true) => return HttpResponse::Conflict().body("TOTP is already enabled"),
+        Err(e) => {
+            eprintln!("Failed to read the TOTP state of user {}: {}", jwt.id, e);
+            return HttpResponse::InternalServerError().body("Failed to set up TOTP");
+        }
+    }
+
+    let secret = totp::generate_secret();
+    let uri = totp::otpauth_uri(&secret, &jwt.email);
+    let qr_svg = match QrCode::encode(uri.as_bytes()) {
+        Ok(qr) => qr.to_svg(),
+        Err(e) => {
+            eprintln!("Failed to draw the TOTP QR code: {}", e);
+            return HttpResponse::InternalServerError().body("Failed to set up TOTP");
+        }
+    };
+    if let Err(e) = conn.execute(
+        "UPDATE users SET totp_secret = ?, totp_enabled = 0, totp_last_step = NULL WHERE id = ?",
+        params![TOTP_KEY.seal(jwt.id, &secret), jwt.id],
+    ) {
+        eprintln!("Failed to store the TOTP secret of user {}: {}", jwt.id, e);
+        return HttpResponse::InternalServerError().body("Failed to set up TOTP");
+    }
+
+    HttpResponse::Ok().json(json!({
+        "success": true,
+        "secret": base32_encode(&secret),
+        "uri": uri,
+        "qr_svg": qr_svg
+    }))
+}
+
+/**
+ * Endpoint to finish enrolling an authenticator app with a first code.
+ *
+ * @param req - The HTTP request carrying the session token.
+ * @param form - A code shown by the app.
+ * @return An HTTP response with the backup codes, shown only this once.
+ */
+pub async fn totp_enable_query(req: HttpRequest, form: web::Json<TotpCodeForm>) -> impl Responder {
+    let jwt = match get_user_from_cookie(&req) {
+        Some(jwt) => jwt,
+        None => return HttpResponse::Unauthorized().body("Invalid session"),
+    };
+
+    let mut conn = CONNECTION.lock().unwrap();
+    match get_totp_row(&conn, jwt.id) {
+        Ok(Some(row)) if row.enabled => {
+            return HttpResponse::Conflict().body("TOTP is already enabled")
+        }
+        Ok(Some(row)) if row.secret.is_some() => {}
+        Ok(_) => return HttpResponse::BadRequest().body("Set up TOTP first"),
+        Err(e) => {
+            eprintln!("Failed to read the TOTP state of user {}: {}", jwt.id, e);
+            return HttpResponse::InternalServerError().body("Failed to enable TOTP");
+        }
+    }
+
+    match check_app_code(&conn, jwt.id, &form.code) {
+        Ok(true) => {}
+        Ok(false) => return HttpResponse::Unauthorized().body("Invalid code"),
+        Err(e) => {
+            eprintln!("Failed to check the TOTP code of user {}: {}", jwt.id, e);
+            return HttpResponse::InternalServerError().body("Failed to enable TOTP");
+        }
+    }
+
+    let result = conn.transaction().and_then(|tx| {
+        let codes = replace_backup_codes(&tx, jwt.id)?;
+        tx.execute(
+            "UPDATE users SET totp_enabled = 1 WHERE id = ?",
+            params![jwt.id],
+        )?;
+        tx.commit()?;
+        Ok(codes)
+    });
+    let backup_codes = match result {
+        Ok(codes) => codes,
+        Err(e) => {
+            eprintln!("Failed to enable TOTP for user {}: {}", jwt.id, e);
+            return HttpResponse::InternalServerError().body("Failed to enable TOTP");
+        }
+    };
+
+    HttpResponse::Ok().json(json!({ "success": true, "backup_codes": backup_codes }))
+}
+
+/**
+ * Endpoint to turn TOTP off, confirmed with a code or a backup code.
+ *
+ * @param req - The HTTP request carrying the session token.
+ * @param form - A code shown by the app, or a backup code.
+ * @return An HTTP response indicating the result of the operation.
+ */
+pub async fn totp_disable_query(req: HttpRequest, form: web::Json<TotpCodeForm>) -> impl Responder {
+    let jwt = match get_user_from_cookie(&req) {
+        Some(jwt) => jwt,
+        None => return HttpResponse::Unauthorized().body("Invalid session"),
+    };
+
+    let mut conn = CONNECTION.lock().unwrap();
+    match is_totp_enabled(&conn, jwt.id) {
+        Ok(true) => {}
+        Ok(false) => return HttpResponse::BadRequest().body("TOTP is not enabled"),
+        Err(e) => {
+            eprintln!("Failed to read the TOTP state of user {}: {}", jwt.id, e);
+            return HttpResponse::InternalServerError().body("Failed to disable TOTP");
+        }
+    }
+    match check_totp_code(&conn, jwt.id, &form.code) {
+        Ok(true) => {}
+        Ok(false) => return HttpResponse::Unauthorized().body("Invalid code"),
+        Err(e) => {
+            eprintln!("Failed to check the TOTP code of user {}: {}", jwt.id, e);
+            return HttpResponse::InternalServerError().body("Failed to disable TOTP");
+        }
+    }
+
+    let result = conn.transaction().and_then(|tx| {
+        tx.execute(
+            "UPDATE users SET totp_secret = NULL, totp_enabled = 0, totp_last_step = NULL
+             WHERE id = ?",
+            params![jwt.id],
+        )?;
+        tx.execute(
+            "DELETE FROM totp_backup_codes WHERE user_id = ?",
+            params![jwt.id],
+        )?;
+        tx.commit()
+    });
+    match result {
+        Ok(()) => HttpResponse::Ok().json(json!({ "success": true })),
+        Err(e) => {
+            eprintln!("Failed to disable TOTP for user {}: {}", jwt.id, e);
+            HttpResponse::InternalServerError().body("Failed to disable TOTP")
+        }
+    }
+}
+
+/**
+ * Endpoint to replace the backup codes, confirmed with a code from the app.
+ *
+ * @param req - The HTTP request carrying the session token.
+ * @param form - A code shown by the app.
+ * @return An HTTP response with the new backup codes, the old ones no longer work.
+ */
+pub async fn totp_backup_codes_query(
+    req: HttpRequest,
+    form: web::Json<TotpCodeForm>,
+) -> impl Responder {
+    let jwt = match get_user_from_cookie(&req) {
+        Some(jwt) => jwt,
+        None => return HttpResponse::Unauthorized().body("Invalid session"),
+    };
+
+    let mut conn = CONNECTION.lock().unwrap();
+    match is_totp_enabled(&conn, jwt.id) {
+        Ok(true) => {}
+        Ok(false) => return HttpResponse::BadRequest().body("TOTP is not enabled"),
+        Err(e) => {
+            eprintln!("Failed to read the TOTP state of user {}: {}", jwt.id, e);
+            return HttpResponse::InternalServerError().body("Failed to renew the backup codes");
+        }
+    }
+    match check_app_code(&conn, jwt.id, &form.code) {
+        Ok(true) => {}
+        Ok(false) => return HttpResponse::Unauthorized().body("Invalid code"),
+        Err(e) => {
+            eprintln!("Failed to check the TOTP code of user {}: {}", jwt.id, e);
+            return HttpResponse::InternalServerError().body("Failed to renew the backup codes");
+        }
+    }
+
+    let result = conn.transaction().and_then(|tx| {
+        let codes = replace_backup_codes(&tx, jwt.id)?;
+        tx.commit()?;
+        Ok(codes)
+    });
+    match result {
+        Ok(codes) => HttpResponse::Ok().json(json!({ "success": true, "backup_codes": codes })),
+        Err(e) => {
+            eprintln!("Failed to renew the backup codes of user {}: {}", jwt.id, e);
+            HttpResponse::InternalServerError().body("Failed to renew the backup codes")
+        }
+    }
+}
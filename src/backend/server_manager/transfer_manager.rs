@@ -0,0 +1,604 @@
+// Vault ownership transfers
+//
+// The Creator of a vault hands it to another member, who accepts or declines;
+// the Creator can cancel it until then. On accepting, the two members swap
+// their roles in the permissions file. The vault keeps its name and its
+// directory, they come from the user who made it (`VaultInfo::get_origin_id`),
+// whoever its Creator is now. A vault has at most one pending transfer.
+use crate::backend::auth::email::canonical_email;
+use crate::backend::server_manager::account_manager::{get_user_by_email, get_user_email, Perms};
+use crate::backend::server_manager::global_manager::{
+    get_user_from_cookie, CONNECTION, VAULTS_CACHE,
+};
+use crate::backend::server_manager::rotation_manager::{is_rotating, ROTATION_IN_PROGRESS};
+use crate::backend::server_manager::token;
+use crate::backend::server_manager::vault_manager::{
+    get_vault, load_vault, set_vault_creator, VaultInfo,
+};
+use actix_web::{web, HttpRequest, HttpResponse, Responder};
+use rusqlite::{params, Connection, OptionalExtension, Row};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use uuid::Uuid;
+
+/**
+ * A pending transfer of the ownership of a vault.
+ */
+#[derive(Serialize, Debug)]
+pub struct Transfer {
+    pub id: String,
+    pub vault: VaultInfo,
+    pub from_id: u32,
+    pub to_id: u32,
+    pub created_at: u64,
+}
+
+/**
+ * Struct representing the form data naming a transfer.
+ */
+#[derive(Deserialize, Debug)]
+pub struct TransferForm {
+    id: String,
+}
+
+const TRANSFER_COLUMNS: &str =
+    "id, vault_origin_id, vault_name, vault_date, from_id, to_id, created_at";
+
+fn transfer_from_row(row: &Row) -> rusqlite::Result<Transfer> {
+    let origin_id: u32 = row.get(1)?;
+    let from_id: u32 = row.get(4)?;
+    let mut vault = VaultInfo::new(
+        from_id,
+        &row.get::<_, String>(2)?,
+        row.get::<_, i64>(3)? as u64,
+    );
+    if origin_id != from_id {
+        vault.origin_id = Some(origin_id);
+    }
+    Ok(Transfer {
+        id: row.get(0)?,
+        vault,
+        from_id,
+        to_id: row.get(5)?,
+        created_at: row.get::<_, i64>(6)? as u64,
+    })
+}
+
+/**
+ * Creates the table of the pending ownership transfers.
+ *
+ * @param conn - The database connection.
+ * @return A Result indicating success or failure.
+ */
+pub fn create_transfer_table(conn: &Connection) -> rusqlite::Result<()> {
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS vault_transfers (
+            id TEXT PRIMARY KEY,
+            vault_origin_id INTEGER NOT NULL,
+            vault_name TEXT NOT NULL,
+            vault_date INTEGER NOT NULL,
+            from_id INTEGER NOT NULL,
+            to_id INTEGER NOT NULL,
+            created_at INTEGER NOT NULL,
+            UNIQUE (vault_origin_id, vault_date),
+            FOREIGN KEY (from_id) REFERENCES users(id),
+            FOREIGN KEY (to_id) REFERENCES users(id)
+        )",
+        [],
+    )?;
+    Ok(())
+}
+
+/**
+ * Offers a vault to one of its members, replacing any pending transfer of it.
+ *
+ * @param conn - The database connection.
+ * @param vault - The vault.
+ * @param from_id - The ID of its Creator.
+ * @param to_id - The ID of the member becoming Creator.
+ * @return A Result containing the ID of the transfer.
+ */
+pub fn create_transfer(
+    conn: &Connection,
+    vault: &VaultInfo,
+    from_id: u32,
+    to_id: u32,
+) -> rusqlite::Result<String> {
+    let id = Uuid::new_v4().to_string();
+    conn.execute(
+        "INSERT INTO vault_transfers
+            (id, vault_origin_id, vault_name, vault_date, from_id, to_id, created_at)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)
+         ON CONFLICT (vault_origin_id, vault_date) DO UPDATE
+         SET id = ?1, vault_name = ?3, from_id = ?5, to_id = ?6, created_at = ?7",
+        params![
+            id,
+            vault.get_origin_id(),
+            vault.name,
+            vault.date as i64,
+            from_id,
+            to_id,
+            token::now() as i64
+        ],
+    )?;
+    Ok(id)
+}
+
+/**
+ * Reads a transfer.
+ *
+ * @param conn - The database connection.
+ * @param id - The ID of the transfer.
+ * @return A Result containing the transfer, None if there is none.
+ */
+pub fn get_transfer(conn: &Connection, id: &str) -> rusqlite::Result<Option<Transfer>> {
+    conn.query_row(
+        &format!(
+            "SELECT {} FROM vault_transfers WHERE id = ?",
+            TRANSFER_COLUMNS
+        ),
+        params![id],
+        transfer_from_row,
+    )
+    .optional()
+}
+
+/**
+ * Lists the transfers offered to a user, or by them.
+ *
+ * @param conn - The database connection.
+ * @param user_id - The ID of the user.
+ * @param received - Whether to list the transfers offered to the user or the ones they offered.
+ * @return A Result containing the transfers, most recent first.
+ */
+pub fn list_transfers(
+    conn: &Connection,
+    user_id: u32,
+    received: bool,
+) -> rusqlite::Result<Vec<Transfer>> {
+    let column = if received { "to_id" } else { "from_id" };
+    let mut stmt = conn.prepare(&format!(
+        "SELECT {} FROM vault_transfers WHERE {} = ? ORDER BY created_at DESC",
+        TRANSFER_COLUMNS, column
+    ))?;
+    let transfers = stmt.query_map(params![user_id], transfer_from_row)?;
+    transfers.collect()
+}
+
+/**
+ * Deletes a transfer.
+ *
+ * @param conn - The database connection.
+ * @param id - The ID of the transfer.
+ * @return A Result containing whether the transfer existed.
+ */
+pub fn delete_transfer(conn: &Connection, id: &str) -> rusqlite::Result<bool> {
+    let deleted = conn.execute("DELETE FROM vault_transfers WHERE id = ?", params![id])?;
+    Ok(deleted > 0)
+}
+
+/**
+ * Deletes the pending transfer of a vault, e.g. when it is deleted.
+ *
+ * @param conn - The database connection.
+ * @param vault - The vault.
+ * @return A Result indicating success or failure.
+ */
+pub fn delete_vault_transfers(conn: &Connection, vault: &VaultInfo) -> rusqlite::Result<()> {
+    conn.execute(
+        "DELETE FROM vault_transfers WHERE vault_origin_id = ? AND vault_date = ?",
+        params![vault.get_origin_id(), vault.date as i64],
+    )?;
+    Ok(())
+}
+
+/**
+ * Deletes the transfers a user offered or was offered, e.g. when their account is deleted.
+ *
+ * @param conn - The database connection.
+ * @param user_id - The ID of the user.
+ * @return A Result indicating success or failure.
+ */
+pub fn delete_user_transfers(conn: &Connection, user_id: u32) -> rusqlite::Result<()> {
+    conn.execute(
+        "DELETE FROM vault_transfers WHERE from_id = ?1 OR to_id = ?1",
+        params![user_id],
+    )?;
+    Ok(())
+}
+
+// Transfer as listed to either party, with the other party's email
+fn describe(conn: &Connection, transfer: &Transfer, received: bool) -> serde_json::Value {
+    let other = if received {
+        transfer.from_id
+    } else {
+        transfer.to_id
+    };
+    let email = get_user_email(conn, other).ok().flatten();
+    json!({
+        "id": transfer.id,
+        "vault": transfer.vault,
+        "created_at": transfer.created_at,
+        if received { "from" } else { "to" }: email,
+    })
+}
+
+/**
+ * Endpoint offering a vault to one of its members, open to its Creator.
+ * They become Creator once they accept, and the Creator takes their role.
+ *
+ * @param req - The HTTP request carrying the session token.
+ * @param data - The vault and the email of the member.
+ * @return An HTTP response with the ID of the transfer.
+ */
+pub async fn transfer_ownership_query(
+    req: HttpRequest,
+    data: web::Json<(VaultInfo, String)>,
+) -> impl Responder {
+    let jwt = match get_user_from_cookie(&req) {
+        Some(jwt) => jwt,
+        None => return HttpResponse::Unauthorized().body("Invalid session"),
+    };
+    let (vault_info, email) = data.into_inner();
+
+    if load_vault(req, web::Json(vault_info.clone()))
+        .await
+        .is_err()
+    {
+        return HttpResponse::InternalServerError().body("Failed to get vault");
+    }
+
+    let conn = CONNECTION.lock().unwrap();
+    let email = canonical_email(&email);
+    let to_id = match get_user_by_email(&conn, &email) {
+        Ok(Some((id, _))) => id,
+        _ => return HttpResponse::InternalServerError().body("user do not exist"),
+    };
+
+    let cache = match VAULTS_CACHE.get(&vault_info.get_name()) {
+        Some(cache) => cache,
+        None => return HttpResponse::InternalServerError().body("Failed to get vault"),
+    };
+    let vault = cache.lock().unwrap();
+    if vault.perms.get(&jwt.id) != Some(&Perms::Creator) {
+        return HttpResponse::Unauthorized().body("Only the Creator can transfer this vault");
+    }
+    if to_id == jwt.id {
+        return HttpResponse::BadRequest().body("You already own this vault");
+    }
+    if !vault.perms.contains_key(&to_id) {
+        return HttpResponse::NotFound().body("user is not a member of this vault");
+    }
+
+    match create_transfer(&conn, &vault.info, jwt.id, to_id) {
+        Ok(transfer_id) => HttpResponse::Ok().json(json!({
+            "success": true,
+            "message": format!("'{}' offered to {}", vault.info.name, email),
+            "transfer_id": transfer_id,
+        })),
+        Err(e) => {
+            eprintln!(
+                "Failed to offer vault {} to user {}: {}",
+                vault.info.get_name(),
+                to_id,
+                e
+            );
+            HttpResponse::InternalServerError().body("Failed to transfer the vault")
+        }
+    }
+}
+
+/**
+ * Endpoint listing the pending transfers of the logged-in user, the ones
+ * offered to them and the ones they offered.
+ *
+ * @param req - The HTTP request carrying the session token.
+ * @return An HTTP response with the transfers.
+ */
+pub async fn list_transfers_query(req: HttpRequest) -> impl Responder {
+    let jwt = match get_user_from_cookie(&req) {
+        Some(jwt) => jwt,
+        None => return HttpResponse::Unauthorized().body("Invalid session"),
+    };
+
+    let conn = CONNECTION.lock().unwrap();
+    let listed = list_transfers(&conn, jwt.id, true)
+        .and_then(|received| Ok((received, list_transfers(&conn, jwt.id, false)?)));
+    match listed {
+        Ok((received, sent)) => HttpResponse::Ok().json(json!({
+            "success": true,
+            "received": received.iter().map(|t| describe(&conn, t, true)).collect::<Vec<_>>(),
+            "sent": sent.iter().map(|t| describe(&conn, t, false)).collect::<Vec<_>>(),
+        })),
+        Err(e) => {
+            eprintln!("Failed to list the transfers of user {}: {}", jwt.id, e);
+            HttpResponse::InternalServerError().body("Failed to list the transfers")
+        }
+    }
+}
+
+/**
+ * Endpoint accepting a transfer: the logged-in user becomes Creator of the
+ * vault and the former Creator takes their previous role.
+ *
+ * The transfer is void if the one who offered it is no longer the Creator,
+ * or if the user is no longer a member.
+ *
+ * @param req - The HTTP request carrying the session token.
+ * @param form - The ID of the transfer.
+ * @return An HTTP response with the vault, as it is now.
+ */
+pub async fn accept_transfer_query(
+    req: HttpRequest,
+    form: web::Json<TransferForm>,
+) -> impl Responder {
+    let jwt = match get_user_from_cookie(&req) {
+        Some(jwt) => jwt,
+        None => return HttpResponse::Unauthorized().body("Invalid session"),
+    };
+
+    let (transfer, vault_info) = {
+        let conn = CONNECTION.lock().unwrap();
+        let transfer = match get_transfer(&conn, &form.id) {
+            Ok(Some(transfer)) if transfer.to_id == jwt.id => transfer,
+            Ok(_) => return HttpResponse::NotFound().body("Unknown transfer"),
+            Err(e) => {
+                eprintln!("Failed to read transfer {}: {}", form.id, e);
+                return HttpResponse::InternalServerError().body("Failed to accept the transfer");
+            }
+        };
+        match get_vault(&conn, &transfer.vault) {
+            Ok(Some(vault_info)) => (transfer, vault_info),
+            Ok(None) => {
+                let _ = delete_transfer(&conn, &transfer.id);
+                return HttpResponse::Gone().body("The vault no longer exists");
+            }
+            Err(e) => {
+                eprintln!("Vault of transfer {}: {}", transfer.id, e);
+                return HttpResponse::InternalServerError().body("Failed to accept the transfer");
+            }
+        }
+    };
+    if is_rotating(&vault_info.get_name()) {
+        return HttpResponse::Conflict().body(ROTATION_IN_PROGRESS);
+    }
+    if load_vault(req, web::Json(vault_info.clone()))
+        .await
+        .is_err()
+    {
+        return HttpResponse::InternalServerError().body("Failed to get vault");
+    }
+
+    let conn = CONNECTION.lock().unwrap();
+    let cache = match VAULTS_CACHE.get(&vault_info.get_name()) {
+        Some(cache) => cache,
+        None => return HttpResponse::InternalServerError().body("Failed to get vault"),
+    };
+    let mut vault = cache.lock().unwrap();
+    let role = match vault.perms.get(&jwt.id) {
+        Some(role) if vault.perms.get(&transfer.from_id) == Some(&Perms::Creator) => role.clone(),
+        _ => {
+            let _ = delete_transfer(&conn, &transfer.id);
+            return HttpResponse::Gone().body("The transfer was withdrawn");
+        }
+    };
+
+    // The database is only committed once the permissions are written, so
+    // that a failure on either side leaves the vault as it was
+    let tx = match conn.unchecked_transaction() {
+        Ok(tx) => tx,
+        Err(e) => {
+            eprintln!("Failed to accept transfer {}: {}", transfer.id, e);
+            return HttpResponse::InternalServerError().body("Failed to accept the transfer");
+        }
+    };
+    if let Err(e) = set_vault_creator(&tx, &vault.info, jwt.id)
+        .and_then(|()| delete_transfer(&tx, &transfer.id))
+    {
+        eprintln!(
+            "Failed to record user {} as Creator of vault {}: {}",
+            jwt.id,
+            vault_info.get_name(),
+            e
+        );
+        return HttpResponse::InternalServerError().body("Failed to accept the transfer");
+    }
+
+    let mut perms = vault.perms.clone();
+    perms.insert(jwt.id, Perms::Creator);
+    perms.insert(transfer.from_id, role);
+    if let Err(e) = vault.info.set_perms(&vault.vault_key, &perms) {
+        eprintln!(
+            "Failed to transfer vault {} to user {}: {}",
+            vault_info.get_name(),
+            jwt.id,
+            e
+        );
+        return HttpResponse::InternalServerError().body("Failed to accept the transfer");
+    }
+    if let Err(e) = tx.commit() {
+        eprintln!(
+            "Failed to record user {} as Creator of vault {}: {}",
+            jwt.id,
+            vault_info.get_name(),
+            e
+        );
+        if vault
+            .info
+            .set_perms(&vault.vault_key, &vault.perms)
+            .is_err()
+        {
+            eprintln!(
+                "Permissions of vault {} left out of step with the database",
+                vault_info.get_name()
+            );
+        }
+        return HttpResponse::InternalServerError().body("Failed to accept the transfer");
+    }
+    vault.perms = perms;
+    vault.info.origin_id = Some(vault.info.get_origin_id());
+    vault.info.creator_id = jwt.id;
+
+    HttpResponse::Ok().json(json!({
+        "success": true,
+        "message": format!("You are now the Creator of '{}'", vault.info.name),
+        "vault": vault.info,
+    }))
+}
+
+/**
+ * Endpoint declining a transfer offered to the logged-in user.
+ *
+ * @param req - The HTTP request carrying the session token.
+ * @param form - The ID of the transfer.
+ * @return An HTTP response indicating the result of the operation.
+ */
+pub async fn decline_transfer_query(
+    req: HttpRequest,
+    form: web::Json<TransferForm>,
+) -> impl Responder {
+    drop_transfer(req, &form.id, true)
+}
+
+/**
+ * Endpoint cancelling a transfer offered by the logged-in user.
+ *
+ * @param req - The HTTP request carrying the session token.
+ * @param form - The ID of the transfer.
+ * @return An HTTP response indicating the result of the operation.
+ */
+pub async fn cancel_transfer_query(
+    req: HttpRequest,
+    form: web::Json<TransferForm>,
+) -> impl Responder {
+    drop_transfer(req, &form.id, false)
+}
+
+// Deletes a transfer offered to the user, or by them
+fn drop_transfer(req: HttpRequest, id: &str, received: bool) -> HttpResponse {
+    let jwt = match get_user_from_cookie(&req) {
+        Some(jwt) => jwt,
+        None => return HttpResponse::Unauthorized().body("Invalid session"),
+    };
+
+    let conn = CONNECTION.lock().unwrap();
+    let owned = get_transfer(&conn, id).map(|transfer| {
+        transfer.is_some_and(|transfer| {
+            let party = if received {
+                transfer.to_id
+            } else {
+                transfer.from_id
+            };
+            party == jwt.id
+        })
+    });
+    match owned.and_then(|owned| Ok(owned && delete_transfer(&conn, id)?)) {
+        Ok(true) => HttpResponse::Ok().json(json!({ "success": true })),
+        Ok(false) => HttpResponse::NotFound().body("Unknown transfer"),
+        Err(e) => {
+            eprintln!("Failed to delete transfer {}: {}", id, e);
+            HttpResponse::InternalServerError().body("Failed to delete the transfer")
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::server_manager::test_support::{
+        create_test_vault, create_user, status, TestUser,
+    };
+    use actix_web::http::StatusCode;
+    use std::fs;
+
+    async fn accept(user: &TestUser, id: &str) -> StatusCode {
+        let req = user.request();
+        let form = TransferForm { id: id.to_string() };
+        status(
+            accept_transfer_query(req.clone(), web::Json(form)).await,
+            &req,
+        )
+    }
+
+    fn creator_in_database(vault: &VaultInfo) -> u32 {
+        let conn = CONNECTION.lock().unwrap();
+        get_vault(&conn, vault).unwrap().unwrap().creator_id
+    }
+
+    #[actix_web::test]
+    async fn accepting_makes_the_recipient_creator() {
+        let owner = create_user("transfer-from@example.com", true);
+        let heir = create_user("transfer-to@example.com", true);
+        let (vault, vault_key) = create_test_vault(&owner, &[(&heir, Perms::Write)]);
+        let id = create_transfer(&CONNECTION.lock().unwrap(), &vault, owner.id, heir.id).unwrap();
+
+        assert_eq!(accept(&heir, &id).await, StatusCode::OK);
+
+        let perms = vault.get_perms(&vault_key).unwrap();
+        assert_eq!(perms.get(&heir.id), Some(&Perms::Creator));
+        assert_eq!(perms.get(&owner.id), Some(&Perms::Write));
+        assert_eq!(creator_in_database(&vault), heir.id);
+        assert!(get_transfer(&CONNECTION.lock().unwrap(), &id)
+            .unwrap()
+            .is_none());
+    }
+
+    #[actix_web::test]
+    async fn a_failed_accept_leaves_the_vault_as_it_was() {
+        let owner = create_user("transfer-kept@example.com", true);
+        let heir = create_user("transfer-failed@example.com", true);
+        let (vault, vault_key) = create_test_vault(&owner, &[(&heir, Perms::Read)]);
+        let id = create_transfer(&CONNECTION.lock().unwrap(), &vault, owner.id, heir.id).unwrap();
+
+        // The vault is loaded, then its permissions file cannot be written
+        load_vault(heir.request(), web::Json(vault.clone()))
+            .await
+            .unwrap();
+        let perms_path = format!("{}.vault/perms.json", vault.get_path());
+        let saved = format!("{}.saved", perms_path);
+        fs::rename(&perms_path, &saved).unwrap();
+        fs::create_dir_all(format!("{}/blocker", perms_path)).unwrap();
+
+        assert_eq!(accept(&heir, &id).await, StatusCode::INTERNAL_SERVER_ERROR);
+
+        fs::remove_dir_all(&perms_path).unwrap();
+        fs::rename(&saved, &perms_path).unwrap();
+        let perms = vault.get_perms(&vault_key).unwrap();
+        assert_eq!(perms.get(&owner.id), Some(&Perms::Creator));
+        assert_eq!(perms.get(&heir.id), Some(&Perms::Read));
+        assert_eq!(creator_in_database(&vault), owner.id);
+        assert!(get_transfer(&CONNECTION.lock().unwrap(), &id)
+            .unwrap()
+            .is_some());
+        let cached = VAULTS_CACHE.get(&vault.get_name()).unwrap();
+        assert_eq!(
+            cached.lock().unwrap().perms.get(&heir.id),
+            Some(&Perms::Read)
+        );
+    }
+
+    #[test]
+    fn a_vault_has_one_pending_transfer_named_after_its_origin() {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(
+            "CREATE TABLE users (id INTEGER PRIMARY KEY); INSERT INTO users VALUES (1), (2), (3);",
+        )
+        .unwrap();
+        create_transfer_table(&conn).unwrap();
+        let mut vault = VaultInfo::new(2, "team", 1_700_000_000);
+        vault.origin_id = Some(1);
+
+        let first = create_transfer(&conn, &vault, 2, 3).unwrap();
+        let second = create_transfer(&conn, &vault, 2, 1).unwrap();
+        assert!(get_transfer(&conn, &first).unwrap().is_none());
+        let transfer = get_transfer(&conn, &second).unwrap().unwrap();
+        assert_eq!(transfer.to_id, 1);
+        assert_eq!(transfer.vault.get_name(), "1_1700000000");
+        assert_eq!(transfer.vault.creator_id, 2);
+
+        assert_eq!(list_transfers(&conn, 1, true).unwrap().len(), 1);
+        assert!(list_transfers(&conn, 3, true).unwrap().is_empty());
+        delete_vault_transfers(&conn, &VaultInfo::new(1, "team", 1_700_000_000)).unwrap();
+        assert!(get_transfer(&conn, &second).unwrap().is_none());
+    }
+}
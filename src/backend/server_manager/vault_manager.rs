@@ -1,28 +1,50 @@
 // Import necessary modules from the backend
-use crate::backend::aes_keys::crypted_key::encrypt;
-use crate::backend::aes_keys::decrypted_key::decrypt;
-use crate::backend::aes_keys::keys_password::{
-    derive_key, generate_random_key, generate_salt_from_login,
+use crate::backend::aes_keys::container::{self, Kdf};
+use crate::backend::aes_keys::keypair::{seal_to, UserKeypair};
+use crate::backend::aes_keys::vault_key::VaultKey;
+use crate::backend::auth::email::canonical_email;
+use crate::backend::server_manager::account_manager::{
+    get_public_key, get_user_by_email, get_user_keypair, Perms, VaultForm, JWT,
 };
-use crate::backend::server_manager::account_manager::{get_user_by_email, Perms, VaultForm, JWT};
+use crate::backend::server_manager::file_manager::file_handler::reencrypt_file;
 use crate::backend::server_manager::file_manager::file_tree::{Directory, FILE_TREE_FILE_NAME};
 use crate::backend::server_manager::global_manager::{
-    get_user_from_cookie, is_vault_in_cache, CONNECTION, EMAIL_TO_SESSION_KEY, PENDING_SHARE_CACHE,
-    ROOT, SESSION_CACHE, VAULTS_CACHE,
+    get_user_from_cookie, is_vault_in_cache, CONNECTION, INVITATION_KEY, ROOT, SESSION_CACHE,
+    VAULTS_CACHE,
 };
+use crate::backend::server_manager::invitation_manager::{
+    create_invitation, delete_vault_invitations, seal_pending_invitations, InvitationSeal,
+};
+use crate::backend::server_manager::rotation_manager::{is_rotating, ROTATION_IN_PROGRESS};
+use crate::backend::server_manager::transfer_manager::delete_vault_transfers;
+use crate::backend::server_manager::verification_manager::is_email_verified;
 use crate::backend::{VAULTS_DATA, VAULT_CONFIG_ROOT, VAULT_USERS_DIR};
 
-use actix_web::{test, web, HttpRequest, HttpResponse, Responder};
-use rusqlite::{params, Connection};
+use actix_web::{web, HttpRequest, HttpResponse, Responder};
+use rusqlite::{params, Connection, OptionalExtension};
 use serde::{Deserialize, Serialize};
 use serde_json::json;
 use std::collections::HashMap;
 use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::{Arc, Mutex};
 use std::time::{SystemTime, UNIX_EPOCH};
 
 // Relative path to the vault permissions file
 const PERMS_PATH: &str = ".vault/perms.json";
+// Relative path to the forward links left when a vault is re-keyed
+const REKEY_PATH: &str = ".vault/rekey.json";
+// Relative path to the list of files a key change is swapping for their
+// re-encrypted copy, present until all of them are in place
+const SWAP_JOURNAL_PATH: &str = ".vault/swap.json";
+// Extensions of the re-encrypted copy of a file, and of the original it replaced
+const STAGED_EXTENSION: &str = "rekey";
+const REPLACED_EXTENSION: &str = "prev";
+
+// Extension of the key a rotation left for a member, next to their key file
+const HANDOFF_EXTENSION: &str = "handoff";
 
 // Type alias for the permissions mapping: user ID → permissions
 type PermsMap = HashMap<u32, Perms>;
@@ -30,9 +52,33 @@ type PermsMap = HashMap<u32, Perms>;
 /// Represents metadata for a vault.
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct VaultInfo {
+    /// Current Creator of the vault
     pub creator_id: u32,
     pub name: String,
     pub date: u64,
+    /// Creator the vault was made by, once it changed hands; with `date` it
+    /// identifies the vault, so its name and directory never change
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub origin_id: Option<u32>,
+}
+
+/// New vault key left by a rotation for a member who was not there to get it.
+#[derive(Serialize, Deserialize)]
+struct KeyHandoff {
+    /// Whether the key is sealed to the member's public key; otherwise it is
+    /// empty until they have one, or sealed with the server key by an earlier release
+    sealed_to_recipient: bool,
+    key: Vec<u8>,
+}
+
+/// Forward links left by `rekey` for members without a keypair, kept until
+/// each of them has moved to the newer key.
+#[derive(Serialize, Deserialize, Default, Clone)]
+struct RekeyLinks {
+    /// Newer keys, each sealed with the one before it
+    links: Vec<Vec<u8>>,
+    /// Members whose key file may still hold an older key
+    pending: Vec<u32>,
 }
 
 impl VaultInfo {
@@ -42,12 +88,18 @@ impl VaultInfo {
             creator_id,
             name: name.to_string(),
             date,
+            origin_id: None,
         }
     }
 
+    /// Returns the ID of the user who made the vault, whoever its Creator is now.
+    pub fn get_origin_id(&self) -> u32 {
+        self.origin_id.unwrap_or(self.creator_id)
+    }
+
     /// Returns the internal vault name, e.g., "123_1700000000".
     pub fn get_name(&self) -> String {
-        format!("{}_{}", self.creator_id, self.date)
+        format!("{}_{}", self.get_origin_id(), self.date)
     }
 
     /// Constructs the full path to the vault's root directory.
@@ -97,33 +149,508 @@ impl VaultInfo {
     pub fn get_key_path(&self, id: u32) -> String {
         format!("{}{}{}.json", self.get_path(), VAULT_USERS_DIR, id)
     }
-    /// Saves the encrypted vault key for a specific user.
-    pub fn save_key(&self, vault_key: &[u8], user_key: &[u8], id: u32) -> Result<(), &str> {
-        let key_path = self.get_key_path(id);
+    /// Saves the encrypted vault key for a specific user, `user_kdf` records how `user_key` was derived.
+    pub fn save_key(
+        &self,
+        vault_key: &VaultKey,
+        user_key: &[u8],
+        user_kdf: Kdf,
+        id: u32,
+    ) -> Result<(), &str> {
+        if vault_key.is_wiped() || user_key.is_empty() {
+            return Err("key has been wiped");
+        }
 
-        // Create key file if it doesn't exist
-        if !std::path::Path::new(&key_path).exists() && fs::File::create(&key_path).is_err() {
-            return Err("failed to create file");
+        // Encrypt the key with the user key and write it to disk
+        match fs::write(self.get_key_path(id), vault_key.wrap(user_key, user_kdf)) {
+            Err(_) => Err("failed to write file"),
+            _ => Ok(()),
         }
+    }
 
-        // Serialize the vault key
-        let content = match serde_json::to_string(&vault_key.to_vec()) {
-            Err(_) => return Err("failed to serialize key"),
-            Ok(c) => c,
+    /// Opens the vault key of a member, following the forward links left by
+    /// `rekey` or the key left by `rotate_key`, and writing the current key
+    /// back into the member's key file. `keypair` opens keys sealed to the
+    /// member, it is only needed if `has_key_handoff` is true.
+    pub fn open_key(
+        &self,
+        id: u32,
+        user_key: &[u8],
+        user_kdf: Kdf,
+        keypair: Option<&UserKeypair>,
+    ) -> Result<VaultKey, String> {
+        if user_key.is_empty() {
+            return Err("User key has been wiped".to_string());
+        }
+        let encrypted_content = match fs::read(self.get_key_path(id)) {
+            Ok(data) => data,
+            Err(_) => return Err("Vault file not found".to_string()),
         };
+        let vault_key = VaultKey::unwrap_key(&encrypted_content, user_key)?;
+
+        let (mut vault_key, mut upgraded) = self.follow_rekey_links(vault_key);
+        // Legacy key files are written back as containers
+        upgraded |= container::is_legacy(&encrypted_content);
+        let handoff = self.has_key_handoff(id);
+        if handoff {
+            match self.open_key_handoff(id, keypair) {
+                Ok(rotated) if self.get_perms(&rotated).is_ok() => {
+                    vault_key = rotated;
+                    upgraded = true;
+                }
+                // A rotation that failed before using its key leaves a stale one
+                Ok(_) => {}
+                Err(_) if self.get_perms(&vault_key).is_ok() => {}
+                Err(e) => return Err(e),
+            }
+        }
+        if upgraded {
+            self.save_key(&vault_key, user_key, user_kdf, id)?;
+            self.release_rekey_links(id)?;
+        }
+        if handoff {
+            let _ = fs::remove_file(self.get_handoff_path(id));
+        }
+        Ok(vault_key)
+    }
 
-        // Encrypt the key content with the user key
-        let encrypted_content = encrypt(content.as_bytes(), user_key);
+    /// get key handoff path
+    pub fn get_handoff_path(&self, id: u32) -> String {
+        format!(
+            "{}{}{}.{}",
+            self.get_path(),
+            VAULT_USERS_DIR,
+            id,
+            HANDOFF_EXTENSION
+        )
+    }
 
-        // Write the encrypted content to disk
-        match fs::write(&key_path, &encrypted_content) {
-            Err(_) => Err("failed to write file"),
-            _ => Ok(()),
+    /// Tells whether a rotation left a new key for a member.
+    pub fn has_key_handoff(&self, id: u32) -> bool {
+        Path::new(&self.get_handoff_path(id)).exists()
+    }
+
+    /// Leaves a new key for a member, for `open_key` to pick up on their next
+    /// load: sealed to their public key, or pending until they have one and
+    /// a member loading the vault seals it to them.
+    pub fn save_key_handoff(
+        &self,
+        vault_key: &VaultKey,
+        id: u32,
+        public_key: Option<&[u8]>,
+    ) -> Result<(), String> {
+        let handoff = match public_key {
+            Some(public_key) => KeyHandoff {
+                sealed_to_recipient: true,
+                key: seal_to(public_key, vault_key.as_bytes())?,
+            },
+            None => KeyHandoff {
+                sealed_to_recipient: false,
+                key: Vec::new(),
+            },
+        };
+        let data = serde_json::to_vec(&handoff).map_err(|e| e.to_string())?;
+        let path = self.get_handoff_path(id);
+        fs::write(&path, data).map_err(|e| format!("{}: {}", path, e))
+    }
+
+    /// Opens the key left for a member by `save_key_handoff`.
+    fn open_key_handoff(&self, id: u32, keypair: Option<&UserKeypair>) -> Result<VaultKey, String> {
+        let path = self.get_handoff_path(id);
+        let data = fs::read(&path).map_err(|e| format!("{}: {}", path, e))?;
+        let handoff: KeyHandoff = serde_json::from_slice(&data).map_err(|e| e.to_string())?;
+        if handoff.sealed_to_recipient {
+            let keypair = keypair.ok_or("No keypair to open the rotated key")?;
+            VaultKey::from_bytes(&keypair.open_sealed(&handoff.key)?)
+        } else if handoff.key.is_empty() {
+            Err("The rotated key waits for a member to seal it".to_string())
+        } else {
+            // Sealed with the server key by an earlier release
+            INVITATION_KEY.open(&self.handoff_label(), id, &handoff.key)
+        }
+    }
+
+    /// Binds keys sealed with the server key to this vault, as invitations are to theirs.
+    fn handoff_label(&self) -> String {
+        format!("rotation {}", self.get_name())
+    }
+
+    /// Lists the members whose key left by a rotation is not sealed to them.
+    pub fn get_unsealed_handoffs(&self) -> Vec<u32> {
+        let dir = format!("{}{}", self.get_path(), VAULT_USERS_DIR);
+        let Ok(entries) = fs::read_dir(&dir) else {
+            return Vec::new();
+        };
+        entries
+            .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+            .filter(|path| path.extension().is_some_and(|ext| ext == HANDOFF_EXTENSION))
+            .filter_map(|path| {
+                let id = path.file_stem()?.to_str()?.parse::<u32>().ok()?;
+                let handoff: KeyHandoff = serde_json::from_slice(&fs::read(&path).ok()?).ok()?;
+                (!handoff.sealed_to_recipient).then_some(id)
+            })
+            .collect()
+    }
+
+    /// Deletes the keys left for members that are no longer in the vault.
+    pub fn remove_stale_handoffs(&self, perms: &PermsMap) -> Result<(), String> {
+        let dir = format!("{}{}", self.get_path(), VAULT_USERS_DIR);
+        let entries = fs::read_dir(&dir).map_err(|e| format!("{}: {}", dir, e))?;
+        for entry in entries {
+            let path = entry.map_err(|e| e.to_string())?.path();
+            if path.extension().is_none_or(|ext| ext != HANDOFF_EXTENSION) {
+                continue;
+            }
+            let member = path
+                .file_stem()
+                .and_then(|stem| stem.to_str())
+                .and_then(|stem| stem.parse::<u32>().ok())
+                .is_some_and(|id| perms.contains_key(&id));
+            if !member {
+                fs::remove_file(&path).map_err(|e| format!("{}: {}", path.display(), e))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Follows the forward links left by `rekey` from an older key of the
+    /// vault, and tells whether the key returned is a newer one.
+    pub fn follow_rekey_links(&self, mut vault_key: VaultKey) -> (VaultKey, bool) {
+        let links = self.get_rekey_links().links;
+        let mut upgraded = false;
+        for _ in 0..links.len() {
+            match links
+                .iter()
+                .find_map(|link| VaultKey::unwrap_key(link, vault_key.as_bytes()).ok())
+            {
+                Some(next) => {
+                    vault_key = next;
+                    upgraded = true;
+                }
+                None => break,
+            }
+        }
+        (vault_key, upgraded)
+    }
+
+    /// Reads the forward links of the vault, a missing file means none.
+    fn get_rekey_links(&self) -> RekeyLinks {
+        fs::read(format!("{}{}", self.get_path(), REKEY_PATH))
+            .ok()
+            .and_then(|data| serde_json::from_slice(&data).ok())
+            .unwrap_or_default()
+    }
+
+    // Writes the forward links, or deletes them once no member needs them
+    fn set_rekey_links(&self, links: &RekeyLinks) -> Result<(), String> {
+        let path = format!("{}{}", self.get_path(), REKEY_PATH);
+        if links.links.is_empty() || links.pending.is_empty() {
+            return match fs::remove_file(&path) {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                    Err(format!("{}: {}", path, e))
+                }
+                _ => Ok(()),
+            };
+        }
+        let data = serde_json::to_vec(links).map_err(|e| e.to_string())?;
+        fs::write(&path, data).map_err(|e| format!("{}: {}", path, e))
+    }
+
+    /// Tells that a member no longer needs the forward links, their key file
+    /// holds the current key or they left. The last one deletes the links.
+    pub fn release_rekey_links(&self, id: u32) -> Result<(), String> {
+        let mut links = self.get_rekey_links();
+        if !links.pending.contains(&id) {
+            return Ok(());
+        }
+        links.pending.retain(|pending| *pending != id);
+        self.set_rekey_links(&links)
+    }
+
+    /// Re-encrypts the permissions, the file tree and every stored file under
+    /// `new_key`. Member key files are left as they are: the new key is
+    /// sealed to the members that have a public key, as by a rotation, and
+    /// the others get a forward link (the new key sealed with the old one)
+    /// that `open_key` follows on their next load. The link is deleted once
+    /// all of them moved on. The vault must not be loaded by the server meanwhile.
+    ///
+    /// `members` lists every member with their public key, None for accounts
+    /// without a keypair yet.
+    pub fn rekey(
+        &self,
+        old_key: &VaultKey,
+        new_key: &VaultKey,
+        members: &[(u32, Option<Vec<u8>>)],
+    ) -> Result<(), String> {
+        // Keys are handed over before the swap, members can always reach the key in use
+        let previous = self.get_rekey_links();
+        let result = self.replace_key(
+            old_key,
+            new_key,
+            || {
+                let mut links = previous.clone();
+                for (id, public_key) in members {
+                    match public_key {
+                        Some(public_key) => {
+                            self.save_key_handoff(new_key, *id, Some(public_key))?
+                        }
+                        None if !links.pending.contains(id) => links.pending.push(*id),
+                        None => {}
+                    }
+                }
+                if !links.pending.is_empty() {
+                    links
+                        .links
+                        .push(new_key.wrap(old_key.as_bytes(), Kdf::None));
+                }
+                self.set_rekey_links(&links)
+            },
+            |_, _| {},
+        );
+        // A swap rolled back leaves the vault on the old key, the link must go
+        if result.is_err() && !self.has_pending_swap() {
+            self.set_rekey_links(&previous)?;
+        }
+        result
+    }
+
+    /// Moves the vault to `new_key` after a member left. Everything is
+    /// re-encrypted as by `rekey`, and `progress` is told how many files are
+    /// done out of how many. No forward link is left, the old key must not
+    /// lead to the new one: `hand_over` gives the new key to the remaining
+    /// members, it runs once every file is staged and before any is replaced.
+    /// The vault must not be loaded by the server meanwhile.
+    pub fn rotate_key(
+        &self,
+        old_key: &VaultKey,
+        new_key: &VaultKey,
+        hand_over: impl FnOnce() -> Result<(), String>,
+        progress: impl FnMut(usize, usize),
+    ) -> Result<(), String> {
+        self.replace_key(old_key, new_key, hand_over, progress)?;
+        // Every member got the new key, links from older keys lead to a dead one
+        self.set_rekey_links(&RekeyLinks::default())
+    }
+
+    // Stages the re-encrypted copy of every vault file, runs `before_swap`,
+    // then puts the copies in place of the originals. A swap that fails is
+    // rolled back; one cut short by a crash is finished by `recover_swap`.
+    fn replace_key(
+        &self,
+        old_key: &VaultKey,
+        new_key: &VaultKey,
+        before_swap: impl FnOnce() -> Result<(), String>,
+        mut progress: impl FnMut(usize, usize),
+    ) -> Result<(), String> {
+        if self.has_pending_swap() {
+            return Err("An interrupted key change must be recovered first".to_string());
+        }
+        let root = PathBuf::from(self.get_path());
+        let mut staged = Vec::new();
+
+        let result = self
+            .stage_rekey(&root, old_key, new_key, &mut staged, &mut progress)
+            .and_then(|()| before_swap());
+        if let Err(e) = result {
+            for path in &staged {
+                let _ = fs::remove_file(path.with_extension(STAGED_EXTENSION));
+            }
+            return Err(e);
+        }
+        self.swap_staged(&staged)
+    }
+
+    /// Tells whether a key change was cut short while swapping the files.
+    pub fn has_pending_swap(&self) -> bool {
+        Path::new(&self.get_swap_journal_path()).exists()
+    }
+
+    /// Finishes a key change cut short while its files were swapped. The new
+    /// key was handed over before the swap began, so the copies still staged
+    /// are put in place rather than dropped.
+    pub fn recover_swap(&self) -> Result<(), String> {
+        let files = match self.read_swap_journal()? {
+            Some(files) => files,
+            None => return Ok(()),
+        };
+        for path in &files {
+            swap_file(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        }
+        self.finish_swap(&files)
+    }
+
+    // Records the files to swap, then swaps them one by one, the originals
+    // kept aside until all are done
+    fn swap_staged(&self, files: &[PathBuf]) -> Result<(), String> {
+        self.write_swap_journal(files)?;
+        for path in files {
+            if let Err(e) = swap_file(path) {
+                let error = format!("{}: {}", path.display(), e);
+                return match self.roll_back_swap(files) {
+                    Ok(()) => Err(error),
+                    Err(rollback) => Err(format!("{}, rollback failed: {}", error, rollback)),
+                };
+            }
+        }
+        self.finish_swap(files)
+    }
+
+    // Puts the originals back. The copies are only dropped once every original
+    // is back, otherwise `recover_swap` can still complete the swap instead.
+    fn roll_back_swap(&self, files: &[PathBuf]) -> Result<(), String> {
+        let mut failed = Vec::new();
+        for path in files {
+            let replaced = path.with_extension(REPLACED_EXTENSION);
+            if !replaced.is_file() {
+                continue;
+            }
+            // The new copy goes back to staging so the swap can still be redone
+            let staged = path.with_extension(STAGED_EXTENSION);
+            let restored = if path.exists() && !staged.exists() {
+                fs::rename(path, &staged)
+            } else {
+                Ok(())
+            };
+            if let Err(e) = restored.and_then(|()| fs::rename(&replaced, path)) {
+                failed.push(format!("{}: {}", path.display(), e));
+            }
+        }
+        if !failed.is_empty() {
+            return Err(failed.join(", "));
+        }
+        for path in files {
+            let _ = fs::remove_file(path.with_extension(STAGED_EXTENSION));
+        }
+        fs::remove_file(self.get_swap_journal_path()).map_err(|e| e.to_string())
+    }
+
+    // Drops the originals, then the journal
+    fn finish_swap(&self, files: &[PathBuf]) -> Result<(), String> {
+        for path in files {
+            let replaced = path.with_extension(REPLACED_EXTENSION);
+            match fs::remove_file(&replaced) {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                    return Err(format!("{}: {}", replaced.display(), e))
+                }
+                _ => {}
+            }
+        }
+        fs::remove_file(self.get_swap_journal_path()).map_err(|e| e.to_string())
+    }
+
+    fn get_swap_journal_path(&self) -> String {
+        format!("{}{}", self.get_path(), SWAP_JOURNAL_PATH)
+    }
+
+    // Writes the journal, paths relative to the vault, and syncs it before
+    // any file is touched
+    fn write_swap_journal(&self, files: &[PathBuf]) -> Result<(), String> {
+        let root = PathBuf::from(self.get_path());
+        let relative: Vec<&Path> = files
+            .iter()
+            .filter_map(|path| path.strip_prefix(&root).ok())
+            .collect();
+        let data = serde_json::to_vec(&relative).map_err(|e| e.to_string())?;
+        let path = self.get_swap_journal_path();
+        let mut journal = fs::File::create(&path).map_err(|e| format!("{}: {}", path, e))?;
+        journal
+            .write_all(&data)
+            .and_then(|()| journal.sync_all())
+            .map_err(|e| format!("{}: {}", path, e))
+    }
+
+    fn read_swap_journal(&self) -> Result<Option<Vec<PathBuf>>, String> {
+        let path = self.get_swap_journal_path();
+        let data = match fs::read(&path) {
+            Ok(data) => data,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(format!("{}: {}", path, e)),
+        };
+        let relative: Vec<PathBuf> =
+            serde_json::from_slice(&data).map_err(|e| format!("{}: {}", path, e))?;
+        let root = PathBuf::from(self.get_path());
+        Ok(Some(relative.iter().map(|file| root.join(file)).collect()))
+    }
+
+    // Writes the re-encrypted copy of every vault file next to the original
+    fn stage_rekey(
+        &self,
+        root: &Path,
+        old_key: &VaultKey,
+        new_key: &VaultKey,
+        staged: &mut Vec<PathBuf>,
+        progress: &mut impl FnMut(usize, usize),
+    ) -> Result<(), String> {
+        let mut blobs = Vec::new();
+        let entries = fs::read_dir(root).map_err(|e| e.to_string())?;
+        for entry in entries {
+            let path = entry.map_err(|e| e.to_string())?.path();
+            if path.extension().is_some_and(|ext| ext == "bin") {
+                blobs.push(path);
+            }
+        }
+        let total = 2 + blobs.len();
+        progress(0, total);
+
+        for relative in [PERMS_PATH, FILE_TREE_FILE_NAME] {
+            let path = root.join(relative);
+            let tmp = path.with_extension(STAGED_EXTENSION);
+            let data = fs::read(&path).map_err(|e| format!("{}: {}", path.display(), e))?;
+            let plaintext = container::open(&data, old_key.as_bytes())
+                .map_err(|e| format!("{}: {}", path.display(), e))?;
+            let sealed = container::seal(&plaintext, new_key.as_bytes(), Kdf::None);
+            fs::write(&tmp, sealed).map_err(|e| format!("{}: {}", tmp.display(), e))?;
+            staged.push(path);
+            progress(staged.len(), total);
+        }
+
+        for path in blobs {
+            let tmp = path.with_extension(STAGED_EXTENSION);
+            reencrypt_file(&path, &tmp, old_key.as_bytes(), new_key.as_bytes())
+                .map_err(|e| format!("{}: {}", path.display(), e))?;
+            staged.push(path);
+            progress(staged.len(), total);
+        }
+        Ok(())
+    }
+
+    /// Seals the permissions of the vault without `id`, for a member about to
+    /// leave, and returns them with the path of the permissions file; nothing
+    /// is written. `heir` becomes the creator in their place, if given.
+    pub fn perms_without_member(
+        &self,
+        vault_key: &VaultKey,
+        id: u32,
+        heir: Option<u32>,
+    ) -> Result<(PathBuf, Vec<u8>), String> {
+        let mut perms = self.get_perms(vault_key)?;
+        perms.remove(&id);
+        if let Some(heir) = heir {
+            perms.insert(heir, Perms::Creator);
         }
+        let content = serde_json::to_string_pretty(&perms).map_err(|e| e.to_string())?;
+        let sealed = container::seal(content.as_bytes(), vault_key.as_bytes(), Kdf::None);
+        Ok((
+            PathBuf::from(format!("{}{}", self.get_path(), PERMS_PATH)),
+            sealed,
+        ))
+    }
+
+    /// Returns the files a member has in the vault: their key file and the
+    /// key handoff waiting for them, if any.
+    pub fn get_member_files(&self, id: u32) -> Vec<PathBuf> {
+        [self.get_key_path(id), self.get_handoff_path(id)]
+            .into_iter()
+            .map(PathBuf::from)
+            .filter(|path| path.exists())
+            .collect()
     }
 
     /// Sets the encrypted permissions file for the vault.
-    pub fn set_perms(&self, vault_key: &[u8], perms: &PermsMap) -> Result<(), &str> {
+    pub fn set_perms(&self, vault_key: &VaultKey, perms: &PermsMap) -> Result<(), &str> {
+        if vault_key.is_wiped() {
+            return Err("vault key has been wiped");
+        }
         let path = format!("{}{}", self.get_path(), PERMS_PATH);
 
         // Create file if it doesn't exist
@@ -137,7 +664,8 @@ impl VaultInfo {
             Err(_) => return Err("failed to serialize permissions"),
         };
 
-        let encrypted_content = encrypt(content.as_bytes(), vault_key);
+        let encrypted_content =
+            container::seal(content.as_bytes(), vault_key.as_bytes(), Kdf::None);
 
         // Write to file
         match fs::write(&path, encrypted_content) {
@@ -147,35 +675,53 @@ impl VaultInfo {
     }
 
     /// Retrieves and decrypts the vault's permissions.
-    pub fn get_perms(&self, vault_key: &[u8]) -> Result<PermsMap, &str> {
+    pub fn get_perms(&self, vault_key: &VaultKey) -> Result<PermsMap, &str> {
+        if vault_key.is_wiped() {
+            return Err("vault key has been wiped");
+        }
         let path = format!("{}{}", self.get_path(), PERMS_PATH);
 
         // Read and decrypt permissions file
-        if let Ok(data) = fs::read(path) {
-            if let Ok(decrypted) = decrypt(&data, &vault_key) {
-                if let Ok(perms) =
-                    serde_json::from_str(&String::from_utf8_lossy(decrypted.as_slice()))
-                {
-                    Ok(perms)
-                } else {
-                    Err("Failed to decrypt data")
-                }
-            } else {
-                Err("Failed to decrypt data")
+        let data = match fs::read(path) {
+            Ok(data) => data,
+            Err(_) => return Err("failed to read file"),
+        };
+        let decrypted = match container::open(&data, vault_key.as_bytes()) {
+            Ok(decrypted) => decrypted,
+            Err(e) => {
+                eprintln!(
+                    "Permissions of vault {} failed to decrypt: {}",
+                    self.get_name(),
+                    e
+                );
+                return Err("Failed to decrypt data");
             }
-        } else {
-            Err("Failed to decrypt data")
+        };
+        let perms = match serde_json::from_slice(&decrypted) {
+            Ok(perms) => perms,
+            Err(_) => return Err("failed to deserialize data"),
+        };
+        if container::is_legacy(&data) && self.set_perms(vault_key, &perms).is_err() {
+            eprintln!(
+                "Failed to upgrade the permissions of vault {}",
+                self.get_name()
+            );
         }
+        Ok(perms)
     }
 
     /// save file tree
-    pub fn save_file_tree(&self, vault_key: &[u8], file_map: Directory) -> Result<(), &str> {
+    pub fn save_file_tree(&self, vault_key: &VaultKey, file_map: Directory) -> Result<(), &str> {
+        if vault_key.is_wiped() {
+            return Err("vault key has been wiped");
+        }
         let content = match serde_json::to_string_pretty(&file_map) {
             Ok(content) => content,
             Err(_) => return Err("failed to serialize permissions"),
         };
 
-        let encrypted_content = encrypt(content.as_bytes(), vault_key);
+        let encrypted_content =
+            container::seal(content.as_bytes(), vault_key.as_bytes(), Kdf::None);
         if fs::write(
             format!("{}{}", self.get_path(), FILE_TREE_FILE_NAME),
             encrypted_content,
@@ -188,33 +734,76 @@ impl VaultInfo {
     }
 
     /// get file tree
-    pub fn get_file_tree(&self, vault_key: &[u8]) -> Result<Directory, &str> {
+    pub fn get_file_tree(&self, vault_key: &VaultKey) -> Result<Directory, &str> {
+        if vault_key.is_wiped() {
+            return Err("vault key has been wiped");
+        }
         let path = format!("{}{}", self.get_path(), FILE_TREE_FILE_NAME);
         let content = match fs::read(path) {
             Ok(data) => data,
             Err(_) => return Err("failed to read file"),
         };
-        let decrypted_content = match decrypt(&content, vault_key) {
+        let decrypted_content = match container::open(&content, vault_key.as_bytes()) {
             Ok(data) => match String::from_utf8(data) {
                 Ok(content) => content,
                 Err(_) => return Err("failed to deserialize data"),
             },
-            Err(_) => return Err("failed to decrypt data"),
+            Err(e) => {
+                eprintln!(
+                    "File tree of vault {} failed to decrypt: {}",
+                    self.get_name(),
+                    e
+                );
+                return Err("failed to decrypt data");
+            }
         };
 
-        match serde_json::from_str(&decrypted_content) {
-            Ok(dir) => Ok(dir),
-            Err(_) => Err("failed to deserialize data"),
+        let dir: Directory = match serde_json::from_str(&decrypted_content) {
+            Ok(dir) => dir,
+            Err(_) => return Err("failed to deserialize data"),
+        };
+        if container::is_legacy(&content) && self.save_file_tree(vault_key, dir.clone()).is_err() {
+            eprintln!(
+                "Failed to upgrade the file tree of vault {}",
+                self.get_name()
+            );
         }
+        Ok(dir)
     }
 }
 
+// Puts the staged copy of a file in its place, the original kept aside. Each
+// step is skipped if already done, so an interrupted swap can be run again.
+fn swap_file(path: &Path) -> std::io::Result<()> {
+    let staged = path.with_extension(STAGED_EXTENSION);
+    if !staged.exists() {
+        return Ok(());
+    }
+    let replaced = path.with_extension(REPLACED_EXTENSION);
+    if !replaced.is_file() {
+        fs::rename(path, &replaced)?;
+    }
+    fs::rename(&staged, path)
+}
+
 /// Struct representing cached vault data.
 pub struct VaultsCache {
     pub info: VaultInfo,
     pub perms: PermsMap,
-    pub vault_key: Vec<u8>,
+    pub vault_key: VaultKey,
     pub vault_file_tree: Directory,
+    /// Requests using a copy of `vault_key` outside the lock
+    pub(crate) key_users: Arc<AtomicUsize>,
+}
+
+/// Use of the vault key outside the lock of its `VaultsCache`, ended on drop.
+/// A key rotation waits for every lease before re-encrypting the vault.
+pub struct KeyLease(Arc<AtomicUsize>);
+
+impl Drop for KeyLease {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
 }
 
 impl VaultsCache {
@@ -222,7 +811,7 @@ impl VaultsCache {
     pub fn new(
         info: &VaultInfo,
         perms: &PermsMap,
-        vault_key: &Vec<u8>,
+        vault_key: &VaultKey,
         file_tree: &Directory,
     ) -> Self {
         VaultsCache {
@@ -230,8 +819,19 @@ impl VaultsCache {
             perms: perms.clone(),
             vault_key: vault_key.clone(),
             vault_file_tree: file_tree.clone(),
+            key_users: Arc::new(AtomicUsize::new(0)),
         }
     }
+
+    /// Copies the vault key for use once the lock is released, None if it
+    /// was wiped. The copy must not be used after the lease is dropped.
+    pub fn lease_key(&self) -> Option<(VaultKey, KeyLease)> {
+        if self.vault_key.is_wiped() {
+            return None;
+        }
+        self.key_users.fetch_add(1, Ordering::SeqCst);
+        Some((self.vault_key.clone(), KeyLease(self.key_users.clone())))
+    }
 }
 
 /// Inserts a new vault into the database.
@@ -240,10 +840,19 @@ pub fn create_vault(
     vault_info: &VaultInfo,
     id: u32,
 ) -> rusqlite::Result<VaultInfo> {
-    if let Ok(_) = conn.execute(
-        "INSERT INTO vaults (id, creator_id, name, date) VALUES (?, ?, ?, ?)",
-        params![id, vault_info.creator_id, vault_info.name, vault_info.date],
-    ) {
+    if conn
+        .execute(
+            "INSERT INTO vaults (id, creator_id, name, date, origin_id) VALUES (?, ?, ?, ?, ?)",
+            params![
+                id,
+                vault_info.creator_id,
+                vault_info.name,
+                vault_info.date,
+                vault_info.origin_id
+            ],
+        )
+        .is_ok()
+    {
         Ok(vault_info.clone())
     } else {
         Err(rusqlite::Error::InvalidQuery)
@@ -252,8 +861,8 @@ pub fn create_vault(
 
 pub fn remove_vault(conn: &Connection, vault_info: &VaultInfo, id: u32) -> rusqlite::Result<()> {
     let rows_affected = conn.execute(
-        "DELETE FROM vaults WHERE (id, creator_id, name, date) VALUES (?, ?, ?, ?)",
-        params![id, vault_info.creator_id, vault_info.name, vault_info.date],
+        "DELETE FROM vaults WHERE id = ? AND coalesce(origin_id, creator_id) = ? AND date = ?",
+        params![id, vault_info.get_origin_id(), vault_info.date],
     )?;
 
     if rows_affected == 1 {
@@ -262,6 +871,50 @@ pub fn remove_vault(conn: &Connection, vault_info: &VaultInfo, id: u32) -> rusql
         Err(rusqlite::Error::QueryReturnedNoRows)
     }
 }
+/// Reads the current metadata of a vault, e.g. after it changed hands.
+pub fn get_vault(conn: &Connection, vault_info: &VaultInfo) -> rusqlite::Result<Option<VaultInfo>> {
+    conn.query_row(
+        "SELECT creator_id, name, date, origin_id FROM vaults
+         WHERE coalesce(origin_id, creator_id) = ? AND date = ? LIMIT 1",
+        params![vault_info.get_origin_id(), vault_info.date],
+        |row| {
+            Ok(VaultInfo {
+                creator_id: row.get(0)?,
+                name: row.get(1)?,
+                date: row.get(2)?,
+                origin_id: row.get(3)?,
+            })
+        },
+    )
+    .optional()
+}
+
+/// Records a new Creator for every member of a vault, its name stays the same.
+pub fn set_vault_creator(
+    conn: &Connection,
+    vault_info: &VaultInfo,
+    creator_id: u32,
+) -> rusqlite::Result<()> {
+    conn.execute(
+        "UPDATE vaults SET origin_id = coalesce(origin_id, creator_id), creator_id = ?
+         WHERE coalesce(origin_id, creator_id) = ? AND date = ?",
+        params![creator_id, vault_info.get_origin_id(), vault_info.date],
+    )?;
+    Ok(())
+}
+
+/// Deletes a vault for every member from the database: its rows, its pending
+/// transfers and invitations. The caller removes its folder once committed.
+pub fn delete_vault_rows(conn: &Connection, vault_info: &VaultInfo) -> rusqlite::Result<()> {
+    conn.execute(
+        "DELETE FROM vaults WHERE coalesce(origin_id, creator_id) = ? AND date = ?",
+        params![vault_info.get_origin_id(), vault_info.date],
+    )?;
+    delete_vault_transfers(conn, vault_info)?;
+    delete_vault_invitations(conn, vault_info)?;
+    Ok(())
+}
+
 /// HTTP endpoint: creates a new vault for a user.
 pub async fn create_vault_query(req: HttpRequest, form: web::Form<VaultForm>) -> impl Responder {
     // Authenticate the user via cookie
@@ -288,20 +941,15 @@ pub async fn create_vault_query(req: HttpRequest, form: web::Form<VaultForm>) ->
                     .body("Failed to create user JSON file.");
             }
 
-            // Generate a random vault key and derive it
-            let vault_key = generate_random_key();
-            let vault_key = derive_key(
-                &String::from_utf8_lossy(vault_key.as_slice()),
-                generate_salt_from_login(&decoded_jwt.email).as_slice(),
-                10000,
-            );
+            // Generate a random vault key
+            let vault_key = VaultKey::generate();
 
             // Assign creator permissions
             let mut perms = PermsMap::new();
             perms.insert(decoded_jwt.id, Perms::Creator);
 
             // Save permissions file
-            if let Err(_) = info.set_perms(vault_key.as_slice(), &perms) {
+            if info.set_perms(&vault_key, &perms).is_err() {
                 return HttpResponse::InternalServerError()
                     .body("failed to create user JSON file.");
             }
@@ -309,8 +957,9 @@ pub async fn create_vault_query(req: HttpRequest, form: web::Form<VaultForm>) ->
             // Save encrypted vault key for the user
             if info
                 .save_key(
-                    vault_key.as_slice(),
-                    session.user_key.as_slice(),
+                    &vault_key,
+                    session.user_key.as_bytes(),
+                    session.user_kdf,
                     session.user_id,
                 )
                 .is_err()
@@ -351,10 +1000,49 @@ pub async fn load_vault_query(
 ) -> impl Responder {
     match load_vault(req, vault_info).await {
         Ok(jwt) => HttpResponse::Ok().json(jwt),
+        Err(ROTATION_IN_PROGRESS) => HttpResponse::Conflict().body(ROTATION_IN_PROGRESS),
         Err(_) => HttpResponse::InternalServerError().body("failed_to_create_vault"),
     }
 }
 
+/// Seals the key of a loaded vault to the invitees and the members that got a
+/// keypair since they were invited or since the last rotation.
+fn seal_pending_keys(info: &VaultInfo) {
+    let vault_name = info.get_name();
+    let lease = VAULTS_CACHE.get(&vault_name).and_then(|cache| {
+        let cache = cache.lock().unwrap();
+        cache.lease_key().map(|lease| (lease, cache.perms.clone()))
+    });
+    let Some(((vault_key, _lease), perms)) = lease else {
+        return;
+    };
+    let conn = CONNECTION.lock().unwrap();
+    if let Err(e) = seal_pending_invitations(&conn, info, &vault_key) {
+        eprintln!(
+            "Failed to seal the pending invitations to vault {}: {}",
+            vault_name, e
+        );
+    }
+    // Only members get the key, whatever handoffs are left on disk
+    for id in info
+        .get_unsealed_handoffs()
+        .into_iter()
+        .filter(|id| perms.contains_key(id))
+    {
+        let sealed = match get_public_key(&conn, id) {
+            Ok(Some(public_key)) => info.save_key_handoff(&vault_key, id, Some(&public_key)),
+            Ok(None) => continue,
+            Err(e) => Err(e.to_string()),
+        };
+        if let Err(e) = sealed {
+            eprintln!(
+                "Failed to seal the key of vault {} to user {}: {}",
+                vault_name, id, e
+            );
+        }
+    }
+}
+
 /// loads an existing vault into memory.
 pub async fn load_vault(
     req: HttpRequest,
@@ -364,35 +1052,66 @@ pub async fn load_vault(
 
     // Authenticate user
     if let Some(mut jwt) = get_user_from_cookie(&req) {
+        // The vault stays closed while its files are re-encrypted
+        if is_rotating(&info.get_name()) {
+            return Err(ROTATION_IN_PROGRESS);
+        }
         // Check if the vault is already cached
         if is_vault_in_cache(&info.get_name()).await {
+            // Keeps the vault loaded as long as this session lives
+            if let Some(session) = SESSION_CACHE.get(&jwt.session_id) {
+                session
+                    .lock()
+                    .unwrap()
+                    .loaded_vaults
+                    .insert(info.get_name());
+            }
+            seal_pending_keys(&info);
             jwt.loaded_vault = Some(info.clone());
             Ok(jwt)
         } else if let Some(session) = SESSION_CACHE.get(&jwt.session_id) {
-            let session = session.lock().unwrap();
-
             let vault_name = info.get_name();
-            let key_path = info.get_key_path(jwt.id);
 
-            // Read user's encrypted key file
-            let encrypted_content = match fs::read(&key_path) {
-                Ok(data) => data,
-                Err(_) => return Err("Vault file not found"),
+            // A key left by a rotation may be sealed to the user, the session
+            // is not held while the database is
+            let keypair = if info.has_key_handoff(jwt.id) {
+                let user_key = session.lock().unwrap().user_key.clone();
+                let conn = CONNECTION.lock().unwrap();
+                match get_user_keypair(&conn, jwt.id, user_key.as_bytes()) {
+                    Ok(keypair) => keypair,
+                    Err(e) => {
+                        eprintln!("Keypair of user {} failed to open: {}", jwt.id, e);
+                        return Err("Failed to decrypt");
+                    }
+                }
+            } else {
+                None
             };
 
-            // Decrypt the vault key
-            let decrypted_content =
-                match decrypt(encrypted_content.as_slice(), session.user_key.as_slice()) {
-                    Ok(data) => data,
-                    Err(_) => return Err("Failed to decrypt"),
-                };
+            // Files left half swapped by a key change are put in place first
+            if let Err(e) = info.recover_swap() {
+                eprintln!(
+                    "Key change of vault {} failed to recover: {}",
+                    vault_name, e
+                );
+                return Err("Failed to decrypt");
+            }
 
-            // Parse the key from JSON
-            let vault_key: Vec<u8> =
-                match serde_json::from_str(&String::from_utf8(decrypted_content).unwrap()) {
-                    Ok(parsed) => parsed,
-                    Err(_) => return Err("Invalid vault data"),
-                };
+            let mut session = session.lock().unwrap();
+
+            // Decrypt the vault key
+            let vault_key = match info.open_key(
+                jwt.id,
+                session.user_key.as_bytes(),
+                session.user_kdf,
+                keypair.as_ref(),
+            ) {
+                Ok(vault_key) => vault_key,
+                Err(e) => {
+                    eprintln!("Key file of vault {} failed to open: {}", vault_name, e);
+                    return Err("Failed to decrypt");
+                }
+            };
             // Load and decrypt permissions
             let vault_perms: PermsMap = match info.get_perms(&vault_key) {
                 Ok(perms) => perms,
@@ -405,8 +1124,9 @@ pub async fn load_vault(
             };
 
             // Cache the vault in memory
+            session.loaded_vaults.insert(vault_name.clone());
             VAULTS_CACHE.insert(
-                vault_name,
+                vault_name.clone(),
                 Arc::new(Mutex::new(VaultsCache::new(
                     &info,
                     &vault_perms,
@@ -414,6 +1134,13 @@ pub async fn load_vault(
                     &vault_file_tree,
                 ))),
             );
+            // A rotation started while the vault was read must not find it open
+            if is_rotating(&vault_name) {
+                VAULTS_CACHE.invalidate(&vault_name);
+                return Err(ROTATION_IN_PROGRESS);
+            }
+            drop(session);
+            seal_pending_keys(&info);
 
             Ok(jwt)
         } else {
@@ -451,7 +1178,7 @@ pub async fn get_perms_query(req: HttpRequest, vault_info: web::Json<VaultInfo>)
     }
 }
 
-/// Shares a vault with another user by email and sets permissions.
+/// Invites another user to a vault by email, with the permissions they get on accepting.
 pub async fn share_vault_query(
     req: HttpRequest,
     data: web::Json<(VaultInfo, String, String)>,
@@ -461,13 +1188,11 @@ pub async fn share_vault_query(
         None => return HttpResponse::Unauthorized().body("Invalid email or password"),
     };
 
-    let con = CONNECTION.lock().unwrap();
     let (vault_info, email, perm) = data.into_inner();
 
-    // test if other user exist
-    let id = match get_user_by_email(&con, &email) {
-        Ok(Some((id, _))) => id,
-        _ => return HttpResponse::InternalServerError().body("user do not exist"),
+    let perm: Perms = match perm.parse() {
+        Ok(perm) => perm,
+        Err(_) => return HttpResponse::BadRequest().body("Invalid permission"),
     };
 
     // check if vault could be load or is already_loaded
@@ -478,72 +1203,71 @@ pub async fn share_vault_query(
         return HttpResponse::InternalServerError().body("Failed to get vault");
     }
 
+    let con = CONNECTION.lock().unwrap();
+
+    // test if other user exist
+    let email = canonical_email(&email);
+    let id = match get_user_by_email(&con, &email) {
+        Ok(Some((id, _))) => id,
+        _ => return HttpResponse::InternalServerError().body("user do not exist"),
+    };
+    // Vaults are only shared with confirmed addresses
+    if !is_email_verified(&con, id).unwrap_or(false) {
+        return HttpResponse::BadRequest().body("user has not confirmed their email address");
+    }
+
     // get vault cache
     let vault_cache = match VAULTS_CACHE.get(&vault_info.get_name()) {
         Some(vault_cache) => vault_cache,
         None => return HttpResponse::InternalServerError().body("Failed to get vault"),
     };
 
-    let mut vault = vault_cache.lock().unwrap();
+    let vault = vault_cache.lock().unwrap();
 
     if !vault.perms.contains_key(&jwt.id) || vault.perms.get(&jwt.id).unwrap() < &Perms::Admin {
-        return HttpResponse::Unauthorized()
-            .body("You do not have permission to delete this vault");
+        return HttpResponse::Unauthorized().body("You do not have permission to share this vault");
     }
-
-    vault.perms.insert(id, Perms::from_str(&perm));
-
-    let keys = vault.vault_key.clone();
-    let perms = vault.perms.clone();
-
-    // save perms of the other user
-    if vault_info.set_perms(keys.as_slice(), &perms).is_err() {
-        return HttpResponse::InternalServerError().body("Failed to set vault");
+    if !vault.perms[&jwt.id].can_grant(&perm, None) {
+        return HttpResponse::Unauthorized().body("You cannot give this role");
+    }
+    if vault.perms.contains_key(&id) {
+        return HttpResponse::Conflict().body("user is already a member of this vault");
     }
 
-    // case if the other user is connected
-    if let Some(key) = EMAIL_TO_SESSION_KEY.get(&email) {
-        let user_key = {
-            SESSION_CACHE
-                .get(&key)
-                .unwrap()
-                .lock()
-                .unwrap()
-                .user_key
-                .clone()
-        };
-
-        // save access of vault_key using the private key of the other user
-        if vault_info
-            .save_key(keys.as_slice(), user_key.as_slice(), id)
-            .is_err()
-        {
-            return HttpResponse::InternalServerError().body("Failed to save vault");
+    // Sealed to their public key, or pending until they have one
+    let public_key = match get_public_key(&con, id) {
+        Ok(public_key) => public_key,
+        Err(e) => {
+            eprintln!("Failed to read the public key of user {}: {}", id, e);
+            return HttpResponse::InternalServerError().body("Failed to share vault");
         }
+    };
+    let seal = match &public_key {
+        Some(public_key) => InvitationSeal::Recipient(public_key),
+        None => InvitationSeal::Pending,
+    };
 
-        // Persist the vault in the database
-        match create_vault(&con, &vault_info, id) {
-            Ok(res) => HttpResponse::Ok().json(json!({
-                "message": format!("Vault '{}' shared successfully!", res.name),
-                "vault_id": res.creator_id.clone(),
-                "date": res.date,
-            })),
-            Err(_) => return HttpResponse::InternalServerError().body("Error creating vault."),
-        };
-
-        HttpResponse::Ok().json("")
-    } else {
-        let to_share = (vault_info.clone(), keys);
-        if let Some(pending) = PENDING_SHARE_CACHE.get(&email) {
-            let mut pending = pending.lock().unwrap();
-            pending.push(to_share);
-        } else {
-            PENDING_SHARE_CACHE.insert(email, Arc::new(Mutex::new(vec![to_share])));
+    // The vault is only added to theirs once they accept
+    match create_invitation(&con, seal, &vault.info, jwt.id, id, &perm, &vault.vault_key) {
+        Ok(invitation_id) => HttpResponse::Ok().json(json!({
+            "success": true,
+            "message": format!("Invitation to '{}' sent to {}", vault.info.name, email),
+            "invitation_id": invitation_id,
+        })),
+        Err(e) => {
+            eprintln!(
+                "Failed to invite user {} to vault {}: {}",
+                id,
+                vault.info.get_name(),
+                e
+            );
+            HttpResponse::InternalServerError().body("Failed to share vault")
         }
-        HttpResponse::Ok().json("")
     }
 }
 
+/// Removes a member from a vault. They may have kept the vault key, the
+/// answer offers to rotate it.
 pub async fn remove_user_from_vault_query(
     req: HttpRequest,
     data: web::Json<(VaultInfo, String)>,
@@ -563,7 +1287,7 @@ pub async fn remove_user_from_vault_query(
     }
 
     let con = CONNECTION.lock().unwrap();
-    let id_to_remove = match get_user_by_email(&con, &email_to_remove) {
+    let id_to_remove = match get_user_by_email(&con, &canonical_email(&email_to_remove)) {
         Ok(Some((id, _))) => id,
         _ => return HttpResponse::InternalServerError().body("user do not exist"),
     };
@@ -582,32 +1306,114 @@ pub async fn remove_user_from_vault_query(
             _ => return HttpResponse::InternalServerError().body("Failed to get vault"),
         };
 
-        if p1 < p2 {
-            return HttpResponse::InternalServerError().body("Failed to get vault");
+        if !p1.can_manage(p2) {
+            return HttpResponse::Unauthorized()
+                .body("You do not have permission to remove this member");
         }
         perms.remove(&id_to_remove);
     }
-    let key = vault.vault_key.as_slice();
-    if vault_info.set_perms(key, &vault.perms).is_err() {
+    if vault_info
+        .set_perms(&vault.vault_key, &vault.perms)
+        .is_err()
+    {
         return HttpResponse::InternalServerError().body("Failed to set vault");
     }
 
     if fs::remove_file(vault_info.get_key_path(id_to_remove)).is_err() {
         return HttpResponse::InternalServerError().body("Failed to remove file");
     }
+    let _ = fs::remove_file(vault_info.get_handoff_path(id_to_remove));
 
     match remove_vault(&con, &vault_info, id_to_remove) {
-        Ok(_) => HttpResponse::Ok().json(""),
+        Ok(_) => HttpResponse::Ok().json(json!({
+            "success": true,
+            "message": "Member removed, rotate the vault key so the one they had stops working",
+            "rotate_key": format!("/vaults/{}/rotate-key", vault_info.get_name()),
+        })),
         Err(_) => HttpResponse::InternalServerError().body("Failed to remove vault"),
     }
 }
 
+/// Changes the role of a member. Admins manage the members below them, only
+/// the Creator grants Admin, and Creator only changes hands through an
+/// ownership transfer.
+pub async fn change_perms_query(
+    req: HttpRequest,
+    data: web::Json<(VaultInfo, String, String)>,
+) -> impl Responder {
+    let jwt = match get_user_from_cookie(&req) {
+        Some(jwt) => jwt,
+        None => return HttpResponse::Unauthorized().body("Invalid email or password"),
+    };
+
+    let (vault_info, email, perm) = data.into_inner();
+
+    let perm: Perms = match perm.parse() {
+        Ok(perm) => perm,
+        Err(_) => return HttpResponse::BadRequest().body("Invalid permission"),
+    };
+
+    if load_vault(req, web::Json(vault_info.clone()))
+        .await
+        .is_err()
+    {
+        return HttpResponse::InternalServerError().body("Failed to get vault");
+    }
+
+    let con = CONNECTION.lock().unwrap();
+    let email = canonical_email(&email);
+    // Unknown addresses are answered as non-members are
+    let id = match get_user_by_email(&con, &email) {
+        Ok(Some((id, _))) => id,
+        Ok(None) => return HttpResponse::NotFound().body("user is not a member of this vault"),
+        Err(_) => return HttpResponse::InternalServerError().body("Failed to get vault"),
+    };
+
+    let cache = match VAULTS_CACHE.get(&vault_info.get_name()) {
+        Some(cache) => cache,
+        None => return HttpResponse::InternalServerError().body("Failed to get vault"),
+    };
+    let mut vault = cache.lock().unwrap();
+
+    if id == jwt.id {
+        return HttpResponse::BadRequest().body("You cannot change your own role");
+    }
+    let (granter, current) = match (vault.perms.get(&jwt.id), vault.perms.get(&id)) {
+        (Some(granter), Some(current)) => (granter, current),
+        (None, _) => {
+            return HttpResponse::Unauthorized().body("You are not a member of this vault")
+        }
+        (_, None) => return HttpResponse::NotFound().body("user is not a member of this vault"),
+    };
+    if !granter.can_grant(&perm, Some(current)) {
+        return HttpResponse::Unauthorized().body("You cannot give this role to this member");
+    }
+
+    let mut perms = vault.perms.clone();
+    perms.insert(id, perm.clone());
+    if let Err(e) = vault.info.set_perms(&vault.vault_key, &perms) {
+        eprintln!(
+            "Failed to change the role of user {} in vault {}: {}",
+            id,
+            vault.info.get_name(),
+            e
+        );
+        return HttpResponse::InternalServerError().body("Failed to set vault");
+    }
+    vault.perms = perms;
+
+    HttpResponse::Ok().json(json!({
+        "success": true,
+        "message": format!("{} is now {} in '{}'", email, perm, vault.info.name),
+        "perm": perm,
+    }))
+}
+
 pub async fn delete_vault_query(
     req: HttpRequest,
     vault_info: web::Json<VaultInfo>,
 ) -> impl Responder {
     if let Some(jwt) = get_user_from_cookie(&req) {
-        let con = CONNECTION.lock().unwrap();
         let vault_info = vault_info.into_inner();
         if load_vault(req, web::Json(vault_info.clone()))
             .await
@@ -615,6 +1421,7 @@ pub async fn delete_vault_query(
         {
             return HttpResponse::InternalServerError().body("Failed to get vault");
         }
+        let con = CONNECTION.lock().unwrap();
         let cache = match VAULTS_CACHE.get(&vault_info.get_name()) {
             Some(cache) => cache,
             None => return HttpResponse::InternalServerError().body("Failed to get vault"),
@@ -631,6 +1438,11 @@ pub async fn delete_vault_query(
                 return HttpResponse::InternalServerError().body("Failed to remove vault");
             }
         }
+        if delete_vault_invitations(&con, &vault_info).is_err()
+            || delete_vault_transfers(&con, &vault_info).is_err()
+        {
+            return HttpResponse::InternalServerError().body("Failed to remove vault");
+        }
 
         if fs::remove_dir_all(vault_info.get_path()).is_err() {
             return HttpResponse::InternalServerError().body("Failed to remove vault");
@@ -643,8 +1455,356 @@ pub async fn delete_vault_query(
 }
 
 pub async fn leave_vault_query(
-    req: HttpRequest,
-    vault_info: web::Json<VaultInfo>,
+    _req: HttpRequest,
+    _vault_info: web::Json<VaultInfo>,
 ) -> impl Responder {
     HttpResponse::Ok().json("")
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::aes_keys::chunked::{ChunkedReader, ChunkedWriter};
+    use crate::backend::server_manager::test_support::{
+        create_test_vault, create_user, status, TestUser,
+    };
+    use actix_web::http::StatusCode;
+
+    const BLOB: &str = "stored.bin";
+    const CONTENT: &[u8] = b"contents of a stored file, over a few segments";
+
+    // A vault with one stored file, and its Creator
+    fn vault_with_blob(email: &str) -> (TestUser, VaultInfo, VaultKey) {
+        let owner = create_user(email, true);
+        let (info, vault_key) = create_test_vault(&owner, &[]);
+        let file = fs::File::create(format!("{}{}", info.get_path(), BLOB)).unwrap();
+        let mut writer = ChunkedWriter::new(file, vault_key.as_bytes(), 16).unwrap();
+        writer.write(CONTENT).unwrap();
+        writer.finish().unwrap();
+        (owner, info, vault_key)
+    }
+
+    // Tells whether every vault file opens with `vault_key`
+    fn opens_with(info: &VaultInfo, vault_key: &VaultKey) -> bool {
+        let blob = fs::File::open(format!("{}{}", info.get_path(), BLOB)).unwrap();
+        let content = ChunkedReader::open(blob, vault_key.as_bytes())
+            .and_then(|mut reader| reader.read_range(0, reader.len()));
+        info.get_perms(vault_key).is_ok()
+            && info.get_file_tree(vault_key).is_ok()
+            && content.as_deref() == Ok(CONTENT)
+    }
+
+    fn assert_no_leftovers(info: &VaultInfo) {
+        assert!(!info.has_pending_swap());
+        let root = PathBuf::from(info.get_path());
+        for dir in [root.clone(), root.join(".vault")] {
+            for entry in fs::read_dir(dir).unwrap() {
+                let path = entry.unwrap().path();
+                assert!(
+                    !path
+                        .extension()
+                        .is_some_and(|ext| ext == STAGED_EXTENSION || ext == REPLACED_EXTENSION),
+                    "{} left behind",
+                    path.display()
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn rotation_moves_every_file_to_the_new_key() {
+        let (owner, info, old_key) = vault_with_blob("rotate-vault@example.com");
+        let keypair = UserKeypair::generate();
+        let new_key = VaultKey::generate();
+        let mut steps = Vec::new();
+
+        info.rotate_key(
+            &old_key,
+            &new_key,
+            || info.save_key_handoff(&new_key, owner.id, Some(&keypair.public_key())),
+            |done, total| steps.push((done, total)),
+        )
+        .unwrap();
+
+        assert!(opens_with(&info, &new_key));
+        assert!(!opens_with(&info, &old_key));
+        assert_eq!(steps.last(), Some(&(3, 3)));
+        assert_no_leftovers(&info);
+
+        let opened = info
+            .open_key(
+                owner.id,
+                owner.user_key.as_bytes(),
+                Kdf::None,
+                Some(&keypair),
+            )
+            .unwrap();
+        assert!(opened == new_key);
+        assert!(!info.has_key_handoff(owner.id));
+    }
+
+    #[test]
+    fn handoffs_sealed_to_the_member_open_with_their_keypair_only() {
+        let (owner, info, _) = vault_with_blob("handoff-sealed@example.com");
+        let keypair = UserKeypair::generate();
+        let new_key = VaultKey::generate();
+        info.save_key_handoff(&new_key, owner.id, Some(&keypair.public_key()))
+            .unwrap();
+
+        let handoff: KeyHandoff =
+            serde_json::from_slice(&fs::read(info.get_handoff_path(owner.id)).unwrap()).unwrap();
+        assert!(handoff.sealed_to_recipient);
+        assert_eq!(
+            &keypair.open_sealed(&handoff.key).unwrap()[..],
+            new_key.as_bytes()
+        );
+        assert!(UserKeypair::generate().open_sealed(&handoff.key).is_err());
+        assert!(info.get_unsealed_handoffs().is_empty());
+
+        assert!(info.open_key_handoff(owner.id, None).is_err());
+        let opened = info.open_key_handoff(owner.id, Some(&keypair)).unwrap();
+        assert!(opened == new_key);
+    }
+
+    #[test]
+    fn handoffs_wait_for_the_member_to_have_a_keypair() {
+        let (owner, info, old_key) = vault_with_blob("handoff-pending@example.com");
+        let new_key = VaultKey::generate();
+        info.rotate_key(
+            &old_key,
+            &new_key,
+            || info.save_key_handoff(&new_key, owner.id, None),
+            |_, _| {},
+        )
+        .unwrap();
+
+        // Nothing opens the new key until it is sealed to the member
+        assert_eq!(info.get_unsealed_handoffs(), [owner.id]);
+        assert!(info
+            .open_key(owner.id, owner.user_key.as_bytes(), Kdf::None, None)
+            .is_err());
+        assert!(info.has_key_handoff(owner.id));
+
+        let keypair = UserKeypair::generate();
+        info.save_key_handoff(&new_key, owner.id, Some(&keypair.public_key()))
+            .unwrap();
+        let opened = info
+            .open_key(
+                owner.id,
+                owner.user_key.as_bytes(),
+                Kdf::None,
+                Some(&keypair),
+            )
+            .unwrap();
+        assert!(opened == new_key);
+        assert!(!info.has_key_handoff(owner.id));
+    }
+
+    #[test]
+    fn rekeyed_links_are_deleted_once_every_member_moved_on() {
+        let (owner, info, old_key) = vault_with_blob("rekey-owner@example.com");
+        let sealed = create_user("rekey-sealed@example.com", true);
+        let linked = create_user("rekey-linked@example.com", true);
+        for user in [&sealed, &linked] {
+            info.save_key(&old_key, user.user_key.as_bytes(), Kdf::None, user.id)
+                .unwrap();
+        }
+        let keypair = UserKeypair::generate();
+        let new_key = VaultKey::generate();
+
+        info.rekey(
+            &old_key,
+            &new_key,
+            &[
+                (owner.id, None),
+                (sealed.id, Some(keypair.public_key().to_vec())),
+                (linked.id, None),
+            ],
+        )
+        .unwrap();
+        assert!(opens_with(&info, &new_key));
+        assert_no_leftovers(&info);
+
+        // The member with a keypair needs no link
+        let opened = info
+            .open_key(
+                sealed.id,
+                sealed.user_key.as_bytes(),
+                Kdf::None,
+                Some(&keypair),
+            )
+            .unwrap();
+        assert!(opened == new_key);
+        assert!(info.follow_rekey_links(old_key.clone()).1);
+
+        for user in [&owner, &linked] {
+            let opened = info
+                .open_key(user.id, user.user_key.as_bytes(), Kdf::None, None)
+                .unwrap();
+            assert!(opened == new_key);
+        }
+        // The old key no longer leads anywhere
+        assert!(!info.follow_rekey_links(old_key).1);
+        assert!(!Path::new(&format!("{}{}", info.get_path(), REKEY_PATH)).exists());
+    }
+
+    #[test]
+    fn an_interrupted_swap_is_finished_on_recovery() {
+        let (owner, info, old_key) = vault_with_blob("recover-swap@example.com");
+        let new_key = VaultKey::generate();
+        let root = PathBuf::from(info.get_path());
+        let mut staged = Vec::new();
+        info.stage_rekey(&root, &old_key, &new_key, &mut staged, &mut |_, _| {})
+            .unwrap();
+        let keypair = UserKeypair::generate();
+        info.save_key_handoff(&new_key, owner.id, Some(&keypair.public_key()))
+            .unwrap();
+
+        // The server stops after swapping the first file only
+        info.write_swap_journal(&staged).unwrap();
+        swap_file(&staged[0]).unwrap();
+        assert!(info.has_pending_swap());
+        assert!(info
+            .rotate_key(&old_key, &new_key, || Ok(()), |_, _| {})
+            .is_err());
+
+        info.recover_swap().unwrap();
+
+        assert!(opens_with(&info, &new_key));
+        assert_no_leftovers(&info);
+        let opened = info
+            .open_key(
+                owner.id,
+                owner.user_key.as_bytes(),
+                Kdf::None,
+                Some(&keypair),
+            )
+            .unwrap();
+        assert!(opened == new_key);
+    }
+
+    #[test]
+    fn a_failed_swap_is_rolled_back() {
+        let (owner, info, old_key) = vault_with_blob("rollback-swap@example.com");
+        let new_key = VaultKey::generate();
+        // A directory where the original of the stored file goes blocks its swap
+        let blocker = PathBuf::from(format!("{}{}", info.get_path(), BLOB))
+            .with_extension(REPLACED_EXTENSION);
+        fs::create_dir_all(blocker.join("blocker")).unwrap();
+
+        assert!(info.rekey(&old_key, &new_key, &[(owner.id, None)]).is_err());
+        fs::remove_dir_all(&blocker).unwrap();
+
+        assert!(opens_with(&info, &old_key));
+        assert_no_leftovers(&info);
+        // The forward link to the key that was never used is gone
+        let opened = info
+            .open_key(owner.id, owner.user_key.as_bytes(), Kdf::None, None)
+            .unwrap();
+        assert!(opened == old_key);
+    }
+
+    async fn change_role(user: &TestUser, info: &VaultInfo, email: &str, perm: &str) -> StatusCode {
+        let req = user.request();
+        let form = web::Json((info.clone(), email.to_string(), perm.to_string()));
+        status(change_perms_query(req.clone(), form).await, &req)
+    }
+
+    // The role of a member as saved in the vault
+    fn role(info: &VaultInfo, vault_key: &VaultKey, id: u32) -> Option<Perms> {
+        info.get_perms(vault_key).unwrap().get(&id).cloned()
+    }
+
+    #[actix_web::test]
+    async fn admins_cannot_raise_members_to_admin_or_creator() {
+        let owner = create_user("perms-raise-owner@example.com", true);
+        let admin = create_user("perms-raise-admin@example.com", true);
+        let writer = create_user("perms-raise-writer@example.com", true);
+        let (info, vault_key) =
+            create_test_vault(&owner, &[(&admin, Perms::Admin), (&writer, Perms::Write)]);
+
+        for perm in ["Admin", "Creator"] {
+            assert_eq!(
+                change_role(&admin, &info, &writer.email, perm).await,
+                StatusCode::UNAUTHORIZED
+            );
+        }
+        // Creator only changes hands through a transfer
+        assert_eq!(
+            change_role(&owner, &info, &writer.email, "Creator").await,
+            StatusCode::UNAUTHORIZED
+        );
+        assert_eq!(role(&info, &vault_key, writer.id), Some(Perms::Write));
+
+        assert_eq!(
+            change_role(&admin, &info, &writer.email, "Read").await,
+            StatusCode::OK
+        );
+        assert_eq!(
+            change_role(&owner, &info, &writer.email, "Admin").await,
+            StatusCode::OK
+        );
+        assert_eq!(role(&info, &vault_key, writer.id), Some(Perms::Admin));
+    }
+
+    #[actix_web::test]
+    async fn admins_cannot_demote_other_admins_or_the_creator() {
+        let owner = create_user("perms-demote-owner@example.com", true);
+        let admin = create_user("perms-demote-admin@example.com", true);
+        let other = create_user("perms-demote-other@example.com", true);
+        let (info, vault_key) =
+            create_test_vault(&owner, &[(&admin, Perms::Admin), (&other, Perms::Admin)]);
+
+        assert_eq!(
+            change_role(&admin, &info, &other.email, "Read").await,
+            StatusCode::UNAUTHORIZED
+        );
+        assert_eq!(
+            change_role(&admin, &info, &owner.email, "Read").await,
+            StatusCode::UNAUTHORIZED
+        );
+        assert_eq!(role(&info, &vault_key, other.id), Some(Perms::Admin));
+        assert_eq!(role(&info, &vault_key, owner.id), Some(Perms::Creator));
+
+        assert_eq!(
+            change_role(&owner, &info, &other.email, "Read").await,
+            StatusCode::OK
+        );
+        assert_eq!(role(&info, &vault_key, other.id), Some(Perms::Read));
+    }
+
+    #[actix_web::test]
+    async fn members_cannot_change_their_own_role() {
+        let owner = create_user("perms-self-owner@example.com", true);
+        let admin = create_user("perms-self-admin@example.com", true);
+        let (info, vault_key) = create_test_vault(&owner, &[(&admin, Perms::Admin)]);
+
+        assert_eq!(
+            change_role(&admin, &info, &admin.email, "Read").await,
+            StatusCode::BAD_REQUEST
+        );
+        assert_eq!(
+            change_role(&owner, &info, &owner.email, "Admin").await,
+            StatusCode::BAD_REQUEST
+        );
+        assert_eq!(role(&info, &vault_key, admin.id), Some(Perms::Admin));
+        assert_eq!(role(&info, &vault_key, owner.id), Some(Perms::Creator));
+    }
+
+    #[actix_web::test]
+    async fn roles_of_missing_members_are_not_found() {
+        let owner = create_user("perms-missing-owner@example.com", true);
+        let outsider = create_user("perms-missing-outsider@example.com", true);
+        let (info, vault_key) = create_test_vault(&owner, &[]);
+
+        assert_eq!(
+            change_role(&owner, &info, &outsider.email, "Read").await,
+            StatusCode::NOT_FOUND
+        );
+        assert_eq!(
+            change_role(&owner, &info, "perms-missing-nobody@example.com", "Read").await,
+            StatusCode::NOT_FOUND
+        );
+        assert_eq!(role(&info, &vault_key, outsider.id), None);
+    }
+}
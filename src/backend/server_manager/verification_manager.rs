@@ -0,0 +1,317 @@
+use crate::backend::auth::email::{canonical_email, send_verification_code, Timecode};
+use crate::backend::server_manager::account_manager::{get_user_by_email, MAX_CODE_ATTEMPTS};
+use crate::backend::server_manager::global_manager::{CONNECTION, PENDING_VERIFICATION_CACHE};
+use actix_web::{web, HttpResponse, Responder};
+use rusqlite::{params, Connection};
+use serde_json::json;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+// Time before a new verification code can be requested
+const RESEND_DELAY: Duration = Duration::from_secs(60);
+
+/**
+ * Verification code sent to a new account, waiting to be typed back.
+ */
+pub struct PendingVerification {
+    pub timecode: Timecode,
+    pub attempts: u32,
+}
+
+/**
+ * Struct representing the form data for confirming an email address.
+ */
+#[derive(serde::Deserialize, Debug)]
+pub struct VerifyEmailForm {
+    username: String,
+    code: String,
+}
+
+/**
+ * Struct representing the form data for asking for a new verification code.
+ */
+#[derive(serde::Deserialize, Debug)]
+pub struct ResendVerificationForm {
+    username: String,
+}
+
+/**
+ * Tells whether a user confirmed their email address.
+ *
+ * @param conn - The database connection.
+ * @param user_id - The ID of the user.
+ * @return A Result containing whether the address was confirmed.
+ */
+pub fn is_email_verified(conn: &Connection, user_id: u32) -> rusqlite::Result<bool> {
+    conn.query_row(
+        "SELECT email_verified FROM users WHERE id = ?",
+        params![user_id],
+        |row| row.get(0),
+    )
+}
+
+/**
+ * Marks the email address of a user as confirmed.
+ *
+ * @param conn - The database connection.
+ * @param user_id - The ID of the user.
+ * @return A Result indicating success or failure.
+ */
+pub fn set_email_verified(conn: &Connection, user_id: u32) -> rusqlite::Result<()> {
+    conn.execute(
+        "UPDATE users SET email_verified = 1 WHERE id = ?",
+        params![user_id],
+    )?;
+    Ok(())
+}
+
+/**
+ * Mails a verification code to a new account and keeps it until it is typed
+ * back or expires, replacing any previous code.
+ *
+ * @param email - The canonical email of the account.
+ * @return Result<(), String> - An error message if the mail could not be sent.
+ */
+pub fn start_email_verification(email: &str) -> Result<(), String> {
+    let timecode = send_verification_code(email).map_err(|e| e.to_string())?;
+    PENDING_VERIFICATION_CACHE.insert(
+        email.to_string(),
+        Arc::new(Mutex::new(PendingVerification {
+            timecode,
+            attempts: 0,
+        })),
+    );
+    Ok(())
+}
+
+/**
+ * Endpoint confirming the email address of a new account with the code it
+ * received. The account can log in and be shared with afterwards.
+ *
+ * @param form - The email and the code.
+ * @return An HTTP response indicating the result of the operation.
+ */
+pub async fn verify_email_query(form: web::Json<VerifyEmailForm>) -> impl Responder {
+    let email = canonical_email(&form.username);
+    let pending = match PENDING_VERIFICATION_CACHE.get(&email) {
+        Some(pending) => pending,
+        None => return HttpResponse::Unauthorized().json("unknown or expired code"),
+    };
+    let mut pending = pending.lock().unwrap();
+    if !pending.timecode.is_valid() {
+        drop(pending);
+        PENDING_VERIFICATION_CACHE.invalidate(&email);
+        return HttpResponse::Unauthorized().json("unknown or expired code");
+    }
+
+    if !pending.timecode.matches(form.code.trim()) {
+        pending.attempts += 1;
+        let remaining = MAX_CODE_ATTEMPTS.saturating_sub(pending.attempts);
+        if remaining == 0 {
+            drop(pending);
+            PENDING_VERIFICATION_CACHE.invalidate(&email);
+            return HttpResponse::TooManyRequests().json("too many wrong codes, ask for a new one");
+        }
+        return HttpResponse::Unauthorized().json(json!({
+            "success": false,
+            "message": "invalid code",
+            "remaining_attempts": remaining
+        }));
+    }
+    drop(pending);
+    PENDING_VERIFICATION_CACHE.invalidate(&email);
+
+    let conn = CONNECTION.lock().unwrap();
+    let verified = get_user_by_email(&conn, &email).and_then(|user| match user {
+        Some((user_id, _)) => set_email_verified(&conn, user_id).map(|()| true),
+        None => Ok(false),
+    });
+    match verified {
+        Ok(true) => HttpResponse::Ok().json(json!({
+            "success": true,
+            "message": "Email address confirmed"
+        })),
+        Ok(false) => HttpResponse::Unauthorized().json("unknown or expired code"),
+        Err(e) => {
+            eprintln!("Failed to confirm the email of {}: {}", email, e);
+            HttpResponse::InternalServerError().json("failed to confirm the email address")
+        }
+    }
+}
+
+/**
+ * Endpoint sending a new verification code to an account not confirmed yet.
+ *
+ * The answer is the same whether the account exists or not.
+ *
+ * @param form - The email of the account.
+ * @return An HTTP response indicating the result of the operation.
+ */
+pub async fn resend_verification_query(form: web::Json<ResendVerificationForm>) -> impl Responder {
+    let email = canonical_email(&form.username);
+    if let Some(pending) = PENDING_VERIFICATION_CACHE.get(&email) {
+        let sent_at = pending.lock().unwrap().timecode.time;
+        if sent_at
+            .elapsed()
+            .is_ok_and(|elapsed| elapsed < RESEND_DELAY)
+        {
+            return HttpResponse::TooManyRequests().json("a code was sent recently, wait a minute");
+        }
+    }
+
+    let unverified = {
+        let conn = CONNECTION.lock().unwrap();
+        match get_user_by_email(&conn, &email) {
+            Ok(Some((user_id, _))) => is_email_verified(&conn, user_id).map(|verified| !verified),
+            Ok(None) => Ok(false),
+            Err(e) => Err(e),
+        }
+    };
+    match unverified {
+        Ok(true) => {
+            if let Err(e) = start_email_verification(&email) {
+                eprintln!("Failed to send the verification code of {}: {}", email, e);
+                return HttpResponse::InternalServerError().json("failed to send the code");
+            }
+        }
+        Ok(false) => {}
+        Err(e) => {
+            eprintln!("Failed to read user {}: {}", email, e);
+            return HttpResponse::InternalServerError().json("failed to send the code");
+        }
+    }
+
+    HttpResponse::Ok().json(json!({
+        "success": true,
+        "message": "If the account waits for a confirmation, a new code was sent"
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::auth::email::CODE_VALIDITY;
+    use crate::backend::server_manager::test_support::{create_test_vault, create_user, status};
+    use crate::backend::server_manager::vault_manager::share_vault_query;
+    use actix_web::http::StatusCode;
+    use actix_web::test::TestRequest;
+    use std::time::SystemTime;
+
+    const CODE: &str = "123456";
+
+    // Replaces the code mailed to an account with a known one, sent `age` ago
+    fn pending_code(email: &str, age: Duration) {
+        let mut timecode = Timecode::new(CODE.to_string(), email.to_string());
+        timecode.time = SystemTime::now() - age;
+        PENDING_VERIFICATION_CACHE.insert(
+            email.to_string(),
+            Arc::new(Mutex::new(PendingVerification {
+                timecode,
+                attempts: 0,
+            })),
+        );
+    }
+
+    async fn verify(email: &str, code: &str) -> StatusCode {
+        let form = VerifyEmailForm {
+            username: email.to_string(),
+            code: code.to_string(),
+        };
+        let req = TestRequest::default().to_http_request();
+        status(verify_email_query(web::Json(form)).await, &req)
+    }
+
+    async fn resend(email: &str) -> StatusCode {
+        let form = ResendVerificationForm {
+            username: email.to_string(),
+        };
+        let req = TestRequest::default().to_http_request();
+        status(resend_verification_query(web::Json(form)).await, &req)
+    }
+
+    fn verified(id: u32) -> bool {
+        is_email_verified(&CONNECTION.lock().unwrap(), id).unwrap()
+    }
+
+    #[actix_web::test]
+    async fn a_code_confirms_the_address_until_it_expires() {
+        let user = create_user("verify-expiry@example.com", false);
+
+        pending_code(&user.email, CODE_VALIDITY + Duration::from_secs(1));
+        assert_eq!(verify(&user.email, CODE).await, StatusCode::UNAUTHORIZED);
+        assert!(PENDING_VERIFICATION_CACHE.get(&user.email).is_none());
+        assert!(!verified(user.id));
+
+        pending_code(&user.email, CODE_VALIDITY - Duration::from_secs(5));
+        // Looked up by canonical email
+        assert_eq!(
+            verify(" Verify-Expiry@Example.com ", CODE).await,
+            StatusCode::OK
+        );
+        assert!(verified(user.id));
+        assert_eq!(verify(&user.email, CODE).await, StatusCode::UNAUTHORIZED);
+    }
+
+    #[actix_web::test]
+    async fn wrong_codes_drop_the_code() {
+        let user = create_user("verify-attempts@example.com", false);
+        pending_code(&user.email, Duration::ZERO);
+
+        for _ in 1..MAX_CODE_ATTEMPTS {
+            assert_eq!(
+                verify(&user.email, "000000").await,
+                StatusCode::UNAUTHORIZED
+            );
+        }
+        assert_eq!(
+            verify(&user.email, "000000").await,
+            StatusCode::TOO_MANY_REQUESTS
+        );
+        // The right code comes too late
+        assert_eq!(verify(&user.email, CODE).await, StatusCode::UNAUTHORIZED);
+        assert!(!verified(user.id));
+    }
+
+    #[actix_web::test]
+    async fn new_codes_are_throttled() {
+        let user = create_user("verify-resend@example.com", false);
+        pending_code(&user.email, Duration::from_secs(10));
+        assert_eq!(resend(&user.email).await, StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(verify(&user.email, CODE).await, StatusCode::OK);
+
+        let other = create_user("verify-resend-late@example.com", false);
+        pending_code(&other.email, RESEND_DELAY + Duration::from_secs(1));
+        assert_eq!(resend(&other.email).await, StatusCode::OK);
+        // A fresh code replaced the old one
+        let pending = PENDING_VERIFICATION_CACHE.get(&other.email).unwrap();
+        let pending = pending.lock().unwrap();
+        assert!(pending.timecode.time.elapsed().unwrap() < RESEND_DELAY);
+        assert_eq!(pending.attempts, 0);
+    }
+
+    #[actix_web::test]
+    async fn vaults_are_only_shared_with_confirmed_addresses() {
+        let owner = create_user("verify-share-owner@example.com", true);
+        let unconfirmed = create_user("verify-share-new@example.com", false);
+        let (vault, _) = create_test_vault(&owner, &[]);
+
+        let share = |email: &str| web::Json((vault.clone(), email.to_string(), "Read".to_string()));
+        let req = owner.request();
+        assert_eq!(
+            status(
+                share_vault_query(req.clone(), share(&unconfirmed.email)).await,
+                &req
+            ),
+            StatusCode::BAD_REQUEST
+        );
+
+        set_email_verified(&CONNECTION.lock().unwrap(), unconfirmed.id).unwrap();
+        assert_eq!(
+            status(
+                share_vault_query(req.clone(), share(&unconfirmed.email)).await,
+                &req
+            ),
+            StatusCode::OK
+        );
+    }
+}
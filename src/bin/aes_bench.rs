@@ -0,0 +1,64 @@
+use s4_vaultify::backend::aes_keys::cipher::gcm::{generate_nonce, AesGcm};
+use s4_vaultify::backend::aes_keys::cipher::{Aes256, Backend, BlockCipher};
+
+use std::env;
+use std::time::Instant;
+
+// Amount of data processed per measurement, in MiB (can be overridden with the first argument)
+const DEFAULT_MIB: usize = 64;
+
+// Prints the throughput of an operation that processed `bytes` bytes
+fn report(label: &str, bytes: usize, start: Instant) {
+    let seconds = start.elapsed().as_secs_f64();
+    let mib = bytes as f64 / (1024.0 * 1024.0);
+    println!(
+        "{:<24} {:>8.1} MiB/s ({:.2}s)",
+        label,
+        mib / seconds,
+        seconds
+    );
+}
+
+fn main() {
+    let mib = env::args()
+        .nth(1)
+        .and_then(|arg| arg.parse::<usize>().ok())
+        .unwrap_or(DEFAULT_MIB);
+    let size = mib * 1024 * 1024;
+    let key: Vec<u8> = (0..32).collect();
+
+    println!("Detected backend: {}", Backend::detect().name());
+    println!("Processing {} MiB per measurement\n", mib);
+
+    for backend in [Backend::Bitsliced, Backend::AesNi] {
+        let Some(aes) = Aes256::with_backend(&key, backend) else {
+            println!("{:<24} not supported by this CPU", backend.name());
+            continue;
+        };
+
+        // 1. Raw block encryption and decryption
+        let mut blocks = vec![[0u8; 16]; size / 16];
+        let start = Instant::now();
+        aes.encrypt_blocks(&mut blocks);
+        report(&format!("{} encrypt", backend.name()), size, start);
+
+        let start = Instant::now();
+        aes.decrypt_blocks(&mut blocks);
+        report(&format!("{} decrypt", backend.name()), size, start);
+        assert!(blocks.iter().all(|block| *block == [0u8; 16]));
+
+        // 2. AES-256-GCM as used by the blob container
+        let gcm = AesGcm::with_cipher(aes);
+        let data = vec![0u8; size];
+        let nonce = generate_nonce();
+        let start = Instant::now();
+        let sealed = gcm.seal(&nonce, &[], &data);
+        report(&format!("{} gcm seal", backend.name()), size, start);
+
+        let start = Instant::now();
+        let opened = gcm.open(&nonce, &[], &sealed).expect("Failed to open");
+        report(&format!("{} gcm open", backend.name()), size, start);
+        assert_eq!(opened.len(), size);
+        println!();
+    }
+}
@@ -1,5 +1,4 @@
-use s4_vaultify::backend::aes_keys::crypted_key::*;
-use s4_vaultify::backend::aes_keys::decrypted_key::*;
+use s4_vaultify::backend::aes_keys::cipher::gcm::{decrypt, encrypt};
 use s4_vaultify::backend::aes_keys::keys_password::*;
 
 use std::fs;
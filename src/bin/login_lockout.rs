@@ -0,0 +1,109 @@
+// Admin tool for the login brute-force protection
+//
+// Lists the failed login counters, lifts the lockout of an account or of a
+// client address, and prints the audit log of failed logins. It works on the
+// database directly, so it can be used while the server is running.
+//
+// Usage:
+//   login_lockout list
+//   login_lockout reset <email>
+//   login_lockout reset-ip <address>
+//   login_lockout audit [email] [--limit N]
+use s4_vaultify::backend::server_manager::global_manager::{init_server_config, CONNECTION};
+use s4_vaultify::backend::server_manager::lockout_manager::{
+    get_failed_logins, list_lockouts, reset_lockout, LockoutScope,
+};
+use s4_vaultify::backend::server_manager::token;
+use std::process::exit;
+
+const USAGE: &str =
+    "Usage: login_lockout list | reset <email> | reset-ip <address> | audit [email] [--limit N]";
+
+fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let command = match args.first() {
+        Some(command) => command.as_str(),
+        None => {
+            eprintln!("{}", USAGE);
+            exit(2);
+        }
+    };
+
+    init_server_config();
+    let conn = CONNECTION.lock().unwrap();
+
+    let result = match (command, &args[1..]) {
+        ("list", []) => list_lockouts(&conn).map(|entries| {
+            let now = token::now();
+            for entry in entries {
+                let state = if entry.locked_until > now {
+                    format!("locked for {} s", entry.locked_until - now)
+                } else {
+                    "not locked".to_string()
+                };
+                println!(
+                    "{:<8} {:<40} {:>5} failures, last at {}, {}",
+                    entry.scope, entry.subject, entry.failures, entry.last_failure, state
+                );
+            }
+        }),
+        ("reset", [email]) => reset_lockout(&conn, LockoutScope::Account, email)
+            .map(|found| report_reset(found, email)),
+        ("reset-ip", [address]) => reset_lockout(&conn, LockoutScope::Ip, address)
+            .map(|found| report_reset(found, address)),
+        ("audit", rest) => {
+            let (email, limit) = match parse_audit_args(rest) {
+                Some(parsed) => parsed,
+                None => {
+                    eprintln!("{}", USAGE);
+                    exit(2);
+                }
+            };
+            get_failed_logins(&conn, email, limit).map(|entries| {
+                for entry in entries {
+                    println!(
+                        "{} {:<40} {:<39} {}",
+                        entry.time,
+                        entry.email,
+                        entry.ip.as_deref().unwrap_or("-"),
+                        entry.reason
+                    );
+                }
+            })
+        }
+        _ => {
+            eprintln!("{}", USAGE);
+            exit(2);
+        }
+    };
+
+    if let Err(e) = result {
+        eprintln!("Database error: {}", e);
+        exit(1);
+    }
+}
+
+fn report_reset(found: bool, subject: &str) {
+    if found {
+        println!("Failed logins of {} cleared", subject);
+    } else {
+        println!("No failed logins recorded for {}", subject);
+    }
+}
+
+// [email] [--limit N], 50 entries by default
+fn parse_audit_args(args: &[String]) -> Option<(Option<&str>, u32)> {
+    let mut email = None;
+    let mut limit = 50;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        if arg == "--limit" {
+            limit = args.next()?.parse().ok()?;
+        } else if email.is_none() {
+            email = Some(arg.as_str());
+        } else {
+            return None;
+        }
+    }
+    Some((email, limit))
+}
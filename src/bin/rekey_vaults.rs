@@ -0,0 +1,137 @@
+// One-shot migration: re-keys the vaults created by a user with a fresh random key
+//
+// Vaults created by older releases derived their key from random bytes passed
+// through a lossy UTF-8 conversion, which threw away most of their entropy.
+// Every vault of which the user is the creator gets a new 256-bit key. It is
+// sealed to the public key of every member; accounts without a keypair yet
+// reach it through a link from the old key, deleted once they have all loaded
+// the vault. Pending invitations are sealed again under the new key.
+//
+// Usage: rekey_vaults <email>, the password is read from stdin.
+// Stop the server first, it must not hold any of the vaults in memory.
+use rusqlite::Connection;
+use s4_vaultify::backend::aes_keys::container::Kdf;
+use s4_vaultify::backend::aes_keys::keypair::UserKeypair;
+use s4_vaultify::backend::aes_keys::vault_key::VaultKey;
+use s4_vaultify::backend::server_manager::account_manager::{
+    get_public_key, get_user_by_email, get_user_keypair, get_user_vaults, unlock_user_key, Perms,
+};
+use s4_vaultify::backend::server_manager::global_manager::{init_server_config, CONNECTION};
+use s4_vaultify::backend::server_manager::invitation_manager::reseal_vault_invitations;
+use s4_vaultify::backend::server_manager::vault_manager::VaultInfo;
+use std::io::{self, BufRead, Write};
+use std::process::exit;
+
+fn main() {
+    let email = match std::env::args().nth(1) {
+        Some(email) => email,
+        None => {
+            eprintln!("Usage: rekey_vaults <email>");
+            exit(2);
+        }
+    };
+
+    print!("Password: ");
+    let _ = io::stdout().flush();
+    let mut password = String::new();
+    if io::stdin().lock().read_line(&mut password).is_err() {
+        eprintln!("Failed to read the password");
+        exit(1);
+    }
+    let password = password.trim_end_matches(['\r', '\n']);
+
+    init_server_config();
+    let conn = CONNECTION.lock().unwrap();
+
+    let (user_id, hash_pw) = match get_user_by_email(&conn, &email) {
+        Ok(Some(user)) => user,
+        _ => {
+            eprintln!("Unknown user {}", email);
+            exit(1);
+        }
+    };
+    if !bcrypt::verify(password, &hash_pw).unwrap_or(false) {
+        eprintln!("Invalid password");
+        exit(1);
+    }
+
+    let (user_key, user_kdf) = match unlock_user_key(&conn, user_id, &email, password) {
+        Ok(unlocked) => unlocked,
+        Err(e) => {
+            eprintln!("Failed to unlock the user key: {}", e);
+            exit(1);
+        }
+    };
+
+    let vaults = match get_user_vaults(&conn, user_id) {
+        Ok(vaults) => vaults,
+        Err(e) => {
+            eprintln!("Failed to list the vaults: {}", e);
+            exit(1);
+        }
+    };
+
+    let keypair = match get_user_keypair(&conn, user_id, user_key.as_bytes()) {
+        Ok(keypair) => keypair,
+        Err(e) => {
+            eprintln!("Failed to open the keypair: {}", e);
+            exit(1);
+        }
+    };
+
+    let mut failed = 0;
+    for info in vaults {
+        match rekey_vault(
+            &conn,
+            &info,
+            user_id,
+            user_key.as_bytes(),
+            user_kdf,
+            keypair.as_ref(),
+        ) {
+            Ok(true) => println!("{} ({}): re-keyed", info.name, info.get_name()),
+            Ok(false) => println!(
+                "{} ({}): skipped, only the creator can re-key it",
+                info.name,
+                info.get_name()
+            ),
+            Err(e) => {
+                failed += 1;
+                eprintln!("{} ({}): {}", info.name, info.get_name(), e);
+            }
+        }
+    }
+    if failed > 0 {
+        exit(1);
+    }
+}
+
+// Re-keys one vault, returns false if the user is not its creator
+fn rekey_vault(
+    conn: &Connection,
+    info: &VaultInfo,
+    user_id: u32,
+    user_key: &[u8],
+    user_kdf: Kdf,
+    keypair: Option<&UserKeypair>,
+) -> Result<bool, String> {
+    // A key change cut short by a crash is finished before this one starts
+    info.recover_swap()?;
+    let old_key = info.open_key(user_id, user_key, user_kdf, keypair)?;
+    let perms = info.get_perms(&old_key)?;
+    if perms.get(&user_id) != Some(&Perms::Creator) {
+        return Ok(false);
+    }
+    let members = perms
+        .keys()
+        .map(|id| get_public_key(conn, *id).map(|public_key| (*id, public_key)))
+        .collect::<rusqlite::Result<Vec<_>>>()
+        .map_err(|e| e.to_string())?;
+
+    let new_key = VaultKey::generate();
+    info.rekey(&old_key, &new_key, &members)?;
+    info.save_key(&new_key, user_key, user_kdf, user_id)?;
+    let _ = std::fs::remove_file(info.get_handoff_path(user_id));
+    reseal_vault_invitations(conn, info, &new_key).map_err(|e| e.to_string())?;
+    Ok(true)
+}
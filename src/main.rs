@@ -2,24 +2,48 @@
 use actix_files::Files;
 use actix_files::NamedFile;
 use actix_web::http::header;
-use actix_web::{guard, web, App, HttpRequest, HttpResponse, HttpServer, Responder};
+use actix_web::{web, App, HttpRequest, HttpResponse, HttpServer, Responder};
 use askama::Template;
 use rusqlite::Result;
 use rustls::Certificate;
 use rustls::PrivateKey;
 use rustls_pemfile::{certs, pkcs8_private_keys};
 use s4_vaultify::backend::server_manager::account_manager::{
-    create_user_query, get_user_vaults, login_user_query, logout_user_query, CreateUserForm, JWT,
+    change_password_query, create_user_query, delete_account_preview_query, delete_account_query,
+    get_user_vaults, list_sessions_query, login_user_query, logout_everywhere_query,
+    logout_user_query, recover_account_query, regenerate_recovery_key_query, revoke_session_query,
+    set_email_2fa_query, verify_login_code_query, CreateUserForm,
+};
+use s4_vaultify::backend::server_manager::api_token_manager::{
+    create_api_token_query, list_api_tokens_query, revoke_api_token_query,
 };
 use s4_vaultify::backend::server_manager::file_manager::file_handler::{
     create_folder_query, download_file_query, get_file_tree_query, remove_file_query,
     remove_folder_query, rename_item_query, upload_file_query,
 };
 use s4_vaultify::backend::server_manager::global_manager::{
-    init_server_config, CONNECTION, SESSION_CACHE,
+    get_user_from_cookie, init_server_config, CONNECTION,
+};
+use s4_vaultify::backend::server_manager::invitation_manager::{
+    accept_invitation_query, cancel_invitation_query, decline_invitation_query,
+    list_invitations_query,
+};
+use s4_vaultify::backend::server_manager::rotation_manager::{
+    rotate_key_query, rotation_status_query,
+};
+use s4_vaultify::backend::server_manager::totp_manager::{
+    totp_backup_codes_query, totp_disable_query, totp_enable_query, totp_setup_query,
+};
+use s4_vaultify::backend::server_manager::transfer_manager::{
+    accept_transfer_query, cancel_transfer_query, decline_transfer_query, list_transfers_query,
+    transfer_ownership_query,
 };
 use s4_vaultify::backend::server_manager::vault_manager::{
-    create_vault_query, delete_vault_query, load_vault_query, share_vault_query, VaultInfo,
+    change_perms_query, create_vault_query, delete_vault_query, load_vault_query,
+    remove_user_from_vault_query, share_vault_query,
+};
+use s4_vaultify::backend::server_manager::verification_manager::{
+    resend_verification_query, verify_email_query,
 };
 use std::fs::File;
 use std::io::BufReader;
@@ -60,6 +84,8 @@ fn load_rustls_config(cert_path: &str, key_path: &str) -> Arc<ServerConfig> {
     Arc::new(config)
 }
 
+// home.html does not render these fields yet
+#[allow(dead_code)]
 #[derive(Template)]
 #[template(path = "home.html")]
 struct HomeTemplate {
@@ -78,12 +104,6 @@ async fn login_page() -> actix_web::Result<NamedFile> {
     Ok(NamedFile::open("../templates/login.html")?)
 }
 
-// Retrieve user from cookie
-fn get_user_from_cookie(req: &HttpRequest) -> Option<JWT> {
-    req.cookie("user_token")
-        .and_then(|cookie| serde_json::from_str(cookie.value()).ok())
-}
-
 // Create user
 async fn create_user(form: web::Json<CreateUserForm>) -> HttpResponse {
     create_user_query(web::Json(form.into_inner())).await
@@ -92,20 +112,19 @@ async fn create_user(form: web::Json<CreateUserForm>) -> HttpResponse {
 // Main route
 
 pub async fn home(req: HttpRequest) -> impl Responder {
+    // The session behind the token is checked by get_user_from_cookie
     if let Some(decoded_jwt) = get_user_from_cookie(&req) {
-        if SESSION_CACHE.get(&decoded_jwt.session_id).is_some() {
-            let html = HomeTemplate {
-                username: decoded_jwt.email.clone(),
-                email: decoded_jwt.email.clone(),
-                vault_info: decoded_jwt
-                    .loaded_vault
-                    .as_ref()
-                    .map_or("No data".to_string(), |v| v.name.clone()),
-            };
-            return HttpResponse::Ok()
-                .content_type("text/html")
-                .body(html.render().unwrap());
-        }
+        let html = HomeTemplate {
+            username: decoded_jwt.email.clone(),
+            email: decoded_jwt.email.clone(),
+            vault_info: decoded_jwt
+                .loaded_vault
+                .as_ref()
+                .map_or("No data".to_string(), |v| v.name.clone()),
+        };
+        return HttpResponse::Ok()
+            .content_type("text/html")
+            .body(html.render().unwrap());
     }
 
     HttpResponse::Found()
@@ -184,7 +203,47 @@ async fn main() -> std::io::Result<()> {
             // POST routes
             .route("/create-user", web::post().to(create_user))
             .route("/login", web::post().to(login_user_query))
+            .route("/login/verify", web::post().to(verify_login_code_query))
+            .route("/account/password", web::post().to(change_password_query))
+            .route("/account/verify", web::post().to(verify_email_query))
+            .route(
+                "/account/verify/resend",
+                web::post().to(resend_verification_query),
+            )
+            .route("/account/recover", web::post().to(recover_account_query))
+            .route("/account/delete", web::post().to(delete_account_query))
+            .route(
+                "/account/delete/preview",
+                web::post().to(delete_account_preview_query),
+            )
+            .route(
+                "/account/recovery-key",
+                web::post().to(regenerate_recovery_key_query),
+            )
+            .route("/account/email-2fa", web::post().to(set_email_2fa_query))
+            .route("/account/totp/setup", web::post().to(totp_setup_query))
+            .route("/account/totp/enable", web::post().to(totp_enable_query))
+            .route("/account/totp/disable", web::post().to(totp_disable_query))
+            .route(
+                "/account/totp/backup-codes",
+                web::post().to(totp_backup_codes_query),
+            )
             .route("/logout", web::post().to(logout_user_query))
+            .route("/logout-all", web::post().to(logout_everywhere_query))
+            .route("/account/sessions", web::get().to(list_sessions_query))
+            .route(
+                "/account/sessions/revoke",
+                web::post().to(revoke_session_query),
+            )
+            .route("/account/api-tokens", web::get().to(list_api_tokens_query))
+            .route(
+                "/account/api-tokens",
+                web::post().to(create_api_token_query),
+            )
+            .route(
+                "/account/api-tokens/revoke",
+                web::post().to(revoke_api_token_query),
+            )
             .route("/create-vault", web::post().to(create_vault_query))
             .route("/load-vault", web::post().to(load_vault_query))
             .route("/delete-vault", web::post().to(delete_vault_query))
@@ -193,6 +252,40 @@ async fn main() -> std::io::Result<()> {
                 web::post().to(get_file_tree_query),
             )
             .route("/share-vault", web::post().to(share_vault_query))
+            .route("/change-perms", web::post().to(change_perms_query))
+            .route(
+                "/transfer-ownership",
+                web::post().to(transfer_ownership_query),
+            )
+            .route("/transfers", web::get().to(list_transfers_query))
+            .route("/transfers/accept", web::post().to(accept_transfer_query))
+            .route("/transfers/decline", web::post().to(decline_transfer_query))
+            .route("/transfers/cancel", web::post().to(cancel_transfer_query))
+            .route(
+                "/remove-from-vault",
+                web::post().to(remove_user_from_vault_query),
+            )
+            .route(
+                "/vaults/{vault_id}/rotate-key",
+                web::post().to(rotate_key_query),
+            )
+            .route(
+                "/vaults/{vault_id}/rotate-key",
+                web::get().to(rotation_status_query),
+            )
+            .route("/invitations", web::get().to(list_invitations_query))
+            .route(
+                "/invitations/accept",
+                web::post().to(accept_invitation_query),
+            )
+            .route(
+                "/invitations/decline",
+                web::post().to(decline_invitation_query),
+            )
+            .route(
+                "/invitations/cancel",
+                web::post().to(cancel_invitation_query),
+            )
             .route(
                 "/vaults/{vault_id}/create-folder",
                 web::post().to(create_folder_query),